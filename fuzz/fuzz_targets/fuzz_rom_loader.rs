@@ -0,0 +1,28 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nes::bus::RomBus;
+use nes::cpu::cpu::CPU;
+use nes::ppu::StubPpu;
+use nes::rom::rom_from_bytes;
+
+// Malformed cartridges have to be rejected with an error, never with a panic. Cartridges that
+// load are read back in full, to catch out of bounds accesses in the mappers, and then run for a
+// short while: whatever the program does, the cpu may fail with an error but not panic.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(rom) = rom_from_bytes(data, None) {
+        for addr in 0x8000..=0xffff {
+            rom.prg_read(addr);
+        }
+        for addr in 0x0000..0x2000 {
+            rom.chr_read(addr);
+        }
+
+        let mut bus = RomBus::new();
+        bus.set_rom(rom);
+        bus.set_ppu(Box::new(StubPpu::new()));
+        let mut cpu = CPU::new(bus, false);
+        cpu.reset();
+        let _ = cpu.run_for_cycles(10_000);
+    }
+});
@@ -0,0 +1,312 @@
+// The APU registers at $4000..=$4013, $4015 and $4017. No sound is generated yet: the registers are
+// latched, and the parts games poll are emulated, i.e. the length counters and the DMC sample
+// progress reported by $4015, and the frame and DMC interrupts.
+// See: https://www.nesdev.org/wiki/APU
+
+// Length counter values, indexed by the upper 5 bits of the fourth register of a channel.
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Cpu cycles per sample bit of the DMC, indexed by the rate in $4010 (NTSC).
+const DMC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+
+// Frame counter steps, in cpu cycles since it was reset (NTSC). The length counters are clocked
+// on the half frames.
+const HALF_FRAME: u32 = 14913;
+const FOUR_STEP_END: u32 = 29829;
+const FIVE_STEP_END: u32 = 37281;
+
+// Channels with a length counter, in the order of their bits in $4015.
+const PULSE_1: usize = 0;
+const PULSE_2: usize = 1;
+const TRIANGLE: usize = 2;
+const NOISE: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct Apu {
+    // $4000..=$4013, as last written.
+    registers: [u8; 0x14],
+    // Channels enabled in $4015.
+    enabled: u8,
+    length: [u8; 4],
+    // DMC sample bytes left to play, and cpu cycles into the current byte.
+    dmc_bytes: u16,
+    dmc_cycles: u16,
+    dmc_irq: bool,
+    // Address of the next sample byte, the last byte fetched, and the fetch the bus has to do.
+    dmc_address: u16,
+    dmc_buffer: u8,
+    dmc_request: Option<u16>,
+    // Frame counter, set up through $4017.
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    cycles: u32,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            registers: [0; 0x14],
+            enabled: 0,
+            length: [0; 4],
+            dmc_bytes: 0,
+            dmc_cycles: 0,
+            dmc_irq: false,
+            dmc_address: 0xc000,
+            dmc_buffer: 0,
+            dmc_request: None,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            cycles: 0,
+        }
+    }
+
+    pub fn register(&self, addr: u16) -> u8 {
+        self.registers[(addr - 0x4000) as usize]
+    }
+
+    pub fn frame_irq(&self) -> bool {
+        self.frame_irq
+    }
+
+    pub fn dmc_irq(&self) -> bool {
+        self.dmc_irq
+    }
+
+    // Halt flag of the channel, which also stops its length counter.
+    fn halted(&self, channel: usize) -> bool {
+        match channel {
+            TRIANGLE => self.registers[0x08] & 0x80 != 0,
+            _ => self.registers[channel * 4] & 0x20 != 0,
+        }
+    }
+
+    fn dmc_sample_length(&self) -> u16 {
+        self.registers[0x13] as u16 * 16 + 1
+    }
+
+    fn start_dmc_sample(&mut self) {
+        self.dmc_bytes = self.dmc_sample_length();
+        self.dmc_address = 0xc000 | (self.registers[0x12] as u16) << 6;
+        self.dmc_cycles = 0;
+        self.dmc_request = Some(self.dmc_address);
+    }
+
+    // The DMC reads its sample bytes from memory through DMA, one before each byte is played.
+    // The bus polls for the address to fetch, and hands the byte back with dmc_fill.
+    pub fn take_dmc_request(&mut self) -> Option<u16> {
+        self.dmc_request.take()
+    }
+
+    pub fn dmc_fill(&mut self, val: u8) {
+        self.dmc_buffer = val;
+        // The address wraps around to 0x8000.
+        self.dmc_address = self.dmc_address.checked_add(1).unwrap_or(0x8000);
+    }
+
+    pub fn dmc_buffer(&self) -> u8 {
+        self.dmc_buffer
+    }
+
+    pub fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4000..=0x4013 => {
+                self.registers[(addr - 0x4000) as usize] = val;
+                match addr {
+                    // The fourth register of a channel loads its length counter, if enabled.
+                    0x4003 | 0x4007 | 0x400b | 0x400f => {
+                        let channel = (addr as usize - 0x4003) / 4;
+                        if self.enabled & (1 << channel) != 0 {
+                            self.length[channel] = LENGTH_TABLE[val as usize >> 3];
+                        }
+                    },
+                    0x4010 if val & 0x80 == 0 => self.dmc_irq = false,
+                    _ => {},
+                }
+            },
+            0x4015 => {
+                self.enabled = val & 0x1f;
+                for channel in [PULSE_1, PULSE_2, TRIANGLE, NOISE] {
+                    if val & (1 << channel) == 0 { self.length[channel] = 0; }
+                }
+                // Enabling the DMC restarts the sample, unless it is still playing.
+                if val & 0x10 == 0 {
+                    self.dmc_bytes = 0;
+                    self.dmc_request = None;
+                } else if self.dmc_bytes == 0 {
+                    self.start_dmc_sample();
+                }
+                self.dmc_irq = false;
+            },
+            0x4017 => {
+                self.five_step = val & 0x80 != 0;
+                self.irq_inhibit = val & 0x40 != 0;
+                if self.irq_inhibit { self.frame_irq = false; }
+                // The 5-step mode clocks the length counters right away.
+                if self.five_step { self.clock_length(); }
+                self.cycles = 0;
+            },
+            _ => {},
+        }
+    }
+
+    // Reading $4015 returns which channels are still playing, and the interrupt flags. It
+    // acknowledges the frame interrupt. Bit 5 is open bus, left to the bus.
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        for channel in [PULSE_1, PULSE_2, TRIANGLE, NOISE] {
+            if self.length[channel] > 0 { status |= 1 << channel; }
+        }
+        if self.dmc_bytes > 0 { status |= 0x10; }
+        if self.frame_irq { status |= 0x40; }
+        if self.dmc_irq { status |= 0x80; }
+        self.frame_irq = false;
+        status
+    }
+
+    fn clock_length(&mut self) {
+        for channel in [PULSE_1, PULSE_2, TRIANGLE, NOISE] {
+            if self.length[channel] > 0 && !self.halted(channel) {
+                self.length[channel] -= 1;
+            }
+        }
+    }
+
+    // The APU is clocked every other cpu cycle, but its timings are counted in cpu cycles here.
+    pub fn tick(&mut self, cycles: u16) {
+        for _ in 0..cycles {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        self.cycles += 1;
+        let end = if self.five_step { FIVE_STEP_END } else { FOUR_STEP_END };
+        if self.cycles == HALF_FRAME || self.cycles == end {
+            self.clock_length();
+        }
+        if self.cycles == end && !self.five_step && !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+        if self.cycles > end {
+            self.cycles = 0;
+        }
+
+        if self.dmc_bytes == 0 { return; }
+        self.dmc_cycles += 1;
+        if self.dmc_cycles < DMC_RATES[self.registers[0x10] as usize & 0x0f] * 8 { return; }
+        self.dmc_cycles = 0;
+        self.dmc_bytes -= 1;
+        if self.dmc_bytes > 0 {
+            self.dmc_request = Some(self.dmc_address);
+            return;
+        }
+        // End of the sample: loop, or raise the interrupt if enabled.
+        if self.registers[0x10] & 0x40 != 0 {
+            self.start_dmc_sample();
+        } else if self.registers[0x10] & 0x80 != 0 {
+            self.dmc_irq = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_length_counter() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4003, 0x08); // not enabled, ignored
+        assert_eq!(apu.read_status(), 0x00);
+
+        apu.write_register(0x4015, 0x05); // pulse 1 and triangle
+        apu.write_register(0x4003, 0x18); // length 2
+        apu.write_register(0x4008, 0x80); // halted
+        apu.write_register(0x400b, 0x18);
+        assert_eq!(apu.read_status(), 0x05);
+
+        // Clocked twice per frame.
+        apu.tick(29830);
+        assert_eq!(apu.read_status() & 0x0f, 0x04);
+
+        apu.write_register(0x4015, 0x00);
+        assert_eq!(apu.read_status(), 0x00);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new();
+        apu.tick(29828);
+        assert!(!apu.frame_irq());
+        apu.tick(1);
+        assert!(apu.frame_irq());
+        assert_eq!(apu.read_status(), 0x40);
+        assert!(!apu.frame_irq()); // acknowledged
+
+        // Not raised in the 5-step mode, or when inhibited.
+        apu.write_register(0x4017, 0x80);
+        apu.tick(40000);
+        apu.write_register(0x4017, 0x40);
+        apu.tick(40000);
+        assert!(!apu.frame_irq());
+    }
+
+    #[test]
+    fn test_dmc() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4010, 0x8f); // irq enabled, fastest rate
+        apu.write_register(0x4013, 0x01); // 17 bytes
+        apu.write_register(0x4012, 0xff); // $ffc0
+        apu.write_register(0x4015, 0x10);
+        assert_eq!(apu.read_status(), 0x10);
+        assert_eq!(apu.take_dmc_request(), Some(0xffc0));
+        assert_eq!(apu.take_dmc_request(), None);
+        for i in 0..16 {
+            apu.dmc_fill(i);
+            apu.tick(54 * 8);
+            assert_eq!(apu.take_dmc_request(), Some(0xffc1 + i as u16));
+        }
+        apu.dmc_fill(0x10);
+        apu.tick(54 * 8);
+        assert_eq!(apu.take_dmc_request(), None);
+        assert_eq!(apu.dmc_buffer(), 0x10);
+        assert_eq!(apu.read_status(), 0x80);
+        apu.write_register(0x4015, 0x00);
+        assert!(!apu.dmc_irq());
+
+        // Reads wrap around to $8000.
+        apu.write_register(0x4013, 0x04);
+        apu.write_register(0x4015, 0x10);
+        assert_eq!(apu.take_dmc_request(), Some(0xffc0));
+        for _ in 0..0x40 { apu.dmc_fill(0); }
+        apu.tick(54 * 8);
+        assert_eq!(apu.take_dmc_request(), Some(0x8000));
+    }
+
+    #[test]
+    fn test_dmc_timing() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4010, 0x8f); // irq enabled, fastest rate
+        apu.write_register(0x4013, 0x01); // 17 bytes
+        apu.write_register(0x4015, 0x10);
+        assert_eq!(apu.read_status(), 0x10);
+
+        apu.tick(17 * 54 * 8 - 1);
+        assert_eq!(apu.read_status(), 0x10);
+        apu.tick(1);
+        assert_eq!(apu.read_status(), 0x80);
+        apu.write_register(0x4015, 0x00);
+        assert!(!apu.dmc_irq());
+    }
+}
@@ -1,179 +1,1683 @@
+use std::collections::{HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
 use crate::rom::{Rom, EmptyRom};
+use crate::game_genie::{decode_game_genie, GameGeniePatch};
+use crate::ppu::{Ppu, OAMDATA};
+use crate::joypad::Joypad;
+use crate::apu::Apu;
+use crate::event_bus::{Event, EventBus};
+use crate::bus_trace::{BusAccess, BusRecorder};
+use crate::bus_guard::{AccessGuard, Protection};
+use crate::mirror::{ppu_register, ram_index};
 
 pub enum ControlSignal {
     MemEnable = 0b0000_0001,
     AccessMode = 0b0000_0010,
 }
 
+// Devices that can pull the IRQ line low. The line stays asserted until every source cleared it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IrqSource {
+    Mapper = 0b0000_0001,
+    FrameCounter = 0b0000_0010,
+    Dmc = 0b0000_0100,
+}
+
+// The NMI and IRQ lines of the cpu. The bus owns them, but the cpu owns the bus, so devices that
+// live outside of it (e.g. a PPU run by the frontend) get a handle with RomBus::interrupt_lines
+// and signal the cpu through it. The cpu sees the lines between instructions, see Mem::poll_nmi.
+#[derive(Debug, Default)]
+pub struct InterruptLines {
+    nmi: Cell<bool>,
+    irq: Cell<u8>, // IrqSource bits
+}
+
+impl InterruptLines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Edges are not counted, triggering again before the cpu polls has no effect.
+    pub fn trigger_nmi(&self) {
+        self.nmi.set(true);
+    }
+
+    // Reports (and clears) the NMI edge.
+    pub fn take_nmi(&self) -> bool {
+        self.nmi.replace(false)
+    }
+
+    pub fn assert_irq(&self, source: IrqSource) {
+        self.irq.set(self.irq.get() | source as u8);
+    }
+
+    pub fn clear_irq(&self, source: IrqSource) {
+        self.irq.set(self.irq.get() & !(source as u8));
+    }
+
+    pub fn irq_asserted(&self, source: IrqSource) -> bool {
+        self.irq.get() & source as u8 != 0
+    }
+
+    // Whether any source pulls the IRQ line.
+    pub fn irq(&self) -> bool {
+        self.irq.get() != 0
+    }
+}
+
 pub trait Mem {
-    fn new() -> Self;
-    fn set_address_bus(&mut self, addr: u16);
-    fn set_data_bus(&mut self, val: u8);
-    fn get_data_bus(&self) -> u8;
-    fn set_control_signal(&mut self, control: ControlSignal, val: bool);
-    fn get_control_signal(&self, control: ControlSignal) -> bool;
+    // A read or write by the cpu, with the side effects it has on the devices (e.g. reading
+    // PPUSTATUS clears the vblank flag).
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, val: u8);
+    // Number of addressable bytes of the memory unit. Not callable on a dyn Mem.
+    fn size() -> usize where Self: Sized;
+    // Implementations can override this to mark ranges that are not backed by anything.
+    fn is_valid_address(&self, _addr: u16) -> bool { true }
+    // Returns the address of the last invalid access (e.g. a write to ROM) since the previous call.
+    fn take_fault(&mut self) -> Option<u16> { None }
+    // Value at the address without going through the buses, so without side effects. None if the
+    // address doesn't hold a known value.
+    fn probe(&self, _addr: u16) -> Option<u8> { None }
+    // Interrupt lines, checked by the cpu between instructions. poll_nmi reports (and clears) an
+    // NMI edge since the previous call, poll_irq reports whether the IRQ line is currently asserted.
+    fn poll_nmi(&mut self) -> bool { false }
+    fn poll_irq(&mut self) -> bool { false }
+    // Cycles the cpu has to wait for the bus (e.g. for DMA) since the previous call. Checked after
+    // every instruction.
+    fn take_stall(&mut self) -> u16 { 0 }
+    // Lets the devices on the bus keep up with the cpu. The cpu ticks a cycle before every access
+    // it makes, and the rest of the cycles of the instruction (stalls included) at its end.
+    fn tick(&mut self, _cycles: u16) {}
+    // Blank scanlines the PPU adds to every frame for overclocking, the cpu clock speeds up to
+    // match.
+    fn extra_scanlines(&self) -> u16 { 0 }
+    // The pushes and pulls of the cpu, which go to the stack page. The same as read and write for
+    // buses that don't tell them apart, see Protection::StackOnly.
+    fn read_stack(&mut self, addr: u16) -> u8 { self.read(addr) }
+    fn write_stack(&mut self, addr: u16, val: u8) { self.write(addr, val) }
+    // Contents of the RAM, for save states. load_ram expects the same layout as dump_ram returns,
+    // and fails on RAM of another size, e.g. a snapshot taken on another bus.
+    fn dump_ram(&self) -> Vec<u8> { Vec::new() }
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        check_ram_size(data, 0)
+    }
+    // Contents of any range, for inspecting in a hex editor. Addresses without a known value (see
+    // probe), e.g. registers, dump as 0.
+    fn dump(&self, range: RangeInclusive<u16>) -> Vec<u8> {
+        range.map(|addr| self.probe(addr).unwrap_or(0)).collect()
+    }
+    // Writes a dump back from 'base', e.g. to reproduce a bug. Meant for RAM: the bytes are
+    // written like the cpu would, so registers in the range see the writes.
+    fn restore(&mut self, base: u16, data: &[u8]) {
+        for (offset, val) in data.iter().enumerate() {
+            self.write(base.wrapping_add(offset as u16), *val);
+        }
+    }
 }
 
-pub struct ArrayBus {
+// RAM loaded into a bus (see Mem::load_ram) has to be the size of the bus's own.
+fn check_ram_size(data: &[u8], size: usize) -> Result<(), String> {
+    if data.len() != size {
+        return Err(format!("The RAM is {} bytes, expected {}.", data.len(), size));
+    }
+    Ok(())
+}
+
+// Signal level view of a memory unit, as on the real hardware:
+//
+//    address_bus : address of the memory cell to read from, or write to
+//    data_bus    : the value to write, or the value read
+//    control_bus : AccessMode (1: read, 0: write), and MemEnable. The access is performed when
+//                  MemEnable is set.
+pub struct SignalBus<M: Mem> {
+    mem: M,
     address_bus: u16,
     data_bus: u8,
     control_bus: u8,
-    data: [u8; 0xffff],
 }
 
-impl ArrayBus {
-    // Currently I assume that 0 is 'save into mem' and 1 is 'read from mem', but this might change...
-    fn update(&mut self) {
-        if !self.get_control_signal(ControlSignal::MemEnable) { return; }
-    
-        if self.get_control_signal(ControlSignal::AccessMode) {
-            self.data_bus = self.data[self.address_bus as usize];
-        } else {
-            self.data[self.address_bus as usize] = self.data_bus;
-        }
+impl<M: Mem> SignalBus<M> {
+    pub fn new(mem: M) -> Self {
+        SignalBus { mem, address_bus: 0, data_bus: 0, control_bus: 0 }
     }
-}
 
-impl Mem for ArrayBus {
-    fn new() -> Self {
-        ArrayBus {
-            address_bus : 0,
-            data_bus : 0,
-            control_bus : 0,
-            data : [0; 0xffff],
-        }
+    pub fn into_inner(self) -> M {
+        self.mem
     }
 
-    fn set_address_bus(&mut self, addr: u16) {
+    pub fn set_address_bus(&mut self, addr: u16) {
         self.address_bus = addr;
     }
 
-    fn set_data_bus(&mut self, val: u8) {
+    pub fn set_data_bus(&mut self, val: u8) {
         self.data_bus = val;
     }
 
-    fn get_data_bus(&self) -> u8 {
+    pub fn get_data_bus(&self) -> u8 {
         self.data_bus
-    }   
+    }
 
-    fn set_control_signal(&mut self, control: ControlSignal, val: bool) {
+    pub fn set_control_signal(&mut self, control: ControlSignal, val: bool) {
         let mask = control as u8;
         if val  { self.control_bus |= mask; }
         else { self.control_bus &= !mask; }
-        self.update();
+        if !self.get_control_signal(ControlSignal::MemEnable) { return; }
+
+        if self.get_control_signal(ControlSignal::AccessMode) {
+            self.data_bus = self.mem.read(self.address_bus);
+        } else {
+            self.mem.write(self.address_bus, self.data_bus);
+        }
     }
 
-    fn get_control_signal(&self, control: ControlSignal) -> bool {
+    pub fn get_control_signal(&self, control: ControlSignal) -> bool {
         (self.control_bus & (control as u8)) != 0
     }
 }
 
+// What the RAM holds at power on. It is not cleared on the real hardware, and its contents vary
+// between consoles: some games behave differently depending on it, and random contents help
+// catching programs that read RAM before initializing it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RamPattern {
+    Zeros,
+    Ones, // all $ff
+    // $00 on even pages, $ff on odd ones.
+    AlternatingPages,
+    // Random bytes, the same ones for the same seed.
+    Random(u64),
+}
+
+impl RamPattern {
+    pub fn fill(&self, ram: &mut [u8]) {
+        match self {
+            RamPattern::Zeros => ram.fill(0x00),
+            RamPattern::Ones => ram.fill(0xff),
+            RamPattern::AlternatingPages => {
+                for (page, chunk) in ram.chunks_mut(0x100).enumerate() {
+                    chunk.fill(if page % 2 == 0 { 0x00 } else { 0xff });
+                }
+            },
+            RamPattern::Random(seed) => StdRng::seed_from_u64(*seed).fill(ram),
+        }
+    }
+}
+
+pub struct ArrayBus {
+    data: [u8; 0x10000],
+}
 
+impl Default for ArrayBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
+impl ArrayBus {
+    pub fn new() -> Self {
+        ArrayBus { data : [0; 0x10000] }
+    }
+
+    pub fn ram_snapshot(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn set_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        check_ram_size(data, self.data.len())?;
+        self.data.copy_from_slice(data);
+        Ok(())
+    }
+
+    // Overwrites the whole memory with 'pattern', as at power on.
+    pub fn fill_ram(&mut self, pattern: RamPattern) {
+        pattern.fill(&mut self.data);
+    }
+
+    // Copies 'data' to memory starting at 'base', e.g. to load a test program. Writes to the
+    // mirrored RAM area end up in the internal RAM.
+    pub fn load(&mut self, base: u16, data: &[u8]) {
+        for (offset, val) in data.iter().enumerate() {
+            let addr = ArrayBus::mirror(base.wrapping_add(offset as u16));
+            self.data[addr as usize] = *val;
+        }
+    }
+
+    // The 2KB internal RAM is mirrored three times in 0x0800..=0x1fff, like on RomBus.
+    fn mirror(addr: u16) -> u16 {
+        match addr {
+            0..=0x1fff => ram_index(addr) as u16,
+            _ => addr,
+        }
+    }
+}
+
+impl Mem for ArrayBus {
+    fn size() -> usize {
+        0x10000
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        self.data[ArrayBus::mirror(addr) as usize]
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.data[ArrayBus::mirror(addr) as usize] = val;
+    }
+
+    fn probe(&self, addr: u16) -> Option<u8> {
+        Some(self.data[ArrayBus::mirror(addr) as usize])
+    }
+
+    // The whole address space is RAM.
+    fn dump_ram(&self) -> Vec<u8> {
+        self.data.to_vec()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        self.set_ram(data)
+    }
+}
+
+// Memory unit backed by a HashMap, for tests that only need a handful of addresses populated.
+// Reading an address that was never written returns 0xff.
+#[derive(Default)]
+pub struct SparseMemory {
+    pub data: HashMap<u16, u8>,
+}
+
+impl SparseMemory {
+    pub fn new() -> Self {
+        SparseMemory { data : HashMap::new() }
+    }
+
+    pub fn load(&mut self, addr: u16, values: &[u8]) {
+        for (offset, val) in values.iter().enumerate() {
+            self.data.insert(addr.wrapping_add(offset as u16), *val);
+        }
+    }
+}
+
+impl Mem for SparseMemory {
+    fn size() -> usize {
+        0x10000
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        self.data.get(&addr).copied().unwrap_or(0xff)
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.data.insert(addr, val);
+    }
+
+    // Only addresses that were loaded or written hold a value.
+    fn probe(&self, addr: u16) -> Option<u8> {
+        self.data.get(&addr).copied()
+    }
+}
+
+
+// Devices that can be mapped into the address space with RomBus::map_region, e.g. the FDS,
+// expansion audio or exotic controllers. 'addr' is already mirrored by the mask of the region.
+pub trait BusDevice {
+    // None if the device doesn't drive the data bus, which then reads as open bus.
+    fn read(&mut self, addr: u16) -> Option<u8>;
+    fn write(&mut self, addr: u16, val: u8);
+    fn probe(&self, _addr: u16) -> Option<u8> { None }
+    // Called with the cpu cycles that passed, like Mem::tick.
+    fn tick(&mut self, _cycles: u16) {}
+    // Whether the device pulls the IRQ line, which is level triggered.
+    fn irq(&self) -> bool { false }
+}
+
+// What answers to the accesses in a region. The devices built into RomBus, or a BusDevice.
+pub enum RegionHandler {
+    Ram,
+    Ppu,
+    // APU, controllers and OAM DMA.
+    Io,
+    // The expansion area, cartridge RAM and PRG ROM, as decoded by the mapper.
+    Cartridge,
+    Device(Box<dyn BusDevice>),
+}
+
+// A range of the address space, answered by 'handler'. Addresses are and-ed with 'mirror_mask'
+// before being passed on, e.g. the 8 PPU registers repeat every 8 bytes with the mask 0x2007.
+pub struct MemoryRegion {
+    pub range: RangeInclusive<u16>,
+    pub mirror_mask: u16,
+    pub handler: RegionHandler,
+}
+
+impl MemoryRegion {
+    pub fn new(range: RangeInclusive<u16>, mirror_mask: u16, handler: RegionHandler) -> Self {
+        MemoryRegion { range, mirror_mask, handler }
+    }
+}
 
 pub struct RomBus {
-    address_bus: u16,
-    data_bus: u8,
-    control_bus: u8,
+    // Address decoding, see map_region.
+    regions: Vec<MemoryRegion>,
     data: [u8; 0x0800],
     rom: Box<dyn Rom>,
+    ppu: Option<Box<dyn Ppu>>,
+    apu: Apu,
+    // Controllers at $4016 and $4017, see set_joypad.
+    joypads: [Option<Rc<RefCell<Joypad>>>; 2],
+    game_genie: Vec<GameGeniePatch>,
+    ignore_rom_writes: bool,
+    fault: Option<u16>,
+    // Unmapped expansion area addresses the program accessed. Each is only warned about once, games
+    // may poll them every frame.
+    unmapped_warned: HashSet<u16>,
+    interrupts: Rc<InterruptLines>,
+    // Level of the IRQ line at the previous poll, to publish IrqTriggered on its edges.
+    irq_line: bool,
+    events: EventBus,
+    // Last value driven on the data bus. Reads from addresses nothing answers to return it.
+    open_bus: u8,
+    // Cpu cycles so far, counted by tick.
+    cycles: u64,
+    // Cycles the cpu has to wait for DMA, see take_stall.
+    stall: u16,
+    // Cycles left in the OAM DMA under way, which DMC fetches interleave with.
+    oam_dma_cycles: u16,
+    recorder: Option<BusRecorder>,
+    guard: AccessGuard,
+    // Writes to this address are printed, see set_debug_port.
+    debug_port: Option<u16>,
+    debug_line: String,
+    debug_output: Vec<String>,
+}
+
+impl Default for RomBus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RomBus {
-    
-    fn update(&mut self) {
-        if !self.get_control_signal(ControlSignal::MemEnable) { return; }
+    pub fn new() -> Self {
+        // 0x4018..=0x401f is normally disabled, nothing answers there.
+        let regions = vec![
+            MemoryRegion::new(0x0000..=0x1fff, 0x07ff, RegionHandler::Ram),
+            MemoryRegion::new(0x2000..=0x3fff, 0x2007, RegionHandler::Ppu),
+            MemoryRegion::new(0x4000..=0x4017, 0xffff, RegionHandler::Io),
+            MemoryRegion::new(0x4020..=0xffff, 0xffff, RegionHandler::Cartridge),
+        ];
+        Self {
+            regions,
+            data : [0; 0x0800],
+            rom : Box::new(EmptyRom::new()),
+            ppu : None,
+            apu : Apu::new(),
+            joypads : [None, None],
+            game_genie : Vec::new(),
+            ignore_rom_writes : false,
+            fault : None,
+            unmapped_warned : HashSet::new(),
+            interrupts : Rc::new(InterruptLines::new()),
+            irq_line : false,
+            events : EventBus::new(),
+            open_bus : 0,
+            cycles : 0,
+            stall : 0,
+            oam_dma_cycles : 0,
+            recorder : None,
+            guard : AccessGuard::new(),
+            debug_port : None,
+            debug_line : String::new(),
+            debug_output : Vec::new(),
+        }
+    }
 
-        if self.get_control_signal(ControlSignal::AccessMode) { // read from mem
-            match self.address_bus {
-                0..=0x1fff => {
-                    let addr: u16 = self.address_bus % 0x0800;
-                    self.data_bus = self.data[addr as usize];
-                },
-                0x2000..=0x3fff => {
-                    let _ppu_reg = self.address_bus % 0x0008;
-                    
-                }, // ppu registers
-                0x4000..=0x4017 => {}, // apu and io registers
-                0x4018..=0x401f => {}, // apu and io func normally disabled.
-                0x6000..=0x7fff => {
-
-                }, // Cartridge RAM when present
-                0x8000..=0xffff => {
-                    self.data_bus = (*self.rom).prg_read(self.address_bus);
-                },
-                _ => {todo!("what happens in this range?")},
-            };
-        } else {
-            match self.address_bus {
-                0..=0x1fff => {
-                    let addr: u16 = self.address_bus % 0x0800;
-                    self.data[addr as usize] = self.data_bus;
-                },
-                0x2000..=0x3fff => {
-                    let _ppu_reg = self.address_bus % 0x0008;
-                    
-                }, // ppu registers
-                0x4000..=0x4017 => {}, // apu and io registers
-                0x4018..=0x401f => {}, // apu and io func normally disabled.
-                0x6000..=0x7fff => {
-
-                }, // Cartridge RAM when present
-                0x8000..=0xffff => {
-                    panic!("Program trying to write to ROM.")
-                },
-                _ => {todo!("what happens in this range?")},
+    fn region_index(&self, addr: u16) -> Option<usize> {
+        // Regions mapped later take precedence.
+        self.regions.iter().rposition(|region| region.range.contains(&addr))
+    }
+
+    fn read_device(&mut self, addr: u16) -> u8 {
+        // Nothing drives the data bus outside of the regions.
+        let Some(index) = self.region_index(addr) else { return self.open_bus; };
+        let region = &mut self.regions[index];
+        let addr = addr & region.mirror_mask;
+        if let RegionHandler::Device(device) = &mut region.handler {
+            return device.read(addr).unwrap_or(self.open_bus);
+        }
+        match region.handler {
+            RegionHandler::Ram => self.data[ram_index(addr)],
+            RegionHandler::Ppu => {
+                let ppu_reg = ppu_register(addr);
+                // Nothing drives the data bus without a ppu, so it reads as open bus.
+                match &mut self.ppu {
+                    Some(ppu) => ppu.read_register(ppu_reg),
+                    None => self.open_bus,
+                }
+            },
+            RegionHandler::Io => self.read_io(addr),
+            RegionHandler::Cartridge => self.read_cartridge(addr),
+            RegionHandler::Device(_) => unreachable!(),
+        }
+    }
+
+    fn write_device(&mut self, addr: u16, val: u8) {
+        let Some(index) = self.region_index(addr) else { return; };
+        let region = &mut self.regions[index];
+        let addr = addr & region.mirror_mask;
+        if let RegionHandler::Device(device) = &mut region.handler {
+            return device.write(addr, val);
+        }
+        match region.handler {
+            RegionHandler::Ram => self.data[ram_index(addr)] = val,
+            RegionHandler::Ppu => {
+                let ppu_reg = ppu_register(addr);
+                if let Some(ppu) = &mut self.ppu {
+                    ppu.write_register(ppu_reg, val);
+                }
+            },
+            RegionHandler::Io => self.write_io(addr, val),
+            RegionHandler::Cartridge => self.write_cartridge(addr, val),
+            RegionHandler::Device(_) => unreachable!(),
+        }
+    }
+
+    // The apu and io registers.
+    fn read_io(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4016..=0x4017 => {
+                // Only bit 0 is driven by the controller, the upper bits are open bus.
+                let port = (addr - 0x4016) as usize;
+                let bit = self.joypads[port].as_ref().map_or(0, |joypad| joypad.borrow_mut().read());
+                self.open_bus & 0xe0 | bit
+            }, // controllers
+            0x4015 => {
+                // Interrupt flags in bits 7 and 6, and the channels still playing in the low
+                // bits. Bit 5 is open bus. Reading clears the frame interrupt.
+                let mut status = self.apu.read_status() | self.open_bus & 0x20;
+                if self.interrupts.irq_asserted(IrqSource::Dmc) { status |= 0x80; }
+                if self.interrupts.irq_asserted(IrqSource::FrameCounter) { status |= 0x40; }
+                self.clear_irq(IrqSource::FrameCounter);
+                status
+            }, // apu status
+            _ => self.open_bus, // write only apu and io registers
+        }
+    }
+
+    fn write_io(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4014 => self.oam_dma(val),
+            0x4016 => {
+                for joypad in self.joypads.iter().flatten() {
+                    joypad.borrow_mut().write(val);
+                }
+            }, // controller strobe, for both ports
+            _ => {
+                self.apu.write_register(addr, val);
+                // Writes can acknowledge the apu interrupts.
+                if !self.apu.frame_irq() { self.clear_irq(IrqSource::FrameCounter); }
+                if !self.apu.dmc_irq() { self.clear_irq(IrqSource::Dmc); }
+            }, // apu registers
+        }
+    }
+
+    // The expansion area (used by some mappers), the cartridge RAM when present, and the PRG ROM.
+    fn read_cartridge(&mut self, addr: u16) -> u8 {
+        if self.rom.maps_address(addr) {
+            let original = self.rom.prg_read(addr);
+            return self.game_genie.iter().fold(original, |val, patch| patch.patch(addr, val));
+        }
+        if addr < 0x6000 && self.unmapped_warned.insert(addr) {
+            println!("WARN\tProgram trying to read unmapped expansion area (addr: {:x}).", addr);
+        }
+        // No cartridge, or no RAM.
+        self.open_bus
+    }
+
+    fn write_cartridge(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4020..=0x5fff => {
+                let mapped = self.rom.maps_address(addr) && self.rom.prg_write(addr, val);
+                if !mapped && self.unmapped_warned.insert(addr) {
+                    println!("WARN\tProgram trying to write unmapped expansion area (addr: {:x}, val: {:x}).", addr, val);
+                }
+            },
+            0x6000..=0x7fff => {
+                // The mapper ignores writes when there is no RAM, or it is write protected.
+                if self.rom.maps_address(addr) {
+                    self.rom.prg_write(addr, val);
+                }
+            },
+            _ => {
+                // Mapper registers. Mappers acknowledge their interrupt through them.
+                if self.rom.prg_write(addr, val) {
+                    if !self.rom.irq() { self.clear_irq(IrqSource::Mapper); }
+                    return;
+                }
+                println!("WARN\tProgram trying to write to ROM (addr: {:x}, val: {:x}).", addr, val);
+                if !self.ignore_rom_writes { self.fault = Some(addr); }
+            },
+        }
+    }
+
+    // Copies the page to the PPU OAM through OAMDATA, one byte at a time. The cpu is halted
+    // meanwhile, for 513 cycles, or 514 when the DMA starts on an odd cycle.
+    // See: https://www.nesdev.org/wiki/PPU_registers#OAMDMA
+    fn oam_dma(&mut self, page: u8) {
+        for offset in 0..=0xff {
+            let addr = (page as u16) << 8 | offset;
+            let val = self.read_device(addr);
+            self.record(addr, val, false);
+            if let Some(ppu) = &mut self.ppu {
+                ppu.write_register(OAMDATA, val);
             }
         }
+        self.events.publish(Event::DmaStarted(page));
+        let cycles = 513 + (self.cycles % 2) as u16;
+        self.stall += cycles;
+        self.oam_dma_cycles += cycles;
+    }
+
+    // Reads a sample byte for the DMC. The cpu is halted for 4 cycles, or only 2 when the fetch
+    // happens during an OAM DMA: it takes the place of the cycles the OAM DMA spends aligning, and
+    // the one it spends reading. The OAM DMA resumes after it.
+    // The fetch is done between cpu accesses, so the extra controller read it causes on hardware,
+    // which makes a button get skipped, doesn't happen here.
+    // See: https://www.nesdev.org/wiki/DMA#DMC_DMA_during_OAM_DMA
+    fn dmc_dma(&mut self, addr: u16) {
+        let val = self.read_device(addr);
+        self.record(addr, val, false);
+        self.open_bus = val;
+        self.apu.dmc_fill(val);
+        self.stall += if self.oam_dma_cycles > 0 { 2 } else { 4 };
+    }
+
+    // The accesses of the cpu, 'stack' is set for its pushes and pulls.
+    fn cpu_read(&mut self, addr: u16, stack: bool) -> u8 {
+        let val = self.read_device(addr);
+        self.record(addr, val, false);
+        self.guard_check(addr, val, false, stack);
+        // $4015 is inside the cpu, reading it doesn't drive the external data bus.
+        if addr != 0x4015 { self.open_bus = val; }
+        val
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8, stack: bool) {
+        self.record(addr, val, true);
+        self.guard_check(addr, val, true, stack);
+        if self.debug_port == Some(addr) { self.debug_write(val); }
+        self.write_device(addr, val);
+        self.open_bus = val;
+    }
+
+    fn guard_check(&mut self, addr: u16, value: u8, write: bool, stack: bool) {
+        for violation in self.guard.check(self.cycles, addr, value, write, stack) {
+            self.events.publish(Event::AccessViolation(violation));
+        }
+    }
+
+    fn record(&mut self, addr: u16, value: u8, write: bool) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(BusAccess { cycle: self.cycles, addr, value, write });
+        }
+    }
+
+    // Records every access from now on, see BusRecorder. None stops recording.
+    pub fn set_recorder(&mut self, recorder: Option<BusRecorder>) {
+        self.recorder = recorder;
+    }
+
+    // Cpu cycles the bus was ticked for.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn recorder(&self) -> Option<&BusRecorder> {
+        self.recorder.as_ref()
+    }
+
+    // For homebrew and test roms printing diagnostics: the characters written to 'port' (e.g.
+    // $401a, which nothing else uses) are printed a line at a time. The write still goes to the
+    // device at the address, if any.
+    pub fn set_debug_port(&mut self, port: Option<u16>) {
+        self.debug_port = port;
+    }
+
+    fn debug_write(&mut self, val: u8) {
+        if val != b'\n' && val != 0 {
+            self.debug_line.push(val as char);
+            return;
+        }
+        let line = std::mem::take(&mut self.debug_line);
+        println!("DBG\t{}", line);
+        self.debug_output.push(line);
+    }
+
+    // Reports the cpu accesses to 'range' that break 'protection' as Event::AccessViolation. The
+    // accesses still go through.
+    pub fn protect(&mut self, range: RangeInclusive<u16>, protection: Protection) {
+        self.guard.protect(range, protection);
+    }
+
+    pub fn clear_protections(&mut self) {
+        self.guard.clear();
+    }
+
+    // Lines printed through the debug port since the previous call.
+    pub fn take_debug_output(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.debug_output)
+    }
+
+    // Test roms following the convention of blargg's tests write their status to $6000, and a
+    // null terminated message from $6004, marked valid by DE B0 61 at $6001.
+    pub fn test_rom_text(&self) -> Option<String> {
+        let signature = [0x6001, 0x6002, 0x6003].map(|addr| self.probe(addr));
+        if signature != [Some(0xde), Some(0xb0), Some(0x61)] { return None; }
+        let text = (0x6004..=0x7fff)
+            .map_while(|addr| self.probe(addr).filter(|&val| val != 0))
+            .map(|val| val as char)
+            .collect();
+        Some(text)
+    }
+
+    // Maps a region on top of the existing ones, e.g. a device in the expansion area. Accesses in
+    // its range go to it instead of the regions mapped before.
+    pub fn map_region(&mut self, region: MemoryRegion) {
+        self.regions.push(region);
+    }
+
+    fn devices_mut(&mut self) -> impl Iterator<Item = &mut Box<dyn BusDevice>> {
+        self.regions.iter_mut().filter_map(|region| match &mut region.handler {
+            RegionHandler::Device(device) => Some(device),
+            _ => None,
+        })
     }
 
     pub fn set_rom(&mut self, rom: Box<dyn Rom>) {
         self.rom = rom;
     }
+
+    pub fn set_ppu(&mut self, ppu: Box<dyn Ppu>) {
+        self.ppu = Some(ppu);
+    }
+
+    // Connects a controller to 'port', 0 for $4016 and 1 for $4017. The frontend keeps a handle to
+    // it, to set the buttons held.
+    pub fn set_joypad(&mut self, port: usize, joypad: Rc<RefCell<Joypad>>) {
+        self.joypads[port] = Some(joypad);
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    // For devices that drive the data bus outside of cpu accesses, e.g. DMA.
+    pub fn set_open_bus(&mut self, val: u8) {
+        self.open_bus = val;
+    }
+
+    // Signals an NMI edge to the cpu, which services it after the current instruction. Edges are not
+    // counted, triggering again before the cpu polls has no effect.
+    pub fn trigger_nmi(&mut self) {
+        self.interrupts.trigger_nmi();
+    }
+
+    // The IRQ line is level triggered: the cpu keeps servicing it (when the I flag is clear) until
+    // the source clears it, usually when the handler acknowledges the interrupt.
+    pub fn assert_irq(&mut self, source: IrqSource) {
+        self.interrupts.assert_irq(source);
+    }
+
+    pub fn clear_irq(&mut self, source: IrqSource) {
+        self.interrupts.clear_irq(source);
+    }
+
+    // The events of the devices on the bus (DMA, interrupts, and the PPU's vblank and frames), for
+    // the frontend or debuggers to subscribe to. They are delivered when the bus is ticked.
+    pub fn events(&mut self) -> &mut EventBus {
+        &mut self.events
+    }
+
+    // Handle to the interrupt lines, for signalling the cpu once it owns the bus.
+    pub fn interrupt_lines(&self) -> Rc<InterruptLines> {
+        Rc::clone(&self.interrupts)
+    }
+
+    // The real hardware ignores writes to ROM, which some buggy games rely on. If not ignored,
+    // such writes are reported to the CPU as a segmentation fault.
+    pub fn set_ignore_rom_writes(&mut self, ignore: bool) {
+        self.ignore_rom_writes = ignore;
+    }
+
+    // Copy of the internal RAM, for inspecting the RAM without going through the buses.
+    pub fn ram_snapshot(&self) -> [u8; 0x0800] {
+        self.data
+    }
+
+    pub fn set_ram(&mut self, data: [u8; 0x0800]) {
+        self.data = data;
+    }
+
+    // Overwrites the internal RAM with 'pattern', as at power on. The cartridge RAM is left alone.
+    pub fn fill_ram(&mut self, pattern: RamPattern) {
+        pattern.fill(&mut self.data);
+    }
+
+    pub fn add_game_genie(&mut self, code: &str) -> Result<(), String> {
+        let patch = decode_game_genie(code)?;
+        self.game_genie.push(patch);
+        Ok(())
+    }
+
+    pub fn address_space() -> RangeInclusive<u16> {
+        0x0000..=0xffff
+    }
 }
 
 impl Mem for RomBus {
-    fn new() -> Self {
-        Self {
-            address_bus : 0,
-            data_bus : 0,
-            control_bus : 0,
-            data : [0; 0x0800],
-            rom : Box::new(EmptyRom::new()),
+    fn size() -> usize {
+        RomBus::address_space().count()
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        self.cpu_read(addr, false)
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.cpu_write(addr, val, false)
+    }
+
+    fn read_stack(&mut self, addr: u16) -> u8 {
+        self.cpu_read(addr, true)
+    }
+
+    fn write_stack(&mut self, addr: u16, val: u8) {
+        self.cpu_write(addr, val, true)
+    }
+
+    // The expansion area between the apu / io registers and the cartridge ram is only backed by
+    // some mappers.
+    fn is_valid_address(&self, addr: u16) -> bool {
+        if !(0x4020..=0x5fff).contains(&addr) { return true; }
+        match self.region_index(addr).map(|index| &self.regions[index].handler) {
+            Some(RegionHandler::Cartridge) => self.rom.maps_address(addr),
+            Some(_) => true,
+            None => false,
         }
     }
 
-    fn set_address_bus(&mut self, addr: u16) {
-        self.address_bus = addr;
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
     }
 
-    fn set_data_bus(&mut self, val: u8) {
-        self.data_bus = val;
+    fn take_stall(&mut self) -> u16 {
+        std::mem::take(&mut self.stall)
     }
 
-    fn get_data_bus(&self) -> u8 {
-        self.data_bus
-    }   
+    fn tick(&mut self, cycles: u16) {
+        self.cycles += cycles as u64;
+        // The DMC asks for its bytes at a given cycle, so it is ticked one cycle at a time, and so
+        // is the PPU, since the APU stands still during the extra scanlines of overclocking: the
+        // sound and the frame IRQ keep to the stock speed.
+        for _ in 0..cycles {
+            let overclocked = self.ppu.as_mut().is_some_and(|ppu| {
+                ppu.tick(1);
+                ppu.in_extra_scanlines()
+            });
+            if !overclocked {
+                self.apu.tick(1);
+                if let Some(addr) = self.apu.take_dmc_request() {
+                    self.dmc_dma(addr);
+                }
+            }
+            self.oam_dma_cycles = self.oam_dma_cycles.saturating_sub(1);
+        }
+        if let Some(ppu) = &mut self.ppu {
+            for event in ppu.take_events() {
+                self.events.publish(event);
+            }
+        }
+        if self.apu.frame_irq() { self.assert_irq(IrqSource::FrameCounter); }
+        if self.apu.dmc_irq() { self.assert_irq(IrqSource::Dmc); }
+        self.rom.tick(cycles);
+        if self.rom.irq() { self.assert_irq(IrqSource::Mapper); }
+        for device in self.devices_mut() {
+            device.tick(cycles);
+        }
+        self.events.flush();
+    }
 
-    fn set_control_signal(&mut self, control: ControlSignal, val: bool) {
-        if val { self.control_bus |= control as u8; }
-        else { self.control_bus &= !(control as u8); }
+    fn extra_scanlines(&self) -> u16 {
+        self.ppu.as_ref().map_or(0, |ppu| ppu.extra_scanlines())
+    }
 
-        self.update();
+    fn poll_nmi(&mut self) -> bool {
+        let ppu_nmi = self.ppu.as_mut().is_some_and(|ppu| ppu.poll_nmi());
+        let nmi = self.interrupts.take_nmi() || ppu_nmi;
+        if nmi { self.events.publish(Event::NmiTriggered); }
+        nmi
     }
 
-    fn get_control_signal(&self, control: ControlSignal) -> bool {
-        (self.control_bus & (control as u8)) != 0
+    fn poll_irq(&mut self) -> bool {
+        let irq = self.interrupts.irq() || self.devices_mut().any(|device| device.irq());
+        if irq && !self.irq_line { self.events.publish(Event::IrqTriggered); }
+        self.irq_line = irq;
+        irq
     }
-}
 
+    // Only the internal 2KB RAM, the cartridge is not saved.
+    fn dump_ram(&self) -> Vec<u8> {
+        self.data.to_vec()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        check_ram_size(data, self.data.len())?;
+        self.data.copy_from_slice(data);
+        Ok(())
+    }
+
+    // Not recorded or checked for protections, it isn't the program accessing the bus.
+    fn restore(&mut self, base: u16, data: &[u8]) {
+        for (offset, val) in data.iter().enumerate() {
+            self.write_device(base.wrapping_add(offset as u16), *val);
+        }
+    }
+
+    // Registers are not probed, since reading them can have side effects.
+    fn probe(&self, addr: u16) -> Option<u8> {
+        let region = &self.regions[self.region_index(addr)?];
+        let addr = addr & region.mirror_mask;
+        match &region.handler {
+            RegionHandler::Ram => Some(self.data[ram_index(addr)]),
+            RegionHandler::Cartridge if self.rom.maps_address(addr) => {
+                let original = self.rom.prg_read(addr);
+                Some(self.game_genie.iter().fold(original, |val, patch| patch.patch(addr, val)))
+            },
+            RegionHandler::Device(device) => device.probe(addr),
+            _ => None,
+        }
+    }
+}
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::event_bus::EventKind;
 
     #[test]
     fn test() {
 
     }
+
+    #[test]
+    fn test_array_bus_size() {
+        let bus = ArrayBus::new();
+        assert_eq!(ArrayBus::size(), bus.data.len());
+        assert!(bus.is_valid_address(0xffff));
+    }
+
+    // Generic code sees the size through the trait.
+    fn size_of<M: Mem>() -> usize {
+        M::size()
+    }
+
+    #[test]
+    fn test_size_through_trait() {
+        assert_eq!(size_of::<ArrayBus>(), 0x10000);
+        assert_eq!(size_of::<SparseMemory>(), 0x10000);
+        assert_eq!(size_of::<RomBus>(), 0x10000);
+        assert_eq!(size_of::<crate::testing::TestBus>(), 0x10000);
+    }
+
+    #[test]
+    fn test_ram_pattern() {
+        let mut bus = RomBus::new();
+        bus.fill_ram(RamPattern::Ones);
+        assert_eq!(bus.read(0x07ff), 0xff);
+        bus.fill_ram(RamPattern::AlternatingPages);
+        assert_eq!((bus.read(0x00ff), bus.read(0x0100), bus.read(0x0200)), (0x00, 0xff, 0x00));
+
+        let mut other = ArrayBus::new();
+        other.fill_ram(RamPattern::Random(7));
+        let first = other.ram_snapshot().to_vec();
+        other.fill_ram(RamPattern::Zeros);
+        assert!(other.ram_snapshot().iter().all(|&val| val == 0));
+        other.fill_ram(RamPattern::Random(7));
+        assert_eq!(other.ram_snapshot(), first); // same seed, same contents
+        assert!(first.iter().any(|&val| val != first[0]));
+    }
+
+    #[test]
+    fn test_rom_bus_size() {
+        let bus = RomBus::new();
+        assert_eq!(RomBus::size(), 0x10000);
+        assert_eq!(RomBus::address_space(), 0x0000..=0xffff);
+        assert!(bus.is_valid_address(0x07ff));
+        assert!(!bus.is_valid_address(0x4020));
+        assert!(!bus.is_valid_address(0x5fff));
+        assert!(bus.is_valid_address(0x6000));
+    }
+
+    // Rom filled with the same value everywhere.
+    struct ConstRom(u8);
+
+    impl Rom for ConstRom {
+        fn load(&mut self, _raw: &[u8], _trainer: bool) -> Result<(), String> { Ok(()) }
+        fn prg_read(&self, _address: u16) -> u8 { self.0 }
+        fn chr_read(&self, _address: u16) -> u8 { self.0 }
+    }
+
+    // Rom with registers in the expansion area, like MMC5.
+    struct ExpansionRom([u8; 0x2000]);
+
+    impl Rom for ExpansionRom {
+        fn load(&mut self, _raw: &[u8], _trainer: bool) -> Result<(), String> { Ok(()) }
+        fn prg_read(&self, address: u16) -> u8 { self.0[address as usize % 0x2000] }
+        fn chr_read(&self, _address: u16) -> u8 { 0 }
+        fn maps_address(&self, address: u16) -> bool { address >= 0x5000 }
+        fn prg_write(&mut self, address: u16, val: u8) -> bool { self.0[address as usize % 0x2000] = val; true }
+    }
+
+    #[test]
+    fn test_dyn_mem() {
+        let mut buses: Vec<Box<dyn Mem>> = vec![Box::new(ArrayBus::new()), Box::new(RomBus::new())];
+        for bus in buses.iter_mut() {
+            bus.write(0x0801, 0x42);
+            assert_eq!(bus.read(0x0001), 0x42);
+        }
+    }
+
+    #[test]
+    fn test_signal_bus() {
+        let mut bus = SignalBus::new(ArrayBus::new());
+        bus.set_address_bus(0x0810);
+        bus.set_control_signal(ControlSignal::AccessMode, false);
+        bus.set_data_bus(0x42);
+        bus.set_control_signal(ControlSignal::MemEnable, true);
+        bus.set_control_signal(ControlSignal::MemEnable, false);
+
+        bus.set_data_bus(0x00);
+        bus.set_address_bus(0x0010);
+        bus.set_control_signal(ControlSignal::AccessMode, true);
+        assert_eq!(bus.get_data_bus(), 0x00); // not enabled yet
+        bus.set_control_signal(ControlSignal::MemEnable, true);
+        assert_eq!(bus.get_data_bus(), 0x42);
+        bus.set_control_signal(ControlSignal::MemEnable, false);
+        assert_eq!(bus.into_inner().probe(0x0010), Some(0x42));
+    }
+
+    // Device with a single register at odd addresses, when mapped with the mirror mask 0x0001.
+    struct Latch(u8);
+
+    impl BusDevice for Latch {
+        fn read(&mut self, addr: u16) -> Option<u8> { (addr == 1).then_some(self.0) }
+        fn write(&mut self, addr: u16, val: u8) { if addr == 1 { self.0 = val; } }
+        fn probe(&self, addr: u16) -> Option<u8> { (addr == 1).then_some(self.0) }
+    }
+
+    #[test]
+    fn test_map_region() {
+        let mut bus = RomBus::new();
+        assert!(!bus.is_valid_address(0x5001));
+        bus.map_region(MemoryRegion::new(0x5000..=0x5fff, 0x0001, RegionHandler::Device(Box::new(Latch(0)))));
+        assert!(bus.is_valid_address(0x5001));
+
+        bus.write(0x5f03, 0x42);
+        assert_eq!(bus.read(0x5001), 0x42);
+        assert_eq!(bus.probe(0x5003), Some(0x42));
+        bus.write(0x0000, 0x37);
+        assert_eq!(bus.read(0x5000), 0x37); // not driven, open bus
+
+        // Mapped over the RAM.
+        bus.map_region(MemoryRegion::new(0x0800..=0x0fff, 0x0001, RegionHandler::Device(Box::new(Latch(0x99)))));
+        assert_eq!(bus.read(0x0801), 0x99);
+        assert_eq!(bus.read(0x0001), 0x00);
+    }
+
+    // Raises an IRQ every 100 cycles, acknowledged by writing to it.
+    #[derive(Default)]
+    struct Timer {
+        cycles: u16,
+        irq: bool,
+    }
+
+    impl BusDevice for Timer {
+        fn read(&mut self, _addr: u16) -> Option<u8> { None }
+        fn write(&mut self, _addr: u16, _val: u8) { self.irq = false; }
+        fn tick(&mut self, cycles: u16) {
+            self.cycles += cycles;
+            if self.cycles >= 100 {
+                self.cycles -= 100;
+                self.irq = true;
+            }
+        }
+        fn irq(&self) -> bool { self.irq }
+    }
+
+    #[test]
+    fn test_device_irq() {
+        let mut bus = RomBus::new();
+        bus.map_region(MemoryRegion::new(0x4100..=0x4100, 0xffff, RegionHandler::Device(Box::<Timer>::default())));
+        bus.tick(99);
+        assert!(!bus.poll_irq());
+        bus.tick(1);
+        assert!(bus.poll_irq());
+        assert!(bus.poll_irq()); // until acknowledged
+        bus.write(0x4100, 0x00);
+        assert!(!bus.poll_irq());
+    }
+
+    #[test]
+    fn test_game_genie() {
+        let mut bus = RomBus::new();
+        bus.set_rom(Box::new(ConstRom(0x00)));
+        bus.add_game_genie("SXIOPO").unwrap();
+        bus.add_game_genie("YEUZUGAA").unwrap();
+        assert!(bus.add_game_genie("SXIOP").is_err());
+
+        assert_eq!(bus.read(0x91d9), 0xad);
+        assert_eq!(bus.read(0x91da), 0x00);
+        assert_eq!(bus.read(0xacb3), 0x07);
+
+        // The compare value of the 8-letter code does not match.
+        bus.set_rom(Box::new(ConstRom(0x01)));
+        assert_eq!(bus.read(0xacb3), 0x01);
+        assert_eq!(bus.read(0x91d9), 0xad);
+    }
+
+    #[test]
+    fn test_expansion_area() {
+        let mut bus = RomBus::new();
+        bus.set_rom(Box::new(ConstRom(0x00)));
+        bus.write(0x4020, 0x12);
+        bus.write(0x5fff, 0x34);
+        bus.read(0x5fff);
+        bus.read(0x5fff);
+        assert_eq!(bus.take_fault(), None);
+        assert_eq!(bus.unmapped_warned, HashSet::from([0x4020, 0x5fff])); // warned once each
+
+        bus.set_rom(Box::new(ExpansionRom([0; 0x2000])));
+        bus.write(0x5105, 0x44);
+        bus.write(0x4105, 0x55);
+        assert_eq!(bus.read(0x5105), 0x44);
+        assert_eq!(bus.read(0x4105), 0x44); // not mapped, the data bus keeps its value
+        assert!(bus.is_valid_address(0x5105));
+        assert!(!bus.is_valid_address(0x4105));
+    }
+
+    #[test]
+    fn test_rom_bus_ram_snapshot() {
+        let mut bus = RomBus::new();
+        let mut ram = [0; 0x0800];
+        ram[0x01ff] = 0x80;
+        bus.set_ram(ram);
+        assert_eq!(bus.read(0x01ff), 0x80);
+        assert_eq!(bus.read(0x09ff), 0x80); // mirrored
+
+        bus.write(0x1005, 0x42);
+        let snapshot = bus.ram_snapshot();
+        assert_eq!(snapshot[0x0005], 0x42);
+        assert_eq!(snapshot[0x01ff], 0x80);
+    }
+
+    #[test]
+    fn test_array_bus_ram_snapshot() {
+        let mut bus = ArrayBus::new();
+        let mut ram = vec![0; 0x10000];
+        ram[0xffff] = 0x80;
+        bus.set_ram(&ram).unwrap();
+        assert_eq!(bus.read(0xffff), 0x80);
+
+        bus.write(0x1234, 0x42);
+        assert_eq!(bus.ram_snapshot()[0x0234], 0x42); // mirrored
+        assert_eq!(bus.ram_snapshot().len(), 0x10000);
+    }
+
+    #[test]
+    fn test_array_bus_mirroring() {
+        let mut bus = ArrayBus::new();
+        bus.write(0x0000, 0x42);
+        for addr in [0x0800, 0x1000, 0x1800] {
+            assert_eq!(bus.read(addr), 0x42);
+        }
+
+        bus.write(0x1234, 0x55);
+        assert_eq!(bus.read(0x0234), 0x55);
+        assert_eq!(bus.read(0x0a34), 0x55);
+        assert_eq!(bus.probe(0x1a34), Some(0x55));
+
+        // Above the RAM there is no mirroring.
+        bus.write(0x2000, 0x12);
+        assert_eq!(bus.read(0x2800), 0x00);
+    }
+
+    #[test]
+    fn test_array_bus_load() {
+        let mut bus = ArrayBus::new();
+        bus.load(0x8000, &[0xa9, 0x42]);
+        assert_eq!(bus.read(0x8000), 0xa9);
+        assert_eq!(bus.read(0x8001), 0x42);
+
+        bus.load(0x17ff, &[0x01, 0x02]);
+        assert_eq!(bus.read(0x07ff), 0x01);
+        assert_eq!(bus.read(0x1800), 0x02);
+    }
+
+    #[test]
+    fn test_dump_ram() {
+        let mut bus = RomBus::new();
+        bus.write(0x0801, 0x42);
+        let ram = bus.dump_ram();
+        assert_eq!(ram.len(), 0x800);
+        assert_eq!(ram[1], 0x42);
+
+        let mut other = RomBus::new();
+        other.load_ram(&ram).unwrap();
+        assert_eq!(other.read(0x0001), 0x42);
+
+        let mut bus = ArrayBus::new();
+        bus.write(0x8000, 0x55);
+        let mut other = ArrayBus::new();
+        other.load_ram(&bus.dump_ram()).unwrap();
+        assert_eq!(other.read(0x8000), 0x55);
+
+        // RAM of the other bus doesn't fit, and is left alone.
+        assert!(other.load_ram(&ram).is_err());
+        let mut rom_bus = RomBus::new();
+        assert_eq!(rom_bus.load_ram(&bus.dump_ram()), Err(String::from("The RAM is 65536 bytes, expected 2048.")));
+        assert_eq!(rom_bus.read(0x0001), 0x00);
+        assert!(crate::testing::TestBus::new().load_ram(&ram).is_err());
+    }
+
+    #[test]
+    fn test_rom_write() {
+        let mut bus = RomBus::new();
+        bus.set_rom(Box::new(ConstRom(0x00)));
+
+        bus.write(0x8000, 0x42);
+        assert_eq!(bus.take_fault(), Some(0x8000));
+        assert_eq!(bus.take_fault(), None);
+        assert_eq!(bus.read(0x8000), 0x00);
+
+        bus.set_ignore_rom_writes(true);
+        bus.write(0x8000, 0x42);
+        assert_eq!(bus.take_fault(), None);
+        assert_eq!(bus.read(0x8000), 0x00);
+    }
+
+    #[test]
+    fn test_mapper_register_writes() {
+        use crate::rom::rom_from_bytes;
+        use crate::test_rom::TestRomBuilder;
+
+        let prg: Vec<u8> = (0..2 * 0x4000).map(|i| (i / 0x4000) as u8).collect();
+        let raw = TestRomBuilder::new().mapper(1).prg_banks(2).prg_data(&prg).build();
+        let mut bus = RomBus::new();
+        bus.set_rom(rom_from_bytes(&raw, None).unwrap());
+
+        assert_eq!(bus.read(0x8000), 0);
+        for _ in 0..5 { bus.write(0xe000, 1); }
+        assert_eq!(bus.take_fault(), None);
+        assert_eq!(bus.read(0x8000), 1);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        use crate::rom::rom_from_bytes;
+        use crate::test_rom::TestRomBuilder;
+
+        let mut bus = RomBus::new();
+        bus.set_rom(rom_from_bytes(&TestRomBuilder::new().mapper(1).prg_banks(2).build(), None).unwrap());
+        bus.write(0x6004, 0x42);
+        assert_eq!(bus.read(0x6004), 0x42);
+        assert_eq!(bus.probe(0x6004), Some(0x42));
+
+        // Disabled by the mapper, reads are open bus.
+        for i in 0..5 { bus.write(0xe000, (0b1_0000 >> i) & 1); }
+        bus.write(0x0000, 0x37);
+        assert_eq!(bus.read(0x6004), 0x37);
+        assert_eq!(bus.take_fault(), None);
+    }
+
+    // Ppu that records the register accesses.
+    #[derive(Default)]
+    struct RecordingPpu {
+        writes: std::rc::Rc<std::cell::RefCell<Vec<(u8, u8)>>>,
+    }
+
+    impl Ppu for RecordingPpu {
+        fn read_register(&mut self, reg: u8) -> u8 { reg | 0x80 }
+        fn write_register(&mut self, reg: u8, val: u8) { self.writes.borrow_mut().push((reg, val)); }
+    }
+
+    #[test]
+    fn test_ppu_registers() {
+        let mut bus = RomBus::new();
+        bus.write(0x2000, 0x80);
+        assert_eq!(bus.read(0x2002), 0x80); // no ppu connected, open bus
+
+        let ppu = RecordingPpu::default();
+        let writes = ppu.writes.clone();
+        bus.set_ppu(Box::new(ppu));
+        assert_eq!(bus.read(0x2002), 0x82);
+        assert_eq!(bus.read(0x3ffe), 0x86); // mirrored
+        bus.write(0x2006, 0x3f);
+        bus.write(0x3f07, 0x12);
+        assert_eq!(*writes.borrow(), vec![(6, 0x3f), (7, 0x12)]);
+    }
+
+    #[test]
+    fn test_stub_ppu_vram() {
+        let mut bus = RomBus::new();
+        bus.set_ppu(Box::new(crate::ppu::StubPpu::new()));
+        bus.write(0x2006, 0x20);
+        bus.write(0x200e, 0x40); // PPUADDR, mirrored
+        bus.write(0x3ff7, 0x99); // PPUDATA, mirrored
+        bus.write(0x2006, 0x20);
+        bus.write(0x2006, 0x40);
+        bus.read(0x2007);
+        assert_eq!(bus.read(0x2007), 0x99);
+    }
+
+    #[test]
+    fn test_recorder() {
+        let mut bus = RomBus::new();
+        let mut recorder = BusRecorder::new(300);
+        recorder.add_range(0x0200..=0x02ff);
+        bus.set_recorder(Some(recorder));
+        bus.write(0x0000, 0x01);
+        bus.tick(4);
+        bus.write(0x0201, 0x02);
+        bus.write(0x4014, 0x02); // DMA reads are recorded too
+
+        let accesses: Vec<BusAccess> = bus.recorder().unwrap().accesses().copied().collect();
+        assert_eq!(accesses.len(), 257);
+        assert_eq!(accesses[0], BusAccess { cycle: 4, addr: 0x0201, value: 0x02, write: true });
+        assert_eq!(accesses[2], BusAccess { cycle: 4, addr: 0x0201, value: 0x02, write: false });
+
+        bus.set_recorder(None);
+        bus.read(0x0201);
+        assert!(bus.recorder().is_none());
+    }
+
+    #[test]
+    fn test_debug_port() {
+        let mut bus = RomBus::new();
+        bus.write(0x401a, b'x'); // not enabled
+        bus.set_debug_port(Some(0x401a));
+        for val in b"ok\npassed\0" { bus.write(0x401a, *val); }
+        bus.write(0x401a, b'.');
+        assert_eq!(bus.take_debug_output(), ["ok", "passed"]);
+        assert!(bus.take_debug_output().is_empty());
+    }
+
+    #[test]
+    fn test_test_rom_text() {
+        use crate::rom::rom_from_bytes;
+        use crate::test_rom::TestRomBuilder;
+
+        let mut bus = RomBus::new();
+        bus.set_rom(rom_from_bytes(&TestRomBuilder::new().build(), None).unwrap());
+        assert_eq!(bus.test_rom_text(), None);
+        for (i, val) in [0x80, 0xde, 0xb0, 0x61].iter().chain(b"Passed\n\0").enumerate() {
+            bus.write(0x6000 + i as u16, *val);
+        }
+        assert_eq!(bus.test_rom_text().as_deref(), Some("Passed\n"));
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut bus = RomBus::new();
+        let ppu = RecordingPpu::default();
+        let writes = ppu.writes.clone();
+        bus.set_ppu(Box::new(ppu));
+        for i in 0..=0xff {
+            bus.write(0x0200 + i, i as u8 ^ 0x5a);
+        }
+
+        bus.write(0x4014, 0x02);
+        let expected: Vec<(u8, u8)> = (0..=0xff).map(|i| (OAMDATA, i ^ 0x5a)).collect();
+        assert_eq!(*writes.borrow(), expected);
+        assert_eq!(bus.take_stall(), 513);
+        assert_eq!(bus.take_stall(), 0);
+
+        // One more cycle to align, when started on an odd cycle.
+        bus.tick(3);
+        bus.write(0x4014, 0x02);
+        assert_eq!(bus.take_stall(), 514);
+        assert_eq!(bus.read(0x0210), 0x10 ^ 0x5a); // the bus still works as before
+    }
+
+    #[test]
+    fn test_dmc_dma() {
+        let mut bus = RomBus::new();
+        bus.set_rom(Box::new(ConstRom(0x42)));
+        bus.write(0x4010, 0x0f); // fastest rate, 54 * 8 cycles per byte
+        bus.write(0x4012, 0x01); // $c040
+        bus.write(0x4013, 0x00); // 1 byte
+        bus.write(0x4015, 0x10);
+        bus.tick(1);
+        assert_eq!(bus.take_stall(), 4);
+        assert_eq!(bus.apu().dmc_buffer(), 0x42);
+        assert_eq!(bus.read(0x4018), 0x42); // the fetch drove the data bus
+
+        // During an OAM DMA, the fetch only adds 2 cycles.
+        bus.tick(54 * 8);
+        assert_eq!(bus.read(0x4015) & 0x10, 0x00);
+        bus.write(0x4014, 0x02);
+        bus.write(0x4015, 0x10);
+        let stall = bus.take_stall();
+        bus.tick(stall);
+        assert_eq!(bus.take_stall(), 2);
+
+        // Long after it, the fetch stalls for 4 cycles again.
+        bus.tick(54 * 8 * 2);
+        bus.write(0x4015, 0x10);
+        bus.tick(1);
+        assert_eq!(bus.take_stall(), 4);
+    }
+
+    #[test]
+    fn test_joypad() {
+        let mut bus = RomBus::new();
+        let joypad = Rc::new(RefCell::new(Joypad::new()));
+        bus.set_joypad(1, Rc::clone(&joypad));
+        joypad.borrow_mut().set_buttons(0b1000_0010); // B, Right
+
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+        bus.set_open_bus(0x40);
+        let bits: Vec<u8> = (0..8).map(|_| bus.read(0x4017) & 1).collect();
+        assert_eq!(bits, [0, 1, 0, 0, 0, 0, 0, 1]);
+
+        // The upper bits are open bus, and nothing is connected to $4016.
+        bus.set_open_bus(0x40);
+        assert_eq!(bus.read(0x4017), 0x41);
+        bus.set_open_bus(0x40);
+        assert_eq!(bus.read(0x4016), 0x40);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut bus = RomBus::new();
+        bus.write(0x0010, 0x42);
+        assert_eq!(bus.read(0x4018), 0x42); // the value written
+
+        bus.write(0x0010, 0x00);
+        bus.write(0x0020, 0x55);
+        assert_eq!(bus.read(0x0010), 0x00);
+        assert_eq!(bus.read(0x6000), 0x00); // the value read, not the one written
+
+        bus.write(0x0010, 0x37);
+        assert_eq!(bus.read(0x5000), 0x37); // unmapped expansion area
+        bus.set_open_bus(0x99);
+        assert_eq!(bus.read(0x4000), 0x99);
+    }
+
+    #[test]
+    fn test_apu_registers() {
+        let mut bus = RomBus::new();
+        bus.write(0x4015, 0x01);
+        bus.write(0x4003, 0xf8); // pulse 1 length 30
+        bus.write(0x4017, 0x00);
+        assert_eq!(bus.apu().register(0x4003), 0xf8);
+        bus.set_open_bus(0x00);
+        assert_eq!(bus.read(0x4015), 0x01);
+
+        // The frame interrupt goes through the irq line, until acknowledged.
+        bus.tick(29829);
+        assert!(bus.poll_irq());
+        bus.set_open_bus(0x00);
+        assert_eq!(bus.read(0x4015), 0x41);
+        assert!(!bus.poll_irq());
+
+        bus.tick(29830);
+        assert!(bus.poll_irq());
+        bus.write(0x4017, 0x40); // inhibited
+        assert!(!bus.poll_irq());
+    }
+
+    #[test]
+    fn test_partial_open_bus() {
+        let mut bus = RomBus::new();
+        bus.set_open_bus(0xff);
+        assert_eq!(bus.read(0x4016), 0xe0); // no controller, bits 4..0 are driven low
+        assert_eq!(bus.read(0x4017), 0xe0);
+
+        bus.set_open_bus(0xff);
+        assert_eq!(bus.read(0x4015), 0x20);
+        assert_eq!(bus.read(0x4018), 0xff); // $4015 didn't change the latch
+
+        bus.assert_irq(IrqSource::FrameCounter);
+        bus.assert_irq(IrqSource::Dmc);
+        bus.set_open_bus(0x00);
+        assert_eq!(bus.read(0x4015), 0xc0);
+        assert_eq!(bus.read(0x4015), 0x80); // the frame interrupt is acknowledged
+        assert!(bus.poll_irq());
+    }
+
+    #[test]
+    fn test_rom_bus_without_rom() {
+        let mut bus = RomBus::new();
+        bus.write(0x0000, 0x42);
+        assert_eq!(bus.read(0x8000), 0x42); // open bus
+        assert_eq!(bus.probe(0x8000), None);
+        bus.write(0x8000, 0x01);
+        assert_eq!(bus.take_fault(), Some(0x8000));
+    }
+
+    #[test]
+    fn test_rom_bus_nmi() {
+        let mut bus = RomBus::new();
+        assert!(!bus.poll_nmi());
+        bus.trigger_nmi();
+        bus.trigger_nmi();
+        assert!(bus.poll_nmi());
+        assert!(!bus.poll_nmi());
+    }
+
+    #[test]
+    fn test_rom_bus_irq() {
+        let mut bus = RomBus::new();
+        assert!(!bus.poll_irq());
+        bus.assert_irq(IrqSource::Mapper);
+        bus.assert_irq(IrqSource::FrameCounter);
+        assert!(bus.poll_irq());
+        assert!(bus.poll_irq()); // polling doesn't clear it
+
+        bus.clear_irq(IrqSource::Mapper);
+        assert!(bus.poll_irq());
+        bus.clear_irq(IrqSource::FrameCounter);
+        assert!(!bus.poll_irq());
+    }
+
+    // Mapper raising its interrupt after 'period' cycles, acknowledged by any register write.
+    struct IrqRom { period: u16, cycles: u16 }
+
+    impl Rom for IrqRom {
+        fn load(&mut self, _raw: &[u8], _trainer: bool) -> Result<(), String> { Ok(()) }
+        fn prg_read(&self, _address: u16) -> u8 { 0 }
+        fn chr_read(&self, _address: u16) -> u8 { 0 }
+        fn prg_write(&mut self, _address: u16, _val: u8) -> bool {
+            self.cycles = 0;
+            true
+        }
+        fn tick(&mut self, cycles: u16) {
+            self.cycles = self.cycles.saturating_add(cycles);
+        }
+        fn irq(&self) -> bool { self.cycles >= self.period }
+    }
+
+    // The violations published on 'bus' from now on, as (cycle, address).
+    fn subscribe_violations(bus: &mut RomBus) -> Rc<RefCell<Vec<(u64, u16)>>> {
+        let violations = Rc::new(RefCell::new(Vec::new()));
+        let received = Rc::clone(&violations);
+        bus.events().subscribe(EventKind::AccessViolation, move |event| {
+            if let Event::AccessViolation(violation) = event {
+                received.borrow_mut().push((violation.cycle, violation.addr));
+            }
+        });
+        violations
+    }
+
+    #[test]
+    fn test_protection() {
+        let mut bus = RomBus::new();
+        let violations = subscribe_violations(&mut bus);
+        bus.protect(0x0100..=0x01ff, Protection::ReadOnly);
+        bus.write(0x01ff, 0x42);
+        assert_eq!(bus.read(0x01ff), 0x42); // reported, not blocked
+        bus.tick(5);
+        bus.write(0x01fe, 0x01);
+        bus.tick(1);
+        assert_eq!(*violations.borrow(), [(0, 0x01ff), (5, 0x01fe)]);
+
+        bus.clear_protections();
+        bus.write(0x01fe, 0x01);
+        bus.tick(1);
+        assert_eq!(violations.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_dump_and_restore() {
+        use crate::rom::rom_from_bytes;
+        use crate::test_rom::TestRomBuilder;
+        let raw = TestRomBuilder::new().prg_data(&[0xea]).build();
+        let mut bus = RomBus::new();
+        bus.set_rom(rom_from_bytes(&raw, None).unwrap());
+        bus.write(0x0001, 0x11);
+        bus.write(0x6000, 0x60);
+        assert_eq!(bus.dump(0x0000..=0x0002), [0x00, 0x11, 0x00]);
+        assert_eq!(bus.dump(0x2000..=0x2001), [0x00, 0x00]); // registers
+        let prg_ram = bus.dump(0x6000..=0x7fff);
+        assert_eq!((prg_ram.len(), prg_ram[0]), (0x2000, 0x60));
+
+        let mut other = RomBus::new();
+        other.set_rom(rom_from_bytes(&raw, None).unwrap());
+        other.restore(0x6000, &prg_ram);
+        other.restore(0x0800, &[0x22]); // mirror of 0x0000
+        assert_eq!(other.dump(0x6000..=0x7fff), prg_ram);
+        assert_eq!(other.read(0x0000), 0x22);
+    }
+
+    #[test]
+    fn test_events() {
+        use crate::ppu::StubPpu;
+
+        let mut bus = RomBus::new();
+        bus.set_ppu(Box::new(StubPpu::new()));
+        let received = Rc::new(RefCell::new(Vec::new()));
+        for kind in [EventKind::DmaStarted, EventKind::NmiTriggered, EventKind::IrqTriggered, EventKind::VblankStarted] {
+            let received = Rc::clone(&received);
+            bus.events().subscribe(kind, move |event| received.borrow_mut().push(*event));
+        }
+
+        bus.write(0x4014, 0x02);
+        bus.trigger_nmi();
+        assert!(bus.poll_nmi());
+        bus.assert_irq(IrqSource::Mapper);
+        assert!(bus.poll_irq());
+        assert!(bus.poll_irq()); // still the same interrupt
+        assert!(received.borrow().is_empty()); // delivered on the next tick
+
+        bus.tick(29781);
+        assert_eq!(*received.borrow(), [
+            Event::DmaStarted(0x02), Event::NmiTriggered, Event::IrqTriggered, Event::VblankStarted,
+        ]);
+    }
+
+    #[test]
+    fn test_mapper_irq() {
+        let mut bus = RomBus::new();
+        bus.set_rom(Box::new(IrqRom { period: 100, cycles: 0 }));
+        bus.tick(99);
+        assert!(!bus.poll_irq());
+        bus.tick(1);
+        assert!(bus.poll_irq());
+        bus.write(0xe000, 0x00);
+        assert!(!bus.poll_irq());
+    }
+
+    #[test]
+    fn test_interrupt_lines() {
+        let mut bus = RomBus::new();
+        let lines = bus.interrupt_lines();
+        lines.trigger_nmi();
+        assert!(bus.poll_nmi());
+        assert!(!lines.take_nmi()); // polled by the bus
+
+        lines.assert_irq(IrqSource::FrameCounter);
+        assert!(bus.poll_irq());
+        assert_eq!(bus.read(0x4015) & 0x40, 0x40);
+        assert!(!lines.irq()); // acknowledged through $4015
+    }
+
+    #[test]
+    fn test_sparse_memory() {
+        let mut bus = SparseMemory::new();
+        assert_eq!(bus.read(0x1234), 0xff);
+
+        bus.write(0x1234, 0x42);
+        assert_eq!(bus.read(0x1234), 0x42);
+        assert_eq!(bus.data.len(), 1);
+
+        bus.load(0x8000, &[0x01, 0x02]);
+        assert_eq!(bus.read(0x8001), 0x02);
+    }
+
+    #[test]
+    fn test_probe() {
+        let mut sparse = SparseMemory::new();
+        sparse.write(0x1234, 0x42);
+        assert_eq!(sparse.probe(0x1234), Some(0x42));
+        assert_eq!(sparse.probe(0x1235), None);
+
+        assert_eq!(ArrayBus::new().probe(0xffff), Some(0));
+
+        let mut bus = RomBus::new();
+        bus.set_rom(Box::new(ConstRom(0x00)));
+        bus.add_game_genie("SXIOPO").unwrap();
+        bus.write(0x0010, 0x42);
+        assert_eq!(bus.probe(0x0810), Some(0x42)); // mirrored
+        assert_eq!(bus.probe(0x2002), None);
+        assert_eq!(bus.probe(0x4020), None);
+        assert_eq!(bus.probe(0x8000), Some(0x00));
+        assert_eq!(bus.probe(0x91d9), Some(0xad));
+    }
+
+    // Overclocking doesn't change the sound: the APU stands still during the extra scanlines, so
+    // the frame IRQs come after the same number of stock cycles.
+    #[test]
+    fn test_overclock_apu_timing() {
+        // The stock cycles at which the first frame IRQs fire, and the cpu cycles it took.
+        fn frame_irqs(extra_scanlines: u16) -> (Vec<u64>, u64) {
+            let mut ppu = crate::ppu::StubPpu::new();
+            ppu.set_extra_scanlines(extra_scanlines);
+            let mut bus = RomBus::new();
+            bus.set_ppu(Box::new(ppu));
+            let mut stock_cycles = 0;
+            let mut irqs = Vec::new();
+            while irqs.len() < 4 {
+                bus.tick(1);
+                if !bus.ppu.as_ref().unwrap().in_extra_scanlines() { stock_cycles += 1; }
+                if bus.interrupts.irq_asserted(IrqSource::FrameCounter) {
+                    irqs.push(stock_cycles);
+                    bus.read(0x4015); // acknowledges it
+                }
+            }
+            (irqs, bus.cycles)
+        }
+
+        let (stock, stock_cycles) = frame_irqs(0);
+        let (overclocked, overclocked_cycles) = frame_irqs(20);
+        assert_eq!(overclocked, stock);
+        // Three vblanks with 20 extra scanlines (20 * 341 / 3 cycles) each, give or take the dots
+        // left over from a cycle.
+        assert!((6819..=6822).contains(&(overclocked_cycles - stock_cycles)));
+    }
 }
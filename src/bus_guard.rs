@@ -0,0 +1,129 @@
+// Diagnostics for the accesses of a program: address ranges can be marked read-only, write-only,
+// trapped, reserved for the stack, or checked for reads before initialization. Violations don't
+// stop anything, the access still goes through, they are returned for the caller to report (see
+// Event::AccessViolation).
+
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
+
+use crate::mirror::ram_index;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protection {
+    // Writes are violations.
+    ReadOnly,
+    // Reads are violations.
+    WriteOnly,
+    // Any access is a violation.
+    Trap,
+    // Accesses other than the pushes and pulls of the cpu are violations, e.g. a program writing
+    // over the stack page with sta.
+    StackOnly,
+    // Reads of addresses not written since the range was guarded, e.g. RAM the program relies on
+    // being cleared at power on.
+    Uninitialized,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccessViolation {
+    // Cpu cycles ticked on the bus when the access happened.
+    pub cycle: u64,
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
+    pub protection: Protection,
+}
+
+#[derive(Debug, Default)]
+pub struct AccessGuard {
+    ranges: Vec<(RangeInclusive<u16>, Protection)>,
+    // Addresses written in the Uninitialized ranges, with the RAM mirrors folded.
+    written: HashSet<u16>,
+}
+
+impl AccessGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn protect(&mut self, range: RangeInclusive<u16>, protection: Protection) {
+        self.ranges.push((range, protection));
+    }
+
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+        self.written.clear();
+    }
+
+    // Writes to a mirror of the internal RAM initialize the address it mirrors.
+    fn cell(addr: u16) -> u16 {
+        if addr < 0x2000 { ram_index(addr) as u16 } else { addr }
+    }
+
+    // Returns the protections the access breaks. 'stack' is set for the pushes and pulls of the cpu.
+    pub fn check(&mut self, cycle: u64, addr: u16, value: u8, write: bool, stack: bool) -> Vec<AccessViolation> {
+        let mut violations = Vec::new();
+        for (range, protection) in self.ranges.iter() {
+            if !range.contains(&addr) { continue; }
+            let violated = match protection {
+                Protection::ReadOnly => write,
+                Protection::WriteOnly => !write,
+                Protection::Trap => true,
+                Protection::StackOnly => !stack,
+                Protection::Uninitialized if write => {
+                    self.written.insert(Self::cell(addr));
+                    false
+                },
+                Protection::Uninitialized => !self.written.contains(&Self::cell(addr)),
+            };
+            if violated {
+                violations.push(AccessViolation { cycle, addr, value, write, protection: *protection });
+            }
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_protections() {
+        let mut guard = AccessGuard::new();
+        guard.protect(0x8000..=0xffff, Protection::ReadOnly);
+        guard.protect(0x2000..=0x2000, Protection::WriteOnly);
+        guard.protect(0x0100..=0x01ff, Protection::Trap);
+        let cycles: Vec<u64> = [
+            guard.check(0, 0x8000, 0x01, false, false),
+            guard.check(1, 0x8000, 0x01, true, false),
+            guard.check(2, 0x2000, 0x80, true, false),
+            guard.check(3, 0x2000, 0x00, false, false),
+            guard.check(4, 0x01fd, 0x42, false, true),
+            guard.check(5, 0x0200, 0x42, true, false),
+        ].iter().flatten().map(|violation| violation.cycle).collect();
+        assert_eq!(cycles, [1, 3, 4]);
+    }
+
+    #[test]
+    fn test_stack_only() {
+        let mut guard = AccessGuard::new();
+        guard.protect(0x0100..=0x01ff, Protection::StackOnly);
+        assert!(guard.check(0, 0x01ff, 0x42, true, true).is_empty()); // push
+        assert!(guard.check(1, 0x01ff, 0x42, false, true).is_empty()); // pull
+        assert_eq!(guard.check(2, 0x0150, 0x42, true, false), [
+            AccessViolation { cycle: 2, addr: 0x0150, value: 0x42, write: true, protection: Protection::StackOnly },
+        ]);
+    }
+
+    #[test]
+    fn test_uninitialized_reads() {
+        let mut guard = AccessGuard::new();
+        guard.protect(0x0000..=0x1fff, Protection::Uninitialized);
+        assert_eq!(guard.check(0, 0x0010, 0x00, false, false), [
+            AccessViolation { cycle: 0, addr: 0x0010, value: 0x00, write: false, protection: Protection::Uninitialized },
+        ]);
+        assert!(guard.check(1, 0x0810, 0x42, true, false).is_empty()); // mirror of 0x0010
+        assert!(guard.check(2, 0x0010, 0x42, false, false).is_empty());
+    }
+}
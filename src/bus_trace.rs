@@ -0,0 +1,99 @@
+// Records the accesses on the bus, for debugging mappers and registers. Only the last 'capacity'
+// accesses are kept, and when address ranges are given, only the accesses inside them.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BusAccess {
+    // Cpu cycles ticked on the bus when the access happened.
+    pub cycle: u64,
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+pub struct BusRecorder {
+    ranges: Vec<RangeInclusive<u16>>,
+    capacity: usize,
+    accesses: VecDeque<BusAccess>,
+}
+
+impl BusRecorder {
+    pub fn new(capacity: usize) -> Self {
+        BusRecorder { ranges: Vec::new(), capacity, accesses: VecDeque::with_capacity(capacity) }
+    }
+
+    // Only record accesses in 'range' (and the other ranges added).
+    pub fn add_range(&mut self, range: RangeInclusive<u16>) {
+        self.ranges.push(range);
+    }
+
+    pub fn record(&mut self, access: BusAccess) {
+        if self.capacity == 0 { return; }
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|range| range.contains(&access.addr)) {
+            return;
+        }
+        if self.accesses.len() == self.capacity {
+            self.accesses.pop_front();
+        }
+        self.accesses.push_back(access);
+    }
+
+    // Oldest first.
+    pub fn accesses(&self) -> impl Iterator<Item = &BusAccess> {
+        self.accesses.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.accesses.clear();
+    }
+
+    // One line per access, oldest first:
+    //
+    //     CYC:1234 W $4014 = $02
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        for access in self.accesses.iter() {
+            let kind = if access.write { 'W' } else { 'R' };
+            writeln!(out, "CYC:{} {} ${:04X} = ${:02X}", access.cycle, kind, access.addr, access.value)?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_to(&mut out)?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn access(addr: u16, write: bool) -> BusAccess {
+        BusAccess { cycle: 7, addr, value: 0x42, write }
+    }
+
+    #[test]
+    fn test_ring_buffer_and_filters() {
+        let mut recorder = BusRecorder::new(2);
+        recorder.add_range(0x2000..=0x3fff);
+        recorder.add_range(0x4014..=0x4014);
+        for addr in [0x2002, 0x0000, 0x2007, 0x4014, 0x8000] {
+            recorder.record(access(addr, addr == 0x4014));
+        }
+        let addrs: Vec<u16> = recorder.accesses().map(|access| access.addr).collect();
+        assert_eq!(addrs, [0x2007, 0x4014]);
+
+        let mut out = Vec::new();
+        recorder.write_to(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "CYC:7 R $2007 = $42\nCYC:7 W $4014 = $42\n");
+
+        recorder.clear();
+        assert_eq!(recorder.accesses().count(), 0);
+    }
+}
@@ -1,10 +1,17 @@
-#![allow(arithmetic_overflow)]
+#[allow(clippy::module_inception)]
 pub mod cpu {
 
-    use crate::bus::{ControlSignal, Mem};
-    use std::{thread, time};
-
-    enum AddressingMode {
+    use crate::bus::Mem;
+    use crate::instruction_table::{Mnemonic, TABLE};
+    use crate::disasm::disassemble;
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::ops::RangeInclusive;
+    use std::{fmt, io, thread, time};
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum AddressingMode {
+        Implied,
+        Accumulator,
         Immediate,
         ZeroPage,
         ZeroPageX,
@@ -17,10 +24,12 @@ pub mod cpu {
         IndexedIndirectY,
         IndirectIndexedX,
         IndirectIndexedY,
+        Relative,
     }
 
     #[repr(u8)]
-    enum Flag {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Flag {
         N = 0b1000_0000, // negative
         V = 0b0100_0000, // overflow
         //
@@ -31,6 +40,345 @@ pub mod cpu {
         C = 0b0000_0001, // carry
     }
 
+    #[derive(Debug, PartialEq)]
+    pub enum CpuError {
+        // The program tried to write to read-only memory at the address.
+        SegmentationFault(u16),
+        // The opcode is not implemented.
+        IllegalOpcode(u8),
+        // A KIL opcode locked up the cpu. Only a reset gets it going again.
+        Halted(u8),
+        // A call (see CPU::call_subroutine) didn't return within the number of instructions.
+        StepLimit(u64),
+    }
+
+    impl fmt::Display for CpuError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                CpuError::SegmentationFault(addr) => write!(f, "invalid write to {:#06x}", addr),
+                CpuError::IllegalOpcode(opcode) => write!(f, "illegal opcode {:#04x}", opcode),
+                CpuError::Halted(opcode) => write!(f, "halted by opcode {:#04x}", opcode),
+                CpuError::StepLimit(steps) => write!(f, "gave up after {} instructions", steps),
+            }
+        }
+    }
+
+    impl std::error::Error for CpuError {}
+
+    // Which 6502 the core behaves as. Only the differences below are modelled, the instructions
+    // the 65C02 added are not implemented.
+    #[derive(Debug, Clone, Copy, PartialEq, Default)]
+    pub enum CpuVariant {
+        // The NES cpu: an NMOS 6502 with the decimal mode removed. The D flag can be set, but adc
+        // and sbc ignore it.
+        #[default]
+        Ricoh2A03,
+        // NMOS 6502 with decimal mode. In decimal mode, N, V and Z are set as the binary operation
+        // (or an intermediate result of it) would set them.
+        Nmos6502,
+        // CMOS 65C02: decimal mode sets N and Z from the result and takes a cycle more, jmp ($xxff)
+        // reads the high byte of the pointer from the next page, and interrupts clear D.
+        Cmos65C02,
+    }
+
+    // What the cpu does with the opcodes it doesn't implement (the "???" ones of the instruction
+    // table).
+    #[derive(Debug, Clone, Copy, PartialEq, Default)]
+    pub enum IllegalOpcodePolicy {
+        // step returns CpuError::IllegalOpcode, and runs stop with StopReason::IllegalOpcode. The
+        // program counter is left on the opcode, as for a breakpoint.
+        #[default]
+        Break,
+        // Locks up the cpu like a KIL opcode.
+        Halt,
+        // Logs a warning and skips the instruction, taking its documented length and cycles.
+        Nop,
+        Panic,
+    }
+
+    // When CPU::run should stop.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum RunMode {
+        Unlimited,
+        InstructionLimit(u64),
+        // Stops at the first instruction boundary after the given number of cycles.
+        CycleBudget(u64),
+        // Stops before executing the instruction at the address.
+        UntilPc(u16),
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum StopReason {
+        LimitReached,
+        PcReached,
+        // The condition given to run_until became true.
+        ConditionMet,
+        // The program counter reached a breakpoint.
+        Breakpoint(u16),
+        // The last instruction accessed a watched address.
+        Watchpoint(WatchHit),
+        IllegalOpcode(u8),
+        Halted(u8),
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct RunResult {
+        pub instructions: u64,
+        pub cycles: u64,
+        pub stop_reason: StopReason,
+    }
+
+    // Accesses a watchpoint reacts to.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum WatchKind {
+        Read,
+        Write,
+        ReadWrite,
+    }
+
+    // The first access to a watched address during an instruction.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct WatchHit {
+        pub addr: u16,
+        pub value: u8,
+        pub write: bool,
+        // Address of the instruction that made the access.
+        pub pc: u16,
+    }
+
+    // A subroutine call on the shadow call stack, see CPU::set_call_tracking.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct CallFrame {
+        // Address of the jsr instruction.
+        pub caller: u16,
+        pub target: u16,
+        // The stack pointer after the return address was pushed. The frame is dropped once the stack
+        // pointer moves above it, however the return address got pulled.
+        pub stack_pointer: u8,
+    }
+
+    // An rts that pulled a different return address than the one its jsr pushed.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct ReturnMismatch {
+        // Address of the rts instruction.
+        pub pc: u16,
+        pub frame: CallFrame,
+        // Where the rts went.
+        pub returned_to: u16,
+    }
+
+    // What the rest of the emulator needs from a cpu core, so other cores (a cycle-stepped one, a
+    // 65C02) can be plugged in, and tests can run two of them in lockstep. Interrupts are raised on
+    // the bus, the core polls it between instructions.
+    pub trait Cpu {
+        type Bus: Mem;
+
+        // Executes one instruction, and returns the number of cycles it took.
+        fn step(&mut self) -> Result<u64, CpuError>;
+        fn reset(&mut self);
+        fn state(&self) -> CpuState;
+        fn set_state(&mut self, state: CpuState);
+        // Cycles executed since the cpu was created.
+        fn cycles(&self) -> u64;
+        fn bus(&self) -> &Self::Bus;
+        fn bus_mut(&mut self) -> &mut Self::Bus;
+    }
+
+    // Register values of the cpu at a point in time.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct CpuState {
+        pub register_a: u8,
+        pub register_x: u8,
+        pub register_y: u8,
+        pub stack_pointer: u8,
+        pub status: u8,
+        pub program_counter: u16,
+    }
+
+    impl CpuState {
+        // Lists what differs in 'actual', when self is the expected state.
+        pub fn diff(&self, actual: &CpuState) -> CpuStateDiff {
+            let registers = [
+                (RegisterName::A, self.register_a as u16, actual.register_a as u16),
+                (RegisterName::X, self.register_x as u16, actual.register_x as u16),
+                (RegisterName::Y, self.register_y as u16, actual.register_y as u16),
+                (RegisterName::StackPointer, self.stack_pointer as u16, actual.stack_pointer as u16),
+                (RegisterName::ProgramCounter, self.program_counter, actual.program_counter),
+            ];
+            let flags = [Flag::N, Flag::V, Flag::B, Flag::D, Flag::I, Flag::Z, Flag::C]
+                .map(|flag| (flag, self.status & flag as u8 != 0, actual.status & flag as u8 != 0));
+
+            CpuStateDiff {
+                changed_registers: registers.into_iter().filter(|(_, expected, got)| expected != got).collect(),
+                changed_flags: flags.into_iter().filter(|(_, expected, got)| expected != got).collect(),
+            }
+        }
+    }
+
+    // Everything needed to resume the execution later: the registers, the cycle count and the RAM
+    // of the bus (see Mem::dump_ram).
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct CpuSnapshot {
+        pub register_a: u8,
+        pub register_x: u8,
+        pub register_y: u8,
+        pub stack_pointer: u8,
+        pub status: u8,
+        pub program_counter: u16,
+        pub cycles: u64,
+        pub ram: Vec<u8>,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum RegisterName {
+        A,
+        X,
+        Y,
+        StackPointer,
+        ProgramCounter,
+    }
+
+    impl fmt::Display for RegisterName {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                RegisterName::A => write!(f, "register_a"),
+                RegisterName::X => write!(f, "register_x"),
+                RegisterName::Y => write!(f, "register_y"),
+                RegisterName::StackPointer => write!(f, "stack_pointer"),
+                RegisterName::ProgramCounter => write!(f, "program_counter"),
+            }
+        }
+    }
+
+    // Cycles spent executing each address, and each subroutine. See CPU::set_profiling.
+    #[derive(Debug, Default, Clone, PartialEq)]
+    pub struct Profile {
+        pub cycles_by_pc: HashMap<u16, u64>,
+        // Keyed by the jsr target. Only the instructions of the subroutine itself are counted, not
+        // those of the subroutines it calls.
+        pub cycles_by_subroutine: HashMap<u16, u64>,
+    }
+
+    impl Profile {
+        // The 'n' subroutines that took the most cycles, hottest first.
+        pub fn hot_subroutines(&self, n: usize) -> Vec<(u16, u64)> {
+            Self::top(&self.cycles_by_subroutine, n)
+        }
+
+        // The 'n' instructions that took the most cycles, hottest first.
+        pub fn hot_addresses(&self, n: usize) -> Vec<(u16, u64)> {
+            Self::top(&self.cycles_by_pc, n)
+        }
+
+        fn top(cycles: &HashMap<u16, u64>, n: usize) -> Vec<(u16, u64)> {
+            let mut entries: Vec<(u16, u64)> = cycles.iter().map(|(addr, cycles)| (*addr, *cycles)).collect();
+            entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            entries.truncate(n);
+            entries
+        }
+    }
+
+    // Snapshots taken every 'interval' instructions for CPU::step_back, the oldest dropped after
+    // 'capacity'. Each is kept with the number of instructions executed before it.
+    struct Rewind {
+        interval: u64,
+        capacity: usize,
+        snapshots: VecDeque<(u64, CpuSnapshot)>,
+    }
+
+    // Called with the address and the opcode of an instruction, and the registers before (or after)
+    // executing it. See CPU::on_before_instruction.
+    pub type InstructionHook = Box<dyn FnMut(u16, u8, &CpuState)>;
+
+    // Registers and flags that differ between two states, as (name, expected, got). The status
+    // register is compared flag by flag.
+    #[derive(Debug, Default, PartialEq)]
+    pub struct CpuStateDiff {
+        pub changed_registers: Vec<(RegisterName, u16, u16)>,
+        pub changed_flags: Vec<(Flag, bool, bool)>,
+    }
+
+    impl CpuStateDiff {
+        pub fn is_empty(&self) -> bool {
+            self.changed_registers.is_empty() && self.changed_flags.is_empty()
+        }
+    }
+
+    impl fmt::Display for CpuStateDiff {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            for (name, expected, got) in self.changed_registers.iter() {
+                let width = if *name == RegisterName::ProgramCounter { 6 } else { 4 };
+                writeln!(f, "{}: expected {:#0width$x}, got {:#0width$x}", name, expected, got)?;
+            }
+            for (flag, expected, got) in self.changed_flags.iter() {
+                writeln!(f, "flag {:?}: expected {}, got {}", flag, expected, got)?;
+            }
+            Ok(())
+        }
+    }
+
+    // Return address of subroutines called through CPU::call_subroutine.
+    const SUBROUTINE_SENTINEL: u16 = 0xfffe;
+    // Instructions a subroutine called by the emulator gets to return, e.g. a subroutine that never
+    // returns, or unbalances the stack, doesn't hang the caller.
+    const CALL_STEP_LIMIT: u64 = 1_000_000;
+
+    // Locations of the interrupt handler addresses.
+    const NMI_VECTOR: u16 = 0xfffa;
+    const IRQ_VECTOR: u16 = 0xfffe;
+    const RESET_VECTOR: u16 = 0xfffc;
+
+    // Scratch RAM programs passed to CPU::execute_inline are copied to.
+    const INLINE_ADDRESS: u16 = 0x0200;
+
+    // Clock speeds of the NTSC and PAL cpus, run throttles execution to the NTSC one by default.
+    pub const NTSC_CLOCK_HZ: u64 = 1_789_773;
+    pub const PAL_CLOCK_HZ: u64 = 1_662_607;
+
+    // The TV system the console was made for. It sets the cpu clock, and the length of a frame.
+    // See: https://www.nesdev.org/wiki/Cycle_reference_chart
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Region {
+        Ntsc,
+        Pal,
+    }
+
+    impl Region {
+        pub fn clock_hz(self) -> u64 {
+            match self {
+                Region::Ntsc => NTSC_CLOCK_HZ,
+                Region::Pal => PAL_CLOCK_HZ,
+            }
+        }
+
+        // Cpu cycles per frame, rounded. Throttled runs catch up with the clock once per frame
+        // instead of every instruction.
+        pub fn cycles_per_frame(self) -> u64 {
+            match self {
+                Region::Ntsc => 29_781,
+                Region::Pal => 33_248,
+            }
+        }
+
+        pub fn scanlines(self) -> u64 {
+            match self {
+                Region::Ntsc => 262,
+                Region::Pal => 312,
+            }
+        }
+    }
+
+    // Bit 5 of the status register is unused, and always reads as 1.
+    const UNUSED_FLAG: u8 = 0b0010_0000;
+
+    // Location an instruction operates on. Accumulator mode instructions use register a instead of memory.
+    enum Operand {
+        Accumulator,
+        Memory(u16),
+    }
+
+    #[allow(clippy::upper_case_acronyms)]
     pub struct CPU <T: Mem>{
         pub register_a: u8,
         pub register_x: u8,
@@ -39,20 +387,58 @@ pub mod cpu {
         pub status: u8,
         pub program_counter: u16,
         pub debug: bool,
+        // Cycles executed since the cpu was created.
+        pub cycles: u64,
+        // Whether the indexed address of the current instruction is on another page than its base
+        // address. Reads take a cycle longer then.
+        page_crossed: bool,
+        // Addresses the run loop stops at, before executing the instruction there.
+        breakpoints: HashSet<u16>,
+        watchpoints: Vec<(RangeInclusive<u16>, WatchKind)>,
+        watch_hit: Option<WatchHit>,
+        // Address of the instruction being executed, for watch hits.
+        instruction_pc: u16,
+        // The KIL opcode that halted the cpu, if any.
+        halted: Option<u8>,
+        // Stores and read-modify-write instructions always read the unfixed indexed address (with
+        // the carry not yet added to the high byte), other instructions only on a page cross.
+        always_fixup: bool,
+        // Cycles the cpu is stalled for before the next instruction, see stall.
+        stall_cycles: u64,
+        // Cycles of the current instruction the bus was already ticked for, see mem_read. None
+        // outside of step.
+        bus_cycles: Option<u64>,
+        // Clock speed set with set_clock_speed, instead of the one of the region. Kept apart from
+        // the region, so switching regions or overclocking doesn't undo it.
+        clock_override: Option<u64>,
+        unthrottled: bool,
+        region: Region,
+        illegal_opcode_policy: IllegalOpcodePolicy,
+        // Receives a trace line before every instruction, see trace_line.
+        trace: Option<Box<dyn io::Write>>,
+        // The shadow call stack, None unless call tracking is on.
+        call_stack: Option<Vec<CallFrame>>,
+        return_mismatch: Option<ReturnMismatch>,
+        before_hooks: Vec<InstructionHook>,
+        after_hooks: Vec<InstructionHook>,
+        profile: Option<Profile>,
+        // Instructions executed since the cpu was created, for step_back.
+        instructions: u64,
+        rewind: Option<Rewind>,
+        variant: CpuVariant,
         memory: T,
     }
 
     // Macro for generating instructions cmp, cpx, cpy
-    //
+    // Compares the register with memory by subtracting the value, without storing the result.
+    // sets: Carry, Zero, Negative
     macro_rules! cp {
         ($($name: ident, $register: ident), +) => {
             $(
                 fn $name(&mut self, mode: AddressingMode) {
                     let addr = self.get_target_address(mode);
                     let val = self.mem_read(addr);
-                    self.set_flag(Flag::C, self.$register >= val);
-                    self.set_flag(Flag::Z, self.$register == val);
-                    // need a subtract here...
+                    self.compare(self.$register, val);
                 }
             )+
         }
@@ -93,64 +479,408 @@ pub mod cpu {
                 register_x: 0,
                 register_y: 0,
                 stack_pointer: 0xff,
-                status: 0b0010_0000,
+                status: UNUSED_FLAG,
                 program_counter: 0,
-                debug: debug,
-                memory: memory,
+                debug,
+                cycles: 0,
+                page_crossed: false,
+                breakpoints: HashSet::new(),
+                watchpoints: Vec::new(),
+                watch_hit: None,
+                instruction_pc: 0,
+                halted: None,
+                always_fixup: false,
+                stall_cycles: 0,
+                bus_cycles: None,
+                clock_override: None,
+                unthrottled: false,
+                region: Region::Ntsc,
+                illegal_opcode_policy: IllegalOpcodePolicy::default(),
+                trace: None,
+                call_stack: None,
+                return_mismatch: None,
+                before_hooks: Vec::new(),
+                after_hooks: Vec::new(),
+                profile: None,
+                instructions: 0,
+                rewind: None,
+                variant: CpuVariant::default(),
+                memory,
+            }
+        }
+
+        // A cpu behaving as 'variant' instead of the 2A03.
+        pub fn with_variant(memory: T, debug: bool, variant: CpuVariant) -> Self {
+            let mut cpu = Self::new(memory, debug);
+            cpu.variant = variant;
+            cpu
+        }
+
+        pub fn variant(&self) -> CpuVariant {
+            self.variant
+        }
+
+        // Halts the cpu for 'cycles' cycles, as when DMA holds the RDY line. The cycles are added to
+        // the next step. Devices on the bus stall the cpu through Mem::take_stall instead.
+        pub fn stall(&mut self, cycles: u16) {
+            self.stall_cycles += cycles as u64;
+        }
+
+        // Sets the clock speed run throttles to, None for unlimited. Defaults to the clock of the
+        // region.
+        pub fn set_clock_speed(&mut self, hz: Option<u64>) {
+            self.clock_override = hz;
+            self.unthrottled = hz.is_none();
+        }
+
+        // Clock speed run throttles to, None runs as fast as possible. With overclocking (see
+        // StubPpu::set_extra_scanlines), the clock of the region is sped up so that the longer
+        // frames still take 1/60 s. The game gets more cycles per frame, which reduces slowdown.
+        pub fn clock_hz(&self) -> Option<u64> {
+            if self.unthrottled { return None; }
+            let scanlines = self.region.scanlines();
+            let region_hz = self.region.clock_hz() * (scanlines + self.memory.extra_scanlines() as u64) / scanlines;
+            Some(self.clock_override.unwrap_or(region_hz))
+        }
+
+        // Switches to the clock of 'region'. Defaults to NTSC.
+        pub fn set_region(&mut self, region: Region) {
+            self.region = region;
+        }
+
+        // Cpu cycles per frame, including the extra scanlines.
+        fn cycles_per_frame(&self) -> u64 {
+            let scanlines = self.region.scanlines();
+            self.region.cycles_per_frame() * (scanlines + self.memory.extra_scanlines() as u64) / scanlines
+        }
+
+        pub fn region(&self) -> Region {
+            self.region
+        }
+
+        pub fn set_illegal_opcode_policy(&mut self, policy: IllegalOpcodePolicy) {
+            self.illegal_opcode_policy = policy;
+        }
+
+        // The time the cycles executed so far take on the console, at the clock of the region.
+        pub fn elapsed(&self) -> time::Duration {
+            let nanos = self.cycles as u128 * 1_000_000_000 / self.region.clock_hz() as u128;
+            time::Duration::from_nanos(nanos as u64)
+        }
+
+        pub fn add_breakpoint(&mut self, addr: u16) {
+            self.breakpoints.insert(addr);
+        }
+
+        // Returns false if there was no breakpoint at the address.
+        pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+            self.breakpoints.remove(&addr)
+        }
+
+        // The run loop stops after an instruction that accesses an address in 'range'.
+        pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) {
+            self.watchpoints.push((range, kind));
+        }
+
+        // Returns false if there was no such watchpoint.
+        pub fn remove_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) -> bool {
+            let len = self.watchpoints.len();
+            self.watchpoints.retain(|watchpoint| *watchpoint != (range.clone(), kind));
+            self.watchpoints.len() != len
+        }
+
+        // The watched access made by the last step, if any.
+        pub fn watch_hit(&self) -> Option<WatchHit> {
+            self.watch_hit
+        }
+
+        // The bytes on the stack, from the most recently pushed one down to $01ff. The memory is read
+        // with Mem::probe, so there are no side effects, and bytes the bus can't probe are None.
+        pub fn stack_slice(&self) -> Vec<Option<u8>> {
+            (self.stack_pointer as u16 + 1..=0xff).map(|offset| self.memory.probe(0x0100 + offset)).collect()
+        }
+
+        // The stack pointer and the stack contents on one line, e.g. "SP:FA 01FB: 24 02 C0 ??".
+        pub fn dump_stack(&self) -> String {
+            let bytes: Vec<String> = self.stack_slice().iter()
+                .map(|val| val.map_or(String::from("??"), |val| format!("{:02X}", val)))
+                .collect();
+            if bytes.is_empty() {
+                return format!("SP:{:02X}", self.stack_pointer);
+            }
+            let top = 0x0100 + self.stack_pointer as u16 + 1;
+            format!("SP:{:02X} {:04X}: {}", self.stack_pointer, top, bytes.join(" "))
+        }
+
+        // Turns the shadow call stack on or off. When on, every jsr pushes a CallFrame and every rts
+        // pops one, so a debugger can show the subroutines the cpu is in. Games that drop return
+        // addresses by hand (pla, txs) are handled by dropping the frames above the stack pointer.
+        pub fn set_call_tracking(&mut self, on: bool) {
+            self.call_stack = if on { Some(Vec::new()) } else { None };
+            self.return_mismatch = None;
+        }
+
+        // The calls the cpu is in, innermost last. Empty if call tracking is off.
+        pub fn call_stack(&self) -> &[CallFrame] {
+            self.call_stack.as_deref().unwrap_or(&[])
+        }
+
+        // The rts of the last step, if it didn't return to its caller.
+        pub fn return_mismatch(&self) -> Option<ReturnMismatch> {
+            self.return_mismatch
+        }
+
+        fn track_call(&mut self, target: u16) {
+            if let Some(stack) = self.call_stack.as_mut() {
+                stack.push(CallFrame { caller: self.instruction_pc, target, stack_pointer: self.stack_pointer });
+            }
+        }
+
+        // Called after an rts pulled the return address.
+        fn track_return(&mut self) {
+            let (pc, returned_to) = (self.instruction_pc, self.program_counter);
+            let pulled_at = self.stack_pointer.wrapping_sub(2);
+            let Some(stack) = self.call_stack.as_mut() else { return };
+            stack.retain(|frame| frame.stack_pointer >= pulled_at);
+            // Without a frame there, the game pushed the address itself.
+            if stack.last().map(|frame| frame.stack_pointer) != Some(pulled_at) {
+                return;
+            }
+            let frame = stack.pop().unwrap();
+            if returned_to != frame.caller.wrapping_add(3) {
+                self.return_mismatch = Some(ReturnMismatch { pc, frame, returned_to });
+            }
+        }
+
+        // Drops the frames whose return address is no longer on the stack.
+        fn resync_call_stack(&mut self) {
+            let stack_pointer = self.stack_pointer;
+            if let Some(stack) = self.call_stack.as_mut() {
+                stack.retain(|frame| frame.stack_pointer >= stack_pointer);
+            }
+        }
+
+        // Turns the profiler on (with empty counts) or off. Subroutines are found with the shadow call
+        // stack, so this turns call tracking on too.
+        pub fn set_profiling(&mut self, on: bool) {
+            self.profile = if on { Some(Profile::default()) } else { None };
+            if on && self.call_stack.is_none() {
+                self.set_call_tracking(true);
+            }
+        }
+
+        pub fn profile(&self) -> Option<&Profile> {
+            self.profile.as_ref()
+        }
+
+        // Keeps a snapshot every 'interval' instructions, up to 'capacity' of them, so that step_back
+        // can go back up to about interval * capacity instructions. A capacity of 0 turns it off.
+        // Only the cpu and the RAM are saved, so going back over accesses to other devices (PPU,
+        // mapper registers) doesn't undo them.
+        pub fn set_rewind(&mut self, interval: u64, capacity: usize) {
+            self.rewind = (capacity > 0).then(|| Rewind { interval: interval.max(1), capacity, snapshots: VecDeque::new() });
+        }
+
+        // Undoes the last instruction, by restoring the snapshot before it and executing the
+        // instructions from there again. Trace output, hooks and the profiler are paused meanwhile.
+        // Returns false if rewinding is off, or the instruction is older than the oldest snapshot.
+        pub fn step_back(&mut self) -> Result<bool, CpuError> {
+            let Some(target) = self.instructions.checked_sub(1) else { return Ok(false) };
+            let Some(rewind) = self.rewind.as_mut() else { return Ok(false) };
+            // The snapshots after the target will be taken again on the way forward.
+            while rewind.snapshots.back().is_some_and(|(index, _)| *index > target) {
+                rewind.snapshots.pop_back();
+            }
+            let Some((index, snap)) = rewind.snapshots.back().cloned() else { return Ok(false) };
+
+            self.restore(snap).expect("Rewind snapshots are taken on this bus.");
+            self.halted = None;
+            self.instructions = index;
+            let debug = std::mem::replace(&mut self.debug, false);
+            let trace = self.trace.take();
+            let before_hooks = std::mem::take(&mut self.before_hooks);
+            let after_hooks = std::mem::take(&mut self.after_hooks);
+            let profile = self.profile.take();
+            let mut result = Ok(true);
+            while self.instructions < target && result.is_ok() {
+                result = self.step().map(|_| true);
+            }
+            self.debug = debug;
+            self.trace = trace;
+            self.before_hooks = before_hooks;
+            self.after_hooks = after_hooks;
+            self.profile = profile;
+            result
+        }
+
+        fn record_rewind(&mut self) {
+            let Some(rewind) = self.rewind.as_ref() else { return };
+            let due = self.instructions.is_multiple_of(rewind.interval);
+            let taken = rewind.snapshots.back().is_some_and(|(index, _)| *index == self.instructions);
+            if !due || taken {
+                return;
+            }
+            let snap = self.snapshot();
+            let rewind = self.rewind.as_mut().unwrap();
+            if rewind.snapshots.len() == rewind.capacity {
+                rewind.snapshots.pop_front();
+            }
+            rewind.snapshots.push_back((self.instructions, snap));
+        }
+
+        // Registers a hook called before every instruction, with the registers as they were before
+        // fetching the opcode. Tracers, coverage tools and the like can be built on these without
+        // touching the step loop.
+        pub fn on_before_instruction(&mut self, hook: InstructionHook) {
+            self.before_hooks.push(hook);
+        }
+
+        // Registers a hook called after every instruction that executed successfully, before any
+        // interrupt is serviced.
+        pub fn on_after_instruction(&mut self, hook: InstructionHook) {
+            self.after_hooks.push(hook);
+        }
+
+        pub fn clear_hooks(&mut self) {
+            self.before_hooks.clear();
+            self.after_hooks.clear();
+        }
+
+        // Sets where trace lines are written to, e.g. a file to diff against the nestest log. None
+        // turns tracing off.
+        pub fn set_trace(&mut self, sink: Option<Box<dyn io::Write>>) {
+            self.trace = sink;
+        }
+
+        // The instruction at the program counter and the registers before executing it, in the
+        // format of the nestest log (without the PPU position):
+        //
+        //     C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7
+        //
+        // The instruction is looked at with Mem::probe, so tracing doesn't change what the bus sees.
+        pub fn trace_line(&self) -> String {
+            let (instruction, _) = disassemble(&self.memory, self.program_counter);
+            format!(
+                "{:<48}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+                instruction.trim_start_matches('$'),
+                self.register_a, self.register_x, self.register_y, self.status, self.stack_pointer, self.cycles,
+            )
+        }
+
+        // While stepping, every access ticks the bus by a cycle first, so the devices (e.g. the PPU)
+        // are about where they would be when the access happens. The rest of the cycles of the
+        // instruction are ticked at its end.
+        fn tick_access(&mut self) {
+            if let Some(cycles) = self.bus_cycles.as_mut() {
+                *cycles += 1;
+                self.memory.tick(1);
+            }
+        }
+
+        // Mem::tick takes at most u16::MAX cycles, longer stalls are ticked in parts.
+        fn tick_bus(&mut self, mut cycles: u64) {
+            while cycles > 0 {
+                let part = cycles.min(u16::MAX as u64) as u16;
+                self.memory.tick(part);
+                cycles -= part as u64;
             }
         }
 
         fn mem_read(&mut self, addr: u16) -> u8 {
-            self.memory.set_control_signal(ControlSignal::MemEnable, false);
-            self.memory.set_address_bus(addr);
-            self.memory.set_control_signal(ControlSignal::AccessMode, true);
-            self.memory.set_control_signal(ControlSignal::MemEnable, true);
-            let val: u8 = self.memory.get_data_bus();
-            self.memory.set_control_signal(ControlSignal::MemEnable, false);
+            self.tick_access();
+            let val: u8 = self.memory.read(addr);
+            self.watch(addr, val, false);
             val
         }
 
         fn mem_write(&mut self, addr: u16, value: u8) {
-            self.memory.set_control_signal(ControlSignal::MemEnable, false);
-            self.memory.set_address_bus(addr);
-            self.memory.set_control_signal(ControlSignal::AccessMode, false);
-            self.memory.set_data_bus(value);
-            self.memory.set_control_signal(ControlSignal::MemEnable, true);
-            self.memory.set_control_signal(ControlSignal::MemEnable, false);
+            self.tick_access();
+            self.memory.write(addr, value);
+            self.watch(addr, value, true);
+        }
+
+        // Records the access if it is the first one of the instruction to hit a watchpoint.
+        fn watch(&mut self, addr: u16, value: u8, write: bool) {
+            if self.watch_hit.is_some() { return; }
+            let hit = self.watchpoints.iter().any(|(range, kind)| range.contains(&addr) && match kind {
+                WatchKind::Read => !write,
+                WatchKind::Write => write,
+                WatchKind::ReadWrite => true,
+            });
+            if hit {
+                self.watch_hit = Some(WatchHit { addr, value, write, pc: self.instruction_pc });
+            }
         }
 
         fn mem_read_u16(&mut self, addr: u16) -> u16 {
             let lo = self.mem_read(addr) as u16;
-            let hi = self.mem_read(addr + 1) as u16;
+            let hi = self.mem_read(addr.wrapping_add(1)) as u16;
+            (hi << 8) | lo
+        }
+        // Reads a pointer without carrying into the high byte of the address, so a pointer at $xxff
+        // has its high byte at $xx00. This is how the 6502 reads jmp ($xxff), and zero page pointers
+        // at $ff.
+        fn mem_read_u16_in_page(&mut self, addr: u16) -> u16 {
+            let lo = self.mem_read(addr) as u16;
+            let hi = self.mem_read((addr & 0xff00) | (addr.wrapping_add(1) & 0x00ff)) as u16;
             (hi << 8) | lo
         }
-        fn mem_write_u16(&mut self, addr: u16, value: u16) {
-            let lo = (value & 0xff) as u8;
-            let hi = (value >> 8) as u8;
-            self.mem_write(addr, lo);
-            self.mem_write(addr + 1, hi);
+
+        fn bulk_write(&mut self, addr: u16, data: &[u8]) {
+            for (i, val) in data.iter().enumerate() {
+                self.mem_write(addr.wrapping_add(i as u16), *val);
+            }
         }
 
+        // Pushes and pulls tell the bus they are stack accesses, see Mem::write_stack.
         fn stack_push(&mut self, val: u8) {
             let addr: u16 = 0x0100 + self.stack_pointer as u16;
-            self.mem_write(addr, val);
-            self.stack_pointer -= 1;
+            self.tick_access();
+            self.memory.write_stack(addr, val);
+            self.watch(addr, val, true);
+            self.stack_pointer = self.stack_pointer.wrapping_sub(1);
         }
 
         fn stack_pop(&mut self) -> u8 {
-            self.stack_pointer += 1;
+            self.stack_pointer = self.stack_pointer.wrapping_add(1);
             let addr: u16 = 0x0100 + self.stack_pointer as u16;
-            let val: u8 = self.mem_read(addr);
+            self.tick_access();
+            let val: u8 = self.memory.read_stack(addr);
+            self.watch(addr, val, false);
             val
         }
         
         fn fetch(&mut self) -> u8 {
             let data = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            if self.debug { print!(" {:x}", data) }
+            self.program_counter = self.program_counter.wrapping_add(1);
             data
         }
 
+        // The B flag only exists on the stack: it is set by php and brk, and clear for IRQ and NMI.
+        // Bit 5 is always pushed as 1.
+        fn push_status(&mut self, set_b: bool) {
+            let b = if set_b { Flag::B as u8 } else { 0 };
+            self.stack_push((self.status & !(Flag::B as u8)) | b | UNUSED_FLAG);
+        }
+
+        // Used by plp and rti, which ignore bits 4 and 5 of the pulled value.
+        fn pull_status(&mut self) {
+            self.status = (self.stack_pop() & !(Flag::B as u8)) | UNUSED_FLAG;
+        }
+
+        // Pushes the program counter and the status, disables interrupts and jumps to the handler at
+        // 'vector'.
+        fn interrupt(&mut self, vector: u16, set_b: bool) {
+            self.stack_push((self.program_counter >> 8) as u8);
+            self.stack_push((self.program_counter & 0xff) as u8);
+            self.push_status(set_b);
+            self.set_flag(Flag::I, true);
+            if self.variant == CpuVariant::Cmos65C02 {
+                self.set_flag(Flag::D, false);
+            }
+            self.program_counter = self.mem_read_u16(vector);
+        }
+
         fn set_flag(&mut self, flag: Flag, val: bool) {
             let code = flag as u8;
             if val {
@@ -158,6 +888,7 @@ pub mod cpu {
             } else {
                 self.status &= !code
             };
+            debug_assert!(self.status & UNUSED_FLAG != 0, "Bit 5 of the status register was cleared.");
         }
 
         fn get_flag(&mut self, flag: Flag) -> bool {
@@ -166,10 +897,22 @@ pub mod cpu {
 
         fn get_target_address(&mut self, mode: AddressingMode) -> u16 {
             match mode {
-                AddressingMode::Immediate => {self.program_counter += 1; self.program_counter-1},
+                AddressingMode::Implied => panic!("Implied mode does not refer to a memory address."),
+                AddressingMode::Accumulator => panic!("Accumulator mode does not refer to a memory address."),
+                // The signed offset is relative to the next instruction.
+                AddressingMode::Relative => {
+                    let offset = self.fetch() as i8;
+                    self.program_counter.wrapping_add(offset as u16)
+                },
+                AddressingMode::Immediate => {
+                    let addr = self.program_counter;
+                    self.program_counter = addr.wrapping_add(1);
+                    addr
+                },
                 AddressingMode::ZeroPage => self.fetch() as u16,
-                AddressingMode::ZeroPageX => self.fetch() as u16 + self.register_x as u16,
-                AddressingMode::ZeroPageY => self.fetch() as u16 + self.register_y as u16,
+                // The sum wraps around within the zero page.
+                AddressingMode::ZeroPageX => self.fetch().wrapping_add(self.register_x) as u16,
+                AddressingMode::ZeroPageY => self.fetch().wrapping_add(self.register_y) as u16,
                 AddressingMode::Absolute => {
                     let lo = self.fetch() as u16;
                     let hi = self.fetch() as u16;
@@ -178,70 +921,189 @@ pub mod cpu {
                 AddressingMode::AbsoluteX => {
                     let lo = self.fetch() as u16;
                     let hi = self.fetch() as u16;
-                    self.register_x as u16 + (hi << 8 | lo)
+                    self.indexed(hi << 8 | lo, self.register_x)
                 }
                 AddressingMode::AbsoluteY => {
                     let lo = self.fetch() as u16;
                     let hi = self.fetch() as u16;
-                    self.register_y as u16 + (hi << 8 | lo)
+                    self.indexed(hi << 8 | lo, self.register_y)
                 }
+                // Only used by jmp. The NMOS cpus read the high byte of the pointer from the same page.
                 AddressingMode::Indirect => {
-                    let val = self.fetch() as u16;
-                    self.mem_read_u16(val)
+                    let lo = self.fetch() as u16;
+                    let hi = self.fetch() as u16;
+                    if self.variant == CpuVariant::Cmos65C02 {
+                        self.cycles += 1;
+                        self.mem_read_u16(hi << 8 | lo)
+                    } else {
+                        self.mem_read_u16_in_page(hi << 8 | lo)
+                    }
                 }
+                // The pointers are in the zero page, and wrap around within it.
                 AddressingMode::IndexedIndirectX => {
-                    let val = self.fetch() as u16;
-                    self.mem_read_u16(val + self.register_x as u16)
+                    let ptr = self.fetch().wrapping_add(self.register_x) as u16;
+                    self.mem_read_u16_in_page(ptr)
                 }
                 AddressingMode::IndexedIndirectY => {
-                    let val = self.fetch() as u16;
-                    self.mem_read_u16(val + self.register_y as u16)
+                    let ptr = self.fetch().wrapping_add(self.register_y) as u16;
+                    self.mem_read_u16_in_page(ptr)
                 }
                 AddressingMode::IndirectIndexedX => {
-                    let val = self.fetch() as u16;
-                    self.mem_read_u16(val) + self.register_x as u16
+                    let ptr = self.fetch() as u16;
+                    let base = self.mem_read_u16_in_page(ptr);
+                    self.indexed(base, self.register_x)
                 }
                 AddressingMode::IndirectIndexedY => {
-                    let val = self.fetch() as u16;
-                    self.mem_read_u16(val) + self.register_y as u16
+                    let ptr = self.fetch() as u16;
+                    let base = self.mem_read_u16_in_page(ptr);
+                    self.indexed(base, self.register_y)
                 }
             }
         }
 
-        fn set_zero(&mut self, result: u8) {
-            self.set_flag(Flag::Z, result == 0);
+        fn get_operand(&mut self, mode: AddressingMode) -> Operand {
+            match mode {
+                AddressingMode::Accumulator => Operand::Accumulator,
+                _ => Operand::Memory(self.get_target_address(mode)),
+            }
         }
 
-        fn set_negative(&mut self, result: u8) {
-            let mask: u8 = 0b1000_0000;
-            self.set_flag(Flag::N, (mask & result) != 0);
+        fn read_operand(&mut self, operand: &Operand) -> u8 {
+            match operand {
+                Operand::Accumulator => self.register_a,
+                Operand::Memory(addr) => self.mem_read(*addr),
+            }
         }
 
-        fn set_carry(&mut self, a: u8, b: u8, result: u8) {
-            let mask: u8 = 0b1000_0000;
-            self.set_flag(Flag::C, (a | b) & mask != 0 && result & mask == 0);
+        // 'old' is the value read from the operand, see modify.
+        fn write_operand(&mut self, operand: &Operand, old: u8, new: u8) {
+            match operand {
+                Operand::Accumulator => self.register_a = new,
+                Operand::Memory(addr) => self.modify(*addr, old, new),
+            }
+        }
+
+        // Read-modify-write instructions write the value they read back while they compute the new
+        // one, then write the new value.
+        fn modify(&mut self, addr: u16, old: u8, new: u8) {
+            self.mem_write(addr, old);
+            self.mem_write(addr, new);
         }
 
-        fn set_overflow(&mut self, a: u8, b: u8, result: u8) {
+        fn set_zero(&mut self, result: u8) {
+            self.set_flag(Flag::Z, result == 0);
+        }
+
+        fn set_negative(&mut self, result: u8) {
             let mask: u8 = 0b1000_0000;
-            self.set_flag(
-                Flag::V,
-                (a & mask == b & mask) && (a & mask != result & mask),
-            );
+            self.set_flag(Flag::N, (mask & result) != 0);
         }
 
         // adds the contents of a memory location to the accumulator together with the carry bit
         // sets: Carry, Zero, Overflow, Negative
         fn adc(&mut self, mode: AddressingMode) {
-            let old: u8 = self.register_a;
             let addr: u16 = self.get_target_address(mode);
             let other: u8 = self.mem_read(addr);
-            self.register_a += other;
-            self.register_a += self.get_flag(Flag::C) as u8;
-            self.set_zero(self.register_a);
-            self.set_negative(self.register_a);
-            self.set_carry(old, other, self.register_a);
-            self.set_overflow(old, other, self.register_a);
+            self.add_with_carry(other);
+        }
+
+        fn decimal_mode(&mut self) -> bool {
+            self.variant != CpuVariant::Ricoh2A03 && self.get_flag(Flag::D)
+        }
+
+        // Shared by adc and the unofficial rra.
+        fn add_with_carry(&mut self, val: u8) {
+            if self.decimal_mode() {
+                self.add_decimal(val);
+            } else {
+                self.add_to_accumulator(val);
+            }
+        }
+
+        // Shared by sbc and the unofficial isb.
+        fn subtract_with_borrow(&mut self, val: u8) {
+            if self.decimal_mode() {
+                self.subtract_decimal(val);
+            } else {
+                self.add_to_accumulator(!val);
+            }
+        }
+
+        // Adds two BCD numbers, digit by digit.
+        // See: http://www.6502.org/tutorials/decimal_mode.html
+        fn add_decimal(&mut self, val: u8) {
+            let (a, b) = (self.register_a as u16, val as u16);
+            let carry = self.get_flag(Flag::C) as u16;
+            let mut low = (a & 0x0f) + (b & 0x0f) + carry;
+            if low > 0x09 {
+                low = ((low + 0x06) & 0x0f) + 0x10;
+            }
+            let mut sum = (a & 0xf0) + (b & 0xf0) + low;
+            // N and V are taken before the high digit is adjusted
+            let intermediate = sum as u8;
+            self.set_flag(Flag::V, (self.register_a ^ intermediate) & (val ^ intermediate) & 0b1000_0000 != 0);
+            if sum > 0x9f {
+                sum += 0x60;
+            }
+            self.set_flag(Flag::C, sum > 0xff);
+            let result = sum as u8;
+            if self.variant == CpuVariant::Cmos65C02 {
+                self.set_zero(result);
+                self.set_negative(result);
+                self.cycles += 1;
+            } else {
+                self.set_zero((a + b + carry) as u8);
+                self.set_negative(intermediate);
+            }
+            self.register_a = result;
+        }
+
+        // Subtracts two BCD numbers. The flags are set as by the binary subtraction, except N and Z
+        // on the 65C02.
+        fn subtract_decimal(&mut self, val: u8) {
+            let (a, b) = (self.register_a as i16, val as i16);
+            let borrow = !self.get_flag(Flag::C) as i16;
+            let mut low = (a & 0x0f) - (b & 0x0f) - borrow;
+            let result = if self.variant == CpuVariant::Cmos65C02 {
+                let mut result = a - b - borrow;
+                if result < 0 { result -= 0x60; }
+                if low < 0 { result -= 0x06; }
+                result as u8
+            } else {
+                if low < 0 { low = ((low - 0x06) & 0x0f) - 0x10; }
+                let mut result = (a & 0xf0) - (b & 0xf0) + low;
+                if result < 0 { result -= 0x60; }
+                result as u8
+            };
+            self.add_to_accumulator(!val);
+            self.register_a = result;
+            if self.variant == CpuVariant::Cmos65C02 {
+                self.set_zero(result);
+                self.set_negative(result);
+                self.cycles += 1;
+            }
+        }
+
+        // Shared by adc, sbc, and the unofficial rra and isb. sbc passes the complement of its operand,
+        // so the carry works as an inverted borrow.
+        fn add_to_accumulator(&mut self, other: u8) {
+            let old: u8 = self.register_a;
+            let sum: u16 = old as u16 + other as u16 + self.get_flag(Flag::C) as u16;
+            let result: u8 = sum as u8;
+            self.register_a = result;
+            self.set_zero(result);
+            self.set_negative(result);
+            self.set_flag(Flag::C, sum > 0xff);
+            // overflow if both inputs have the same sign, and the result has a different one
+            self.set_flag(Flag::V, (old ^ result) & (other ^ result) & 0b1000_0000 != 0);
+        }
+
+        // Shared by cmp, cpx, cpy and the unofficial dcp.
+        fn compare(&mut self, register: u8, val: u8) {
+            let diff = register.wrapping_sub(val);
+            self.set_flag(Flag::C, register >= val);
+            self.set_zero(diff);
+            self.set_negative(diff);
         }
 
         // logical and is performed, bit by bit, on the accumulator contents using the contents of a byte of memory
@@ -256,13 +1118,20 @@ pub mod cpu {
         // shifts all the bits of the accumulator or memory contents one bit left
         // sets: Carry, Zero, Negative
         fn asl(&mut self, mode: AddressingMode) {
-            let addr: u16 = self.get_target_address(mode);
-            let old: u8 = self.mem_read(addr);
+            let operand = self.get_operand(mode);
+            self.asl_operand(&operand);
+        }
+
+        // The shifts and rotations return the new value, for the unofficial opcodes combining them
+        // with another instruction.
+        fn asl_operand(&mut self, operand: &Operand) -> u8 {
+            let old: u8 = self.read_operand(operand);
             let new: u8 = old << 1;
-            self.mem_write(addr, new);
+            self.write_operand(operand, old, new);
             self.set_flag(Flag::C, old & 0b1000_0000 != 0);
             self.set_zero(new);
             self.set_negative(new);
+            new
         }
 
         // This instructions is used to test if one or more bits are set in a target memory location. The mask pattern in A is ANDed with the value in memory to set or clear the zero flag, but the result is not kept. Bits 7 and 6 of the value from memory are copied into the N and V flags.
@@ -278,21 +1147,30 @@ pub mod cpu {
 
         cp![cmp, register_a, cpx, register_x, cpy, register_y];
 
-        fn jump_rel(&mut self, condition: bool) {
-            let rel: u8 = self.fetch();
+        // Taken branches take a cycle longer, and another one if the target is on another page.
+        fn branch(&mut self, condition: bool) {
+            let target = self.get_target_address(AddressingMode::Relative);
             if !condition { return; }
-            self.program_counter;
-            if rel & 0b1000_0000 == 0 {
-                self.program_counter += (rel & 0b0111_1111) as u16;
-            } else {
-                self.program_counter += rel as u16 | 0b1111_1111_0000_0000;
+            self.cycles += 1 + (target & 0xff00 != self.program_counter & 0xff00) as u64;
+            self.program_counter = target;
+        }
+
+        // Adds the index to the low byte first, and reads from that address while the carry is added
+        // to the high byte. The dummy read is visible to devices on the bus.
+        fn indexed(&mut self, base: u16, index: u8) -> u16 {
+            let addr = base.wrapping_add(index as u16);
+            self.page_crossed = addr & 0xff00 != base & 0xff00;
+            if self.page_crossed || self.always_fixup {
+                self.mem_read((base & 0xff00) | (addr & 0x00ff));
             }
+            addr
         }
 
         fn dec(&mut self, mode: AddressingMode) {
             let addr: u16 = self.get_target_address(mode);
-            let val: u8 = self.mem_read(addr) + 0b1111_1111;
-            self.mem_write(addr, val);
+            let old: u8 = self.mem_read(addr);
+            let val: u8 = old.wrapping_sub(1);
+            self.modify(addr, old, val);
 
             self.set_zero(val);
             self.set_negative(val);
@@ -301,15 +1179,16 @@ pub mod cpu {
         fn eor(&mut self, mode: AddressingMode) {
             let addr: u16 = self.get_target_address(mode);
             let data: u8 = self.mem_read(addr);
-            self.register_a |= data; 
+            self.register_a ^= data;
             self.set_zero(self.register_a);
             self.set_negative(self.register_a);
         }
 
         fn inc(&mut self, mode: AddressingMode) {
             let addr: u16 = self.get_target_address(mode);
-            let val: u8 = self.mem_read(addr) + 0b0000_0001;
-            self.mem_write(addr, val);
+            let old: u8 = self.mem_read(addr);
+            let val: u8 = old.wrapping_add(1);
+            self.modify(addr, old, val);
 
             self.set_zero(val);
             self.set_negative(val);
@@ -324,14 +1203,19 @@ pub mod cpu {
         ld![lda, register_a, ldx, register_x, ldy, register_y];
 
         fn lsr(&mut self, mode: AddressingMode) {
-            let addr: u16 = self.get_target_address(mode);
-            let val: u8 = self.mem_read(addr);
+            let operand = self.get_operand(mode);
+            self.lsr_operand(&operand);
+        }
+
+        fn lsr_operand(&mut self, operand: &Operand) -> u8 {
+            let val: u8 = self.read_operand(operand);
             let new_val: u8 = val >> 1;
-            self.mem_write(addr, new_val);
+            self.write_operand(operand, val, new_val);
 
             self.set_flag(Flag::C, val & 0b0000_0001 != 0);
             self.set_zero(new_val);
             self.set_negative(new_val);
+            new_val
         }
         
         fn ora(&mut self, mode: AddressingMode) {
@@ -345,447 +1229,700 @@ pub mod cpu {
 
         /// rol - rotate left
         fn rol(&mut self, mode: AddressingMode) {
-            let addr: u16 = self.get_target_address(mode);
-            let val: u8 = self.mem_read(addr);
-            let new_val = (val << 1) + self.get_flag(Flag::C) as u8; // maybe need something more intricate here??
-            self.mem_write(addr, new_val);
+            let operand = self.get_operand(mode);
+            self.rol_operand(&operand);
+        }
+
+        fn rol_operand(&mut self, operand: &Operand) -> u8 {
+            let val: u8 = self.read_operand(operand);
+            let new_val = (val << 1) | self.get_flag(Flag::C) as u8;
+            self.write_operand(operand, val, new_val);
             self.set_flag(Flag::C, val & 0b1000_0000 != 0);
             self.set_zero(new_val);
             self.set_negative(new_val);
+            new_val
         }
 
         fn ror(&mut self, mode: AddressingMode) {
-            let addr: u16 = self.get_target_address(mode);
-            let val: u8 = self.mem_read(addr);
-            let new_val = (val >> 1) | ((self.get_flag(Flag::C) as u8) << 7); 
-            self.mem_write(addr, new_val);
+            let operand = self.get_operand(mode);
+            self.ror_operand(&operand);
+        }
+
+        fn ror_operand(&mut self, operand: &Operand) -> u8 {
+            let val: u8 = self.read_operand(operand);
+            let new_val = (val >> 1) | ((self.get_flag(Flag::C) as u8) << 7);
+            self.write_operand(operand, val, new_val);
             self.set_flag(Flag::C, val & 0b0000_0001 != 0);
             self.set_zero(new_val);
             self.set_negative(new_val);
+            new_val
         }
 
-        fn sbc(&mut self, _mode: AddressingMode) {
-            todo!();
+        // a - m - (1 - c) is the same as a + !m + c.
+        fn sbc(&mut self, mode: AddressingMode) {
+            let addr: u16 = self.get_target_address(mode);
+            let val: u8 = self.mem_read(addr);
+            self.subtract_with_borrow(val);
         }
 
-        st![sta, register_a, stx, register_x, sty, register_y];
+        // Unofficial opcodes. Most of them combine a read-modify-write instruction with an
+        // instruction using the result.
+        // See: https://www.nesdev.org/wiki/Programming_with_unofficial_opcodes
 
-        pub fn start(&mut self) {
-            //self.program_counter = 0xc000; //
-            self.program_counter = self.mem_read_u16(0xFFFC);
-            self.run();
+        // lda and ldx at once
+        fn lax(&mut self, mode: AddressingMode) {
+            let addr: u16 = self.get_target_address(mode);
+            let val: u8 = self.mem_read(addr);
+            self.register_a = val;
+            self.register_x = val;
+            self.set_zero(val);
+            self.set_negative(val);
         }
 
-        pub fn run(&mut self) {
-            loop {
-                if self.debug { print!("prg ctr: {:x}, cd:", self.program_counter) }
-                let opcode: u8 = self.fetch();
-
-                match opcode {
-                    // adc
-                    0x69 => self.adc(AddressingMode::Immediate),
-                    0x65 => self.adc(AddressingMode::ZeroPage),
-                    0x75 => self.adc(AddressingMode::ZeroPageX),
-                    0x6d => self.adc(AddressingMode::Absolute),
-                    0x7d => self.adc(AddressingMode::AbsoluteX),
-                    0x79 => self.adc(AddressingMode::AbsoluteY),
-                    0x61 => self.adc(AddressingMode::IndexedIndirectX),
-                    0x71 => self.adc(AddressingMode::IndirectIndexedY),
-                    // and
-                    0x29 => self.and(AddressingMode::Immediate),
-                    0x25 => self.and(AddressingMode::ZeroPage),
-                    0x35 => self.and(AddressingMode::ZeroPageX),
-                    0x2d => self.and(AddressingMode::Absolute),
-                    0x3d => self.and(AddressingMode::AbsoluteX),
-                    0x39 => self.and(AddressingMode::AbsoluteY),
-                    0x21 => self.and(AddressingMode::IndexedIndirectX),
-                    0x31 => self.and(AddressingMode::IndirectIndexedY),
-                    // asl
-                    0x0a => {
-                        self.set_flag(Flag::C, self.register_a & 0b1000_0000 != 0);
-                        self.register_a = self.register_a << 1;
-                        self.set_zero(self.register_a);
-                        self.set_negative(self.register_a);
-                    },
-                    0x06 => self.asl(AddressingMode::ZeroPage),
-                    0x16 => self.asl(AddressingMode::ZeroPageX),
-                    0x0e => self.asl(AddressingMode::Absolute),
-                    0x1e => self.asl(AddressingMode::AbsoluteX),
-                    // bcc - Branch if carry clear
-                    0x90 => { let carry = self.get_flag(Flag::C); self.jump_rel(!carry); },
-                    // bcs - Branch if carry set
-                    0xb0 => { let carry = self.get_flag(Flag::C); self.jump_rel(carry); },
-                    // beq - Branch if equal
-                    0xf0 => { let zero = self.get_flag(Flag::Z); self.jump_rel(zero); },
-                    // bit
-                    0x24 => self.bit(AddressingMode::ZeroPage),  
-                    0x2c => self.bit(AddressingMode::Absolute),
-                    // bmi - Branch if minus
-                    0x30 => { let neg = self.get_flag(Flag::N); self.jump_rel(neg); },
-                    // bne
-                    0xd0 => { let zero = self.get_flag(Flag::Z); self.jump_rel(!zero); },
-                    // bpl - Branch if positive
-                    0x10 => { let neg = self.get_flag(Flag::N); self.jump_rel(!neg); },
-                    // brk - force interrupt
-                    0x00 => {
-                        let lsb: u8 = (self.program_counter & 0xff) as u8;
-                        let msb: u8 = (self.program_counter >> 8) as u8;
-                        self.stack_push(msb);
-                        self.stack_push(lsb);
-                        self.stack_push(self.status);
-                        
-                        self.program_counter = self.mem_read_u16(0xffff);
-                        self.set_flag(Flag::B, true);
-                    },
-                    // bvc - Branch if overflow clear
-                    0x50 => { let overflow = self.get_flag(Flag::V); self.jump_rel(!overflow); },
-                    // bvs - Branch if overflow set
-                    0x70 => { let overflow = self.get_flag(Flag::V); self.jump_rel(overflow); },
-                    // clc - Clear carry flag
-                    0x18 => self.set_flag(Flag::C, false),
-                    // cld - Clear decimal mode
-                    0xd8 => self.set_flag(Flag::D, false),
-                    // cli - Clear interrupt disable
-                    0x58 => self.set_flag(Flag::I, false),
-                    // clv - Clear overflow
-                    0xb8 => self.set_flag(Flag::V, false),
-                    // cmp - compare accumulator with value in memory
-                    0xc9 => self.cmp(AddressingMode::Immediate), 
-                    0xc5 => self.cmp(AddressingMode::ZeroPage),
-                    0xd5 => self.cmp(AddressingMode::ZeroPageX),
-                    0xcd => self.cmp(AddressingMode::Absolute),
-                    0xdd => self.cmp(AddressingMode::AbsoluteX),
-                    0xd9 => self.cmp(AddressingMode::AbsoluteY),
-                    0xc1 => self.cmp(AddressingMode::IndexedIndirectX),
-                    0xd1 => self.cmp(AddressingMode::IndirectIndexedY),
-                    // cpx - compare register x with value in memory
-                    0xe0 => self.cpx(AddressingMode::Immediate),
-                    0xe4 => self.cpx(AddressingMode::ZeroPage),
-                    0xec => self.cpx(AddressingMode::Absolute),
-                    // cpy - compare register y with value in memory
-                    0xc0 => self.cpy(AddressingMode::Immediate),
-                    0xc4 => self.cpy(AddressingMode::ZeroPage),
-                    0xcc => self.cpy(AddressingMode::Absolute),
-                    // dec - decrement memory
-                    0xc6 => self.dec(AddressingMode::ZeroPage),
-                    0xd6 => self.dec(AddressingMode::ZeroPageX),
-                    0xce => self.dec(AddressingMode::Absolute),
-                    0xde => self.dec(AddressingMode::AbsoluteX),
-                    // dex - decrease register x
-                    0xca => {
-                        self.register_x += 0b1111_1111;
-                        self.set_zero(self.register_x);
-                        self.set_negative(self.register_x);
-                    },
-                    // dey - decrement register y
-                    0x88 => {
-                        self.register_y += 0b1111_1111;
-                        self.set_zero(self.register_y);
-                        self.set_negative(self.register_y);
-                    },
-                    // eor - exclusive or
-                    0x49 => self.eor(AddressingMode::Immediate),
-                    0x45 => self.eor(AddressingMode::ZeroPage),
-                    0x55 => self.eor(AddressingMode::ZeroPageX),
-                    0x4d => self.eor(AddressingMode::Absolute),
-                    0x5d => self.eor(AddressingMode::AbsoluteX),
-                    0x59 => self.eor(AddressingMode::AbsoluteY),
-                    0x41 => self.eor(AddressingMode::IndexedIndirectX),
-                    0x51 => self.eor(AddressingMode::IndirectIndexedY),
-                    // inc - increment memory
-                    0xe6 => self.inc(AddressingMode::ZeroPage),
-                    0xf6 => self.inc(AddressingMode::ZeroPageX),
-                    0xee => self.inc(AddressingMode::Absolute),
-                    0xfe => self.inc(AddressingMode::AbsoluteX),
-                    // inx - increment register x
-                    0xe8 => {
-                        self.register_x += 0b0000_0001;
-                        self.set_zero(self.register_x);
-                        self.set_negative(self.register_x);
-                    },
-                    // dey - decrement register y
-                    0xc8 => {
-                        self.register_y += 0b0000_0001;
-                        self.set_zero(self.register_y);
-                        self.set_negative(self.register_y);
-                    },
-                    // jmp - jump
-                    0x4c => self.jmp(AddressingMode::Absolute),
-                    0x6c => self.jmp(AddressingMode::Indirect),
-                    // jsr - jump to subroutine
-                    0x20 => {
-                        let target_addr: u16 = self.get_target_address(AddressingMode::Absolute);
-                        let lsb: u8 = ((self.program_counter) & 0xff) as u8;
-                        let msb: u8 = ((self.program_counter) >> 8) as u8;                    
-                        self.stack_push(msb);
-                        self.stack_push(lsb);
-                        self.program_counter = target_addr;
-                    }
-                    // lda - load accumulator
-                    0xa9 => self.lda(AddressingMode::Immediate),
-                    0xa5 => self.lda(AddressingMode::ZeroPage),
-                    0xb5 => self.lda(AddressingMode::ZeroPageX),
-                    0xad => self.lda(AddressingMode::Absolute),
-                    0xbd => self.lda(AddressingMode::AbsoluteX),
-                    0xb9 => self.lda(AddressingMode::AbsoluteY),
-                    0xa1 => self.lda(AddressingMode::IndexedIndirectY),
-                    0xb1 => self.lda(AddressingMode::IndirectIndexedY),
-                    // ldx - load register x
-                    0xa2 => self.ldx(AddressingMode::Immediate),
-                    0xa6 => self.ldx(AddressingMode::ZeroPage),
-                    0xb6 => self.ldx(AddressingMode::ZeroPageY),
-                    0xae => self.ldx(AddressingMode::Absolute),
-                    0xbe => self.ldx(AddressingMode::AbsoluteY),
-                    // ldy - load register y
-                    0xa0 => self.ldy(AddressingMode::Immediate),
-                    0xa4 => self.ldy(AddressingMode::ZeroPage),
-                    0xb4 => self.ldy(AddressingMode::ZeroPageX),
-                    0xac => self.ldy(AddressingMode::Absolute),
-                    0xbc => self.ldy(AddressingMode::AbsoluteX),
-                    // lsr - logical shift right
-                    0x4a => { 
-                        self.set_flag(Flag::C, self.register_a & 0b1000_000 != 0);
-                        let new_val: u8 = self.register_a >> 1;
-                        self.register_a = new_val;
-                        self.set_zero(new_val);
-                        self.set_negative(new_val);
-                    },
-                    0x46 => self.lsr(AddressingMode::ZeroPage),
-                    0x56 => self.lsr(AddressingMode::ZeroPageX),
-                    0x4e => self.lsr(AddressingMode::Absolute),
-                    0x54 => self.lsr(AddressingMode::AbsoluteX),
-                    // nop - no operation
-                    0xea => (),
-                    // ora - logical or performed on accumulator
-                    0x09 => self.ora(AddressingMode::Immediate),
-                    0x05 => self.ora(AddressingMode::ZeroPage),
-                    0x15 => self.ora(AddressingMode::ZeroPageX),
-                    0x0d => self.ora(AddressingMode::Absolute),
-                    0x1d => self.ora(AddressingMode::AbsoluteX),
-                    0x19 => self.ora(AddressingMode::AbsoluteY),
-                    0x01 => self.ora(AddressingMode::IndexedIndirectX),
-                    0x11 => self.ora(AddressingMode::IndirectIndexedY),
-                    // pha - push a onto stack
-                    0x48 => self.stack_push(self.register_a), 
-                    // php - push status onto stack
-                    0x08 => self.stack_push(self.status | 0b0001_0000),
-                    // pla - pull accumulator
-                    0x68 =>  {
-                        self.register_a = self.stack_pop();
-                        self.set_zero(self.register_a);
-                        self.set_negative(self.register_a);
-                    },
-                    // plp - pull processor status
-                    0x28 => self.status = self.stack_pop(),
-                    // rol - rotate left
-                    0x2a => {
-                        let val: u8 = self.register_a;
-                        self.register_a = val << 1 + self.get_flag(Flag::C) as u8; // maybe need something more intricate here??
-                        self.set_flag(Flag::C, val & 0b1000_0000 != 0);
-                        self.set_zero(self.register_a);
-                        self.set_negative(self.register_a);
-                    },
-                    0x26 => self.rol(AddressingMode::ZeroPage),
-                    0x36 => self.rol(AddressingMode::ZeroPageX),
-                    0x2e => self.rol(AddressingMode::Absolute),
-                    0x3e => self.rol(AddressingMode::AbsoluteX),
-                    // ror - rotate right
-                    0x6a => {
-                        let val: u8 = self.register_a;
-                        self.register_a = val >> 1 + (0b1000_0000 * (self.get_flag(Flag::C) as u8)); // maybe need something more intricate here??
-                        self.set_flag(Flag::C, val & 0b0000_0001 != 0);
-                        self.set_zero(self.register_a);
-                        self.set_negative(self.register_a);
-                    },
-                    0x66 => self.ror(AddressingMode::ZeroPage),
-                    0x76 => self.ror(AddressingMode::ZeroPageX),
-                    0x6e => self.ror(AddressingMode::Absolute),
-                    0x7e => self.ror(AddressingMode::AbsoluteX),
-                    // rti - return from interrupt
-                    0x40 => {
-                        self.status = self.stack_pop();
-                        let lsb: u8 = self.stack_pop();
-                        let msb: u8 = self.stack_pop();
-                        self.program_counter = lsb as u16 + (msb as u16) << 8;
-                    }
-                    // rts - return from subroutine
-                    0x60 => {
-                        let lsb: u8 = self.stack_pop();
-                        let msb: u8 = self.stack_pop();
-                        let ret_addr = ((msb as u16) << 8) + (lsb as u16);
-                        self.program_counter = ret_addr;
-                    }
-                    // sbc - subtract with carry
-                    0xe9 => self.sbc(AddressingMode::Immediate),
-                    0xe5 => self.sbc(AddressingMode::ZeroPage),
-                    0xf5 => self.sbc(AddressingMode::ZeroPageX),
-                    0xed => self.sbc(AddressingMode::Absolute),
-                    0xfd => self.sbc(AddressingMode::AbsoluteX),
-                    0xf9 => self.sbc(AddressingMode::AbsoluteY),
-                    0xe1 => self.sbc(AddressingMode::IndexedIndirectX),
-                    0xf1 => self.sbc(AddressingMode::IndirectIndexedY),
-                    // sec - set carry flag
-                    0x38 => { self.set_flag(Flag::C, true); },
-                    // sed - set decimal flag
-                    0xf8 => { self.set_flag(Flag::D, true); },
-                    // sei - set interrupt disable 
-                    0x78 => { self.set_flag(Flag::I, true); },
-                    // sta - store accumulator
-                    0x85 => self.sta(AddressingMode::ZeroPage),
-                    0x95 => self.sta(AddressingMode::ZeroPageX),
-                    0x8d => self.sta(AddressingMode::Absolute),
-                    0x9d => self.sta(AddressingMode::AbsoluteX),
-                    0x99 => self.sta(AddressingMode::AbsoluteY),
-                    0x81 => self.sta(AddressingMode::IndexedIndirectX),
-                    0x91 => self.sta(AddressingMode::IndirectIndexedY),
-                    // stx - store register x
-                    0x86 => self.stx(AddressingMode::ZeroPage),
-                    0x96 => self.stx(AddressingMode::ZeroPageY),
-                    0x8e => self.stx(AddressingMode::Absolute),
-                    // sty - store register y
-                    0x84 => self.sty(AddressingMode::ZeroPage),
-                    0x94 => self.sty(AddressingMode::ZeroPageX),
-                    0x8c => self.sty(AddressingMode::Absolute),
-                    // tax - transfer accumulator to x
-                    0xaa => {
-                        self.register_x = self.register_a;
-                        self.set_zero(self.register_x);
-                        self.set_negative(self.register_x);
-                    },
-                    // tay - transfer accumulator to y
-                    0xa8 => {
-                        self.register_y = self.register_a;
-                        self.set_zero(self.register_y);
-                        self.set_negative(self.register_y);
-                    },
-                    // tsx - transfer stack register to x
-                    0xba => {
-                        self.register_x = self.stack_pointer;
-                        self.set_zero(self.register_x);
-                        self.set_negative(self.register_x);
-                    },
-                    // txa - transfer x to accumulator
-                    0x8a => {
-                        self.register_a = self.register_x;
-                        self.set_zero(self.register_a);
-                        self.set_negative(self.register_a);
-                    },
-                    // txs - transfer x to stack pointer
-                    0x9a => self.stack_pointer = self.register_x,
-                    // tya - transfer y to accumulator
-                    0x98 => {
-                        self.register_a = self.register_y;
-                        self.set_zero(self.register_a);
-                        self.set_negative(self.register_a);
-                    },
-                    _ => panic!("Can't recognize instruction instruction {:?}", opcode),
-                }
+        // stores a & x, no flags are affected
+        fn sax(&mut self, mode: AddressingMode) {
+            let addr: u16 = self.get_target_address(mode);
+            self.mem_write(addr, self.register_a & self.register_x);
+        }
+
+        // dec and cmp
+        fn dcp(&mut self, mode: AddressingMode) {
+            let addr: u16 = self.get_target_address(mode);
+            let old: u8 = self.mem_read(addr);
+            let val: u8 = old.wrapping_sub(1);
+            self.modify(addr, old, val);
+            self.compare(self.register_a, val);
+        }
+
+        // inc and sbc
+        fn isb(&mut self, mode: AddressingMode) {
+            let addr: u16 = self.get_target_address(mode);
+            let old: u8 = self.mem_read(addr);
+            let val: u8 = old.wrapping_add(1);
+            self.modify(addr, old, val);
+            self.subtract_with_borrow(val);
+        }
+
+        // asl and ora
+        fn slo(&mut self, mode: AddressingMode) {
+            let operand = self.get_operand(mode);
+            self.register_a |= self.asl_operand(&operand);
+            self.set_zero(self.register_a);
+            self.set_negative(self.register_a);
+        }
+
+        // rol and and
+        fn rla(&mut self, mode: AddressingMode) {
+            let operand = self.get_operand(mode);
+            self.register_a &= self.rol_operand(&operand);
+            self.set_zero(self.register_a);
+            self.set_negative(self.register_a);
+        }
+
+        // lsr and eor
+        fn sre(&mut self, mode: AddressingMode) {
+            let operand = self.get_operand(mode);
+            self.register_a ^= self.lsr_operand(&operand);
+            self.set_zero(self.register_a);
+            self.set_negative(self.register_a);
+        }
+
+        // ror and adc, the carry shifted out is added
+        fn rra(&mut self, mode: AddressingMode) {
+            let operand = self.get_operand(mode);
+            let val: u8 = self.ror_operand(&operand);
+            self.add_with_carry(val);
+        }
+
+        // nop with an operand, the value is read but ignored
+        fn nop(&mut self, mode: AddressingMode) {
+            let addr: u16 = self.get_target_address(mode);
+            self.mem_read(addr);
+        }
 
-                let ten_millis = time::Duration::from_millis(100);
-                thread::sleep(ten_millis);
+        st![sta, register_a, stx, register_x, sty, register_y];
+
+        // Runs the subroutine at 'addr' as if it was called with jsr, and returns once it returns
+        // with rts. The return address is a sentinel that is never executed. Fails with StepLimit
+        // if it doesn't return within CALL_STEP_LIMIT instructions.
+        pub fn call_subroutine(&mut self, addr: u16) -> Result<(), CpuError> {
+            let ret_addr: u16 = SUBROUTINE_SENTINEL - 1;
+            self.stack_push((ret_addr >> 8) as u8);
+            self.stack_push((ret_addr & 0xff) as u8);
+            self.program_counter = addr;
 
-                if self.debug {println!("\t\t\tA: {:?} X: {:?}, Y: {:?} \t\t flags: {:#08b}", self.register_a, self.register_x, self.register_y, self.status) }
+            for _ in 0..CALL_STEP_LIMIT {
+                if self.program_counter == SUBROUTINE_SENTINEL { return Ok(()); }
+                self.step()?;
             }
+            Err(CpuError::StepLimit(CALL_STEP_LIMIT))
         }
-    }
 
-    #[cfg(test)]
-    mod test {
-        use super::*;
-        use rand::prelude::*;
-        
-        pub struct TestBus {
-            address_bus: u16,
-            data_bus: u8,
-            control_bus: u8,
-            read_targets: HashMap<u16, u8>,
-            write_targets: HashMap<u16, u8>,
+        // A cpu with its registers set to 'state', e.g. the initial state of a test case.
+        pub fn from_state(memory: T, state: CpuState) -> Self {
+            let mut cpu = Self::new(memory, false);
+            cpu.set_state(state);
+            cpu
         }
-        
-        impl TestBus {
-        
-            pub fn set_read_target(&mut self, addr: u16, val: u8) {
-                self.read_targets.insert(addr, val);
+
+        // Sets the registers. Bit 5 of the status is always set, whatever 'state' has there.
+        pub fn set_state(&mut self, state: CpuState) {
+            self.register_a = state.register_a;
+            self.register_x = state.register_x;
+            self.register_y = state.register_y;
+            self.stack_pointer = state.stack_pointer;
+            self.status = state.status | UNUSED_FLAG;
+            self.program_counter = state.program_counter;
+            self.clear_call_stack();
+        }
+
+        fn clear_call_stack(&mut self) {
+            if let Some(stack) = self.call_stack.as_mut() {
+                stack.clear();
             }
-        
-            pub fn set_read_u16_target(&mut self, addr: u16, val: u16) {
-                let low: u8 = (val & 0xff) as u8;
-                let high: u8 = (val >> 8) as u8;
-                self.read_targets.insert(addr, low);
-                self.read_targets.insert(addr + 1, high);
+        }
+
+        pub fn state(&self) -> CpuState {
+            CpuState {
+                register_a: self.register_a,
+                register_x: self.register_x,
+                register_y: self.register_y,
+                stack_pointer: self.stack_pointer,
+                status: self.status,
+                program_counter: self.program_counter,
             }
-        
-            pub fn set_vector_read_target(&mut self, addr: u16, values: Vec<u8>) {
-                let mut offset: u16 = 0;
-                for val in values {
-                    self.read_targets.insert(addr + offset, val);
-                    offset += 1;
+        }
+
+        pub fn snapshot(&self) -> CpuSnapshot {
+            CpuSnapshot {
+                register_a: self.register_a,
+                register_x: self.register_x,
+                register_y: self.register_y,
+                stack_pointer: self.stack_pointer,
+                status: self.status,
+                program_counter: self.program_counter,
+                cycles: self.cycles,
+                ram: self.memory.dump_ram(),
+            }
+        }
+
+        // Fails if the RAM of the snapshot doesn't fit the bus (e.g. it was taken on another one),
+        // the cpu is left as it was then.
+        pub fn restore(&mut self, snap: CpuSnapshot) -> Result<(), String> {
+            self.memory.load_ram(&snap.ram)?;
+            self.register_a = snap.register_a;
+            self.register_x = snap.register_x;
+            self.register_y = snap.register_y;
+            self.stack_pointer = snap.stack_pointer;
+            self.status = snap.status;
+            self.program_counter = snap.program_counter;
+            self.cycles = snap.cycles;
+            self.clear_call_stack();
+            Ok(())
+        }
+
+        // Runs 'program' from a scratch address until it reaches a brk, and returns the state of the
+        // cpu at that point. A brk is appended to the program, and the memory it was copied over is
+        // restored afterwards, even if the execution failed. Like call_subroutine, it gives up after
+        // CALL_STEP_LIMIT instructions. The memory is looked at with probe, so the accesses the
+        // program doesn't make don't show up on the bus.
+        pub fn execute_inline(&mut self, program: &[u8]) -> Result<CpuState, CpuError> {
+            let brk_addr = INLINE_ADDRESS.wrapping_add(program.len() as u16);
+            let original: Vec<Option<u8>> = (0..=program.len())
+                .map(|i| self.memory.probe(INLINE_ADDRESS.wrapping_add(i as u16)))
+                .collect();
+            self.bulk_write(INLINE_ADDRESS, program);
+            self.mem_write(brk_addr, 0x00);
+            self.program_counter = INLINE_ADDRESS;
+
+            let mut result: Result<u64, CpuError> = Err(CpuError::StepLimit(CALL_STEP_LIMIT));
+            for _ in 0..CALL_STEP_LIMIT {
+                if self.memory.probe(self.program_counter) == Some(0x00) {
+                    result = Ok(0);
+                    break;
+                }
+                if let Err(e) = self.step() {
+                    result = Err(e);
+                    break;
                 }
             }
-        
-            pub fn set_write_target(&mut self, addr: u16, val: u8) {
-                self.write_targets.insert(addr, val);
+
+            // Addresses that can't be probed have nothing to restore.
+            for (i, val) in original.into_iter().enumerate() {
+                if let Some(val) = val { self.mem_write(INLINE_ADDRESS.wrapping_add(i as u16), val); }
             }
+            result.map(|_| self.state())
         }
-        
-        impl Mem for TestBus {
-            fn new() -> Self {
-                Self {
-                    address_bus: 0,
-                    data_bus: 0,
-                    control_bus: 0,
-                    read_targets: HashMap::new(),
-                    write_targets: HashMap::new(),
+
+        // Executes the instruction at the program counter, and returns the number of cycles it took.
+        // Pending interrupts are serviced afterwards, their cycles are included, and so are stalls.
+        pub fn step(&mut self) -> Result<u64, CpuError> {
+            if let Some(opcode) = self.halted {
+                return Err(CpuError::Halted(opcode));
+            }
+            self.record_rewind();
+            let start_cycles = self.cycles;
+            // The stall comes before the instruction.
+            let stall = std::mem::take(&mut self.stall_cycles);
+            self.cycles += stall;
+            self.tick_bus(stall);
+            self.bus_cycles = Some(stall);
+            self.watch_hit = None;
+            self.return_mismatch = None;
+            self.instruction_pc = self.program_counter;
+            if self.debug || self.trace.is_some() {
+                let line = self.trace_line();
+                if self.debug { println!("{}", line) }
+                if let Some(Err(e)) = self.trace.as_mut().map(|sink| writeln!(sink, "{}", line)) {
+                    println!("WARN\tTracing stopped ({})", e);
+                    self.trace = None;
                 }
             }
-            fn set_address_bus(&mut self, addr: u16) {
-                self.address_bus = addr;
-                
+            let pc = self.program_counter;
+            let before = self.state();
+            let opcode: u8 = self.fetch();
+            for hook in self.before_hooks.iter_mut() {
+                hook(pc, opcode, &before);
+            }
+            let subroutine = self.call_stack().last().map(|frame| frame.target);
+            let execute_start = self.cycles;
+            if let Err(e) = self.execute(opcode) {
+                // The bus catches up with the cycles counted so far, so it stays in step with the
+                // cpu. Instructions that don't run still took the cycle of the opcode fetch.
+                let ticked = self.bus_cycles.take().unwrap_or(0);
+                self.cycles = self.cycles.max(start_cycles + ticked);
+                self.tick_bus(self.cycles - start_cycles - ticked);
+                return Err(e);
             }
-            fn set_data_bus(&mut self, val: u8) {
-                self.data_bus = val;
+            if let Some(profile) = self.profile.as_mut() {
+                let cycles = self.cycles - execute_start;
+                *profile.cycles_by_pc.entry(pc).or_insert(0) += cycles;
+                if let Some(target) = subroutine {
+                    *profile.cycles_by_subroutine.entry(target).or_insert(0) += cycles;
+                }
             }
-            fn get_data_bus(&self) -> u8 {
-                self.data_bus
+            self.resync_call_stack();
+            if !self.after_hooks.is_empty() {
+                let after = self.state();
+                for hook in self.after_hooks.iter_mut() {
+                    hook(pc, opcode, &after);
+                }
             }
-            fn set_control_signal(&mut self, control: ControlSignal, val: bool) {
-                let mask = control as u8;
-                if (val)  { self.control_bus |= mask; }
-                else { self.control_bus &= !mask; }
-                
-                if (!self.get_control_signal(ControlSignal::MemEnable)) { return; }
-        
-                if (self.get_control_signal(ControlSignal::AccessMode)) {
-                    let result: Option<&u8> = self.read_targets.get(&self.address_bus);
-                    self.data_bus = match result {
-                        Some(val) => *val,
-                        None => panic!("Method trying to read from forbidden memory (addr: {:x})", self.address_bus),
-                    }
-                } else {
-                    let result: Option<&u8> = self.write_targets.get(&self.address_bus);
-                    match result {
-                        Some(val) => {
-                            if (*val != self.data_bus) { panic!("Method trying to write invalid data(expected: {:b}, got: {:b})", *val, self.data_bus); }
-                        },
-                        None => panic!("Method trying to write to forbidden memory(addr: {:x}, val: {:b})", self.address_bus, self.data_bus),
+            self.cycles += self.memory.take_stall() as u64;
+
+            // NMI can't be masked, IRQ is only serviced when the I flag is clear.
+            let vector = if self.memory.poll_nmi() {
+                Some(NMI_VECTOR)
+            } else if !self.get_flag(Flag::I) && self.memory.poll_irq() {
+                Some(IRQ_VECTOR)
+            } else {
+                None
+            };
+            if let Some(vector) = vector {
+                self.interrupt(vector, false);
+                self.cycles += 7;
+            }
+            let cycles = self.cycles - start_cycles;
+            let ticked = self.bus_cycles.take().unwrap_or(0);
+            self.tick_bus(cycles.saturating_sub(ticked));
+            self.instructions += 1;
+            Ok(cycles)
+        }
+
+        #[cfg(any(test, feature = "lockstep"))]
+        pub(crate) fn memory(&self) -> &T {
+            &self.memory
+        }
+
+        // Puts the registers in their power-up state and runs the reset sequence. The stack pointer
+        // starts at 0, the reset leaves it at 0xfd.
+        // See: https://www.nesdev.org/wiki/CPU_power_up_state
+        pub fn power_on(&mut self) {
+            self.register_a = 0;
+            self.register_x = 0;
+            self.register_y = 0;
+            self.stack_pointer = 0;
+            self.status = UNUSED_FLAG;
+            self.reset();
+        }
+
+        // The reset sequence, as after pressing the reset button. It goes through the motions of an
+        // interrupt without writing to the stack: the stack pointer is decremented by 3, interrupts
+        // are disabled and execution continues at the reset vector. Other registers are kept.
+        pub fn reset(&mut self) {
+            self.halted = None;
+            self.clear_call_stack();
+            self.stack_pointer = self.stack_pointer.wrapping_sub(3);
+            self.set_flag(Flag::I, true);
+            self.program_counter = self.mem_read_u16(RESET_VECTOR);
+            self.cycles += 7;
+        }
+
+        // Whether a KIL opcode halted the cpu, see CpuError::Halted.
+        pub fn is_halted(&self) -> bool {
+            self.halted.is_some()
+        }
+
+        pub fn start(&mut self) -> Result<RunResult, CpuError> {
+            self.power_on();
+            self.run(RunMode::Unlimited)
+        }
+
+        // Same as start, but begins at 'entry_point' instead of the reset vector. Test ROMs like
+        // nestest are run from $c000 this way.
+        pub fn start_at(&mut self, entry_point: u16) -> Result<RunResult, CpuError> {
+            self.power_on();
+            self.program_counter = entry_point;
+            self.run(RunMode::Unlimited)
+        }
+
+        // Runs until the limit given by 'mode' is reached or an illegal opcode is found. Other errors,
+        // like segmentation faults, are returned as errors.
+        pub fn run(&mut self, mode: RunMode) -> Result<RunResult, CpuError> {
+            let start_cycles = self.cycles;
+            self.run_loop(true, |cpu, instructions| {
+                let limit_reached = match mode {
+                    RunMode::Unlimited => false,
+                    RunMode::InstructionLimit(limit) => instructions >= limit,
+                    RunMode::CycleBudget(budget) => cpu.cycles - start_cycles >= budget,
+                    RunMode::UntilPc(pc) => cpu.program_counter == pc,
+                };
+                match mode {
+                    _ if !limit_reached => None,
+                    RunMode::UntilPc(_) => Some(StopReason::PcReached),
+                    _ => Some(StopReason::LimitReached),
+                }
+            })
+        }
+
+        // Runs until 'stop' returns true, it is checked before every instruction. Unlike run, this
+        // doesn't throttle to the clock speed: it is meant for callers that keep time themselves.
+        pub fn run_until(&mut self, mut stop: impl FnMut(&Self) -> bool) -> Result<RunResult, CpuError> {
+            self.run_loop(false, |cpu, _| stop(cpu).then_some(StopReason::ConditionMet))
+        }
+
+        // Runs for at least 'cycles' cycles, the last instruction may go over. A frame is about 29780
+        // cycles on NTSC. Not throttled, same as run_until.
+        pub fn run_for_cycles(&mut self, cycles: u64) -> Result<RunResult, CpuError> {
+            let end = self.cycles + cycles;
+            self.run_loop(false, |cpu, _| (cpu.cycles >= end).then_some(StopReason::LimitReached))
+        }
+
+        // Steps until 'stop' returns a reason, called with the instructions executed so far. Illegal
+        // opcodes, halting, breakpoints and watchpoints stop the loop, other errors are returned. The breakpoint
+        // at the starting address is skipped, so that a run stopped at one can be continued.
+        fn run_loop(
+            &mut self,
+            throttle: bool,
+            mut stop: impl FnMut(&Self, u64) -> Option<StopReason>,
+        ) -> Result<RunResult, CpuError> {
+            let start = time::Instant::now();
+            let start_cycles = self.cycles;
+            let mut synced_cycles = self.cycles;
+            let mut instructions: u64 = 0;
+            loop {
+                if let Some(stop_reason) = stop(self, instructions) {
+                    return Ok(RunResult { instructions, cycles: self.cycles - start_cycles, stop_reason });
+                }
+                if instructions > 0 && self.breakpoints.contains(&self.program_counter) {
+                    let stop_reason = StopReason::Breakpoint(self.program_counter);
+                    return Ok(RunResult { instructions, cycles: self.cycles - start_cycles, stop_reason });
+                }
+
+                match self.step() {
+                    Ok(_) => instructions += 1,
+                    Err(CpuError::IllegalOpcode(opcode)) => {
+                        let stop_reason = StopReason::IllegalOpcode(opcode);
+                        return Ok(RunResult { instructions, cycles: self.cycles - start_cycles, stop_reason });
+                    },
+                    Err(CpuError::Halted(opcode)) => {
+                        let stop_reason = StopReason::Halted(opcode);
+                        return Ok(RunResult { instructions, cycles: self.cycles - start_cycles, stop_reason });
+                    },
+                    Err(e) => return Err(e),
+                }
+                if let Some(hit) = self.watch_hit {
+                    let stop_reason = StopReason::Watchpoint(hit);
+                    return Ok(RunResult { instructions, cycles: self.cycles - start_cycles, stop_reason });
+                }
+
+                // Once a frame, sleeps until the cycles executed so far are due. Single sleeps
+                // overshoot, but since this is measured from the start, the cpu catches up by not
+                // sleeping afterwards.
+                if let Some(hz) = self.clock_hz().filter(|_| throttle) {
+                    if self.cycles - synced_cycles >= self.cycles_per_frame() {
+                        synced_cycles = self.cycles;
+                        let nanos = (self.cycles - start_cycles) as u128 * 1_000_000_000 / hz as u128;
+                        let due = time::Duration::from_nanos(nanos as u64);
+                        if let Some(ahead) = due.checked_sub(start.elapsed()) {
+                            thread::sleep(ahead);
+                        }
                     }
                 }
             }
-        
-            fn get_control_signal(&self, control: ControlSignal) -> bool {
-                (self.control_bus & (control as u8)) != 0
+        }
+
+        // Handles an unimplemented opcode according to the policy. The program counter is just past
+        // the opcode.
+        fn illegal_opcode(&mut self, opcode: u8) -> Result<(), CpuError> {
+            let opcode_pc = self.program_counter.wrapping_sub(1);
+            if matches!(self.illegal_opcode_policy, IllegalOpcodePolicy::Break | IllegalOpcodePolicy::Halt) {
+                // the instruction doesn't run, so it only takes the cycle of the opcode fetch (see step)
+                self.cycles -= TABLE[opcode as usize].cycles as u64;
+            }
+            match self.illegal_opcode_policy {
+                IllegalOpcodePolicy::Break => {
+                    self.program_counter = opcode_pc;
+                    Err(CpuError::IllegalOpcode(opcode))
+                },
+                IllegalOpcodePolicy::Halt => {
+                    self.program_counter = opcode_pc;
+                    self.halted = Some(opcode);
+                    Err(CpuError::Halted(opcode))
+                },
+                IllegalOpcodePolicy::Nop => {
+                    println!("WARN\tSkipping illegal opcode {:#04x} at {:#06x}", opcode, opcode_pc);
+                    let operand_bytes = TABLE[opcode as usize].bytes as u16 - 1;
+                    self.program_counter = self.program_counter.wrapping_add(operand_bytes);
+                    Ok(())
+                },
+                IllegalOpcodePolicy::Panic => panic!("Illegal opcode {:#04x} at {:#06x}", opcode, opcode_pc),
+            }
+        }
+
+        // Executes a single, already fetched instruction.
+        fn execute(&mut self, opcode: u8) -> Result<(), CpuError> {
+            let info = TABLE[opcode as usize];
+            let mode = info.mode;
+            self.cycles += info.cycles as u64;
+            self.page_crossed = false;
+            // Only the reads have a page penalty, they can skip the fixup.
+            self.always_fixup = !info.page_penalty;
+
+            // The opcode table decides the addressing mode, so every mnemonic is handled once. The
+            // match is exhaustive, a mnemonic added to the table has to be handled here.
+            match info.mnemonic {
+                Mnemonic::Adc => self.adc(mode),
+                Mnemonic::And => self.and(mode),
+                Mnemonic::Asl => self.asl(mode),
+                // bcc - Branch if carry clear
+                Mnemonic::Bcc => { let carry = self.get_flag(Flag::C); self.branch(!carry); },
+                // bcs - Branch if carry set
+                Mnemonic::Bcs => { let carry = self.get_flag(Flag::C); self.branch(carry); },
+                // beq - Branch if equal
+                Mnemonic::Beq => { let zero = self.get_flag(Flag::Z); self.branch(zero); },
+                Mnemonic::Bit => self.bit(mode),
+                // bmi - Branch if minus
+                Mnemonic::Bmi => { let neg = self.get_flag(Flag::N); self.branch(neg); },
+                // bne
+                Mnemonic::Bne => { let zero = self.get_flag(Flag::Z); self.branch(!zero); },
+                // bpl - Branch if positive
+                Mnemonic::Bpl => { let neg = self.get_flag(Flag::N); self.branch(!neg); },
+                // brk - force interrupt
+                // The byte after brk is skipped, the handler returns after it. An NMI that comes in
+                // before the vector is read hijacks it: the NMI handler runs instead, with B set on
+                // the stack, and the NMI is not serviced again.
+                Mnemonic::Brk => {
+                    self.program_counter = self.program_counter.wrapping_add(1);
+                    let vector = if self.memory.poll_nmi() { NMI_VECTOR } else { IRQ_VECTOR };
+                    self.interrupt(vector, true);
+                },
+                // bvc - Branch if overflow clear
+                Mnemonic::Bvc => { let overflow = self.get_flag(Flag::V); self.branch(!overflow); },
+                // bvs - Branch if overflow set
+                Mnemonic::Bvs => { let overflow = self.get_flag(Flag::V); self.branch(overflow); },
+                // clc - Clear carry flag
+                Mnemonic::Clc => self.set_flag(Flag::C, false),
+                // cld - Clear decimal mode
+                Mnemonic::Cld => self.set_flag(Flag::D, false),
+                // cli - Clear interrupt disable
+                Mnemonic::Cli => self.set_flag(Flag::I, false),
+                // clv - Clear overflow
+                Mnemonic::Clv => self.set_flag(Flag::V, false),
+                Mnemonic::Cmp => self.cmp(mode),
+                Mnemonic::Cpx => self.cpx(mode),
+                Mnemonic::Cpy => self.cpy(mode),
+                Mnemonic::Dec => self.dec(mode),
+                // dex - decrease register x
+                Mnemonic::Dex => {
+                    self.register_x = self.register_x.wrapping_sub(1);
+                    self.set_zero(self.register_x);
+                    self.set_negative(self.register_x);
+                },
+                // dey - decrement register y
+                Mnemonic::Dey => {
+                    self.register_y = self.register_y.wrapping_sub(1);
+                    self.set_zero(self.register_y);
+                    self.set_negative(self.register_y);
+                },
+                Mnemonic::Eor => self.eor(mode),
+                Mnemonic::Inc => self.inc(mode),
+                // inx - increment register x
+                Mnemonic::Inx => {
+                    self.register_x = self.register_x.wrapping_add(1);
+                    self.set_zero(self.register_x);
+                    self.set_negative(self.register_x);
+                },
+                // iny - increment register y
+                Mnemonic::Iny => {
+                    self.register_y = self.register_y.wrapping_add(1);
+                    self.set_zero(self.register_y);
+                    self.set_negative(self.register_y);
+                },
+                Mnemonic::Jmp => self.jmp(mode),
+                // jsr - jump to subroutine
+                // The return address pushed is the last byte of the jsr instruction, rts adds one.
+                Mnemonic::Jsr => {
+                    let target_addr: u16 = self.get_target_address(mode);
+                    let ret_addr: u16 = self.program_counter.wrapping_sub(1);
+                    let lsb: u8 = (ret_addr & 0xff) as u8;
+                    let msb: u8 = (ret_addr >> 8) as u8;
+                    self.stack_push(msb);
+                    self.stack_push(lsb);
+                    self.track_call(target_addr);
+                    self.program_counter = target_addr;
+                }
+                Mnemonic::Lda => self.lda(mode),
+                Mnemonic::Ldx => self.ldx(mode),
+                Mnemonic::Ldy => self.ldy(mode),
+                Mnemonic::Lsr => self.lsr(mode),
+                // nop - no operation, the unofficial ones with an operand still read it
+                Mnemonic::Nop => if mode != AddressingMode::Implied { self.nop(mode) },
+                Mnemonic::Ora => self.ora(mode),
+                // pha - push a onto stack
+                Mnemonic::Pha => self.stack_push(self.register_a), 
+                // php - push status onto stack
+                Mnemonic::Php => self.push_status(true),
+                // pla - pull accumulator
+                Mnemonic::Pla =>  {
+                    self.register_a = self.stack_pop();
+                    self.set_zero(self.register_a);
+                    self.set_negative(self.register_a);
+                },
+                // plp - pull processor status
+                Mnemonic::Plp => self.pull_status(),
+                Mnemonic::Rol => self.rol(mode),
+                Mnemonic::Ror => self.ror(mode),
+                // rti - return from interrupt
+                Mnemonic::Rti => {
+                    self.pull_status();
+                    let lsb: u8 = self.stack_pop();
+                    let msb: u8 = self.stack_pop();
+                    self.program_counter = ((msb as u16) << 8) | lsb as u16;
+                }
+                // rts - return from subroutine
+                Mnemonic::Rts => {
+                    let lsb: u8 = self.stack_pop();
+                    let msb: u8 = self.stack_pop();
+                    let ret_addr = ((msb as u16) << 8) | (lsb as u16);
+                    self.program_counter = ret_addr.wrapping_add(1);
+                    self.track_return();
+                }
+                Mnemonic::Sbc => self.sbc(mode),
+                // sec - set carry flag
+                Mnemonic::Sec => { self.set_flag(Flag::C, true); },
+                // sed - set decimal flag
+                Mnemonic::Sed => { self.set_flag(Flag::D, true); },
+                // sei - set interrupt disable 
+                Mnemonic::Sei => { self.set_flag(Flag::I, true); },
+                Mnemonic::Sta => self.sta(mode),
+                Mnemonic::Stx => self.stx(mode),
+                Mnemonic::Sty => self.sty(mode),
+                // tax - transfer accumulator to x
+                Mnemonic::Tax => {
+                    self.register_x = self.register_a;
+                    self.set_zero(self.register_x);
+                    self.set_negative(self.register_x);
+                },
+                // tay - transfer accumulator to y
+                Mnemonic::Tay => {
+                    self.register_y = self.register_a;
+                    self.set_zero(self.register_y);
+                    self.set_negative(self.register_y);
+                },
+                // tsx - transfer stack register to x
+                Mnemonic::Tsx => {
+                    self.register_x = self.stack_pointer;
+                    self.set_zero(self.register_x);
+                    self.set_negative(self.register_x);
+                },
+                // txa - transfer x to accumulator
+                Mnemonic::Txa => {
+                    self.register_a = self.register_x;
+                    self.set_zero(self.register_a);
+                    self.set_negative(self.register_a);
+                },
+                // txs - transfer x to stack pointer
+                Mnemonic::Txs => self.stack_pointer = self.register_x,
+                // tya - transfer y to accumulator
+                Mnemonic::Tya => {
+                    self.register_a = self.register_y;
+                    self.set_zero(self.register_a);
+                    self.set_negative(self.register_a);
+                },
+                // unofficial opcodes
+                Mnemonic::Lax => self.lax(mode),
+                Mnemonic::Sax => self.sax(mode),
+                Mnemonic::Dcp => self.dcp(mode),
+                Mnemonic::Isb => self.isb(mode),
+                Mnemonic::Slo => self.slo(mode),
+                Mnemonic::Rla => self.rla(mode),
+                Mnemonic::Sre => self.sre(mode),
+                Mnemonic::Rra => self.rra(mode),
+                // kil - the cpu stops fetching instructions, the program counter stays on the opcode
+                Mnemonic::Kil => {
+                    self.program_counter = self.program_counter.wrapping_sub(1);
+                    self.halted = Some(opcode);
+                    return Err(CpuError::Halted(opcode));
+                },
+                // the opcodes the cpu doesn't implement
+                Mnemonic::Unknown => return self.illegal_opcode(opcode),
+            }
+
+            // Branches count their extra cycles in branch.
+            if self.page_crossed && info.page_penalty {
+                self.cycles += 1;
+            }
+
+            match self.memory.take_fault() {
+                Some(addr) => Err(CpuError::SegmentationFault(addr)),
+                None => Ok(()),
             }
         }
+    }
+
+    impl<T: Mem> Cpu for CPU<T> {
+        type Bus = T;
+
+        fn step(&mut self) -> Result<u64, CpuError> { CPU::step(self) }
+        fn reset(&mut self) { CPU::reset(self) }
+        fn state(&self) -> CpuState { CPU::state(self) }
+        fn set_state(&mut self, state: CpuState) { CPU::set_state(self, state) }
+        fn cycles(&self) -> u64 { self.cycles }
+        fn bus(&self) -> &T { &self.memory }
+        fn bus_mut(&mut self) -> &mut T { &mut self.memory }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::bus::{ArrayBus, IrqSource, RomBus, SparseMemory};
+        use crate::bus_trace::{BusAccess, BusRecorder};
+        use crate::ppu::StubPpu;
+        use crate::rom::rom_from_bytes;
+        use crate::test_rom::TestRomBuilder;
+        use crate::testing::TestBus;
+        use rand::prelude::*;
 
         macro_rules! run_test {
             ($instr: ident, $($mode: ident),+) => {
                 mod $instr {
                     use super::*;
-                    use rand::prelude::*;
 
                     $(#[test]
+                    #[allow(non_snake_case)]
                     fn $mode() {
-                        let mut cpu = CPU::<TestBus>::new();
+                        let mut cpu = CPU::<TestBus>::new(TestBus::new(), false);
                         let mut rng = rand::thread_rng();
                         let mode = AddressingMode::$mode;
 
@@ -817,9 +1954,12 @@ pub mod cpu {
 
             cpu.adc(mode);
 
-            assert_eq!(cpu.register_a, a + c + mem_value);
+            let signed_sum = a as i8 as i16 + mem_value as i8 as i16 + c as i16;
+
+            assert_eq!(cpu.register_a, a.wrapping_add(c).wrapping_add(mem_value));
             assert_eq!(cpu.get_flag(Flag::Z), cpu.register_a == 0);
             assert_eq!(cpu.get_flag(Flag::N), cpu.register_a  & 0b1000_0000 != 0);
+            assert_eq!(cpu.get_flag(Flag::V), !(-128..=127).contains(&signed_sum));
         }
 
         run_test![
@@ -838,6 +1978,138 @@ pub mod cpu {
             IndirectIndexedY
         ];
 
+        // Cases where the carry-in decides whether the signed result overflows.
+        #[test]
+        fn test_adc_overflow_carry_in() {
+            for (a, mem_value, carry, overflow) in [
+                (0x00, 0x7f, true, true),
+                (0x7f, 0x00, true, true),
+                (0x3f, 0x40, false, false),
+                (0x3f, 0x40, true, true),
+                (0xff, 0x80, true, false),
+                (0x80, 0xff, false, true),
+            ] {
+                let mut cpu = CPU::<TestBus>::new(TestBus::new(), false);
+                cpu.register_a = a;
+                cpu.set_flag(Flag::C, carry);
+                addressing_mode_tester(&mut cpu, mem_value, &AddressingMode::Immediate);
+
+                cpu.adc(AddressingMode::Immediate);
+
+                assert_eq!(cpu.get_flag(Flag::V), overflow, "{:x} + {:x} + {}", a, mem_value, carry);
+            }
+        }
+
+        // Every accumulator, operand and carry combination, against the signed and unsigned sums.
+        #[test]
+        fn test_adc_sbc_exhaustive() {
+            let mut cpu = CPU::new(ArrayBus::new(), false);
+            for a in 0..=255u8 {
+                for val in 0..=255u8 {
+                    for carry in [false, true] {
+                        cpu.register_a = a;
+                        cpu.set_flag(Flag::C, carry);
+                        cpu.program_counter = 0;
+                        cpu.mem_write(0, val);
+                        cpu.adc(AddressingMode::Immediate);
+
+                        let sum = a as u16 + val as u16 + carry as u16;
+                        let signed = a as i8 as i16 + val as i8 as i16 + carry as i16;
+                        assert_eq!(cpu.register_a, sum as u8, "{:#x} + {:#x} + {}", a, val, carry);
+                        assert_eq!(cpu.get_flag(Flag::C), sum > 0xff, "{:#x} + {:#x} + {}", a, val, carry);
+                        assert_eq!(cpu.get_flag(Flag::V), !(-128..=127).contains(&signed), "{:#x} + {:#x} + {}", a, val, carry);
+                        assert_eq!(cpu.get_flag(Flag::Z), sum as u8 == 0);
+                        assert_eq!(cpu.get_flag(Flag::N), sum as u8 & 0b1000_0000 != 0);
+
+                        cpu.register_a = a;
+                        cpu.set_flag(Flag::C, carry);
+                        cpu.program_counter = 0;
+                        cpu.sbc(AddressingMode::Immediate);
+
+                        let borrow = !carry as i16;
+                        let diff = a as i16 - val as i16 - borrow;
+                        let signed = a as i8 as i16 - val as i8 as i16 - borrow;
+                        assert_eq!(cpu.register_a, diff as u8, "{:#x} - {:#x} - {}", a, val, borrow);
+                        assert_eq!(cpu.get_flag(Flag::C), diff >= 0, "{:#x} - {:#x} - {}", a, val, borrow);
+                        assert_eq!(cpu.get_flag(Flag::V), !(-128..=127).contains(&signed), "{:#x} - {:#x} - {}", a, val, borrow);
+                        assert_eq!(cpu.get_flag(Flag::Z), diff as u8 == 0);
+                        assert_eq!(cpu.get_flag(Flag::N), diff as u8 & 0b1000_0000 != 0);
+                    }
+                }
+            }
+        }
+
+        // The opcode is fetched before the addressing mode is resolved, so the program counter
+        // should only move past the operand bytes of the instruction.
+        #[test]
+        fn test_operand_pc_advance() {
+            let operand_lengths = [
+                (AddressingMode::Accumulator, 0),
+                (AddressingMode::Immediate, 1),
+                (AddressingMode::ZeroPage, 1),
+                (AddressingMode::ZeroPageX, 1),
+                (AddressingMode::ZeroPageY, 1),
+                (AddressingMode::Absolute, 2),
+                (AddressingMode::AbsoluteX, 2),
+                (AddressingMode::AbsoluteY, 2),
+                (AddressingMode::Indirect, 2),
+                (AddressingMode::IndexedIndirectX, 1),
+                (AddressingMode::IndexedIndirectY, 1),
+                (AddressingMode::IndirectIndexedX, 1),
+                (AddressingMode::IndirectIndexedY, 1),
+            ];
+
+            for (i, (mode, length)) in operand_lengths.into_iter().enumerate() {
+                let mut cpu = CPU::<TestBus>::new(TestBus::new(), false);
+                addressing_mode_tester(&mut cpu, 0x42, &mode);
+                cpu.get_operand(mode);
+                assert_eq!(cpu.program_counter, length, "addressing mode #{}", i);
+            }
+        }
+
+        #[test]
+        fn test_zero_page_indexed_wrap() {
+            let mut cpu = CPU::new(TestBus::new(), false);
+            cpu.memory.set_vector_read_target(0x0000, vec![0xf0, 0xff]);
+            cpu.register_x = 0x20;
+            cpu.register_y = 0x01;
+
+            assert_eq!(cpu.get_target_address(AddressingMode::ZeroPageX), 0x0010);
+            assert_eq!(cpu.get_target_address(AddressingMode::ZeroPageY), 0x0000);
+        }
+
+        #[test]
+        fn test_zero_page_indexed_wrap_instructions() {
+            let mut cpu = CPU::new(SparseMemory::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.memory.load(0x8000, &[0xb5, 0xf0, 0xb6, 0x80, 0x96, 0xff]); // lda $f0,x, ldx $80,y, stx $ff,y
+            cpu.memory.load(0x0010, &[0x42]);
+            cpu.memory.load(0x0110, &[0x24]);
+            cpu.memory.load(0x0000, &[0x11]);
+            cpu.register_x = 0x20;
+            cpu.register_y = 0x80;
+
+            cpu.step().unwrap();
+            assert_eq!(cpu.register_a, 0x42);
+            cpu.step().unwrap();
+            assert_eq!(cpu.register_x, 0x11);
+            cpu.step().unwrap();
+            assert_eq!(cpu.memory.data[&0x007f], 0x11);
+            assert!(!cpu.memory.data.contains_key(&0x017f));
+        }
+
+        #[test]
+        fn test_immediate_pc_advance() {
+            let mut cpu = CPU::<SparseMemory>::new(SparseMemory::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.memory.load(0x8000, &[0xa9, 0x42]);
+
+            cpu.step().unwrap();
+
+            assert_eq!(cpu.register_a, 0x42);
+            assert_eq!(cpu.program_counter, 0x8002);
+        }
+
         fn and(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
             let a: u8 = next_u8(rng);
             let mem_value: u8 = next_u8(rng);
@@ -869,12 +2141,15 @@ pub mod cpu {
         ];
 
         fn asl(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
+            let accumulator = matches!(mode, AddressingMode::Accumulator);
             let mem_value: u8 = next_u8(rng);
             let addr = addressing_mode_tester(cpu, mem_value, &mode);
 
-            cpu.memory.set_write_target(addr, mem_value << 1);
+            if !accumulator { cpu.memory.set_write_target(addr, mem_value << 1); }
 
             cpu.asl(mode);
+
+            if accumulator { assert_eq!(cpu.register_a, mem_value << 1); }
             
             assert_eq!(cpu.get_flag(Flag::Z), mem_value << 1 == 0);
             assert_eq!(cpu.get_flag(Flag::N), (mem_value << 1)  & 0b1000_0000 != 0);
@@ -883,6 +2158,7 @@ pub mod cpu {
 
         run_test![
             asl,
+            Accumulator,
             ZeroPage,
             ZeroPageX,
             Absolute,
@@ -911,24 +2187,34 @@ pub mod cpu {
 
         /*  ** Logic check for rel_jump. **
             We simulate that a jump instruction was read at the address 0x8000, and the program counter moved to
-            0x8001, where we load the relative jump address. Afterwards, we call the jump_rel instruction logic 
+            0x8001, where we load the relative jump address. Afterwards, we call the branch instruction logic 
             directly, and check if it set the program counter as expected.
-            Note that since the computer is not directly run, we do not need to increase the target program counter
-            to deal with the extra 0x00 that is read to halt the execution.
+            The offset is relative to the address of the next instruction, i.e. 0x8002.
         */
         #[test]
         fn test_rel_jump() {
-            let mut cpu = CPU::<TestBus>::new();
+            let mut cpu = CPU::<SparseMemory>::new(SparseMemory::new(), false);
             
             cpu.program_counter = 0x8001;
-            cpu.memory.set_read_target(0x8001, 0b1001_0101);
-            cpu.jump_rel(true);
-            assert_eq!(cpu.program_counter, 0x7f95);
+            cpu.memory.load(0x8001, &[0b1001_0101]);
+            cpu.branch(true);
+            assert_eq!(cpu.program_counter, 0x7f97);
+
+            cpu.program_counter = 0x8001;
+            cpu.memory.load(0x8001, &[0b0110_0101]);
+            cpu.branch(true);
+            assert_eq!(cpu.program_counter, 0x8067);
 
+            // Wraps around the address space.
+            cpu.program_counter = 0x0001;
+            cpu.memory.load(0x0001, &[0x80]);
+            cpu.branch(true);
+            assert_eq!(cpu.program_counter, 0xff82);
+
+            // The offset is read even if the branch is not taken.
             cpu.program_counter = 0x8001;
-            cpu.memory.set_read_target(0x8001, 0b0110_0101);
-            cpu.jump_rel(true);
-            assert_eq!(cpu.program_counter, 0x8065); 
+            cpu.branch(false);
+            assert_eq!(cpu.program_counter, 0x8002);
         }
 
         /*  ** Checking jump instructions **
@@ -936,111 +2222,110 @@ pub mod cpu {
             method. This method loads the cpu memory with the instruction tested, and a jump pattern, which allows to test
             if the cpu branched or not. We compare this with the expected behavior.
         */
-        fn jump_check(instruction: u8, cpu: &mut CPU<TestBus>) -> bool {
+        fn jump_check(instruction: u8, cpu: &mut CPU<SparseMemory>) -> bool {
             cpu.program_counter = 0x8000;
-            cpu.memory.set_vector_read_target(0x8000, vec![instruction, 0x05, 0x00]);
-            cpu.memory.set_read_target(0x8005, 0x00);
-            cpu.run();
+            cpu.memory.load(0x8000, &[instruction, 0x05]);
+            cpu.step().unwrap();
 
             match cpu.program_counter {
-                0x8006 => true,     // This is 0x8005 + 1, i.e. the program halts on 0x8005
-                0x8003 => false,    // Similarly in this case the program halts on 0x8002
+                0x8007 => true,     // 0x8002 + 0x05, i.e. the branch was taken
+                0x8002 => false,    // The next instruction after the branch
                 _ => panic!("The value of the program counter is unexpected: {:x}", cpu.program_counter),
             }
         }
         
         #[test]
         fn test_bcc_0x90() {
-            let mut cpu = CPU::<TestBus>::new();
+            let mut cpu = CPU::<SparseMemory>::new(SparseMemory::new(), false);
 
             cpu.set_flag(Flag::C, false);
-            assert_eq!(jump_check(0x90, &mut cpu), true);
+            assert!(jump_check(0x90, &mut cpu));
 
             cpu.set_flag(Flag::C, true);
-            assert_eq!(jump_check(0x90, &mut cpu), false);
+            assert!(!jump_check(0x90, &mut cpu));
         }
 
         #[test]
         fn test_bcs_0xb0() {
-            let mut cpu = CPU::<TestBus>::new();
+            let mut cpu = CPU::<SparseMemory>::new(SparseMemory::new(), false);
 
             cpu.set_flag(Flag::C, false);
-            assert_eq!(jump_check(0xb0, &mut cpu), false);
+            assert!(!jump_check(0xb0, &mut cpu));
 
             cpu.set_flag(Flag::C, true);
-            assert_eq!(jump_check(0xb0, &mut cpu), true);
+            assert!(jump_check(0xb0, &mut cpu));
         }
 
         #[test]
         fn test_beq_0xf0() {
-            let mut cpu = CPU::<TestBus>::new();
+            let mut cpu = CPU::<SparseMemory>::new(SparseMemory::new(), false);
 
             cpu.set_flag(Flag::Z, false);
-            assert_eq!(jump_check(0xf0, &mut cpu), false);
+            assert!(!jump_check(0xf0, &mut cpu));
 
             cpu.set_flag(Flag::Z, true);
-            assert_eq!(jump_check(0xf0, &mut cpu), true);
+            assert!(jump_check(0xf0, &mut cpu));
         }
         
         #[test]
         fn test_bne_0xd0() {
-            let mut cpu = CPU::<TestBus>::new();
+            let mut cpu = CPU::<SparseMemory>::new(SparseMemory::new(), false);
 
             cpu.set_flag(Flag::Z, false);
-            assert_eq!(jump_check(0xd0, &mut cpu), true);
+            assert!(jump_check(0xd0, &mut cpu));
 
             cpu.set_flag(Flag::Z, true);
-            assert_eq!(jump_check(0xd0, &mut cpu), false);
+            assert!(!jump_check(0xd0, &mut cpu));
         }
 
         #[test]
         fn test_bmi_0x30() {
-            let mut cpu = CPU::<TestBus>::new();
+            let mut cpu = CPU::<SparseMemory>::new(SparseMemory::new(), false);
 
             cpu.set_flag(Flag::N, false);
-            assert_eq!(jump_check(0x30, &mut cpu), false);
+            assert!(!jump_check(0x30, &mut cpu));
 
             cpu.set_flag(Flag::N, true);
-            assert_eq!(jump_check(0x30, &mut cpu), true);
+            assert!(jump_check(0x30, &mut cpu));
         }
 
         #[test]
         fn test_bpl_0x10() {
-            let mut cpu = CPU::<TestBus>::new();
+            let mut cpu = CPU::<SparseMemory>::new(SparseMemory::new(), false);
 
             cpu.set_flag(Flag::N, false);
-            assert_eq!(jump_check(0x10, &mut cpu), true);
+            assert!(jump_check(0x10, &mut cpu));
 
             cpu.set_flag(Flag::N, true);
-            assert_eq!(jump_check(0x10, &mut cpu), false);
+            assert!(!jump_check(0x10, &mut cpu));
         }
 
         #[test]
         fn test_bvc_0x50() {
-            let mut cpu = CPU::<TestBus>::new();
+            let mut cpu = CPU::<SparseMemory>::new(SparseMemory::new(), false);
 
             cpu.set_flag(Flag::V, false);
-            assert_eq!(jump_check(0x50, &mut cpu), true);
+            assert!(jump_check(0x50, &mut cpu));
 
             cpu.set_flag(Flag::V, true);
-            assert_eq!(jump_check(0x50, &mut cpu), false);
+            assert!(!jump_check(0x50, &mut cpu));
         }
 
         #[test]
         fn test_bvc_0x70() {
-            let mut cpu = CPU::<TestBus>::new();
+            let mut cpu = CPU::<SparseMemory>::new(SparseMemory::new(), false);
 
             cpu.set_flag(Flag::V, false);
-            assert_eq!(jump_check(0x70, &mut cpu), false);
+            assert!(!jump_check(0x70, &mut cpu));
 
             cpu.set_flag(Flag::V, true);
-            assert_eq!(jump_check(0x70, &mut cpu), true);
+            assert!(jump_check(0x70, &mut cpu));
         }
 
         fn dec(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
             let mem_value: u8 = next_u8(rng);
             let addr = addressing_mode_tester(cpu, mem_value, &mode);
-            let new_value = mem_value - 1;
+            let new_value = mem_value.wrapping_sub(1);
 
             cpu.memory.set_write_target(addr, new_value);
 
@@ -1058,121 +2343,1669 @@ pub mod cpu {
             AbsoluteX
         ];
 
-        // what does inc do? well, it increments a memory address...
-        fn inc(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
-            let val = next_u8(rng);
-            let addr = addressing_mode_tester(cpu, val, &mode);
-            cpu.memory.set_write_target(addr, val+1);
+        fn eor(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
+            let val: u8 = next_u8(rng);
+            addressing_mode_tester(cpu, val, &mode);
+            let reg = next_u8(rng);
+            cpu.register_a = reg;
 
-            cpu.inc(mode);
+            cpu.eor(mode);
 
-            assert_eq!(cpu.get_flag(Flag::Z), val+1 == 0);
-            assert_eq!(cpu.get_flag(Flag::N), ((val + 1) & 0b1000_0000) != 0);
+            assert_eq!(cpu.register_a, val ^ reg);
+            assert_eq!(cpu.get_flag(Flag::Z), cpu.register_a == 0);
+            assert_eq!(cpu.get_flag(Flag::N), cpu.register_a & 0b1000_0000 != 0);
         }
 
-        run_test![inc, ZeroPage, ZeroPageX, Absolute, AbsoluteX];
-
-        macro_rules! ld {
-            ($($name: ident, $register: ident),+) => {
-                $(fn $name(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
-                    let val = next_u8(rng);
-                    addressing_mode_tester(cpu, val, &mode);
-                    cpu.$name(mode);
+        run_test![eor, Immediate, ZeroPage, ZeroPageX, Absolute, AbsoluteX, AbsoluteY, IndexedIndirectX, IndirectIndexedY];
 
-                    assert_eq!(cpu.$register, val);
-                })+
+        #[test]
+        fn test_eor_clears_equal_bits() {
+            let mut cpu = CPU::new(ArrayBus::new(), false);
+            let state = cpu.execute_inline(&[0xa9, 0b1010_1010, 0x49, 0b1010_1010]).unwrap(); // lda, eor #
+            assert_eq!(state.register_a, 0);
+            assert!(cpu.get_flag(Flag::Z));
+
+            cpu.execute_inline(&[0x49, 0b1000_0001]).unwrap(); // eor #
+            assert_eq!(cpu.register_a, 0b1000_0001);
+            assert!(cpu.get_flag(Flag::N));
+        }
 
-            }
+        #[test]
+        fn test_wrapping_arithmetic() {
+            let mut cpu = CPU::new(ArrayBus::new(), false);
+            let state = cpu.execute_inline(&[0xa2, 0x00, 0xca, 0xa0, 0xff, 0xc8]).unwrap(); // ldx #0, dex, ldy #$ff, iny
+            assert_eq!((state.register_x, state.register_y), (0xff, 0x00));
+
+            let state = cpu.execute_inline(&[0xa9, 0xff, 0x18, 0x69, 0x01]).unwrap(); // lda #$ff, clc, adc #1
+            assert_eq!(state.register_a, 0x00);
+
+            // The program counter and the stack pointer wrap around.
+            cpu.memory.load(0xffff, &[0xe8]); // inx
+            cpu.program_counter = 0xffff;
+            cpu.stack_pointer = 0x00;
+            cpu.step().unwrap();
+            assert_eq!(cpu.program_counter, 0x0000);
+            cpu.execute_inline(&[0x48]).unwrap(); // pha
+            assert_eq!(cpu.stack_pointer, 0xff);
         }
 
-        ld![lda, register_a, ldx, register_x, ldy, register_y];
-        run_test![lda, Immediate, ZeroPage, ZeroPageX, Absolute, AbsoluteX, AbsoluteY, IndexedIndirectY, IndirectIndexedY];
-        run_test![ldx, Immediate,ZeroPage,ZeroPageY,Absolute,AbsoluteY];
-        run_test![ldy, Immediate, ZeroPage, ZeroPageX, Absolute, AbsoluteX];
+        #[test]
+        fn test_stack_slice() {
+            let mut cpu = CPU::new(ArrayBus::new(), false);
+            cpu.stack_pointer = 0xff;
+            assert!(cpu.stack_slice().is_empty());
+            assert_eq!(cpu.dump_stack(), "SP:FF");
+
+            cpu.execute_inline(&[0xa9, 0x12, 0x48, 0xa9, 0x34, 0x48]).unwrap(); // lda #$12, pha, lda #$34, pha
+            assert_eq!(cpu.stack_slice(), [Some(0x34), Some(0x12)]);
+            assert_eq!(cpu.dump_stack(), "SP:FD 01FE: 34 12");
+
+            cpu.stack_pointer = 0x00;
+            assert_eq!(cpu.stack_slice().len(), 0xff);
+        }
 
-        fn lsr(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
-            let val = next_u8(rng);
-            let addr: u16 = addressing_mode_tester(cpu, val, &mode);
-            let new_val = val >> 1;
-            cpu.memory.set_write_target(addr, new_val);
+        #[test]
+        fn test_call_stack() {
+            let mut bus = ArrayBus::new();
+            bus.load(0x0200, &[0x20, 0x10, 0x02]); // jsr $0210
+            bus.load(0x0210, &[0x20, 0x20, 0x02, 0x60]); // jsr $0220, rts
+            bus.load(0x0220, &[0x60]); // rts
+            let mut cpu = CPU::new(bus, false);
+            cpu.program_counter = 0x0200;
+            cpu.stack_pointer = 0xfd;
+
+            cpu.step().unwrap();
+            assert!(cpu.call_stack().is_empty()); // off by default
+            cpu.set_call_tracking(true);
+            cpu.program_counter = 0x0200;
+            cpu.stack_pointer = 0xfd;
+
+            cpu.step().unwrap();
+            cpu.step().unwrap();
+            assert_eq!(cpu.call_stack(), [
+                CallFrame { caller: 0x0200, target: 0x0210, stack_pointer: 0xfb },
+                CallFrame { caller: 0x0210, target: 0x0220, stack_pointer: 0xf9 },
+            ]);
+            cpu.step().unwrap();
+            assert_eq!(cpu.call_stack().len(), 1);
+            cpu.step().unwrap();
+            assert!(cpu.call_stack().is_empty());
+            assert_eq!(cpu.return_mismatch(), None);
+            assert_eq!(cpu.program_counter, 0x0203);
+        }
 
-            cpu.lsr(mode);
+        #[test]
+        fn test_call_stack_manual_returns() {
+            let mut bus = ArrayBus::new();
+            bus.load(0x0200, &[0x20, 0x10, 0x02]); // jsr $0210
+            bus.load(0x0210, &[0xba, 0xfe, 0x01, 0x01, 0x60]); // tsx, inc $0101,x, rts
+            bus.load(0x0300, &[0x20, 0x10, 0x03]); // jsr $0310
+            bus.load(0x0310, &[0x68, 0x68, 0xa9, 0x04, 0x48, 0xa9, 0x3f, 0x48, 0x60]); // pla, pla, push $043f, rts
+            let mut cpu = CPU::new(bus, false);
+            cpu.set_call_tracking(true);
+
+            // The return address is changed on the stack.
+            cpu.program_counter = 0x0200;
+            for _ in 0..3 {
+                cpu.step().unwrap();
+                assert_eq!(cpu.return_mismatch(), None);
+            }
+            cpu.step().unwrap();
+            assert_eq!(cpu.program_counter, 0x0204);
+            assert_eq!(cpu.return_mismatch(), Some(ReturnMismatch {
+                pc: 0x0214,
+                frame: CallFrame { caller: 0x0200, target: 0x0210, stack_pointer: 0xfd },
+                returned_to: 0x0204,
+            }));
+            assert!(cpu.call_stack().is_empty());
+
+            // The return address is pulled by hand, and the rts goes to an address pushed by the game.
+            cpu.program_counter = 0x0300;
+            cpu.step().unwrap();
+            assert_eq!(cpu.call_stack().len(), 1);
+            cpu.step().unwrap();
+            assert!(cpu.call_stack().is_empty());
+            for _ in 0..6 {
+                cpu.step().unwrap();
+            }
+            assert_eq!(cpu.program_counter, 0x0440);
+            assert_eq!(cpu.return_mismatch(), None);
+        }
 
-            assert_eq!(cpu.get_flag(Flag::C), val & 0b0000_0001 != 0);
-            assert_eq!(cpu.get_flag(Flag::Z), new_val == 0);
-            assert_eq!(cpu.get_flag(Flag::N), new_val & 0b1000_0000 != 0);
+        #[test]
+        fn test_region() {
+            let mut cpu = CPU::new(ArrayBus::new(), false);
+            assert_eq!(cpu.region(), Region::Ntsc);
+            cpu.cycles = NTSC_CLOCK_HZ;
+            assert_eq!(cpu.elapsed(), time::Duration::from_secs(1));
+
+            cpu.set_region(Region::Pal);
+            assert_eq!(cpu.clock_hz(), Some(PAL_CLOCK_HZ));
+            cpu.cycles = PAL_CLOCK_HZ / 2;
+            assert_eq!(cpu.elapsed().as_millis(), 499);
         }
 
-        run_test![lsr, ZeroPage, ZeroPageX, Absolute, AbsoluteX];
+        // A bus with a PPU adding 'extra_scanlines' to every frame.
+        fn overclocked_bus(extra_scanlines: u16) -> RomBus {
+            let mut ppu = StubPpu::new();
+            ppu.set_extra_scanlines(extra_scanlines);
+            let mut bus = RomBus::new();
+            bus.set_ppu(Box::new(ppu));
+            bus
+        }
 
-        fn ora(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
-            let val: u8 = next_u8(rng);
-            addressing_mode_tester(cpu, val, &mode);
-            let reg = next_u8(rng);
-            cpu.register_a = reg;
+        #[test]
+        fn test_overclock() {
+            let mut cpu = CPU::new(overclocked_bus(20), false);
+            assert_eq!(cpu.clock_hz(), Some(1_926_396)); // 282 / 262 of the NTSC clock
+            assert_eq!(cpu.cycles_per_frame(), 32_054);
+            // Switching the region keeps the extra scanlines.
+            cpu.set_region(Region::Pal);
+            assert_eq!(cpu.clock_hz(), Some(1_769_184));
+            cpu.memory.set_ppu(Box::new(StubPpu::new()));
+            assert_eq!(cpu.clock_hz(), Some(PAL_CLOCK_HZ));
+            assert_eq!(cpu.cycles_per_frame(), 33_248);
+        }
 
-            cpu.ora(mode);
+        #[test]
+        fn test_clock_speed_override() {
+            let mut cpu = CPU::new(overclocked_bus(20), false);
+            cpu.set_clock_speed(None);
+            cpu.set_region(Region::Pal);
+            assert_eq!(cpu.clock_hz(), None); // still unlimited
+
+            cpu.set_clock_speed(Some(1_000_000));
+            cpu.set_region(Region::Ntsc);
+            assert_eq!(cpu.clock_hz(), Some(1_000_000));
+            cpu.memory.set_ppu(Box::new(StubPpu::new()));
+            assert_eq!(cpu.cycles_per_frame(), 29_781); // the frame length stays the one of the region
+        }
 
-            assert_eq!(cpu.register_a, val | reg);
-            assert_eq!(cpu.get_flag(Flag::Z), cpu.register_a == 0);
-            assert_eq!(cpu.get_flag(Flag::N), cpu.register_a & 0b1000_0000 != 0);
+        #[test]
+        fn test_instruction_hooks() {
+            use std::cell::RefCell;
+            use std::rc::Rc;
+
+            let mut bus = ArrayBus::new();
+            bus.load(0x0200, &[0xa9, 0x42, 0xe8]); // lda #$42, inx
+            let mut cpu = CPU::new(bus, false);
+            cpu.program_counter = 0x0200;
+
+            let log = Rc::new(RefCell::new(Vec::new()));
+            let before = Rc::clone(&log);
+            cpu.on_before_instruction(Box::new(move |pc, opcode, state| {
+                before.borrow_mut().push((false, pc, opcode, state.register_a));
+            }));
+            let after = Rc::clone(&log);
+            cpu.on_after_instruction(Box::new(move |pc, opcode, state| {
+                after.borrow_mut().push((true, pc, opcode, state.register_a));
+            }));
+
+            cpu.step().unwrap();
+            cpu.step().unwrap();
+            assert_eq!(*log.borrow(), [
+                (false, 0x0200, 0xa9, 0x00), (true, 0x0200, 0xa9, 0x42),
+                (false, 0x0202, 0xe8, 0x42), (true, 0x0202, 0xe8, 0x42),
+            ]);
+
+            cpu.clear_hooks();
+            cpu.step().unwrap();
+            assert_eq!(log.borrow().len(), 4);
         }
 
-        run_test![ora, Immediate, ZeroPage, ZeroPageX, Absolute, AbsoluteX, AbsoluteY, IndexedIndirectX, IndirectIndexedY];
+        #[test]
+        fn test_illegal_opcode_policy() {
+            fn cpu(policy: IllegalOpcodePolicy) -> CPU<ArrayBus> {
+                let mut bus = ArrayBus::new();
+                bus.load(0x0200, &[0x9c, 0x00, 0x03, 0xe8]); // shy $0300,x (unimplemented), inx
+                let mut cpu = CPU::new(bus, false);
+                cpu.program_counter = 0x0200;
+                cpu.set_illegal_opcode_policy(policy);
+                cpu
+            }
 
-        // push instructions
+            let mut break_cpu = cpu(IllegalOpcodePolicy::Break);
+            assert_eq!(break_cpu.step(), Err(CpuError::IllegalOpcode(0x9c)));
+            assert_eq!(break_cpu.program_counter, 0x0200);
 
-        // rti . return from interrupt
+            let mut halt_cpu = cpu(IllegalOpcodePolicy::Halt);
+            assert_eq!(halt_cpu.step(), Err(CpuError::Halted(0x9c)));
+            assert!(halt_cpu.is_halted());
 
-        // rts - return from subroutine
+            let mut nop_cpu = cpu(IllegalOpcodePolicy::Nop);
+            assert_eq!(nop_cpu.step(), Ok(5));
+            assert_eq!(nop_cpu.program_counter, 0x0203);
+            nop_cpu.step().unwrap();
+            assert_eq!(nop_cpu.register_x, 1);
+        }
+
+        #[test]
+        #[should_panic(expected = "Illegal opcode 0x9c at 0x0200")]
+        fn test_illegal_opcode_panic() {
+            let mut bus = ArrayBus::new();
+            bus.load(0x0200, &[0x9c, 0x00, 0x03]);
+            let mut cpu = CPU::new(bus, false);
+            cpu.program_counter = 0x0200;
+            cpu.set_illegal_opcode_policy(IllegalOpcodePolicy::Panic);
+            let _ = cpu.step();
+        }
+
+        #[test]
+        fn test_profiling() {
+            let mut bus = ArrayBus::new();
+            bus.load(0x0200, &[0x20, 0x10, 0x02, 0x20, 0x20, 0x02]); // jsr $0210, jsr $0220
+            bus.load(0x0210, &[0xe8, 0x20, 0x20, 0x02, 0x60]); // inx, jsr $0220, rts
+            bus.load(0x0220, &[0xea, 0xea, 0x60]); // nop, nop, rts
+            let mut cpu = CPU::new(bus, false);
+            cpu.program_counter = 0x0200;
+            assert_eq!(cpu.profile(), None);
+            cpu.set_profiling(true);
+
+            cpu.run(RunMode::UntilPc(0x0206)).unwrap();
+            let profile = cpu.profile().unwrap();
+            assert_eq!(profile.cycles_by_pc[&0x0220], 4); // nop, called twice
+            assert_eq!(profile.cycles_by_pc[&0x0200], 6);
+            // jsr $0220 counts for $0210, the nops and rts for $0220
+            assert_eq!(profile.hot_subroutines(5), [(0x0220, 2 * (2 + 2 + 6)), (0x0210, 2 + 6 + 6)]);
+            assert_eq!(profile.hot_addresses(2), [(0x0222, 12), (0x0200, 6)]); // rts, then the tie broken by address
+        }
+
+        #[test]
+        fn test_step_back() {
+            let mut bus = ArrayBus::new();
+            bus.load(0x0200, &[0xe6, 0x10, 0xe8, 0x4c, 0x00, 0x02]); // inc $10, inx, jmp $0200
+            let mut cpu = CPU::new(bus, false);
+            cpu.program_counter = 0x0200;
+            assert_eq!(cpu.step_back(), Ok(false));
+            cpu.set_rewind(4, 3);
+
+            let mut states = Vec::new();
+            for _ in 0..20 {
+                states.push((cpu.state(), cpu.cycles, cpu.memory.probe(0x10)));
+                cpu.step().unwrap();
+            }
+            // Snapshots were taken before instructions 8, 12 and 16, so 12 steps back are possible.
+            for _ in 0..12 {
+                assert_eq!(cpu.step_back(), Ok(true));
+                assert_eq!(Some(&(cpu.state(), cpu.cycles, cpu.memory.probe(0x10))), states.pop().as_ref());
+            }
+            assert_eq!(cpu.step_back(), Ok(false));
+
+            // Going forward again takes new snapshots.
+            for _ in 0..6 { cpu.step().unwrap(); }
+            assert_eq!(cpu.step_back(), Ok(true));
+            assert_eq!(cpu.instructions, 13);
+            assert_eq!(cpu.memory.probe(0x10), Some(5));
+        }
+
+        #[test]
+        fn test_decimal_mode() {
+            // Runs sed, then the operation on 'a' and 'val' with the carry set to 'carry'.
+            fn run(variant: CpuVariant, opcode: u8, a: u8, val: u8, carry: bool) -> CPU<ArrayBus> {
+                let mut cpu = CPU::with_variant(ArrayBus::new(), false, variant);
+                cpu.register_a = a;
+                cpu.set_flag(Flag::C, carry);
+                cpu.execute_inline(&[0xf8, opcode, val]).unwrap(); // sed, adc/sbc #val
+                cpu
+            }
+
+            // (a, val, carry in, result, carry out)
+            let additions = [
+                (0x12, 0x34, false, 0x46, false),
+                (0x58, 0x46, true, 0x05, true),
+                (0x99, 0x01, false, 0x00, true),
+                (0x81, 0x92, false, 0x73, true),
+            ];
+            for (a, val, carry, result, carry_out) in additions {
+                for variant in [CpuVariant::Nmos6502, CpuVariant::Cmos65C02] {
+                    let mut cpu = run(variant, 0x69, a, val, carry);
+                    assert_eq!((cpu.register_a, cpu.get_flag(Flag::C)), (result, carry_out), "{:x} + {:x}", a, val);
+                }
+            }
+            let subtractions = [
+                (0x46, 0x12, true, 0x34, true),
+                (0x40, 0x13, true, 0x27, true),
+                (0x00, 0x01, true, 0x99, false),
+                (0x32, 0x02, false, 0x29, true),
+            ];
+            for (a, val, carry, result, carry_out) in subtractions {
+                for variant in [CpuVariant::Nmos6502, CpuVariant::Cmos65C02] {
+                    let mut cpu = run(variant, 0xe9, a, val, carry);
+                    assert_eq!((cpu.register_a, cpu.get_flag(Flag::C)), (result, carry_out), "{:x} - {:x}", a, val);
+                }
+            }
+
+            // The NMOS Z flag follows the binary sum, 0x99 + 0x01 = 0x9a.
+            let mut cpu = run(CpuVariant::Nmos6502, 0x69, 0x99, 0x01, false);
+            assert!(!cpu.get_flag(Flag::Z));
+            let mut cpu = run(CpuVariant::Cmos65C02, 0x69, 0x99, 0x01, false);
+            assert!(cpu.get_flag(Flag::Z));
+
+            // The 2A03 ignores the D flag.
+            let mut cpu = run(CpuVariant::Ricoh2A03, 0x69, 0x19, 0x01, false);
+            assert_eq!(cpu.register_a, 0x1a);
+            assert!(cpu.get_flag(Flag::D));
+
+            // The 65C02 clears it on interrupts.
+            let mut cpu = run(CpuVariant::Cmos65C02, 0x69, 0x19, 0x01, false);
+            assert_eq!(cpu.register_a, 0x20);
+            cpu.memory.load(0x0300, &[0x00]); // brk
+            cpu.program_counter = 0x0300;
+            cpu.step().unwrap();
+            assert!(!cpu.get_flag(Flag::D));
+        }
+
+        #[test]
+        fn test_cmos_jmp_indirect() {
+            for (variant, target, cycles) in [(CpuVariant::Ricoh2A03, 0x0600, 5), (CpuVariant::Cmos65C02, 0x0500, 6)] {
+                let mut bus = ArrayBus::new();
+                bus.load(0x0400, &[0x6c, 0xff, 0x02]); // jmp ($02ff)
+                bus.load(0x02ff, &[0x00, 0x05]);
+                bus.load(0x0200, &[0x06]); // the high byte on the NMOS cpus
+                let mut cpu = CPU::with_variant(bus, false, variant);
+                cpu.program_counter = 0x0400;
+                assert_eq!(cpu.step(), Ok(cycles));
+                assert_eq!(cpu.program_counter, target);
+            }
+        }
+
+        #[test]
+        fn test_unofficial_opcodes() {
+            // Runs the program with 'val' at 0x0010, returns the registers and the new value.
+            fn run(program: &[u8], val: u8) -> (CpuState, u8, CPU<ArrayBus>) {
+                let mut cpu = CPU::new(ArrayBus::new(), false);
+                cpu.memory.load(0x0010, &[val]);
+                let state = cpu.execute_inline(program).unwrap();
+                let val = cpu.memory.probe(0x0010).unwrap();
+                (state, val, cpu)
+            }
+
+            let (state, _, mut cpu) = run(&[0xa7, 0x10], 0x80); // lax $10
+            assert_eq!((state.register_a, state.register_x), (0x80, 0x80));
+            assert!(cpu.get_flag(Flag::N));
+
+            let (_, val, _) = run(&[0xa9, 0xf0, 0xa2, 0x3c, 0x87, 0x10], 0x00); // lda #$f0, ldx #$3c, sax $10
+            assert_eq!(val, 0x30);
+
+            let (_, val, mut cpu) = run(&[0xa9, 0x42, 0xc7, 0x10], 0x43); // lda #$42, dcp $10
+            assert_eq!(val, 0x42);
+            assert!(cpu.get_flag(Flag::Z) && cpu.get_flag(Flag::C));
+
+            let (state, val, _) = run(&[0xa9, 0x05, 0x38, 0xe7, 0x10], 0x01); // lda #$05, sec, isb $10
+            assert_eq!((state.register_a, val), (0x03, 0x02));
+
+            let (state, val, mut cpu) = run(&[0xa9, 0x01, 0x07, 0x10], 0x81); // lda #$01, slo $10
+            assert_eq!((state.register_a, val), (0x03, 0x02));
+            assert!(cpu.get_flag(Flag::C));
+
+            let (state, val, _) = run(&[0x38, 0xa9, 0xff, 0x27, 0x10], 0x81); // sec, lda #$ff, rla $10
+            assert_eq!((state.register_a, val), (0x03, 0x03));
+
+            let (state, val, mut cpu) = run(&[0xa9, 0xff, 0x47, 0x10], 0x03); // lda #$ff, sre $10
+            assert_eq!((state.register_a, val), (0xfe, 0x01));
+            assert!(cpu.get_flag(Flag::C) && cpu.get_flag(Flag::N));
+
+            // The bit rotated out is the carry of the addition.
+            let (state, val, _) = run(&[0x18, 0xa9, 0x10, 0x67, 0x10], 0x03); // clc, lda #$10, rra $10
+            assert_eq!((state.register_a, val), (0x12, 0x01));
+
+            let (state, _, _) = run(&[0xa9, 0x05, 0x38, 0xeb, 0x02], 0x00); // lda #$05, sec, sbc #$02
+            assert_eq!(state.register_a, 0x03);
+        }
+
+        #[test]
+        fn test_unofficial_nops() {
+            let program = [
+                0x1a,               // nop
+                0x80, 0x12,         // nop #$12
+                0x04, 0x10,         // nop $10
+                0x14, 0x10,         // nop $10,x
+                0x0c, 0x00, 0x03,   // nop $0300
+                0x1c, 0xff, 0x03,   // nop $03ff,x
+            ];
+            let mut cpu = CPU::new(ArrayBus::new(), false);
+            cpu.register_x = 0x01;
+            let before = cpu.state();
+            let state = cpu.execute_inline(&program).unwrap();
+
+            assert_eq!(state.program_counter, 0x0200 + program.len() as u16);
+            assert_eq!(CpuState { program_counter: before.program_counter, ..state }, before);
+            assert_eq!(cpu.cycles, 2 + 2 + 3 + 4 + 4 + 5); // the last one crosses a page
+        }
+
+        // The accumulator variants go through the same code as the memory ones, this checks that the
+        // opcodes are decoded as one byte instructions working on register a.
+        #[test]
+        fn test_accumulator_opcodes() {
+            let mut bus = ArrayBus::new();
+            bus.load(0x0000, &[0x81; 0x100]);
+            let mut cpu = CPU::new(bus, false);
+            // (opcode, carry in, expected a, expected carry)
+            for (opcode, c, a, carry) in [(0x0a, false, 0x02, true), (0x4a, false, 0x40, true), (0x2a, true, 0x03, true), (0x6a, false, 0x40, true)] {
+                cpu.set_flag(Flag::C, c);
+                let state = cpu.execute_inline(&[0xa9, 0x81, opcode]).unwrap(); // lda #$81
+                assert_eq!(state.register_a, a, "opcode {:#04x}", opcode);
+                assert_eq!(state.program_counter, 0x0203);
+                assert_eq!(cpu.get_flag(Flag::C), carry);
+                assert_eq!(cpu.memory.probe(0x0081), Some(0x81));
+            }
+        }
+
+        #[test]
+        fn test_compare_flags() {
+            // (register, value, C, Z, N)
+            let cases = [
+                (0x30, 0x20, true, false, false),
+                (0x20, 0x20, true, true, false),
+                (0x10, 0x20, false, false, true),
+                (0x80, 0x00, true, false, true),  // unsigned greater, but bit 7 of the difference is set
+                (0x00, 0x01, false, false, true), // wraps to 0xff
+                (0x01, 0xff, false, false, false), // wraps to 0x02
+            ];
+            // (load immediate, compare immediate)
+            for (load, compare) in [(0xa9, 0xc9), (0xa2, 0xe0), (0xa0, 0xc0)] { // cmp, cpx, cpy
+                for (register, val, c, z, n) in cases {
+                    let mut cpu = CPU::new(ArrayBus::new(), false);
+                    cpu.execute_inline(&[load, register, compare, val]).unwrap();
+                    let flags = (cpu.get_flag(Flag::C), cpu.get_flag(Flag::Z), cpu.get_flag(Flag::N));
+                    assert_eq!(flags, (c, z, n), "opcode {:#04x}, register {:#04x}, value {:#04x}", compare, register, val);
+                }
+            }
+        }
+
+        macro_rules! cp {
+            ($($name: ident, $register: ident),+) => {
+                $(fn $name(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
+                    let val = next_u8(rng);
+                    addressing_mode_tester(cpu, val, &mode);
+                    // make equal values come up often enough to exercise Z
+                    let reg = if next_bit(rng) == 1 { val } else { next_u8(rng) };
+                    cpu.$register = reg;
+
+                    cpu.$name(mode);
+
+                    let diff = reg.wrapping_sub(val);
+                    assert_eq!(cpu.$register, reg);
+                    assert_eq!(cpu.get_flag(Flag::C), reg >= val);
+                    assert_eq!(cpu.get_flag(Flag::Z), diff == 0);
+                    assert_eq!(cpu.get_flag(Flag::N), diff & 0b1000_0000 != 0);
+                })+
+            }
+        }
+
+        cp![cmp, register_a, cpx, register_x, cpy, register_y];
+        run_test![cmp, Immediate, ZeroPage, ZeroPageX, Absolute, AbsoluteX, AbsoluteY, IndexedIndirectX, IndirectIndexedY];
+        run_test![cpx, Immediate, ZeroPage, Absolute];
+        run_test![cpy, Immediate, ZeroPage, Absolute];
+
+        // what does inc do? well, it increments a memory address...
+        fn inc(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
+            let val = next_u8(rng);
+            let addr = addressing_mode_tester(cpu, val, &mode);
+            cpu.memory.set_write_target(addr, val.wrapping_add(1));
+
+            cpu.inc(mode);
+
+            assert_eq!(cpu.get_flag(Flag::Z), val.wrapping_add(1) == 0);
+            assert_eq!(cpu.get_flag(Flag::N), (val.wrapping_add(1) & 0b1000_0000) != 0);
+        }
+
+        run_test![inc, ZeroPage, ZeroPageX, Absolute, AbsoluteX];
+
+        macro_rules! ld {
+            ($($name: ident, $register: ident),+) => {
+                $(fn $name(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
+                    let val = next_u8(rng);
+                    addressing_mode_tester(cpu, val, &mode);
+                    cpu.$name(mode);
+
+                    assert_eq!(cpu.$register, val);
+                })+
+
+            }
+        }
+
+        ld![lda, register_a, ldx, register_x, ldy, register_y];
+        run_test![lda, Immediate, ZeroPage, ZeroPageX, Absolute, AbsoluteX, AbsoluteY, IndexedIndirectY, IndirectIndexedY];
+        run_test![ldx, Immediate,ZeroPage,ZeroPageY,Absolute,AbsoluteY];
+        run_test![ldy, Immediate, ZeroPage, ZeroPageX, Absolute, AbsoluteX];
+
+        fn lsr(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
+            let accumulator = matches!(mode, AddressingMode::Accumulator);
+            let val = next_u8(rng);
+            let addr: u16 = addressing_mode_tester(cpu, val, &mode);
+            let new_val = val >> 1;
+            if !accumulator { cpu.memory.set_write_target(addr, new_val); }
+
+            cpu.lsr(mode);
+
+            if accumulator { assert_eq!(cpu.register_a, new_val); }
+
+            assert_eq!(cpu.get_flag(Flag::C), val & 0b0000_0001 != 0);
+            assert_eq!(cpu.get_flag(Flag::Z), new_val == 0);
+            assert_eq!(cpu.get_flag(Flag::N), new_val & 0b1000_0000 != 0);
+        }
+
+        run_test![lsr, Accumulator, ZeroPage, ZeroPageX, Absolute, AbsoluteX];
+
+        fn ora(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
+            let val: u8 = next_u8(rng);
+            addressing_mode_tester(cpu, val, &mode);
+            let reg = next_u8(rng);
+            cpu.register_a = reg;
+
+            cpu.ora(mode);
+
+            assert_eq!(cpu.register_a, val | reg);
+            assert_eq!(cpu.get_flag(Flag::Z), cpu.register_a == 0);
+            assert_eq!(cpu.get_flag(Flag::N), cpu.register_a & 0b1000_0000 != 0);
+        }
+
+        run_test![ora, Immediate, ZeroPage, ZeroPageX, Absolute, AbsoluteX, AbsoluteY, IndexedIndirectX, IndirectIndexedY];
+
+        // push instructions
+        #[test]
+        fn test_pha_pla() {
+            let mut cpu = CPU::<SparseMemory>::new(SparseMemory::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.memory.load(0x8000, &[0x48, 0xa9, 0x00, 0x68]); // pha, lda #$00, pla
+            cpu.register_a = 0x80;
+
+            for _ in 0..3 {
+                cpu.step().unwrap();
+            }
+
+            assert_eq!(cpu.memory.data[&0x01ff], 0x80);
+            assert_eq!(cpu.register_a, 0x80);
+            assert_eq!(cpu.stack_pointer, 0xff);
+            assert!(cpu.get_flag(Flag::N));
+            assert!(!cpu.get_flag(Flag::Z));
+        }
+
+        #[test]
+        fn test_unused_flag_stays_set() {
+            let mut cpu = CPU::<SparseMemory>::new(SparseMemory::new(), false);
+            for flag in [Flag::N, Flag::V, Flag::B, Flag::D, Flag::I, Flag::Z, Flag::C] {
+                cpu.set_flag(flag, false);
+            }
+            assert_eq!(cpu.status, UNUSED_FLAG);
+
+            // plp and rti pulling a status with bit 5 clear.
+            cpu.program_counter = 0x8000;
+            cpu.memory.load(0x8000, &[0x28, 0x40]);
+            cpu.memory.load(0x01fa, &[0x00, 0x00, 0x00, 0x80]);
+            cpu.stack_pointer = 0xf9;
+
+            cpu.step().unwrap();
+            assert_eq!(cpu.status, UNUSED_FLAG);
+
+            cpu.step().unwrap();
+            assert_eq!(cpu.status, UNUSED_FLAG);
+            assert_eq!(cpu.program_counter, 0x8000);
+        }
+
+        #[test]
+        fn test_rom_write_fault() {
+            let raw = TestRomBuilder::new().prg_data(&[0x8d, 0x00, 0x80, 0x8d, 0x00, 0x80]).build(); // sta $8000
+            let mut bus = RomBus::new();
+            bus.set_rom(rom_from_bytes(&raw, None).unwrap());
+            let mut cpu = CPU::<RomBus>::new(bus, false);
+            cpu.program_counter = 0x8000;
+
+            assert_eq!(cpu.step(), Err(CpuError::SegmentationFault(0x8000)));
+
+            cpu.memory.set_ignore_rom_writes(true);
+            assert_eq!(cpu.step(), Ok(4));
+        }
+
+        // Pushes and pulls are stack accesses, a store to the stack page isn't.
+        #[test]
+        fn test_stack_only_protection() {
+            use crate::bus_guard::{AccessViolation, Protection};
+            use crate::event_bus::{Event, EventKind};
+            use std::cell::RefCell;
+            use std::rc::Rc;
+
+            // pha, pla, sta $0150, jsr $8009, nop, rts
+            let program = [0x48, 0x68, 0x8d, 0x50, 0x01, 0x20, 0x09, 0x80, 0xea, 0x60];
+            let raw = TestRomBuilder::new().prg_data(&program).build();
+            let mut bus = RomBus::new();
+            bus.set_rom(rom_from_bytes(&raw, None).unwrap());
+            bus.protect(0x0100..=0x01ff, Protection::StackOnly);
+            let violations = Rc::new(RefCell::new(Vec::new()));
+            let received = Rc::clone(&violations);
+            bus.events().subscribe(EventKind::AccessViolation, move |event| {
+                if let Event::AccessViolation(violation) = event { received.borrow_mut().push(*violation); }
+            });
+            let mut cpu = CPU::<RomBus>::new(bus, false);
+            cpu.program_counter = 0x8000;
+            cpu.register_a = 0x42;
+
+            for _ in 0..5 { cpu.step().unwrap(); }
+            assert_eq!(cpu.program_counter, 0x8008);
+            assert_eq!(*violations.borrow(), [
+                AccessViolation { cycle: 11, addr: 0x0150, value: 0x42, write: true, protection: Protection::StackOnly },
+            ]);
+        }
+
+        // The bus is ticked for all the cycles of an instruction that fails, so it stays in step
+        // with the cpu.
+        #[test]
+        fn test_bus_clock_after_error() {
+            // sta $8000, inc $8000, sta ($10),y with ($10) = $8000, kil, and an illegal opcode
+            for program in [&[0x8d, 0x00, 0x80][..], &[0xee, 0x00, 0x80], &[0x91, 0x10], &[0x02], &[0x0b]] {
+                let raw = TestRomBuilder::new().prg_data(program).build();
+                let mut bus = RomBus::new();
+                bus.set_rom(rom_from_bytes(&raw, None).unwrap());
+                bus.load_ram(&[0x00; 0x800]).unwrap();
+                bus.write(0x0011, 0x80);
+                let mut cpu = CPU::<RomBus>::new(bus, false);
+                cpu.program_counter = 0x8000;
+                cpu.stall(3);
+
+                assert!(cpu.step().is_err());
+                assert_eq!(cpu.memory.cycles(), cpu.cycles, "{:02x?}", program);
+            }
+        }
+
+        #[test]
+        fn test_snapshot_round_trip() {
+            let program = [
+                0xe6, 0x10,         // 0x8000: inc $10
+                0xe8,               // 0x8002: inx
+                0x48,               // 0x8003: pha
+                0x4c, 0x00, 0x80,   // 0x8004: jmp $8000
+            ];
+            let raw = TestRomBuilder::new().prg_data(&program).build();
+            let mut bus = RomBus::new();
+            bus.set_rom(rom_from_bytes(&raw, None).unwrap());
+            let mut cpu = CPU::<RomBus>::new(bus, false);
+            cpu.program_counter = 0x8000;
+
+            for _ in 0..10 { cpu.step().unwrap(); }
+            let snap = cpu.snapshot();
+            let state = cpu.state();
+            assert_eq!(snap.ram.len(), 0x800);
+            assert_eq!(snap.ram[0x10], 3);
+
+            for _ in 0..25 { cpu.step().unwrap(); }
+            assert_ne!(cpu.snapshot(), snap);
+
+            cpu.restore(snap.clone()).unwrap();
+            assert_eq!(cpu.snapshot(), snap);
+            assert!(state.diff(&cpu.state()).is_empty());
+
+            // The execution continues the same way after the restore.
+            for _ in 0..25 { cpu.step().unwrap(); }
+            let after = cpu.snapshot();
+            cpu.restore(snap).unwrap();
+            for _ in 0..25 { cpu.step().unwrap(); }
+            assert_eq!(cpu.snapshot(), after);
+
+            // A snapshot taken on ArrayBus has 64KB of RAM, which doesn't fit.
+            let wrong = CPU::new(ArrayBus::new(), false).snapshot();
+            assert!(cpu.restore(wrong).is_err());
+            assert_eq!(cpu.snapshot(), after);
+        }
+
+        #[test]
+        fn test_run_limits() {
+            let mut bus = ArrayBus::new();
+            bus.load(0x0200, &[0xe8, 0x4c, 0x00, 0x02]); // 0x0200: inx, jmp $0200
+            let mut cpu = CPU::new(bus, false);
+
+            cpu.program_counter = 0x0200;
+            assert_eq!(cpu.run(RunMode::InstructionLimit(10)), Ok(RunResult {
+                instructions: 10, cycles: 5 * (2 + 3), stop_reason: StopReason::LimitReached,
+            }));
+            assert_eq!(cpu.register_x, 5);
+
+            // inx, jmp, inx, jmp, inx is exactly 12 cycles.
+            assert_eq!(cpu.run(RunMode::CycleBudget(12)), Ok(RunResult {
+                instructions: 5, cycles: 12, stop_reason: StopReason::LimitReached,
+            }));
+            // Budgets stop after the instruction that exhausts them.
+            assert_eq!(cpu.run(RunMode::CycleBudget(1)).unwrap().cycles, 3);
+        }
+
+        #[test]
+        fn test_run_until_pc() {
+            let mut bus = ArrayBus::new();
+            bus.load(0x0200, &[
+                0xa2, 0x00, // 0x0200: ldx #$00
+                0xe8,       // 0x0202: inx
+                0xe0, 0x05, // 0x0203: cpx #$05
+                0xd0, 0xfb, // 0x0205: bne $0202
+                0xea,       // 0x0207: nop
+            ]);
+            let mut cpu = CPU::new(bus, false);
+            cpu.program_counter = 0x0200;
+
+            let result = cpu.run(RunMode::UntilPc(0x0207)).unwrap();
+            assert_eq!(result.stop_reason, StopReason::PcReached);
+            assert_eq!(result.instructions, 1 + 5 * 3);
+            assert_eq!(cpu.register_x, 5);
+
+            // Already at the address.
+            assert_eq!(cpu.run(RunMode::UntilPc(0x0207)).unwrap().instructions, 0);
+        }
+
+        #[test]
+        fn test_zero_page_wrap() {
+            let mut cpu = CPU::new(SparseMemory::new(), false);
+            cpu.register_x = 0x20;
+            cpu.register_y = 0x20;
+
+            cpu.memory.load(0x8000, &[0xf0, 0xf0]);
+            cpu.program_counter = 0x8000;
+            assert_eq!(cpu.get_target_address(AddressingMode::ZeroPageX), 0x0010);
+            assert_eq!(cpu.get_target_address(AddressingMode::ZeroPageY), 0x0010);
+        }
+
+        #[test]
+        fn test_indirect_zero_page_wrap() {
+            let mut cpu = CPU::new(SparseMemory::new(), false);
+            cpu.memory.load(0x00ff, &[0x34]);
+            cpu.memory.load(0x0000, &[0x12]);
+            cpu.memory.load(0x0010, &[0x78, 0x56]);
+
+            // The indexed pointer wraps, ($f0,x) with x = 0x20 reads the pointer at $10.
+            cpu.register_x = 0x20;
+            cpu.memory.load(0x8000, &[0xf0]);
+            cpu.program_counter = 0x8000;
+            assert_eq!(cpu.get_target_address(AddressingMode::IndexedIndirectX), 0x5678);
+
+            // A pointer at $ff has its high byte at $00, for both modes.
+            cpu.register_x = 0x0f;
+            cpu.program_counter = 0x8000;
+            assert_eq!(cpu.get_target_address(AddressingMode::IndexedIndirectX), 0x1234);
+
+            cpu.register_y = 0x01;
+            cpu.memory.load(0x8000, &[0xff]);
+            cpu.program_counter = 0x8000;
+            assert_eq!(cpu.get_target_address(AddressingMode::IndirectIndexedY), 0x1235);
+        }
+
+        #[test]
+        fn test_jmp_indirect_page_wrap() {
+            let mut cpu = CPU::new(SparseMemory::new(), false);
+            cpu.memory.load(0x8000, &[0x6c, 0xff, 0x02]); // jmp ($02ff)
+            cpu.memory.load(0x02ff, &[0x34, 0x12]);
+            cpu.memory.load(0x0200, &[0x56]);
+            cpu.program_counter = 0x8000;
+
+            cpu.step().unwrap();
+            assert_eq!(cpu.program_counter, 0x5634);
+        }
+
+        #[test]
+        fn test_trace_line() {
+            let mut cpu = CPU::new(SparseMemory::new(), false);
+            cpu.memory.load(RESET_VECTOR, &[0x00, 0xc0]);
+            cpu.memory.load(0xc000, &[0x4c, 0xf5, 0xc5, 0xea]); // jmp $c5f5
+            cpu.power_on();
+
+            // The first line of the nestest log.
+            assert_eq!(
+                cpu.trace_line(),
+                "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7",
+            );
+        }
+
+        // Writes to a buffer the test can still read after handing it to the cpu.
+        #[derive(Clone, Default)]
+        struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+        impl std::io::Write for SharedBuffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+        }
+
+        #[test]
+        fn test_trace() {
+            let mut cpu = CPU::new(SparseMemory::new(), false);
+            cpu.memory.load(0x8000, &[0xa2, 0x05, 0xe8]); // ldx #5, inx
+            cpu.program_counter = 0x8000;
+            let buffer = SharedBuffer::default();
+            cpu.set_trace(Some(Box::new(buffer.clone())));
+
+            cpu.step().unwrap();
+            cpu.step().unwrap();
+            cpu.set_trace(None);
+            cpu.step().unwrap();
+
+            let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+            assert_eq!(trace.lines().collect::<Vec<_>>(), vec![
+                "8000  A2 05     LDX #$05                        A:00 X:00 Y:00 P:20 SP:FF CYC:0",
+                "8002  E8        INX                             A:00 X:05 Y:00 P:20 SP:FF CYC:2",
+            ]);
+        }
+
+        // The trace shows the instruction without reading it on the bus: no extra accesses, and
+        // running from a register (here PPUSTATUS) doesn't clear it.
+        #[test]
+        fn test_trace_has_no_side_effects() {
+            fn accesses(trace: bool) -> Vec<BusAccess> {
+                let program = [0xad, 0x02, 0x20, 0x85, 0x10, 0x4c, 0x02, 0x20]; // lda $2002, sta $10, jmp $2002
+                let raw = TestRomBuilder::new().prg_data(&program).build();
+                let mut bus = RomBus::new();
+                bus.set_rom(rom_from_bytes(&raw, None).unwrap());
+                bus.set_ppu(Box::new(StubPpu::new()));
+                bus.set_recorder(Some(BusRecorder::new(1000)));
+                let mut cpu = CPU::new(bus, false);
+                cpu.program_counter = 0x8000;
+                if trace { cpu.set_trace(Some(Box::new(std::io::sink()))); }
+                for _ in 0..100 { cpu.step().unwrap(); }
+                cpu.memory.recorder().unwrap().accesses().copied().collect()
+            }
+            assert_eq!(accesses(true), accesses(false));
+        }
+
+        #[test]
+        fn test_stall() {
+            let mut cpu = CPU::new(SparseMemory::new(), false);
+            cpu.memory.load(0x8000, &[0xea, 0xea]); // nop, nop
+            cpu.program_counter = 0x8000;
+
+            cpu.stall(513);
+            assert_eq!(cpu.step(), Ok(513 + 2));
+            assert_eq!(cpu.step(), Ok(2));
+            assert_eq!(cpu.cycles, 517);
+        }
+
+        #[test]
+        fn test_bus_stall() {
+            let mut cpu = CPU::new(TestBus::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.memory.set_vector_read_target(0x8000, vec![0xea, 0xea]); // nop, nop
+
+            // A DMC fetch during the first nop.
+            cpu.memory.stall = 4;
+            assert_eq!(cpu.step(), Ok(2 + 4));
+            assert_eq!(cpu.step(), Ok(2));
+        }
+
+        #[test]
+        fn test_bus_clock() {
+            let mut cpu = CPU::new(TestBus::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.memory.set_vector_read_target(0x8000, vec![0xad, 0x02, 0x20, 0xea]); // lda $2002, nop
+            cpu.memory.set_read_target(0x2002, 0x80);
+
+            // Every access happens after the cycles before it.
+            assert_eq!(cpu.step(), Ok(4));
+            assert_eq!(cpu.memory.access_clock, [1, 2, 3, 4]);
+            assert_eq!(cpu.memory.clock, 4);
+
+            cpu.stall(10);
+            assert_eq!(cpu.step(), Ok(10 + 2));
+            assert_eq!(cpu.memory.access_clock[4], 4 + 10 + 1);
+            assert_eq!(cpu.memory.clock, 4 + 10 + 2);
+        }
+
+        // Stalls add up past what Mem::tick takes at once, none of the cycles get lost.
+        #[test]
+        fn test_long_stall() {
+            let mut cpu = CPU::new(TestBus::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.memory.set_read_target(0x8000, 0xea); // nop
+
+            cpu.stall(0xffff);
+            cpu.stall(0xffff);
+            assert_eq!(cpu.step(), Ok(2 * 0xffff + 2));
+            assert_eq!(cpu.cycles, 2 * 0xffff + 2);
+            assert_eq!(cpu.memory.clock, cpu.cycles);
+        }
+
+        #[test]
+        fn test_clock_speed() {
+            let mut cpu = CPU::new(SparseMemory::new(), false);
+            cpu.memory.load(0x8000, &[0x4c, 0x00, 0x80]); // jmp $8000
+            cpu.program_counter = 0x8000;
+
+            // 60000 cycles at 1 MHz take 60 ms, the last frame is synced after at least 59562.
+            cpu.set_clock_speed(Some(1_000_000));
+            let start = time::Instant::now();
+            cpu.run(RunMode::CycleBudget(60_000)).unwrap();
+            assert!(start.elapsed() >= time::Duration::from_millis(59));
+        }
+
+        #[test]
+        fn test_run_for_cycles() {
+            let mut cpu = CPU::new(SparseMemory::new(), false);
+            cpu.memory.load(0x8000, &[0xe8, 0x4c, 0x00, 0x80]); // inx, jmp $8000
+            cpu.program_counter = 0x8000;
+
+            // 5 cycles per loop, the second jmp goes 2 cycles over.
+            assert_eq!(cpu.run_for_cycles(8), Ok(RunResult {
+                instructions: 4, cycles: 10, stop_reason: StopReason::LimitReached,
+            }));
+            assert_eq!(cpu.run_for_cycles(0).unwrap().instructions, 0);
+        }
+
+        #[test]
+        fn test_run_until() {
+            let mut cpu = CPU::new(SparseMemory::new(), false);
+            cpu.memory.load(0x8000, &[0xe8, 0x4c, 0x00, 0x80]); // inx, jmp $8000
+            cpu.program_counter = 0x8000;
+
+            let result = cpu.run_until(|cpu| cpu.register_x == 3).unwrap();
+            assert_eq!(result.stop_reason, StopReason::ConditionMet);
+            assert_eq!(result.instructions, 5);
+            assert_eq!(cpu.program_counter, 0x8001);
+
+            // Illegal opcodes still stop the run.
+            cpu.memory.load(0x8001, &[0x0b]);
+            let result = cpu.run_until(|_| false).unwrap();
+            assert_eq!(result.stop_reason, StopReason::IllegalOpcode(0x0b));
+        }
+
+        #[test]
+        fn test_breakpoints() {
+            let mut cpu = CPU::new(SparseMemory::new(), false);
+            cpu.memory.load(0x8000, &[0xe8, 0xe8, 0x4c, 0x00, 0x80]); // inx, inx, jmp $8000
+            cpu.program_counter = 0x8000;
+            cpu.add_breakpoint(0x8001);
+
+            let result = cpu.run(RunMode::Unlimited).unwrap();
+            assert_eq!(result.stop_reason, StopReason::Breakpoint(0x8001));
+            assert_eq!(cpu.register_x, 1);
+
+            // Continuing runs past it, until it is hit again.
+            let result = cpu.run(RunMode::Unlimited).unwrap();
+            assert_eq!(result.stop_reason, StopReason::Breakpoint(0x8001));
+            assert_eq!(result.instructions, 3);
+            assert_eq!(cpu.register_x, 3);
+
+            assert!(cpu.remove_breakpoint(0x8001));
+            assert!(!cpu.remove_breakpoint(0x8001));
+            let result = cpu.run(RunMode::InstructionLimit(6)).unwrap();
+            assert_eq!(result.stop_reason, StopReason::LimitReached);
+        }
+
+        #[test]
+        fn test_watchpoints() {
+            let mut cpu = CPU::new(ArrayBus::new(), false);
+            // lda $10, sta $0300, inc $0301, jmp $0200
+            cpu.memory.load(0x0200, &[0xa5, 0x10, 0x8d, 0x00, 0x03, 0xee, 0x01, 0x03, 0x4c, 0x00, 0x02]);
+            cpu.memory.load(0x0010, &[0x42]);
+            cpu.program_counter = 0x0200;
+            cpu.add_watchpoint(0x0300..=0x03ff, WatchKind::Write);
+            cpu.add_watchpoint(0x0010..=0x0010, WatchKind::Read);
+
+            let result = cpu.run(RunMode::Unlimited).unwrap();
+            assert_eq!(result.stop_reason, StopReason::Watchpoint(WatchHit { addr: 0x0010, value: 0x42, write: false, pc: 0x0200 }));
+            assert_eq!(cpu.program_counter, 0x0202); // after the instruction
+
+            let result = cpu.run(RunMode::Unlimited).unwrap();
+            assert_eq!(result.stop_reason, StopReason::Watchpoint(WatchHit { addr: 0x0300, value: 0x42, write: true, pc: 0x0202 }));
+
+            // The read of inc doesn't count, the first write (of the old value) does.
+            let result = cpu.run(RunMode::Unlimited).unwrap();
+            assert_eq!(result.stop_reason, StopReason::Watchpoint(WatchHit { addr: 0x0301, value: 0x00, write: true, pc: 0x0205 }));
+
+            assert!(cpu.remove_watchpoint(0x0300..=0x03ff, WatchKind::Write));
+            assert!(!cpu.remove_watchpoint(0x0300..=0x03ff, WatchKind::Write));
+            assert_eq!(cpu.step(), Ok(3)); // jmp
+            assert_eq!(cpu.watch_hit(), None);
+        }
+
+        #[test]
+        fn test_run_illegal_opcode() {
+            let mut bus = ArrayBus::new();
+            bus.load(0x0200, &[0xe8, 0x0b]); // inx, an opcode missing from the table
+            let mut cpu = CPU::new(bus, false);
+            cpu.program_counter = 0x0200;
+
+            // inx, and the fetch of the opcode that stopped the run
+            assert_eq!(cpu.run(RunMode::Unlimited), Ok(RunResult {
+                instructions: 1, cycles: 2 + 1, stop_reason: StopReason::IllegalOpcode(0x0b),
+            }));
+            assert_eq!(cpu.program_counter, 0x0201); // on the opcode
+        }
+
+        #[test]
+        fn test_kil() {
+            let mut bus = ArrayBus::new();
+            bus.load(0x0200, &[0xe8, 0x02]); // inx, kil
+            bus.load(RESET_VECTOR, &[0x00, 0x02]);
+            let mut cpu = CPU::new(bus, false);
+            cpu.program_counter = 0x0200;
+
+            // inx, and the fetch of the opcode that stopped the run
+            assert_eq!(cpu.run(RunMode::Unlimited), Ok(RunResult {
+                instructions: 1, cycles: 2 + 1, stop_reason: StopReason::Halted(0x02),
+            }));
+            assert!(cpu.is_halted());
+            assert_eq!(cpu.program_counter, 0x0201); // on the opcode
+
+            // Stays halted until a reset.
+            assert_eq!(cpu.step(), Err(CpuError::Halted(0x02)));
+            assert_eq!(cpu.program_counter, 0x0201);
+            cpu.reset();
+            assert!(!cpu.is_halted());
+            assert_eq!(cpu.step(), Ok(2));
+        }
+
+        #[test]
+        fn test_cpu_error_display() {
+            assert_eq!(CpuError::SegmentationFault(0x8000).to_string(), "invalid write to 0x8000");
+            assert_eq!(CpuError::IllegalOpcode(0x0b).to_string(), "illegal opcode 0x0b");
+            assert_eq!(CpuError::Halted(0x02).to_string(), "halted by opcode 0x02");
+        }
+
+        #[test]
+        fn test_reset() {
+            let mut bus = ArrayBus::new();
+            bus.load(RESET_VECTOR, &[0x34, 0x12]);
+            let mut cpu = CPU::new(bus, false);
+            cpu.register_a = 0x42;
+            cpu.stack_pointer = 0xf0;
+
+            cpu.reset();
+            assert_eq!(cpu.program_counter, 0x1234);
+            assert_eq!(cpu.stack_pointer, 0xed);
+            assert!(cpu.get_flag(Flag::I));
+            assert_eq!(cpu.register_a, 0x42); // kept
+            assert_eq!(cpu.cycles, 7);
+        }
+
+        #[test]
+        fn test_start_at() {
+            let mut bus = ArrayBus::new();
+            bus.load(RESET_VECTOR, &[0x00, 0x80]);
+            bus.load(0xc000, &[0xe8, 0x02]); // inx, kil
+            let mut cpu = CPU::new(bus, false);
+
+            let result = cpu.start_at(0xc000).unwrap();
+            assert_eq!(result.stop_reason, StopReason::Halted(0x02));
+            assert_eq!((cpu.register_x, cpu.stack_pointer), (1, 0xfd));
+        }
+
+        #[test]
+        fn test_power_on() {
+            let mut bus = ArrayBus::new();
+            bus.load(RESET_VECTOR, &[0x00, 0xc0]);
+            let mut cpu = CPU::new(bus, false);
+            cpu.register_a = 0x42;
+            cpu.register_x = 0x01;
+            cpu.status = 0xff;
+
+            cpu.power_on();
+            assert_eq!((cpu.register_a, cpu.register_x, cpu.register_y), (0, 0, 0));
+            assert_eq!(cpu.stack_pointer, 0xfd);
+            assert_eq!(cpu.status, 0x24);
+            assert_eq!(cpu.program_counter, 0xc000);
+        }
+
+        // Every opcode in the table is dispatched, and those that don't change the control flow
+        // consume as many bytes as the table says.
+        #[test]
+        fn test_dispatch_matches_table() {
+            for opcode in 0..=255u8 {
+                let info = TABLE[opcode as usize];
+                let mut bus = ArrayBus::new();
+                bus.load(0x0200, &[opcode, 0x10, 0x02]);
+                let mut cpu = CPU::new(bus, false);
+                cpu.program_counter = 0x0200;
+
+                let result = cpu.step();
+                match info.mnemonic {
+                    Mnemonic::Kil => { assert_eq!(result, Err(CpuError::Halted(opcode))); continue; },
+                    Mnemonic::Unknown => { assert_eq!(result, Err(CpuError::IllegalOpcode(opcode))); continue; },
+                    _ => (),
+                }
+                assert!(result.is_ok(), "{:#04x} {}", opcode, info.mnemonic);
+                if info.mode != AddressingMode::Relative && ![Mnemonic::Brk, Mnemonic::Jmp, Mnemonic::Jsr, Mnemonic::Rti, Mnemonic::Rts].contains(&info.mnemonic) {
+                    assert_eq!(cpu.program_counter, 0x0200 + info.bytes as u16, "{:#04x} {}", opcode, info.mnemonic);
+                }
+            }
+        }
+
+        // The standard nestest check: in automation mode (starting at 0xc000) the official opcode
+        // tests end at 0xc66e, with the error codes at 0x0002 and 0x0003 both zero.
+        #[test]
+        #[ignore = "needs cartridges/nestest.nes"]
+        fn test_nestest() {
+            let mut bus = RomBus::new();
+            bus.set_rom(crate::rom::rom_reader("./cartridges/nestest.nes", None).unwrap());
+            let mut cpu = CPU::<RomBus>::new(bus, false);
+            cpu.program_counter = 0xc000;
+
+            let result = cpu.run(RunMode::UntilPc(0xc66e)).unwrap();
+            assert_eq!(result.stop_reason, StopReason::PcReached);
+            assert_eq!((cpu.mem_read(0x0002), cpu.mem_read(0x0003)), (0x00, 0x00));
+        }
+
+        #[test]
+        fn test_rom_bus_irq() {
+            let mut prg: Vec<u8> = vec![0xea; 0x4000]; // nop
+            prg[0x3ffe] = 0x10; // irq vector: 0x8010
+            prg[0x3fff] = 0x80;
+            prg[0x0000] = 0x58; // 0x8000: cli
+            let raw = TestRomBuilder::new().prg_data(&prg).build();
+            let mut bus = RomBus::new();
+            bus.set_rom(rom_from_bytes(&raw, None).unwrap());
+            bus.assert_irq(IrqSource::Mapper);
+            let mut cpu = CPU::<RomBus>::new(bus, false);
+            cpu.program_counter = 0x8000;
+            cpu.set_flag(Flag::I, true);
+
+            // Serviced after cli, the handler runs with the I flag set.
+            assert_eq!(cpu.step(), Ok(2 + 7));
+            assert_eq!(cpu.program_counter, 0x8010);
+            assert!(cpu.get_flag(Flag::I));
+            assert_eq!(cpu.step(), Ok(2));
+
+            // Still asserted once the I flag is cleared again.
+            cpu.set_flag(Flag::I, false);
+            assert_eq!(cpu.step(), Ok(2 + 7));
+            cpu.memory.clear_irq(IrqSource::Mapper);
+            cpu.set_flag(Flag::I, false);
+            assert_eq!(cpu.step(), Ok(2));
+        }
+
+        #[test]
+        fn test_interrupt_lines() {
+            let mut prg: Vec<u8> = vec![0xea; 0x4000]; // nop
+            prg[0x3ffa] = 0x20; // nmi vector: 0x8020
+            prg[0x3ffb] = 0x80;
+            let raw = TestRomBuilder::new().prg_data(&prg).build();
+            let mut bus = RomBus::new();
+            bus.set_rom(rom_from_bytes(&raw, None).unwrap());
+            let lines = bus.interrupt_lines();
+            let mut cpu = CPU::<RomBus>::new(bus, false);
+            cpu.program_counter = 0x8000;
+
+            // Signalled from outside, once the cpu owns the bus.
+            assert_eq!(cpu.step(), Ok(2));
+            lines.trigger_nmi();
+            assert_eq!(cpu.step(), Ok(2 + 7));
+            assert_eq!(cpu.program_counter, 0x8020);
+        }
+
+        #[test]
+        fn test_vblank_wait() {
+            let program = [0xad, 0x02, 0x20, 0x10, 0xfb]; // 0x8000: lda $2002, bpl $8000
+            let raw = TestRomBuilder::new().prg_data(&program).build();
+            let mut bus = RomBus::new();
+            bus.set_rom(rom_from_bytes(&raw, None).unwrap());
+            bus.set_ppu(Box::new(StubPpu::new()));
+            let mut cpu = CPU::<RomBus>::new(bus, false);
+            cpu.program_counter = 0x8000;
+
+            while cpu.program_counter != 0x8005 && cpu.cycles < 40_000 {
+                cpu.step().unwrap();
+            }
+            assert_eq!(cpu.program_counter, 0x8005);
+            assert!(cpu.cycles > 29_000); // about a frame
+        }
+
+        #[test]
+        fn test_execute_inline() {
+            let ram: Vec<u8> = (0..0x10000).map(|i| i as u8).collect();
+            let mut bus = ArrayBus::new();
+            bus.set_ram(&ram).unwrap();
+            let mut cpu = CPU::new(bus, false);
+
+            let state = cpu.execute_inline(&[0xa9, 0x42, 0xaa, 0x85, 0x10]).unwrap(); // lda #$42, tax, sta $10
+            assert_eq!(state, CpuState {
+                register_a: 0x42,
+                register_x: 0x42,
+                register_y: 0,
+                stack_pointer: 0xff,
+                status: UNUSED_FLAG,
+                program_counter: 0x0205,
+            });
+            assert_eq!(cpu.state(), state);
+
+            let mut expected = ram.clone();
+            expected[0x10] = 0x42;
+            assert_eq!(cpu.memory.ram_snapshot(), &expected[..]);
+        }
+
+        #[test]
+        fn test_nmi() {
+            let mut cpu = CPU::new(TestBus::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.set_flag(Flag::I, true);
+            cpu.memory.set_read_target(0x8000, 0xea); // nop
+            cpu.memory.set_read_u16_target(0xfffa, 0x9000);
+            cpu.memory.set_write_target(0x01ff, 0x80);
+            cpu.memory.set_write_target(0x01fe, 0x01);
+            cpu.memory.set_write_target(0x01fd, UNUSED_FLAG | Flag::I as u8);
+            cpu.memory.nmi = true;
+
+            assert_eq!(cpu.step(), Ok(2 + 7));
+            assert_eq!(cpu.program_counter, 0x9000);
+            assert_eq!(cpu.stack_pointer, 0xfc);
+            assert!(cpu.get_flag(Flag::I));
+            assert!(!cpu.memory.nmi);
+        }
+
+        // Steps two cores side by side, and checks that they agree after every instruction.
+        fn lockstep(a: &mut impl Cpu, b: &mut impl Cpu, steps: usize) {
+            for _ in 0..steps {
+                assert_eq!(a.step(), b.step());
+                assert_eq!(a.state(), b.state());
+                assert_eq!(a.cycles(), b.cycles());
+            }
+        }
+
+        #[test]
+        fn test_cpu_trait() {
+            let program = [0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x4c, 0x00, 0x80]; // ldx #3, dex, bne -3, jmp $8000
+            let mut sparse = CPU::new(SparseMemory::new(), false);
+            sparse.bus_mut().load(0x8000, &program);
+            sparse.bus_mut().load(RESET_VECTOR, &[0x00, 0x80]);
+            let mut array = CPU::new(ArrayBus::new(), false);
+            array.bus_mut().load(0x8000, &program);
+            array.bus_mut().load(RESET_VECTOR, &[0x00, 0x80]);
+
+            Cpu::reset(&mut sparse);
+            Cpu::reset(&mut array);
+            // Two times around the loop, 8 instructions each.
+            lockstep(&mut sparse, &mut array, 16);
+            assert_eq!(Cpu::state(&sparse).program_counter, 0x8000);
+        }
+
+        #[test]
+        fn test_from_state() {
+            let state = CpuState {
+                register_a: 0x01, register_x: 0x02, register_y: 0x03,
+                stack_pointer: 0xf0, status: UNUSED_FLAG | Flag::C as u8, program_counter: 0x8000,
+            };
+            let mut cpu = CPU::from_state(SparseMemory::new(), state);
+            assert_eq!(cpu.state(), state);
+
+            cpu.set_state(CpuState { status: 0, ..state });
+            assert_eq!(cpu.status, UNUSED_FLAG);
+        }
+
+        #[test]
+        fn test_dummy_accesses() {
+            // inc $10 writes the old value back before the new one.
+            let mut cpu = CPU::new(TestBus::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.memory.set_vector_read_target(0x8000, vec![0xe6, 0x10]);
+            cpu.memory.set_read_target(0x0010, 0x41);
+            cpu.memory.set_write_target(0x0010, 0x42);
+            cpu.step().unwrap();
+            assert_eq!(cpu.memory.accesses[2..], [(false, 0x0010, 0x41), (true, 0x0010, 0x41), (true, 0x0010, 0x42)]);
+
+            // lda $02f0,x reads $0210 before the carry gets to the high byte.
+            let mut cpu = CPU::new(TestBus::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.register_x = 0x20;
+            cpu.memory.set_vector_read_target(0x8000, vec![0xbd, 0xf0, 0x02]);
+            cpu.memory.set_read_target(0x0210, 0x00);
+            cpu.memory.set_read_target(0x0310, 0x05);
+            cpu.step().unwrap();
+            assert_eq!(cpu.memory.accesses[3..], [(false, 0x0210, 0x00), (false, 0x0310, 0x05)]);
+
+            // Without a page cross, only stores read the address first.
+            let mut cpu = CPU::new(TestBus::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.register_x = 0x01;
+            cpu.memory.set_vector_read_target(0x8000, vec![0xbd, 0x00, 0x02, 0x9d, 0x00, 0x02]); // lda $0200,x, sta $0200,x
+            cpu.memory.set_read_target(0x0201, 0x07);
+            cpu.memory.set_write_target(0x0201, 0x07);
+            cpu.step().unwrap();
+            cpu.step().unwrap();
+            assert_eq!(cpu.memory.accesses[3], (false, 0x0201, 0x07));
+            assert_eq!(cpu.memory.accesses[7..], [(false, 0x0201, 0x07), (true, 0x0201, 0x07)]);
+        }
+
+        #[test]
+        fn test_brk() {
+            let mut cpu = CPU::new(TestBus::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.status = UNUSED_FLAG | Flag::C as u8;
+            cpu.memory.set_read_target(0x8000, 0x00); // brk
+            cpu.memory.set_read_u16_target(0xfffe, 0x9000);
+            cpu.memory.set_write_target(0x01ff, 0x80);
+            cpu.memory.set_write_target(0x01fe, 0x02);
+            cpu.memory.set_write_target(0x01fd, UNUSED_FLAG | Flag::B as u8 | Flag::C as u8);
+
+            assert_eq!(cpu.step(), Ok(7));
+            assert_eq!(cpu.program_counter, 0x9000);
+            assert_eq!(cpu.stack_pointer, 0xfc);
+            assert!(cpu.get_flag(Flag::I));
+            assert!(!cpu.get_flag(Flag::B)); // only set on the stack
+        }
+
+        #[test]
+        fn test_php_plp_status_bits() {
+            let mut cpu = CPU::new(ArrayBus::new(), false);
+            cpu.stack_pointer = 0xff;
+            cpu.status = UNUSED_FLAG | Flag::C as u8;
+            cpu.execute_inline(&[0x08]).unwrap(); // php
+            assert_eq!(cpu.mem_read(0x01ff), UNUSED_FLAG | Flag::B as u8 | Flag::C as u8);
+            assert_eq!(cpu.status, UNUSED_FLAG | Flag::C as u8);
+
+            // plp ignores bits 4 and 5
+            cpu.mem_write(0x01ff, 0b1101_1111);
+            cpu.execute_inline(&[0x28]).unwrap(); // plp
+            assert_eq!(cpu.status, 0b1110_1111);
+            cpu.mem_write(0x01ff, 0x00);
+            cpu.stack_pointer = 0xfe;
+            cpu.execute_inline(&[0x28]).unwrap();
+            assert_eq!(cpu.status, UNUSED_FLAG);
+        }
+
+        #[test]
+        fn test_rti_status_bits() {
+            let mut cpu = CPU::new(ArrayBus::new(), false);
+            cpu.stack_pointer = 0xfc;
+            cpu.bulk_write(0x01fd, &[0b0001_0011, 0x34, 0x12]); // status, pc
+            cpu.execute_inline(&[0x40]).unwrap(); // rti
+            assert_eq!(cpu.status, UNUSED_FLAG | Flag::Z as u8 | Flag::C as u8);
+            assert_eq!(cpu.program_counter, 0x1234);
+        }
+
+        // As in the nmi_and_brk test of cpu_interrupts_v2.
+        #[test]
+        fn test_nmi_hijacks_brk() {
+            let mut cpu = CPU::new(TestBus::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.memory.set_read_target(0x8000, 0x00); // brk
+            cpu.memory.set_read_u16_target(0xfffa, 0x9000);
+            cpu.memory.set_read_u16_target(0xfffe, 0xa000);
+            cpu.memory.set_write_target(0x01ff, 0x80);
+            cpu.memory.set_write_target(0x01fe, 0x02);
+            cpu.memory.set_write_target(0x01fd, UNUSED_FLAG | Flag::B as u8);
+            cpu.memory.nmi = true;
+
+            assert_eq!(cpu.step(), Ok(7)); // no second interrupt
+            assert_eq!(cpu.program_counter, 0x9000);
+            assert_eq!(cpu.stack_pointer, 0xfc);
+            assert!(!cpu.memory.nmi);
+        }
+
+        #[test]
+        fn test_irq() {
+            let mut cpu = CPU::new(TestBus::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.set_flag(Flag::I, true);
+            cpu.memory.set_vector_read_target(0x8000, vec![0xea, 0xea]); // nop, nop
+            cpu.memory.set_read_u16_target(0xfffe, 0xa000);
+            cpu.memory.set_write_target(0x01ff, 0x80);
+            cpu.memory.set_write_target(0x01fe, 0x02);
+            cpu.memory.set_write_target(0x01fd, UNUSED_FLAG);
+            cpu.memory.irq = true;
+
+            // Masked by the I flag.
+            assert_eq!(cpu.step(), Ok(2));
+            assert_eq!(cpu.program_counter, 0x8001);
+
+            cpu.set_flag(Flag::I, false);
+            assert_eq!(cpu.step(), Ok(2 + 7));
+            assert_eq!(cpu.program_counter, 0xa000);
+            assert!(cpu.get_flag(Flag::I));
+        }
+
+        #[test]
+        fn test_probe_writes() {
+            let mut cpu = CPU::new(TestBus::new(), false);
+            cpu.register_a = 0x42;
+            cpu.memory.set_vector_read_target(0x0000, vec![0x85, 0x10]); // sta $10
+            cpu.memory.set_write_target(0x0010, 0x42);
+            cpu.memory.set_write_target(0x0011, 0x42);
+
+            cpu.step().unwrap();
+            assert_eq!(cpu.memory.probe(0x0010), Some(0x42));
+            assert_eq!(cpu.memory.probe(0x0011), None);
+        }
+
+        #[test]
+        fn test_step() {
+            let mut cpu = CPU::new(SparseMemory::new(), false);
+            cpu.program_counter = 0x8000;
+            cpu.memory.load(0x8000, &[0xe8, 0xc8, 0x4c, 0x00, 0x80]); // inx, iny, jmp $8000
+
+            for i in 1..=3 {
+                assert_eq!(cpu.step(), Ok(2));
+                assert_eq!(cpu.register_x, i);
+                assert_eq!(cpu.step(), Ok(2));
+                assert_eq!(cpu.register_y, i);
+                assert_eq!(cpu.step(), Ok(3));
+                assert_eq!(cpu.program_counter, 0x8000);
+            }
+            assert_eq!(cpu.cycles, 3 * 7);
+        }
+
+        // run is a loop around step, both end up in the same state.
+        #[test]
+        fn test_run_matches_step() {
+            let program = [0xa2, 0x03, 0xca, 0xd0, 0xfd, 0xe8]; // ldx #3, dex, bne -3, inx
+            let mut stepped = CPU::new(SparseMemory::new(), false);
+            let mut ran = CPU::new(SparseMemory::new(), false);
+            for cpu in [&mut stepped, &mut ran] {
+                cpu.memory.load(0x8000, &program);
+                cpu.program_counter = 0x8000;
+            }
+
+            let mut cycles: u64 = 0;
+            for _ in 0..8 {
+                cycles += stepped.step().unwrap();
+            }
+            let result = ran.run(RunMode::InstructionLimit(8)).unwrap();
+
+            assert_eq!(result.cycles, cycles);
+            assert_eq!(ran.state(), stepped.state());
+            assert_eq!(ran.register_x, 1);
+        }
+
+        #[test]
+        fn test_cycles() {
+            let mut cpu = CPU::new(ArrayBus::new(), false);
+            cpu.execute_inline(&[0xa9, 0x42, 0xaa, 0x8d, 0x00, 0x03]).unwrap(); // lda #$42, tax, sta $0300
+            assert_eq!(cpu.cycles, 2 + 2 + 4);
+
+            cpu.execute_inline(&[0x20, 0x04, 0x02, 0x00, 0x60]).unwrap(); // jsr $0204, brk, rts
+            assert_eq!(cpu.cycles, 8 + 6 + 6);
+        }
+
+        #[test]
+        fn test_page_cross_cycles() {
+            // Runs the instruction at 'addr' with register x and y set to 'index'.
+            fn cycles(program: &[u8], addr: u16, index: u8) -> u64 {
+                let mut bus = ArrayBus::new();
+                bus.load(addr, program);
+                bus.load(0x0010, &[0xf0, 0x02]); // pointer for (d),y
+                let mut cpu = CPU::new(bus, false);
+                cpu.program_counter = addr;
+                cpu.register_x = index;
+                cpu.register_y = index;
+                cpu.step().unwrap()
+            }
+
+            assert_eq!(cycles(&[0xbd, 0xf0, 0x02], 0x0200, 0x0f), 4); // lda $02f0,x
+            assert_eq!(cycles(&[0xbd, 0xf0, 0x02], 0x0200, 0x10), 5);
+            assert_eq!(cycles(&[0xb9, 0xf0, 0x02], 0x0200, 0xff), 5); // lda $02f0,y
+            assert_eq!(cycles(&[0xb1, 0x10], 0x0200, 0x0f), 5); // lda ($10),y
+            assert_eq!(cycles(&[0xb1, 0x10], 0x0200, 0x10), 6);
+            // Stores and read-modify-write instructions always take the longer path.
+            assert_eq!(cycles(&[0x9d, 0xf0, 0x02], 0x0200, 0x00), 5); // sta $02f0,x
+            assert_eq!(cycles(&[0x9d, 0xf0, 0x02], 0x0200, 0x10), 5);
+            assert_eq!(cycles(&[0x99, 0xf0, 0x02], 0x0200, 0x00), 5); // sta $02f0,y
+            assert_eq!(cycles(&[0x99, 0xf0, 0x02], 0x0200, 0x10), 5);
+            assert_eq!(cycles(&[0x91, 0x10], 0x0200, 0x00), 6); // sta ($10),y
+            assert_eq!(cycles(&[0x91, 0x10], 0x0200, 0x10), 6);
+            assert_eq!(cycles(&[0xfe, 0xf0, 0x02], 0x0200, 0x10), 7); // inc $02f0,x
+
+            // Branches: not taken, taken, and taken to another page.
+            assert_eq!(cycles(&[0xf0, 0x02], 0x0200, 0x00), 2); // beq, Z is clear
+            assert_eq!(cycles(&[0xd0, 0x02], 0x0200, 0x00), 3); // bne
+            assert_eq!(cycles(&[0xd0, 0x20], 0x02f0, 0x00), 4);
+            assert_eq!(cycles(&[0xd0, 0xfc], 0x0200, 0x00), 4);
+        }
+
+        #[test]
+        fn test_state_diff() {
+            let mut cpu = CPU::new(TestBus::new(), false);
+            let expected = cpu.state();
+            assert!(expected.diff(&cpu.state()).is_empty());
+
+            cpu.register_a = 0x40;
+            cpu.program_counter = 0x8000;
+            cpu.set_flag(Flag::C, true);
+            let diff = expected.diff(&cpu.state());
+
+            assert_eq!(diff, CpuStateDiff {
+                changed_registers: vec![(RegisterName::A, 0x00, 0x40), (RegisterName::ProgramCounter, 0x0000, 0x8000)],
+                changed_flags: vec![(Flag::C, false, true)],
+            });
+            assert_eq!(
+                diff.to_string(),
+                "register_a: expected 0x00, got 0x40\nprogram_counter: expected 0x0000, got 0x8000\nflag C: expected false, got true\n",
+            );
+        }
+
+        #[test]
+        fn test_execute_inline_fault() {
+            let mut bus = RomBus::new();
+            bus.set_rom(rom_from_bytes(&TestRomBuilder::new().build(), None).unwrap());
+            bus.set_ram([0x55; 0x800]);
+            let mut cpu = CPU::new(bus, false);
+
+            assert_eq!(cpu.execute_inline(&[0x8d, 0x00, 0x80]), Err(CpuError::SegmentationFault(0x8000))); // sta $8000
+            assert_eq!(cpu.memory.ram_snapshot(), [0x55; 0x800]);
+        }
+
+        #[test]
+        fn test_execute_inline_limits() {
+            let mut bus = RomBus::new();
+            bus.set_ram([0x55; 0x800]);
+            bus.set_recorder(Some(BusRecorder::new(16)));
+            let mut cpu = CPU::new(bus, false);
+            cpu.set_flag(Flag::I, true); // no frame interrupts
+
+            // jmp * never reaches the brk.
+            assert_eq!(cpu.execute_inline(&[0x4c, 0x00, 0x02]), Err(CpuError::StepLimit(CALL_STEP_LIMIT)));
+            assert_eq!(cpu.memory.ram_snapshot(), [0x55; 0x800]);
+
+            // Only the accesses of the program (and copying it) are on the bus.
+            cpu.memory.set_recorder(Some(BusRecorder::new(16)));
+            cpu.execute_inline(&[0xea]).unwrap(); // nop
+            let reads: Vec<u16> = cpu.memory.recorder().unwrap().accesses()
+                .filter(|access| !access.write)
+                .map(|access| access.addr)
+                .collect();
+            assert_eq!(reads, [0x0200]); // the opcode fetch of the nop
+
+            // Programs longer than the address space wrap around instead of overflowing: the brk
+            // lands on the first byte.
+            let mut cpu = CPU::new(ArrayBus::new(), false);
+            assert_eq!(cpu.execute_inline(&vec![0xea; 0x10000]).unwrap().program_counter, 0x0200);
+        }
+
+        #[test]
+        fn test_call_subroutine() {
+            let program: [u8; 14] = [
+                0xa9, 0x20,         // 0x8000: lda #$20
+                0x18,               // 0x8002: clc
+                0x69, 0x22,         // 0x8003: adc #$22
+                0x60,               // 0x8005: rts
+                0xe8,               // 0x8006: inx
+                0x20, 0x00, 0x80,   // 0x8007: jsr $8000
+                0xe8,               // 0x800a: inx
+                0x60,               // 0x800b: rts
+                0x00, 0x00,
+            ];
+            let raw = TestRomBuilder::new().prg_data(&program).build();
+            let mut bus = RomBus::new();
+            bus.set_rom(rom_from_bytes(&raw, None).unwrap());
+            let mut cpu = CPU::<RomBus>::new(bus, false);
+
+            cpu.call_subroutine(0x8000).unwrap();
+            assert_eq!(cpu.register_a, 0x42);
+            assert_eq!(cpu.stack_pointer, 0xff);
+
+            cpu.register_a = 0;
+            cpu.call_subroutine(0x8006).unwrap();
+            assert_eq!(cpu.register_a, 0x42);
+            assert_eq!(cpu.register_x, 2);
+            assert_eq!(cpu.stack_pointer, 0xff);
+        }
+
+        #[test]
+        fn test_call_subroutine_never_returns() {
+            let raw = TestRomBuilder::new().prg_data(&[0x4c, 0x00, 0x80]).build(); // 0x8000: jmp $8000
+            let mut bus = RomBus::new();
+            bus.set_rom(rom_from_bytes(&raw, None).unwrap());
+            let mut cpu = CPU::<RomBus>::new(bus, false);
+            cpu.set_flag(Flag::I, true); // no frame interrupts
+
+            assert_eq!(cpu.call_subroutine(0x8000), Err(CpuError::StepLimit(CALL_STEP_LIMIT)));
+            assert_eq!(cpu.program_counter, 0x8000);
+            assert_eq!(CpuError::StepLimit(5).to_string(), "gave up after 5 instructions");
+        }
+
+        // rti . return from interrupt
+
+        // rts - return from subroutine
 
         fn rol(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
+            let accumulator = matches!(mode, AddressingMode::Accumulator);
             let carry = next_bit(rng);
             cpu.set_flag(Flag::C, carry != 0);
             let val: u8 = next_u8(rng);
             let addr: u16 = addressing_mode_tester(cpu, val, &mode);
 
-            let target_val = (val << 1) + carry;
-            cpu.memory.set_write_target(addr, target_val);
+            let target_val = (val << 1) | carry;
+            if !accumulator { cpu.memory.set_write_target(addr, target_val); }
 
             cpu.rol(mode);
+
+            if accumulator { assert_eq!(cpu.register_a, target_val); }
             
             assert_eq!(cpu.get_flag(Flag::C), val & 0b1000_0000 != 0);
             assert_eq!(cpu.get_flag(Flag::Z), target_val == 0);
             assert_eq!(cpu.get_flag(Flag::N), target_val & 0b1000_0000 != 0);
         }
 
-        run_test![rol, ZeroPage, ZeroPageX, Absolute, AbsoluteX];
+        run_test![rol, Accumulator, ZeroPage, ZeroPageX, Absolute, AbsoluteX];
         
         fn ror(cpu: &mut CPU<TestBus>, mode: AddressingMode, rng: &mut ThreadRng) {
+            let accumulator = matches!(mode, AddressingMode::Accumulator);
             let carry = next_bit(rng);
             cpu.set_flag(Flag::C, carry != 0);
             let val: u8 = next_u8(rng);
             let addr: u16 = addressing_mode_tester(cpu, val, &mode);
 
             let target_val = (val >> 1) | (carry << 7);
-            cpu.memory.set_write_target(addr, target_val);
+            if !accumulator { cpu.memory.set_write_target(addr, target_val); }
 
             cpu.ror(mode);
+
+            if accumulator { assert_eq!(cpu.register_a, target_val); }
             
             assert_eq!(cpu.get_flag(Flag::C), val & 0b0000_0001 != 0);
             assert_eq!(cpu.get_flag(Flag::Z), target_val == 0);
             assert_eq!(cpu.get_flag(Flag::N), target_val & 0b1000_0000 != 0);
         }
 
-        run_test![ror, ZeroPage, ZeroPageX, Absolute, AbsoluteX];
+        run_test![ror, Accumulator, ZeroPage, ZeroPageX, Absolute, AbsoluteX];
 
         // Given a cpu and an addressing mode, this method plants a random number in a pre-defined location according to the indexing procedure, and generates code to to access the hidden information.
         fn addressing_mode_tester(cpu: &mut CPU<TestBus>, secret_value: u8, mode: &AddressingMode) -> u16 {
             let lsb: u8 = 10;
             let msb: u8 = 13;
-            let addr: u16 = (msb as u16) << 8 + (lsb as u16);
+            let addr: u16 = ((msb as u16) << 8) | (lsb as u16);
             let reg: u8 = 53;
             let indirect: u16 = 745;
 
             cpu.program_counter = 0;
 
             match mode {
+                AddressingMode::Implied | AddressingMode::Relative => panic!("{:?} mode has no operand to test.", mode),
+                AddressingMode::Accumulator => {
+                    cpu.register_a = secret_value;
+                    0
+                }
                 AddressingMode::Immediate => { 
                     cpu.memory.set_read_target(cpu.program_counter, secret_value);
                     cpu.program_counter
@@ -1192,7 +4025,7 @@ pub mod cpu {
                     cpu.register_y = reg;
                     cpu.memory.set_read_target(lsb as u16 + reg as u16, secret_value);
                     cpu.memory.set_read_target(cpu.program_counter, lsb);
-                    lsb as u16
+                    lsb as u16 + reg as u16
                 }
                 AddressingMode::Absolute => {
                     cpu.memory.set_read_target(addr, secret_value);
@@ -1201,49 +4034,55 @@ pub mod cpu {
                 }
                 AddressingMode::AbsoluteX => {
                     cpu.register_x = reg;
-                    cpu.memory.set_read_target(addr + reg as u16, secret_value);
+                    cpu.memory.set_read_target(addr.wrapping_add(reg as u16), secret_value);
                     cpu.memory.set_read_u16_target(cpu.program_counter, addr);
-                    addr + (reg as u16)
+                    addr.wrapping_add(reg as u16)
                 }
                 AddressingMode::AbsoluteY => {
                     cpu.register_y = reg;
-                    cpu.memory.set_read_target(addr + reg as u16, secret_value);
+                    cpu.memory.set_read_target(addr.wrapping_add(reg as u16), secret_value);
                     cpu.memory.set_read_u16_target(cpu.program_counter, addr);
-                    addr + (reg as u16)
+                    addr.wrapping_add(reg as u16)
                 }
                 AddressingMode::Indirect => {
-                    cpu.memory.set_read_u16_target(addr, indirect);
+                    // The high byte of the pointer is read from the same page.
+                    cpu.memory.set_read_target(addr, (indirect & 0xff) as u8);
+                    cpu.memory.set_read_target((addr & 0xff00) | (addr.wrapping_add(1) & 0xff), (indirect >> 8) as u8);
                     cpu.memory.set_read_target(indirect, secret_value);
                     cpu.memory.set_read_u16_target(cpu.program_counter, addr);
                     indirect
                 }
                 AddressingMode::IndexedIndirectX => {
                     cpu.register_x = reg;
-                    cpu.memory.set_read_u16_target(addr + reg as u16, indirect);
+                    cpu.memory.set_read_u16_target(lsb as u16 + reg as u16, indirect);
                     cpu.memory.set_read_target(indirect, secret_value);
-                    cpu.memory.set_read_u16_target(cpu.program_counter, addr);
-                    addr + (reg as u16)
+                    cpu.memory.set_read_target(cpu.program_counter, lsb);
+                    indirect
                 }
                 AddressingMode::IndexedIndirectY => {
                     cpu.register_y = reg;
-                    cpu.memory.set_read_u16_target(addr + reg as u16, indirect);
+                    cpu.memory.set_read_u16_target(lsb as u16 + reg as u16, indirect);
                     cpu.memory.set_read_target(indirect, secret_value);
-                    cpu.memory.set_read_u16_target(cpu.program_counter, addr);
-                    addr + (reg as u16)
+                    cpu.memory.set_read_target(cpu.program_counter, lsb);
+                    indirect
                 }
                 AddressingMode::IndirectIndexedX => {
                     cpu.register_x = reg;
-                    cpu.memory.set_read_u16_target(addr, indirect);
-                    cpu.memory.set_read_target(indirect + reg as u16, secret_value);
-                    cpu.memory.set_read_u16_target(cpu.program_counter, addr);
-                    indirect + (reg as u16)
+                    cpu.memory.set_read_u16_target(lsb as u16, indirect);
+                    // The dummy read before the page crossing is fixed.
+                    cpu.memory.set_read_target((indirect & 0xff00) | (indirect.wrapping_add(reg as u16) & 0xff), 0);
+                    cpu.memory.set_read_target(indirect.wrapping_add(reg as u16), secret_value);
+                    cpu.memory.set_read_target(cpu.program_counter, lsb);
+                    indirect.wrapping_add(reg as u16)
                 }
                 AddressingMode::IndirectIndexedY => {
                     cpu.register_y = reg;
-                    cpu.memory.set_read_u16_target(addr, indirect);
-                    cpu.memory.set_read_target(indirect + reg as u16, secret_value);
-                    cpu.memory.set_read_u16_target(cpu.program_counter, addr);
-                    indirect + (reg as u16)
+                    cpu.memory.set_read_u16_target(lsb as u16, indirect);
+                    // The dummy read before the page crossing is fixed.
+                    cpu.memory.set_read_target((indirect & 0xff00) | (indirect.wrapping_add(reg as u16) & 0xff), 0);
+                    cpu.memory.set_read_target(indirect.wrapping_add(reg as u16), secret_value);
+                    cpu.memory.set_read_target(cpu.program_counter, lsb);
+                    indirect.wrapping_add(reg as u16)
                 }
             }
         }
@@ -0,0 +1,239 @@
+use crate::bus::Mem;
+use crate::cpu::cpu::AddressingMode;
+use crate::instruction_table::{Mnemonic, TABLE};
+
+// Turns the machine code in memory back into assembly, one instruction per line:
+//
+//     $C000  A9 05     LDA #$05
+//
+// Memory is looked at with Mem::probe, so disassembling (e.g. for trace logging) has no side
+// effects on the bus. Bytes that can't be probed, like registers, are shown as ??.
+
+// Decodes the instruction at 'addr'. Returns the formatted line and the address of the next
+// instruction. Bytes that aren't a known opcode are shown as data, one at a time.
+pub fn disassemble(mem: &impl Mem, addr: u16) -> (String, u16) {
+    let Some(opcode) = mem.probe(addr) else {
+        return (format!("${:04X}  {:<10}.byte ??", addr, "??"), addr.wrapping_add(1));
+    };
+    let info = TABLE[opcode as usize];
+    if info.mnemonic == Mnemonic::Unknown {
+        return (format!("${:04X}  {:<10}.byte ${:02X}", addr, format!("{:02X}", opcode), opcode), addr.wrapping_add(1));
+    }
+    let len = info.bytes as u16 - 1;
+
+    let mut bytes = vec![Some(opcode)];
+    for i in 1..=len {
+        bytes.push(mem.probe(addr.wrapping_add(i)));
+    }
+    let next = addr.wrapping_add(1 + len);
+
+    let operand_byte = |i: usize| bytes.get(i).copied().flatten();
+    let byte = hex(operand_byte(1));
+    let word = format!("{}{}", hex(operand_byte(2)), byte);
+    let operand = match info.mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => String::from("A"),
+        AddressingMode::Immediate => format!("#${}", byte),
+        AddressingMode::ZeroPage => format!("${}", byte),
+        AddressingMode::ZeroPageX => format!("${},X", byte),
+        AddressingMode::ZeroPageY => format!("${},Y", byte),
+        AddressingMode::Absolute => format!("${}", word),
+        AddressingMode::AbsoluteX => format!("${},X", word),
+        AddressingMode::AbsoluteY => format!("${},Y", word),
+        AddressingMode::Indirect => format!("(${})", word),
+        AddressingMode::IndexedIndirectX => format!("(${},X)", byte),
+        AddressingMode::IndexedIndirectY => format!("(${},Y)", byte),
+        AddressingMode::IndirectIndexedX => format!("(${}),X", byte),
+        AddressingMode::IndirectIndexedY => format!("(${}),Y", byte),
+        // The offset is relative to the next instruction.
+        AddressingMode::Relative => match operand_byte(1) {
+            Some(offset) => format!("${:04X}", next.wrapping_add(offset as i8 as u16)),
+            None => String::from("$????"),
+        },
+    };
+
+    let hex: Vec<String> = bytes.iter().map(|b| hex(*b)).collect();
+    let line = format!("${:04X}  {:<10}{} {}", addr, hex.join(" "), info.mnemonic, operand);
+    (line.trim_end().to_string(), next)
+}
+
+fn hex(byte: Option<u8>) -> String {
+    byte.map_or(String::from("??"), |byte| format!("{:02X}", byte))
+}
+
+// Disassembles the instructions starting in start..end.
+pub fn disassemble_range(mem: &impl Mem, start: u16, end: u16) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut addr = start;
+    while addr < end {
+        let (line, next) = disassemble(mem, addr);
+        lines.push(line);
+        if next < addr { break; } // wrapped around the end of the address space
+        addr = next;
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::SparseMemory;
+
+    fn disassemble_program(program: &[u8]) -> (String, u16) {
+        let mut mem = SparseMemory::new();
+        mem.load(0xc000, program);
+        disassemble(&mem, 0xc000)
+    }
+
+    fn line(program: &[u8]) -> String {
+        disassemble_program(program).0
+    }
+
+    #[test]
+    fn test_implied() {
+        assert_eq!(line(&[0x18]), "$C000  18        CLC");
+    }
+
+    #[test]
+    fn test_accumulator() {
+        assert_eq!(line(&[0x0a]), "$C000  0A        ASL A");
+    }
+
+    #[test]
+    fn test_immediate() {
+        assert_eq!(line(&[0xa9, 0x05]), "$C000  A9 05     LDA #$05");
+    }
+
+    #[test]
+    fn test_zero_page() {
+        assert_eq!(line(&[0xa5, 0x10]), "$C000  A5 10     LDA $10");
+    }
+
+    #[test]
+    fn test_zero_page_x() {
+        assert_eq!(line(&[0xb5, 0x00]), "$C000  B5 00     LDA $00,X");
+    }
+
+    #[test]
+    fn test_zero_page_y() {
+        assert_eq!(line(&[0xb6, 0x80]), "$C000  B6 80     LDX $80,Y");
+    }
+
+    #[test]
+    fn test_absolute() {
+        assert_eq!(line(&[0x8d, 0x00, 0x02]), "$C000  8D 00 02  STA $0200");
+    }
+
+    #[test]
+    fn test_absolute_x() {
+        assert_eq!(line(&[0xbd, 0x34, 0x12]), "$C000  BD 34 12  LDA $1234,X");
+    }
+
+    #[test]
+    fn test_absolute_y() {
+        assert_eq!(line(&[0xb9, 0xff, 0x00]), "$C000  B9 FF 00  LDA $00FF,Y");
+    }
+
+    #[test]
+    fn test_indirect() {
+        assert_eq!(line(&[0x6c, 0x34, 0x12]), "$C000  6C 34 12  JMP ($1234)");
+    }
+
+    #[test]
+    fn test_indexed_indirect_x() {
+        assert_eq!(line(&[0xa1, 0x20]), "$C000  A1 20     LDA ($20,X)");
+    }
+
+    #[test]
+    fn test_indirect_indexed_y() {
+        assert_eq!(line(&[0x91, 0x20]), "$C000  91 20     STA ($20),Y");
+    }
+
+    #[test]
+    fn test_relative_forward() {
+        assert_eq!(line(&[0xf0, 0x1e]), "$C000  F0 1E     BEQ $C020");
+    }
+
+    #[test]
+    fn test_relative_backward() {
+        assert_eq!(line(&[0xd0, 0xfc]), "$C000  D0 FC     BNE $BFFE");
+    }
+
+    #[test]
+    fn test_relative_wraps_address_space() {
+        let mut mem = SparseMemory::new();
+        mem.load(0xfffc, &[0x10, 0x10]);
+        assert_eq!(disassemble(&mem, 0xfffc).0, "$FFFC  10 10     BPL $000E");
+    }
+
+    #[test]
+    fn test_jsr() {
+        assert_eq!(line(&[0x20, 0xf5, 0xc5]), "$C000  20 F5 C5  JSR $C5F5");
+    }
+
+    #[test]
+    fn test_unofficial_opcode() {
+        assert_eq!(line(&[0xa7, 0x10]), "$C000  A7 10     LAX $10");
+    }
+
+    #[test]
+    fn test_kil() {
+        let (line, next) = disassemble_program(&[0x02]);
+        assert_eq!(line, "$C000  02        KIL");
+        assert_eq!(next, 0xc001);
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let (line, next) = disassemble_program(&[0x0b, 0x10]);
+        assert_eq!(line, "$C000  0B        .byte $0B");
+        assert_eq!(next, 0xc001);
+    }
+
+    #[test]
+    fn test_unprobeable_bytes() {
+        let mut mem = SparseMemory::new();
+        mem.load(0xc000, &[0xad, 0x02]);
+        mem.load(0xc010, &[0xd0]);
+        assert_eq!(disassemble(&mem, 0xc000).0, "$C000  AD 02 ??  LDA $??02");
+        assert_eq!(disassemble(&mem, 0xc010).0, "$C010  D0 ??     BNE $????");
+        assert_eq!(disassemble(&mem, 0xc020), (String::from("$C020  ??        .byte ??"), 0xc021));
+    }
+
+    #[test]
+    fn test_next_address() {
+        assert_eq!(disassemble_program(&[0xea]).1, 0xc001);
+        assert_eq!(disassemble_program(&[0xa9, 0x05]).1, 0xc002);
+        assert_eq!(disassemble_program(&[0x4c, 0x00, 0xc0]).1, 0xc003);
+    }
+
+    #[test]
+    fn test_next_address_wraps() {
+        let mut mem = SparseMemory::new();
+        mem.load(0xfffe, &[0xad, 0x00]);
+        mem.load(0x0000, &[0x80]);
+        assert_eq!(disassemble(&mem, 0xfffe), (String::from("$FFFE  AD 00 80  LDA $8000"), 0x0001));
+    }
+
+    #[test]
+    fn test_disassemble_range() {
+        let mut mem = SparseMemory::new();
+        mem.load(0xc000, &[0xa2, 0x00, 0xe8, 0xd0, 0xfd, 0x60]);
+        assert_eq!(disassemble_range(&mem, 0xc000, 0xc006), vec![
+            "$C000  A2 00     LDX #$00",
+            "$C002  E8        INX",
+            "$C003  D0 FD     BNE $C002",
+            "$C005  60        RTS",
+        ]);
+        // Instructions starting before the end are decoded whole.
+        assert_eq!(disassemble_range(&mem, 0xc000, 0xc001).len(), 1);
+        assert!(disassemble_range(&mem, 0xc000, 0xc000).is_empty());
+    }
+
+    #[test]
+    fn test_disassemble_range_end_of_memory() {
+        let mut mem = SparseMemory::new();
+        mem.load(0xfffe, &[0xea, 0xea]);
+        assert_eq!(disassemble_range(&mem, 0xfffe, 0xffff).len(), 1);
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use crate::bus_guard::AccessViolation;
+
+// Components publish events instead of holding references to each other (e.g. the PPU starting
+// vblank, for a frontend presenting frames). Events are queued, and delivered to the subscribers
+// when the bus is flushed between component ticks, see RomBus::events. The cpu doesn't subscribe:
+// it polls the interrupt lines of the bus between instructions.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    NmiTriggered,
+    IrqTriggered,
+    DmaStarted(u8), // page
+    VblankStarted,
+    VblankEnded,
+    FrameComplete,
+    // An access breaking a protection, see RomBus::protect.
+    AccessViolation(AccessViolation),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    NmiTriggered,
+    IrqTriggered,
+    DmaStarted,
+    VblankStarted,
+    VblankEnded,
+    FrameComplete,
+    AccessViolation,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::NmiTriggered => EventKind::NmiTriggered,
+            Event::IrqTriggered => EventKind::IrqTriggered,
+            Event::DmaStarted(_) => EventKind::DmaStarted,
+            Event::VblankStarted => EventKind::VblankStarted,
+            Event::VblankEnded => EventKind::VblankEnded,
+            Event::FrameComplete => EventKind::FrameComplete,
+            Event::AccessViolation(_) => EventKind::AccessViolation,
+        }
+    }
+}
+
+type Callback = Box<dyn FnMut(&Event)>;
+
+#[derive(Default)]
+pub struct EventBus {
+    pending: Vec<Event>,
+    subscribers: HashMap<EventKind, Vec<Callback>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&mut self, event: Event) {
+        self.pending.push(event);
+    }
+
+    pub fn subscribe(&mut self, kind: EventKind, callback: impl FnMut(&Event) + 'static) {
+        self.subscribers.entry(kind).or_default().push(Box::new(callback));
+    }
+
+    // Delivers the pending events in the order they were published.
+    pub fn flush(&mut self) {
+        for event in std::mem::take(&mut self.pending) {
+            if let Some(callbacks) = self.subscribers.get_mut(&event.kind()) {
+                for callback in callbacks.iter_mut() {
+                    callback(&event);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_events_delivered_on_flush() {
+        let mut bus = EventBus::new();
+        let nmi = Rc::new(RefCell::new(0));
+        let nmi_count = nmi.clone();
+        bus.subscribe(EventKind::NmiTriggered, move |_| *nmi_count.borrow_mut() += 1);
+
+        bus.publish(Event::NmiTriggered);
+        bus.publish(Event::VblankStarted);
+        assert_eq!(*nmi.borrow(), 0);
+
+        bus.flush();
+        assert_eq!(*nmi.borrow(), 1);
+
+        bus.flush();
+        assert_eq!(*nmi.borrow(), 1);
+    }
+
+    #[test]
+    fn test_event_payload_and_order() {
+        let mut bus = EventBus::new();
+        let received = Rc::new(RefCell::new(Vec::new()));
+        for kind in [EventKind::DmaStarted, EventKind::FrameComplete] {
+            let received = received.clone();
+            bus.subscribe(kind, move |event| received.borrow_mut().push(*event));
+        }
+
+        bus.publish(Event::DmaStarted(0x02));
+        bus.publish(Event::IrqTriggered);
+        bus.publish(Event::FrameComplete);
+        bus.flush();
+
+        assert_eq!(*received.borrow(), vec![Event::DmaStarted(0x02), Event::FrameComplete]);
+    }
+}
@@ -0,0 +1,91 @@
+// Game Genie codes patch the values read from the PRG ROM. Every letter of a code encodes a nibble,
+// which are shuffled around to obtain the address, the replacement value, and for 8-letter codes a
+// compare value. The patch only applies if the original ROM byte matches the compare value.
+// See: https://www.nesdev.org/wiki/Game_Genie
+
+const LETTERS: [char; 16] = ['A', 'P', 'Z', 'L', 'G', 'I', 'T', 'Y', 'E', 'O', 'X', 'U', 'K', 'S', 'V', 'N'];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GameGeniePatch {
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl GameGeniePatch {
+    // Returns the value the cpu should see when reading 'original' from 'address'.
+    pub fn patch(&self, address: u16, original: u8) -> u8 {
+        if address != self.address { return original; }
+
+        match self.compare {
+            Some(compare) if compare != original => original,
+            _ => self.value,
+        }
+    }
+}
+
+pub fn decode_game_genie(code: &str) -> Result<GameGeniePatch, String> {
+    let mut n: Vec<u16> = Vec::new();
+    for letter in code.chars() {
+        match LETTERS.iter().position(|l| *l == letter.to_ascii_uppercase()) {
+            Some(nibble) => n.push(nibble as u16),
+            None => return Err(format!("Invalid Game Genie letter '{}' in {}.", letter, code)),
+        }
+    }
+
+    if n.len() != 6 && n.len() != 8 {
+        return Err(format!("Game Genie codes have 6 or 8 letters, {} has {}.", code, n.len()));
+    }
+
+    let address: u16 = 0x8000
+        | ((n[3] & 7) << 12)
+        | ((n[5] & 7) << 8) | ((n[4] & 8) << 8)
+        | ((n[2] & 7) << 4) | ((n[1] & 8) << 4)
+        | (n[4] & 7) | (n[3] & 8);
+
+    let value_low_bit: u16 = if n.len() == 6 { n[5] & 8 } else { n[7] & 8 };
+    let value = (((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | value_low_bit) as u8;
+
+    let compare = if n.len() == 8 {
+        Some((((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8)) as u8)
+    } else {
+        None
+    };
+
+    Ok(GameGeniePatch { address, value, compare })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_six_letter_code() {
+        let patch = decode_game_genie("SXIOPO").unwrap();
+        assert_eq!(patch, GameGeniePatch { address: 0x91d9, value: 0xad, compare: None });
+
+        assert_eq!(patch.patch(0x91d9, 0x00), 0xad);
+        assert_eq!(patch.patch(0x91da, 0x00), 0x00);
+    }
+
+    #[test]
+    fn test_eight_letter_code() {
+        let patch = decode_game_genie("YEUZUGAA").unwrap();
+        assert_eq!(patch, GameGeniePatch { address: 0xacb3, value: 0x07, compare: Some(0x00) });
+
+        assert_eq!(patch.patch(0xacb3, 0x00), 0x07);
+        assert_eq!(patch.patch(0xacb3, 0x01), 0x01);
+    }
+
+    #[test]
+    fn test_lowercase_code() {
+        assert_eq!(decode_game_genie("sxiopo"), decode_game_genie("SXIOPO"));
+    }
+
+    #[test]
+    fn test_invalid_codes() {
+        assert!(decode_game_genie("SXIOP").is_err());
+        assert!(decode_game_genie("SXIOPOA").is_err());
+        assert!(decode_game_genie("SXIOPB").is_err());
+    }
+}
@@ -0,0 +1,554 @@
+use crate::cpu::cpu::AddressingMode;
+use crate::cpu::cpu::AddressingMode::*;
+use std::fmt;
+use Mnemonic::*;
+
+// Metadata for every opcode, used by the tooling around the cpu (disassembler, trace logging,
+// cycle counting). Cycle counts are the base counts. With page_penalty set, indexed reads take one
+// more cycle when the address crosses a page, and branches take one more cycle when taken.
+// See: https://www.nesdev.org/wiki/CPU_unofficial_opcodes
+
+// The instructions, for dispatching on in the cpu. Their names are only for the disassembler and
+// the trace output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mnemonic {
+    Adc,
+    And,
+    Asl,
+    Bcc,
+    Bcs,
+    Beq,
+    Bit,
+    Bmi,
+    Bne,
+    Bpl,
+    Brk,
+    Bvc,
+    Bvs,
+    Clc,
+    Cld,
+    Cli,
+    Clv,
+    Cmp,
+    Cpx,
+    Cpy,
+    Dec,
+    Dex,
+    Dey,
+    Eor,
+    Inc,
+    Inx,
+    Iny,
+    Jmp,
+    Jsr,
+    Lda,
+    Ldx,
+    Ldy,
+    Lsr,
+    Nop,
+    Ora,
+    Pha,
+    Php,
+    Pla,
+    Plp,
+    Rol,
+    Ror,
+    Rti,
+    Rts,
+    Sbc,
+    Sec,
+    Sed,
+    Sei,
+    Sta,
+    Stx,
+    Sty,
+    Tax,
+    Tay,
+    Tsx,
+    Txa,
+    Txs,
+    Tya,
+    Lax,
+    Sax,
+    Dcp,
+    Isb,
+    Slo,
+    Rla,
+    Sre,
+    Rra,
+    Kil,
+    // Unstable opcodes the cpu doesn't implement.
+    Unknown,
+}
+
+impl Mnemonic {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Adc => "ADC",
+            And => "AND",
+            Asl => "ASL",
+            Bcc => "BCC",
+            Bcs => "BCS",
+            Beq => "BEQ",
+            Bit => "BIT",
+            Bmi => "BMI",
+            Bne => "BNE",
+            Bpl => "BPL",
+            Brk => "BRK",
+            Bvc => "BVC",
+            Bvs => "BVS",
+            Clc => "CLC",
+            Cld => "CLD",
+            Cli => "CLI",
+            Clv => "CLV",
+            Cmp => "CMP",
+            Cpx => "CPX",
+            Cpy => "CPY",
+            Dec => "DEC",
+            Dex => "DEX",
+            Dey => "DEY",
+            Eor => "EOR",
+            Inc => "INC",
+            Inx => "INX",
+            Iny => "INY",
+            Jmp => "JMP",
+            Jsr => "JSR",
+            Lda => "LDA",
+            Ldx => "LDX",
+            Ldy => "LDY",
+            Lsr => "LSR",
+            Nop => "NOP",
+            Ora => "ORA",
+            Pha => "PHA",
+            Php => "PHP",
+            Pla => "PLA",
+            Plp => "PLP",
+            Rol => "ROL",
+            Ror => "ROR",
+            Rti => "RTI",
+            Rts => "RTS",
+            Sbc => "SBC",
+            Sec => "SEC",
+            Sed => "SED",
+            Sei => "SEI",
+            Sta => "STA",
+            Stx => "STX",
+            Sty => "STY",
+            Tax => "TAX",
+            Tay => "TAY",
+            Tsx => "TSX",
+            Txa => "TXA",
+            Txs => "TXS",
+            Tya => "TYA",
+            Lax => "LAX",
+            Sax => "SAX",
+            Dcp => "DCP",
+            Isb => "ISB",
+            Slo => "SLO",
+            Rla => "RLA",
+            Sre => "SRE",
+            Rra => "RRA",
+            Kil => "KIL",
+            Unknown => "???",
+        }
+    }
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpcodeInfo {
+    pub mnemonic: Mnemonic,
+    pub mode: AddressingMode,
+    // Length of the instruction, opcode included.
+    pub bytes: u8,
+    pub cycles: u8,
+    pub page_penalty: bool,
+    pub official: bool,
+}
+
+const fn bytes(mode: AddressingMode) -> u8 {
+    match mode {
+        Implied | Accumulator => 1,
+        Absolute | AbsoluteX | AbsoluteY | Indirect => 3,
+        _ => 2,
+    }
+}
+
+const fn op(mnemonic: Mnemonic, mode: AddressingMode, cycles: u8, page_penalty: bool) -> OpcodeInfo {
+    OpcodeInfo { mnemonic, mode, bytes: bytes(mode), cycles, page_penalty, official: true }
+}
+
+const fn unofficial(mnemonic: Mnemonic, mode: AddressingMode, cycles: u8, page_penalty: bool) -> OpcodeInfo {
+    OpcodeInfo { mnemonic, mode, bytes: bytes(mode), cycles, page_penalty, official: false }
+}
+
+// Unstable opcodes the cpu doesn't implement. They still get their addressing mode and cycle
+// count, so they can be skipped over, see IllegalOpcodePolicy.
+const fn unknown(mode: AddressingMode, cycles: u8) -> OpcodeInfo {
+    unofficial(Unknown, mode, cycles, false)
+}
+
+// KIL locks up the cpu, so it has no cycle count.
+const KIL: OpcodeInfo = unofficial(Kil, Implied, 0, false);
+
+pub const TABLE: [OpcodeInfo; 256] = {
+    let mut t = [unknown(Implied, 0); 256];
+
+    t[0x69] = op(Adc, Immediate, 2, false);
+    t[0x65] = op(Adc, ZeroPage, 3, false);
+    t[0x75] = op(Adc, ZeroPageX, 4, false);
+    t[0x6d] = op(Adc, Absolute, 4, false);
+    t[0x7d] = op(Adc, AbsoluteX, 4, true);
+    t[0x79] = op(Adc, AbsoluteY, 4, true);
+    t[0x61] = op(Adc, IndexedIndirectX, 6, false);
+    t[0x71] = op(Adc, IndirectIndexedY, 5, true);
+
+    t[0x29] = op(And, Immediate, 2, false);
+    t[0x25] = op(And, ZeroPage, 3, false);
+    t[0x35] = op(And, ZeroPageX, 4, false);
+    t[0x2d] = op(And, Absolute, 4, false);
+    t[0x3d] = op(And, AbsoluteX, 4, true);
+    t[0x39] = op(And, AbsoluteY, 4, true);
+    t[0x21] = op(And, IndexedIndirectX, 6, false);
+    t[0x31] = op(And, IndirectIndexedY, 5, true);
+
+    t[0x0a] = op(Asl, Accumulator, 2, false);
+    t[0x06] = op(Asl, ZeroPage, 5, false);
+    t[0x16] = op(Asl, ZeroPageX, 6, false);
+    t[0x0e] = op(Asl, Absolute, 6, false);
+    t[0x1e] = op(Asl, AbsoluteX, 7, false);
+
+    t[0x90] = op(Bcc, Relative, 2, true);
+    t[0xb0] = op(Bcs, Relative, 2, true);
+    t[0xf0] = op(Beq, Relative, 2, true);
+    t[0x30] = op(Bmi, Relative, 2, true);
+    t[0xd0] = op(Bne, Relative, 2, true);
+    t[0x10] = op(Bpl, Relative, 2, true);
+    t[0x50] = op(Bvc, Relative, 2, true);
+    t[0x70] = op(Bvs, Relative, 2, true);
+
+    t[0x24] = op(Bit, ZeroPage, 3, false);
+    t[0x2c] = op(Bit, Absolute, 4, false);
+
+    t[0x00] = op(Brk, Implied, 7, false);
+
+    t[0x18] = op(Clc, Implied, 2, false);
+    t[0xd8] = op(Cld, Implied, 2, false);
+    t[0x58] = op(Cli, Implied, 2, false);
+    t[0xb8] = op(Clv, Implied, 2, false);
+
+    t[0xc9] = op(Cmp, Immediate, 2, false);
+    t[0xc5] = op(Cmp, ZeroPage, 3, false);
+    t[0xd5] = op(Cmp, ZeroPageX, 4, false);
+    t[0xcd] = op(Cmp, Absolute, 4, false);
+    t[0xdd] = op(Cmp, AbsoluteX, 4, true);
+    t[0xd9] = op(Cmp, AbsoluteY, 4, true);
+    t[0xc1] = op(Cmp, IndexedIndirectX, 6, false);
+    t[0xd1] = op(Cmp, IndirectIndexedY, 5, true);
+
+    t[0xe0] = op(Cpx, Immediate, 2, false);
+    t[0xe4] = op(Cpx, ZeroPage, 3, false);
+    t[0xec] = op(Cpx, Absolute, 4, false);
+
+    t[0xc0] = op(Cpy, Immediate, 2, false);
+    t[0xc4] = op(Cpy, ZeroPage, 3, false);
+    t[0xcc] = op(Cpy, Absolute, 4, false);
+
+    t[0xc6] = op(Dec, ZeroPage, 5, false);
+    t[0xd6] = op(Dec, ZeroPageX, 6, false);
+    t[0xce] = op(Dec, Absolute, 6, false);
+    t[0xde] = op(Dec, AbsoluteX, 7, false);
+
+    t[0xca] = op(Dex, Implied, 2, false);
+    t[0x88] = op(Dey, Implied, 2, false);
+
+    t[0x49] = op(Eor, Immediate, 2, false);
+    t[0x45] = op(Eor, ZeroPage, 3, false);
+    t[0x55] = op(Eor, ZeroPageX, 4, false);
+    t[0x4d] = op(Eor, Absolute, 4, false);
+    t[0x5d] = op(Eor, AbsoluteX, 4, true);
+    t[0x59] = op(Eor, AbsoluteY, 4, true);
+    t[0x41] = op(Eor, IndexedIndirectX, 6, false);
+    t[0x51] = op(Eor, IndirectIndexedY, 5, true);
+
+    t[0xe6] = op(Inc, ZeroPage, 5, false);
+    t[0xf6] = op(Inc, ZeroPageX, 6, false);
+    t[0xee] = op(Inc, Absolute, 6, false);
+    t[0xfe] = op(Inc, AbsoluteX, 7, false);
+
+    t[0xe8] = op(Inx, Implied, 2, false);
+    t[0xc8] = op(Iny, Implied, 2, false);
+
+    t[0x4c] = op(Jmp, Absolute, 3, false);
+    t[0x6c] = op(Jmp, Indirect, 5, false);
+    t[0x20] = op(Jsr, Absolute, 6, false);
+
+    t[0xa9] = op(Lda, Immediate, 2, false);
+    t[0xa5] = op(Lda, ZeroPage, 3, false);
+    t[0xb5] = op(Lda, ZeroPageX, 4, false);
+    t[0xad] = op(Lda, Absolute, 4, false);
+    t[0xbd] = op(Lda, AbsoluteX, 4, true);
+    t[0xb9] = op(Lda, AbsoluteY, 4, true);
+    t[0xa1] = op(Lda, IndexedIndirectX, 6, false);
+    t[0xb1] = op(Lda, IndirectIndexedY, 5, true);
+
+    t[0xa2] = op(Ldx, Immediate, 2, false);
+    t[0xa6] = op(Ldx, ZeroPage, 3, false);
+    t[0xb6] = op(Ldx, ZeroPageY, 4, false);
+    t[0xae] = op(Ldx, Absolute, 4, false);
+    t[0xbe] = op(Ldx, AbsoluteY, 4, true);
+
+    t[0xa0] = op(Ldy, Immediate, 2, false);
+    t[0xa4] = op(Ldy, ZeroPage, 3, false);
+    t[0xb4] = op(Ldy, ZeroPageX, 4, false);
+    t[0xac] = op(Ldy, Absolute, 4, false);
+    t[0xbc] = op(Ldy, AbsoluteX, 4, true);
+
+    t[0x4a] = op(Lsr, Accumulator, 2, false);
+    t[0x46] = op(Lsr, ZeroPage, 5, false);
+    t[0x56] = op(Lsr, ZeroPageX, 6, false);
+    t[0x4e] = op(Lsr, Absolute, 6, false);
+    t[0x5e] = op(Lsr, AbsoluteX, 7, false);
+
+    t[0xea] = op(Nop, Implied, 2, false);
+
+    t[0x09] = op(Ora, Immediate, 2, false);
+    t[0x05] = op(Ora, ZeroPage, 3, false);
+    t[0x15] = op(Ora, ZeroPageX, 4, false);
+    t[0x0d] = op(Ora, Absolute, 4, false);
+    t[0x1d] = op(Ora, AbsoluteX, 4, true);
+    t[0x19] = op(Ora, AbsoluteY, 4, true);
+    t[0x01] = op(Ora, IndexedIndirectX, 6, false);
+    t[0x11] = op(Ora, IndirectIndexedY, 5, true);
+
+    t[0x48] = op(Pha, Implied, 3, false);
+    t[0x08] = op(Php, Implied, 3, false);
+    t[0x68] = op(Pla, Implied, 4, false);
+    t[0x28] = op(Plp, Implied, 4, false);
+
+    t[0x2a] = op(Rol, Accumulator, 2, false);
+    t[0x26] = op(Rol, ZeroPage, 5, false);
+    t[0x36] = op(Rol, ZeroPageX, 6, false);
+    t[0x2e] = op(Rol, Absolute, 6, false);
+    t[0x3e] = op(Rol, AbsoluteX, 7, false);
+
+    t[0x6a] = op(Ror, Accumulator, 2, false);
+    t[0x66] = op(Ror, ZeroPage, 5, false);
+    t[0x76] = op(Ror, ZeroPageX, 6, false);
+    t[0x6e] = op(Ror, Absolute, 6, false);
+    t[0x7e] = op(Ror, AbsoluteX, 7, false);
+
+    t[0x40] = op(Rti, Implied, 6, false);
+    t[0x60] = op(Rts, Implied, 6, false);
+
+    t[0xe9] = op(Sbc, Immediate, 2, false);
+    t[0xe5] = op(Sbc, ZeroPage, 3, false);
+    t[0xf5] = op(Sbc, ZeroPageX, 4, false);
+    t[0xed] = op(Sbc, Absolute, 4, false);
+    t[0xfd] = op(Sbc, AbsoluteX, 4, true);
+    t[0xf9] = op(Sbc, AbsoluteY, 4, true);
+    t[0xe1] = op(Sbc, IndexedIndirectX, 6, false);
+    t[0xf1] = op(Sbc, IndirectIndexedY, 5, true);
+
+    t[0x38] = op(Sec, Implied, 2, false);
+    t[0xf8] = op(Sed, Implied, 2, false);
+    t[0x78] = op(Sei, Implied, 2, false);
+
+    t[0x85] = op(Sta, ZeroPage, 3, false);
+    t[0x95] = op(Sta, ZeroPageX, 4, false);
+    t[0x8d] = op(Sta, Absolute, 4, false);
+    t[0x9d] = op(Sta, AbsoluteX, 5, false);
+    t[0x99] = op(Sta, AbsoluteY, 5, false);
+    t[0x81] = op(Sta, IndexedIndirectX, 6, false);
+    t[0x91] = op(Sta, IndirectIndexedY, 6, false);
+
+    t[0x86] = op(Stx, ZeroPage, 3, false);
+    t[0x96] = op(Stx, ZeroPageY, 4, false);
+    t[0x8e] = op(Stx, Absolute, 4, false);
+
+    t[0x84] = op(Sty, ZeroPage, 3, false);
+    t[0x94] = op(Sty, ZeroPageX, 4, false);
+    t[0x8c] = op(Sty, Absolute, 4, false);
+
+    t[0xaa] = op(Tax, Implied, 2, false);
+    t[0xa8] = op(Tay, Implied, 2, false);
+    t[0xba] = op(Tsx, Implied, 2, false);
+    t[0x8a] = op(Txa, Implied, 2, false);
+    t[0x9a] = op(Txs, Implied, 2, false);
+    t[0x98] = op(Tya, Implied, 2, false);
+
+    // Unofficial opcodes used by some games.
+    // lax - lda and ldx
+    t[0xa7] = unofficial(Lax, ZeroPage, 3, false);
+    t[0xb7] = unofficial(Lax, ZeroPageY, 4, false);
+    t[0xaf] = unofficial(Lax, Absolute, 4, false);
+    t[0xbf] = unofficial(Lax, AbsoluteY, 4, true);
+    t[0xa3] = unofficial(Lax, IndexedIndirectX, 6, false);
+    t[0xb3] = unofficial(Lax, IndirectIndexedY, 5, true);
+    // sax - store a & x
+    t[0x87] = unofficial(Sax, ZeroPage, 3, false);
+    t[0x97] = unofficial(Sax, ZeroPageY, 4, false);
+    t[0x8f] = unofficial(Sax, Absolute, 4, false);
+    t[0x83] = unofficial(Sax, IndexedIndirectX, 6, false);
+    // dcp - dec and cmp
+    t[0xc7] = unofficial(Dcp, ZeroPage, 5, false);
+    t[0xd7] = unofficial(Dcp, ZeroPageX, 6, false);
+    t[0xcf] = unofficial(Dcp, Absolute, 6, false);
+    t[0xdf] = unofficial(Dcp, AbsoluteX, 7, false);
+    t[0xdb] = unofficial(Dcp, AbsoluteY, 7, false);
+    t[0xc3] = unofficial(Dcp, IndexedIndirectX, 8, false);
+    t[0xd3] = unofficial(Dcp, IndirectIndexedY, 8, false);
+    // isb (also called isc) - inc and sbc
+    t[0xe7] = unofficial(Isb, ZeroPage, 5, false);
+    t[0xf7] = unofficial(Isb, ZeroPageX, 6, false);
+    t[0xef] = unofficial(Isb, Absolute, 6, false);
+    t[0xff] = unofficial(Isb, AbsoluteX, 7, false);
+    t[0xfb] = unofficial(Isb, AbsoluteY, 7, false);
+    t[0xe3] = unofficial(Isb, IndexedIndirectX, 8, false);
+    t[0xf3] = unofficial(Isb, IndirectIndexedY, 8, false);
+    // rla - rol and and
+    t[0x27] = unofficial(Rla, ZeroPage, 5, false);
+    t[0x37] = unofficial(Rla, ZeroPageX, 6, false);
+    t[0x2f] = unofficial(Rla, Absolute, 6, false);
+    t[0x3f] = unofficial(Rla, AbsoluteX, 7, false);
+    t[0x3b] = unofficial(Rla, AbsoluteY, 7, false);
+    t[0x23] = unofficial(Rla, IndexedIndirectX, 8, false);
+    t[0x33] = unofficial(Rla, IndirectIndexedY, 8, false);
+    // rra - ror and adc
+    t[0x67] = unofficial(Rra, ZeroPage, 5, false);
+    t[0x77] = unofficial(Rra, ZeroPageX, 6, false);
+    t[0x6f] = unofficial(Rra, Absolute, 6, false);
+    t[0x7f] = unofficial(Rra, AbsoluteX, 7, false);
+    t[0x7b] = unofficial(Rra, AbsoluteY, 7, false);
+    t[0x63] = unofficial(Rra, IndexedIndirectX, 8, false);
+    t[0x73] = unofficial(Rra, IndirectIndexedY, 8, false);
+    // slo - asl and ora
+    t[0x07] = unofficial(Slo, ZeroPage, 5, false);
+    t[0x17] = unofficial(Slo, ZeroPageX, 6, false);
+    t[0x0f] = unofficial(Slo, Absolute, 6, false);
+    t[0x1f] = unofficial(Slo, AbsoluteX, 7, false);
+    t[0x1b] = unofficial(Slo, AbsoluteY, 7, false);
+    t[0x03] = unofficial(Slo, IndexedIndirectX, 8, false);
+    t[0x13] = unofficial(Slo, IndirectIndexedY, 8, false);
+    // sre - lsr and eor
+    t[0x47] = unofficial(Sre, ZeroPage, 5, false);
+    t[0x57] = unofficial(Sre, ZeroPageX, 6, false);
+    t[0x4f] = unofficial(Sre, Absolute, 6, false);
+    t[0x5f] = unofficial(Sre, AbsoluteX, 7, false);
+    t[0x5b] = unofficial(Sre, AbsoluteY, 7, false);
+    t[0x43] = unofficial(Sre, IndexedIndirectX, 8, false);
+    t[0x53] = unofficial(Sre, IndirectIndexedY, 8, false);
+    // sbc immediate duplicate
+    t[0xeb] = unofficial(Sbc, Immediate, 2, false);
+    // nops, the ones with an operand read it
+    let nops: [(u8, AddressingMode, u8); 21] = [
+        (0x1a, Implied, 2), (0x3a, Implied, 2), (0x5a, Implied, 2),
+        (0x7a, Implied, 2), (0xda, Implied, 2), (0xfa, Implied, 2),
+        (0x80, Immediate, 2), (0x82, Immediate, 2), (0x89, Immediate, 2), (0xc2, Immediate, 2), (0xe2, Immediate, 2),
+        (0x04, ZeroPage, 3), (0x44, ZeroPage, 3), (0x64, ZeroPage, 3),
+        (0x14, ZeroPageX, 4), (0x34, ZeroPageX, 4), (0x54, ZeroPageX, 4),
+        (0x74, ZeroPageX, 4), (0xd4, ZeroPageX, 4), (0xf4, ZeroPageX, 4),
+        (0x0c, Absolute, 4),
+    ];
+    let mut i = 0;
+    while i < nops.len() {
+        let (opcode, mode, cycles) = nops[i];
+        t[opcode as usize] = unofficial(Nop, mode, cycles, false);
+        i += 1;
+    }
+    let nops_absolute_x: [u8; 6] = [0x1c, 0x3c, 0x5c, 0x7c, 0xdc, 0xfc];
+    let mut i = 0;
+    while i < nops_absolute_x.len() {
+        t[nops_absolute_x[i] as usize] = unofficial(Nop, AbsoluteX, 4, true);
+        i += 1;
+    }
+
+    // anc, alr, arr, xaa, lxa, axs, sha, tas, shy, shx, sha, las
+    let unknowns: [(u8, AddressingMode, u8); 13] = [
+        (0x0b, Immediate, 2), (0x2b, Immediate, 2), (0x4b, Immediate, 2), (0x6b, Immediate, 2),
+        (0x8b, Immediate, 2), (0xab, Immediate, 2), (0xcb, Immediate, 2),
+        (0x93, IndirectIndexedY, 6), (0x9b, AbsoluteY, 5), (0x9c, AbsoluteX, 5),
+        (0x9e, AbsoluteY, 5), (0x9f, AbsoluteY, 5), (0xbb, AbsoluteY, 4),
+    ];
+    let mut i = 0;
+    while i < unknowns.len() {
+        let (opcode, mode, cycles) = unknowns[i];
+        t[opcode as usize] = unknown(mode, cycles);
+        i += 1;
+    }
+
+    let kil: [u8; 12] = [0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xb2, 0xd2, 0xf2];
+    let mut i = 0;
+    while i < kil.len() {
+        t[kil[i] as usize] = KIL;
+        i += 1;
+    }
+
+    t
+};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::reference_cpu::is_official;
+
+    fn official() -> Vec<(usize, OpcodeInfo)> {
+        TABLE.iter().copied().enumerate().filter(|(_, info)| info.official).collect()
+    }
+
+    #[test]
+    fn test_official_opcodes() {
+        assert_eq!(official().len(), 151);
+        for (opcode, info) in TABLE.iter().enumerate() {
+            assert_eq!(info.official, is_official(opcode as u8), "{:#04x} {}", opcode, info.mnemonic);
+        }
+    }
+
+    #[test]
+    fn test_official_opcodes_unique() {
+        let official = official();
+        for (i, (opcode, info)) in official.iter().enumerate() {
+            for (other, other_info) in official[i + 1..].iter() {
+                assert!(
+                    info.mnemonic != other_info.mnemonic || info.mode != other_info.mode,
+                    "{:#04x} and {:#04x} are both {} {:?}", opcode, other, info.mnemonic, info.mode,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_mnemonics_and_cycles() {
+        for (opcode, info) in official() {
+            assert_ne!(info.mnemonic, Unknown, "{:#04x}", opcode);
+            assert!((2..=7).contains(&info.cycles), "{:#04x} {} takes {} cycles", opcode, info.mnemonic, info.cycles);
+        }
+        assert_eq!(TABLE[0x02], KIL);
+        assert_eq!(TABLE[0xa7].mnemonic, Lax);
+        assert!(!TABLE[0xa7].official);
+    }
+
+    #[test]
+    fn test_bytes() {
+        assert_eq!(TABLE[0xea].bytes, 1); // NOP
+        assert_eq!(TABLE[0x0a].bytes, 1); // ASL A
+        assert_eq!(TABLE[0xa9].bytes, 2); // LDA #
+        assert_eq!(TABLE[0xd0].bytes, 2); // BNE
+        assert_eq!(TABLE[0xb1].bytes, 2); // LDA (d),y
+        assert_eq!(TABLE[0x6c].bytes, 3); // JMP (a)
+        assert_eq!(TABLE[0x1c].bytes, 3); // NOP a,x
+        assert_eq!(TABLE[0x9c].bytes, 3); // unimplemented SHY a,x
+    }
+
+    #[test]
+    fn test_every_opcode_has_cycles() {
+        for (opcode, info) in TABLE.iter().enumerate() {
+            assert!(info.cycles > 0 || info.mnemonic == Kil, "{:#04x} {}", opcode, info.mnemonic);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// Standard controller, connected to $4016 (port 1) or $4017 (port 2). Writing 1 to bit 0 of $4016
+// makes the controllers reload their shift register with the buttons held, continuously, and
+// writing 0 latches it. Every read then returns the next button in bit 0, in the order of Button.
+// After all 8 buttons, reads return 1.
+// See: https://www.nesdev.org/wiki/Standard_controller
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    A      = 0b0000_0001,
+    B      = 0b0000_0010,
+    Select = 0b0000_0100,
+    Start  = 0b0000_1000,
+    Up     = 0b0001_0000,
+    Down   = 0b0010_0000,
+    Left   = 0b0100_0000,
+    Right  = 0b1000_0000,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Joypad {
+    // Buttons held, Button bits.
+    buttons: u8,
+    strobe: bool,
+    shift: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Called by the frontend with the buttons held, as Button bits.
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.buttons |= button as u8;
+        } else {
+            self.buttons &= !(button as u8);
+        }
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    // Bit 0 of the value written to $4016.
+    pub fn write(&mut self, val: u8) {
+        self.strobe = val & 1 != 0;
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+
+    // Bit 0 of the value read from the port, the rest is left to the bus.
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & 1;
+        }
+        let bit = self.shift & 1;
+        self.shift = self.shift >> 1 | 0x80;
+        bit
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_joypad_shift() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::A, true);
+        joypad.set_button(Button::Start, true);
+        joypad.set_button(Button::Left, true);
+        joypad.write(1);
+        joypad.write(0);
+        joypad.set_buttons(0); // latched, doesn't change what is read
+
+        let bits: Vec<u8> = (0..10).map(|_| joypad.read()).collect();
+        assert_eq!(bits, [1, 0, 0, 1, 0, 0, 1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_joypad_strobe() {
+        let mut joypad = Joypad::new();
+        joypad.write(1);
+        // While the strobe is high, reads keep returning the A button.
+        assert_eq!(joypad.read(), 0);
+        joypad.set_button(Button::A, true);
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 1);
+        joypad.set_button(Button::A, false);
+        assert_eq!(joypad.read(), 0);
+        assert_eq!(joypad.buttons(), 0);
+    }
+}
@@ -0,0 +1,25 @@
+
+pub mod cpu;
+pub mod bus;
+pub mod mirror;
+pub mod bus_trace;
+pub mod bus_guard;
+pub mod rom;
+pub mod game_genie;
+pub mod patch;
+pub mod event_bus;
+pub mod instruction_table;
+pub mod disasm;
+pub mod ppu;
+pub mod joypad;
+pub mod apu;
+pub mod testing;
+pub mod nes;
+#[cfg(test)]
+mod test_rom;
+#[cfg(any(test, feature = "lockstep"))]
+mod reference_cpu;
+#[cfg(feature = "lockstep")]
+pub mod lockstep;
+#[cfg(test)]
+mod tests;
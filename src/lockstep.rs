@@ -0,0 +1,206 @@
+// Lockstep mode, for hardening the cpu: every instruction runs on the CPU and on the reference
+// implementation, and the first instruction after which they disagree is reported with the state
+// before and after it. The reference reads memory through Mem::probe, so instructions reading
+// addresses that can't be probed (e.g. PPU registers) are skipped, and so are unofficial opcodes.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::bus::{Mem, SparseMemory};
+use crate::cpu::cpu::{CpuError, CpuState, CPU};
+use crate::disasm::disassemble;
+use crate::reference_cpu::MOS6502Reference;
+
+// An instruction after which the CPU and the reference disagree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub pc: u16,
+    // The instruction, disassembled.
+    pub instruction: String,
+    pub before: CpuState,
+    pub expected: CpuState,
+    pub actual: CpuState,
+    // Writes of the reference the CPU didn't make, as (address, expected, actual).
+    pub memory: Vec<(u16, u8, Option<u8>)>,
+}
+
+fn format_state(state: &CpuState) -> String {
+    format!(
+        "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}",
+        state.register_a, state.register_x, state.register_y, state.status, state.stack_pointer, state.program_counter,
+    )
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.instruction)?;
+        writeln!(f, "  before:   {}", format_state(&self.before))?;
+        writeln!(f, "  expected: {}", format_state(&self.expected))?;
+        write!(f, "  actual:   {}", format_state(&self.actual))?;
+        for (addr, expected, actual) in self.memory.iter() {
+            write!(f, "\n  memory at {:04X} is {:02X?}, expected {:02X}", addr, actual, expected)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Lockstep<T: Mem> {
+    cpu: CPU<T>,
+    // State of the cpu after the last instruction, before servicing interrupts.
+    after: Rc<RefCell<Option<CpuState>>>,
+    // Number of instructions compared, and skipped.
+    pub compared: u64,
+    pub skipped: u64,
+}
+
+impl<T: Mem> Lockstep<T> {
+    // Takes over 'cpu'. It gets an instruction hook, so clearing its hooks stops the comparison.
+    pub fn new(mut cpu: CPU<T>) -> Self {
+        let after = Rc::new(RefCell::new(None));
+        let hook_after = Rc::clone(&after);
+        cpu.on_after_instruction(Box::new(move |_, _, state| {
+            *hook_after.borrow_mut() = Some(*state);
+        }));
+        Lockstep { cpu, after, compared: 0, skipped: 0 }
+    }
+
+    pub fn cpu(&self) -> &CPU<T> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<T> {
+        &mut self.cpu
+    }
+
+    pub fn into_inner(self) -> CPU<T> {
+        self.cpu
+    }
+
+    // Runs the reference on the next instruction. Returns the reference before and after it, with
+    // everything it reads filled in from the bus, or None if the instruction can't be compared.
+    fn run_reference(&self, state: CpuState) -> Option<(MOS6502Reference, MOS6502Reference)> {
+        let mut reference = MOS6502Reference::new();
+        reference.a = state.register_a;
+        reference.x = state.register_x;
+        reference.y = state.register_y;
+        reference.sp = state.stack_pointer;
+        reference.status = state.status;
+        reference.pc = state.program_counter;
+
+        // Every round finds the next level of indirection: opcode, operand, pointer, value.
+        for _ in 0..8 {
+            let mut stepped = reference.clone();
+            let official = stepped.step();
+            let missed = stepped.missed_reads.take();
+            if missed.is_empty() {
+                return official.then_some((reference, stepped));
+            }
+            for addr in missed {
+                reference.memory.insert(addr, self.cpu.memory().probe(addr)?);
+            }
+        }
+        None
+    }
+
+    // Steps the cpu, and compares it with the reference.
+    pub fn step(&mut self) -> Result<Option<Divergence>, CpuError> {
+        let before = self.cpu.state();
+        let reference = self.run_reference(before);
+        self.cpu.step()?;
+        let actual = self.after.borrow_mut().take().expect("The after instruction hook was removed.");
+
+        let Some((initial, stepped)) = reference else {
+            self.skipped += 1;
+            return Ok(None);
+        };
+        self.compared += 1;
+
+        let expected = CpuState {
+            register_a: stepped.a,
+            register_x: stepped.x,
+            register_y: stepped.y,
+            stack_pointer: stepped.sp,
+            status: stepped.status,
+            program_counter: stepped.pc,
+        };
+        let mut memory: Vec<(u16, u8, Option<u8>)> = stepped.memory.iter()
+            .filter(|(addr, val)| initial.memory.get(addr) != Some(val))
+            .map(|(addr, val)| (*addr, *val, self.cpu.memory().probe(*addr)))
+            .filter(|(_, expected, actual)| *actual != Some(*expected))
+            .collect();
+        memory.sort();
+
+        if expected == actual && memory.is_empty() {
+            return Ok(None);
+        }
+        let mut code = SparseMemory::new();
+        code.data.extend(initial.memory.iter());
+        let (instruction, _) = disassemble(&code, before.program_counter);
+        Ok(Some(Divergence { pc: before.program_counter, instruction, before, expected, actual, memory }))
+    }
+
+    // Steps until the first divergence, or at most 'instructions' times.
+    pub fn run(&mut self, instructions: u64) -> Result<Option<Divergence>, CpuError> {
+        for _ in 0..instructions {
+            if let Some(divergence) = self.step()? {
+                return Ok(Some(divergence));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::ArrayBus;
+
+    #[test]
+    fn test_lockstep() {
+        let mut bus = ArrayBus::new();
+        // lda #$05, sta $10, inc $10, jsr $0220, nop (unofficial), jmp $0200
+        bus.load(0x0200, &[0xa9, 0x05, 0x85, 0x10, 0xe6, 0x10, 0x20, 0x20, 0x02, 0x1a, 0x4c, 0x00, 0x02]);
+        bus.load(0x0220, &[0xa6, 0x10, 0x60]); // ldx $10, rts
+        let mut cpu = CPU::new(bus, false);
+        cpu.program_counter = 0x0200;
+
+        let mut lockstep = Lockstep::new(cpu);
+        assert_eq!(lockstep.run(80), Ok(None));
+        assert_eq!((lockstep.compared, lockstep.skipped), (70, 10));
+        assert_eq!(lockstep.cpu().register_x, 0x06);
+    }
+
+    // ArrayBus, except that probing 0x0010 gives a different value than reading it.
+    struct LyingBus(ArrayBus);
+
+    impl Mem for LyingBus {
+        fn size() -> usize { ArrayBus::size() }
+        fn read(&mut self, addr: u16) -> u8 { self.0.read(addr) }
+        fn write(&mut self, addr: u16, val: u8) { self.0.write(addr, val) }
+        fn probe(&self, addr: u16) -> Option<u8> {
+            self.0.probe(addr).map(|val| if addr == 0x0010 { val.wrapping_add(1) } else { val })
+        }
+    }
+
+    #[test]
+    fn test_lockstep_divergence() {
+        let mut bus = LyingBus(ArrayBus::new());
+        bus.0.load(0x0200, &[0xe8, 0xa5, 0x10, 0x85, 0x11]); // inx, lda $10, sta $11
+        let mut cpu = CPU::new(bus, false);
+        cpu.program_counter = 0x0200;
+
+        let mut lockstep = Lockstep::new(cpu);
+        let divergence = lockstep.run(3).unwrap().unwrap();
+        assert_eq!(divergence.pc, 0x0201);
+        assert_eq!(divergence.instruction, "$0201  A5 10     LDA $10");
+        assert_eq!((divergence.expected.register_a, divergence.actual.register_a), (0x01, 0x00));
+        assert!(divergence.memory.is_empty());
+        assert_eq!(divergence.to_string(), [
+            "$0201  A5 10     LDA $10",
+            "  before:   A:00 X:01 Y:00 P:20 SP:FF PC:0201",
+            "  expected: A:01 X:01 Y:00 P:20 SP:FF PC:0203",
+            "  actual:   A:00 X:01 Y:00 P:22 SP:FF PC:0203",
+        ].join("\n"));
+    }
+}
@@ -1,32 +1,228 @@
-mod cpu;
-mod bus;
-mod rom;
+use std::{env, fs, io};
+use std::ops::RangeInclusive;
 
 use config::Config;
 
-use crate::cpu::cpu::{CPU};
-use crate::bus::{Mem, RomBus};
-use crate::rom::{rom_reader};
+use nes::cpu::cpu::{Cpu, IllegalOpcodePolicy, Region, CPU};
+use nes::bus::{Mem, RamPattern, RomBus};
+use nes::rom::{rom_reader};
+use nes::ppu::StubPpu;
+use nes::disasm::disassemble_range;
+
+const DEFAULT_CARTRIDGE: &str = "./cartridges/nestest.nes";
+
+// The cartridge is the first argument that is not an option, or 'cartridge_path' from the config.
+fn cartridge_path(config: &Config) -> String {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--patch" | "--trace" | "--entry-point" | "--dump" | "--restore" => { args.next(); },
+            _ => return arg,
+        }
+    }
+    config.get_string("cartridge_path").unwrap_or(String::from(DEFAULT_CARTRIDGE))
+}
+
+// Reads the IPS patch given with '--patch <file.ips>', if any.
+fn read_patch() -> Result<Option<Vec<u8>>, String> {
+    let args: Vec<String> = env::args().collect();
+    let Some(i) = args.iter().position(|arg| arg == "--patch") else { return Ok(None) };
+
+    match args.get(i + 1) {
+        Some(path) => fs::read(path).map(Some).map_err(|e| format!("{} ({})", e, path)),
+        None => Err(String::from("--patch expects an .ips file")),
+    }
+}
+
+// Opens the file given with '--trace <file>', if any.
+fn open_trace() -> Result<Option<fs::File>, String> {
+    let args: Vec<String> = env::args().collect();
+    let Some(i) = args.iter().position(|arg| arg == "--trace") else { return Ok(None) };
+
+    match args.get(i + 1) {
+        Some(path) => fs::File::create(path).map(Some).map_err(|e| format!("{} ({})", e, path)),
+        None => Err(String::from("--trace expects a file to write to")),
+    }
+}
+
+// 'disasm <cartridge>' prints the PRG ROM as assembly instead of running it.
+fn disasm(path: Option<&String>) {
+    let Some(path) = path else { return println!("ERR:\tdisasm expects a cartridge") };
+    match rom_reader(path, None) {
+        Ok(rom) => {
+            let mut bus = RomBus::new();
+            bus.set_rom(rom);
+            for line in disassemble_range(&bus, 0x8000, 0xffff) {
+                println!("{}", line);
+            }
+        },
+        Err(e) => println!("ERR:\tRom loading failed ({})", e),
+    }
+}
+
+// Hex address, with or without a '$' or '0x' prefix.
+fn parse_address(s: &str) -> Result<u16, String> {
+    let digits = s.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|e| format!("{} ({})", e, s))
+}
+
+// Address to start at instead of the reset vector, from '--entry-point <addr>' or 'entry_point'
+// in the config.
+fn entry_point(config: &Config) -> Result<Option<u16>, String> {
+    let args: Vec<String> = env::args().collect();
+    if let Some(i) = args.iter().position(|arg| arg == "--entry-point") {
+        return match args.get(i + 1) {
+            Some(addr) => parse_address(addr).map(Some),
+            None => Err(String::from("--entry-point expects an address")),
+        };
+    }
+    config_address(config, "entry_point")
+}
+
+// Address under 'key' in the config, if set.
+fn config_address(config: &Config, key: &str) -> Result<Option<u16>, String> {
+    // YAML numbers like 0xC000 are read as integers.
+    if let Ok(addr) = config.get_int(key) {
+        return u16::try_from(addr).map(Some).map_err(|e| format!("{} ({})", e, addr));
+    }
+    match config.get_string(key) {
+        Ok(addr) => parse_address(&addr).map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+// Power-on RAM contents, from 'ram_init' in the config. 'random' takes its seed from 'ram_seed',
+// or a new one every run.
+fn ram_pattern(config: &Config) -> Result<RamPattern, String> {
+    match config.get_string("ram_init").as_deref() {
+        Ok("zeros") | Err(_) => Ok(RamPattern::Zeros),
+        Ok("ff") => Ok(RamPattern::Ones),
+        Ok("alternating") => Ok(RamPattern::AlternatingPages),
+        Ok("random") => {
+            let seed = config.get_int("ram_seed").ok().and_then(|seed| u64::try_from(seed).ok());
+            Ok(RamPattern::Random(seed.unwrap_or_else(rand::random)))
+        },
+        Ok(pattern) => Err(format!("unknown pattern {}", pattern)),
+    }
+}
+
+// Address range of a dump: 'ram' (the internal RAM), 'prg-ram', or '<start>-<end>' in hex.
+fn parse_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    match s {
+        "ram" => Ok(0x0000..=0x07ff),
+        "prg-ram" => Ok(0x6000..=0x7fff),
+        _ => {
+            let Some((start, end)) = s.split_once('-') else { return Err(format!("invalid range {}", s)) };
+            Ok(parse_address(start)?..=parse_address(end)?)
+        },
+    }
+}
+
+// The '<x>:<file>' argument given with 'option', if any.
+fn file_option(option: &str) -> Result<Option<(String, String)>, String> {
+    let args: Vec<String> = env::args().collect();
+    let Some(i) = args.iter().position(|arg| arg == option) else { return Ok(None) };
+
+    match args.get(i + 1).and_then(|arg| arg.split_once(':')) {
+        Some((x, path)) => Ok(Some((x.to_string(), path.to_string()))),
+        None => Err(format!("{} expects <range or address>:<file>", option)),
+    }
+}
+
+// '--restore <addr>:<file>' loads a dump (e.g. of the RAM) from 'addr' before starting.
+fn restore_dump(bus: &mut RomBus) -> Result<(), String> {
+    let Some((addr, path)) = file_option("--restore")? else { return Ok(()) };
+    let data = fs::read(&path).map_err(|e| format!("{} ({})", e, path))?;
+    bus.restore(parse_address(&addr)?, &data);
+    println!("INFO\tRestored {} bytes from {}", data.len(), path);
+    Ok(())
+}
+
+// '--dump <range>:<file>' writes the range to a binary file once execution stops.
+fn write_dump(bus: &RomBus) -> Result<(), String> {
+    let Some((range, path)) = file_option("--dump")? else { return Ok(()) };
+    let range = parse_range(&range)?;
+    fs::write(&path, bus.dump(range)).map_err(|e| format!("{} ({})", e, path))?;
+    println!("INFO\tDumped to {}", path);
+    Ok(())
+}
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).is_some_and(|arg| arg == "disasm") {
+        return disasm(args.get(2));
+    }
 
     let config = Config::builder()
         .add_source(config::File::with_name("./config.yaml"))
         .build()
         .unwrap();
 
-    match rom_reader() {
+    let path = cartridge_path(&config);
+    match read_patch().and_then(|patch| rom_reader(&path, patch.as_deref())) {
         Ok(rom) => {
             println!("{:?}", rom.prg_read(0x8000));
             println!("INFO\tSuccessful initialization");
             let mut bus = RomBus::new();
             bus.set_rom(rom);
+            // Extra blank scanlines per frame, for overclocking. The cpu clock follows the PPU.
+            let extra_scanlines = config.get_int("overclock_scanlines").ok().and_then(|n| u16::try_from(n).ok()).unwrap_or(0);
+            let mut ppu = StubPpu::new();
+            ppu.set_extra_scanlines(extra_scanlines);
+            bus.set_ppu(Box::new(ppu));
+            match ram_pattern(&config) {
+                Ok(pattern) => bus.fill_ram(pattern),
+                Err(e) => println!("WARN\tRAM cleared to zeros ({})", e),
+            }
+            if let Err(e) = restore_dump(&mut bus) {
+                println!("WARN\tDump not restored ({})", e);
+            }
+            // Characters written to this address are printed, for homebrew diagnostics.
+            match config_address(&config, "debug_port") {
+                Ok(port) => bus.set_debug_port(port),
+                Err(e) => println!("WARN\tDebug port disabled ({})", e),
+            }
 
             let debug = config.get_bool("debug").unwrap();
             println!("NFO\tDebug: {:?}", debug);
 
             let mut cpu = CPU::<RomBus>::new(bus, debug);
-            cpu.start();
+            match config.get_string("region").as_deref() {
+                Ok("pal") => cpu.set_region(Region::Pal),
+                Ok("ntsc") | Err(_) => cpu.set_region(Region::Ntsc),
+                Ok(region) => println!("WARN\tUnknown region {}, using ntsc", region),
+            }
+            match config.get_string("illegal_opcodes").as_deref() {
+                Ok("break") | Err(_) => (),
+                Ok("halt") => cpu.set_illegal_opcode_policy(IllegalOpcodePolicy::Halt),
+                Ok("nop") => cpu.set_illegal_opcode_policy(IllegalOpcodePolicy::Nop),
+                Ok("panic") => cpu.set_illegal_opcode_policy(IllegalOpcodePolicy::Panic),
+                Ok(policy) => println!("WARN\tUnknown illegal opcode policy {}, using break", policy),
+            }
+            // 0 runs as fast as possible.
+            if let Ok(hz) = config.get_int("clock_hz") {
+                cpu.set_clock_speed(u64::try_from(hz).ok().filter(|&hz| hz != 0));
+            }
+            match open_trace() {
+                Ok(Some(file)) => cpu.set_trace(Some(Box::new(io::BufWriter::new(file)))),
+                Ok(None) => (),
+                Err(e) => println!("WARN\tTracing disabled ({})", e),
+            }
+            let result = match entry_point(&config) {
+                Ok(Some(addr)) => {
+                    println!("INFO\tStarting at {:#06x}", addr);
+                    cpu.start_at(addr)
+                },
+                Ok(None) => cpu.start(),
+                Err(e) => return println!("ERR:\tInvalid entry point {}", e),
+            };
+            match result {
+                Ok(result) => println!("INFO\tExecution stopped ({:?})", result),
+                Err(e) => println!("ERR:\tExecution stopped ({})", e),
+            }
+            if let Err(e) = write_dump(cpu.bus()) {
+                println!("ERR:\tDump failed ({})", e);
+            }
         },
         Err(e) => {
             println!("ERR:\tRom loading failed ({}), starting without rom...", e);
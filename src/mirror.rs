@@ -0,0 +1,68 @@
+// Address translation for the mirrored parts of the address spaces: the partially decoded
+// addresses repeat the same memory or registers over a larger range.
+// See: https://www.nesdev.org/wiki/Mirroring
+
+// The 2KB internal RAM is mirrored three times in 0x0800..=0x1fff.
+pub fn ram_index(addr: u16) -> usize {
+    addr as usize & 0x07ff
+}
+
+// The 8 PPU registers are mirrored every 8 bytes in 0x2000..=0x3fff. Returns the register number
+// the Ppu trait expects.
+pub fn ppu_register(addr: u16) -> u8 {
+    (addr & 0x0007) as u8
+}
+
+// Index into the 2KB of nametable RAM for a PPU address in 0x2000..=0x3eff, with vertical
+// mirroring: $2000 and $2800 share a nametable, $2400 and $2c00 the other one. 0x3000..=0x3eff
+// mirrors 0x2000..=0x2eff.
+pub fn nametable_index(addr: u16) -> usize {
+    addr as usize & 0x07ff
+}
+
+// Index into the 32 bytes of palette RAM for a PPU address in 0x3f00..=0x3fff. The palette is
+// mirrored every 32 bytes, and the background color of the sprite palettes ($3f10, $3f14, $3f18,
+// $3f1c) is the one of the background palettes.
+pub fn palette_index(addr: u16) -> usize {
+    let index = addr as usize & 0x1f;
+    if index & 0x13 == 0x10 { index & 0x0f } else { index }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ram_mirroring() {
+        for addr in [0x0123, 0x0923, 0x1123, 0x1923] {
+            assert_eq!(ram_index(addr), 0x0123);
+        }
+        assert_eq!(ram_index(0x1fff), 0x07ff);
+    }
+
+    #[test]
+    fn test_ppu_register_mirroring() {
+        assert_eq!(ppu_register(0x2000), 0);
+        assert_eq!(ppu_register(0x2007), 7);
+        assert_eq!(ppu_register(0x2008), 0);
+        assert_eq!(ppu_register(0x3ffe), 6);
+    }
+
+    #[test]
+    fn test_nametable_mirroring() {
+        assert_eq!(nametable_index(0x2000), nametable_index(0x2800));
+        assert_eq!(nametable_index(0x2400), nametable_index(0x2c00));
+        assert_ne!(nametable_index(0x2000), nametable_index(0x2400));
+        assert_eq!(nametable_index(0x3123), nametable_index(0x2123));
+    }
+
+    #[test]
+    fn test_palette_mirroring() {
+        assert_eq!(palette_index(0x3f00), 0x00);
+        assert_eq!(palette_index(0x3f10), 0x00);
+        assert_eq!(palette_index(0x3f14), 0x04);
+        assert_eq!(palette_index(0x3f11), 0x11); // only the background colors are shared
+        assert_eq!(palette_index(0x3f3c), 0x0c);
+        assert_eq!(palette_index(0x3fff), 0x1f);
+    }
+}
@@ -0,0 +1,166 @@
+// The console: the cpu, with the bus, the cartridge and the PPU behind it.
+
+use std::cell::Cell;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::bus::{Mem, RomBus};
+use crate::cpu::cpu::{Cpu, CpuError, StopReason, CPU};
+use crate::event_bus::EventKind;
+use crate::ppu::StubPpu;
+use crate::rom::{rom_reader, Rom};
+
+// Test roms following the convention of blargg's tests write their status here: 0x80 while
+// running, then 0x00 if they passed, or a failure code.
+const TEST_STATUS: u16 = 0x6000;
+const TEST_RUNNING: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestResult {
+    Pass,
+    Fail(u8),
+    // The test didn't finish within the number of frames.
+    Timeout,
+}
+
+pub struct Nes {
+    cpu: CPU<RomBus>,
+    // Frames completed since power on, counted from the FrameComplete events of the PPU.
+    frames: Rc<Cell<u64>>,
+}
+
+impl Nes {
+    // Powers on with 'rom' inserted.
+    pub fn new(rom: Box<dyn Rom>) -> Self {
+        let mut bus = RomBus::new();
+        bus.set_rom(rom);
+        bus.set_ppu(Box::new(StubPpu::new()));
+        let frames = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&frames);
+        bus.events().subscribe(EventKind::FrameComplete, move |_| counter.set(counter.get() + 1));
+
+        let mut cpu = CPU::new(bus, false);
+        cpu.power_on();
+        Nes { cpu, frames }
+    }
+
+    pub fn cpu(&self) -> &CPU<RomBus> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<RomBus> {
+        &mut self.cpu
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames.get()
+    }
+
+    // Runs until the PPU completes the next frame. Illegal opcodes and KIL stop it early, with
+    // the reason.
+    pub fn run_frame(&mut self) -> Result<StopReason, CpuError> {
+        let frame = self.frames.get();
+        let frames = Rc::clone(&self.frames);
+        let result = self.cpu.run_until(move |_| frames.get() != frame)?;
+        Ok(result.stop_reason)
+    }
+
+    // Runs the test rom at 'path' for at most 'max_frames' frames, polling its status once per
+    // frame. See run_test.
+    pub fn run_test_rom(path: &Path, max_frames: u32) -> Result<TestResult, String> {
+        let rom = rom_reader(path, None)?;
+        Nes::new(rom).run_test(max_frames)
+    }
+
+    // Same as run_test_rom, with the message the test left at $6004 (empty if there is none).
+    pub fn run_test_rom_with_text(path: &Path, max_frames: u32) -> Result<(TestResult, String), String> {
+        let rom = rom_reader(path, None)?;
+        let mut nes = Nes::new(rom);
+        let result = nes.run_test(max_frames)?;
+        Ok((result, nes.cpu.bus().test_rom_text().unwrap_or_default()))
+    }
+
+    // Runs the inserted test rom until it reports its result at $6000. The status only counts
+    // once the test reported that it is running, the RAM is cleared before that. Errors of the
+    // cpu (and illegal opcodes or KIL, which stop it) fail the run.
+    pub fn run_test(&mut self, max_frames: u32) -> Result<TestResult, String> {
+        let mut running = false;
+        for _ in 0..max_frames {
+            match self.run_frame() {
+                Ok(StopReason::ConditionMet) => (),
+                Ok(reason) => return Err(format!("Execution stopped ({:?})", reason)),
+                Err(e) => return Err(format!("Execution stopped ({})", e)),
+            }
+            match self.cpu.bus().probe(TEST_STATUS) {
+                Some(TEST_RUNNING) => running = true,
+                Some(0x00) if running => return Ok(TestResult::Pass),
+                Some(code) if running => return Ok(TestResult::Fail(code)),
+                _ => (),
+            }
+        }
+        Ok(TestResult::Timeout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::rom_from_bytes;
+    use crate::test_rom::TestRomBuilder;
+
+    // A test rom that reports running, waits for 'frames' vblanks, and then writes 'code' to $6000
+    // and the message "ok" to $6004.
+    fn test_rom(frames: u8, code: u8) -> Vec<u8> {
+        let mut prg: Vec<u8> = vec![
+            0xa9, 0x80, 0x8d, 0x00, 0x60, // lda #$80, sta $6000
+            0xa2, frames,                 // ldx #frames
+            0x2c, 0x02, 0x20,             // wait: bit $2002
+            0x10, 0xfb,                   // bpl wait
+            0xca,                         // dex
+            0xd0, 0xf8,                   // bne wait
+        ];
+        for (i, val) in [0xde, 0xb0, 0x61, b'o', b'k', 0x00].iter().enumerate() {
+            prg.extend([0xa9, *val, 0x8d, 0x01 + i as u8, 0x60]); // lda #val, sta $6001+i
+        }
+        prg.extend([0xa9, code, 0x8d, 0x00, 0x60]); // lda #code, sta $6000
+        prg.extend([0x4c, prg.len() as u8, 0x80]); // jmp * (prg fits in a page)
+        prg.resize(0x3ffc, 0);
+        prg.extend([0x00, 0x80]); // reset vector
+        TestRomBuilder::new().prg_data(&prg).build()
+    }
+
+    fn nes(raw: &[u8]) -> Nes {
+        Nes::new(rom_from_bytes(raw, None).unwrap())
+    }
+
+    #[test]
+    fn test_run_test() {
+        assert_eq!(nes(&test_rom(3, 0x00)).run_test(10), Ok(TestResult::Pass));
+        assert_eq!(nes(&test_rom(3, 0x02)).run_test(10), Ok(TestResult::Fail(0x02)));
+        assert_eq!(nes(&test_rom(30, 0x00)).run_test(10), Ok(TestResult::Timeout));
+
+        let mut nes = nes(&test_rom(3, 0x00));
+        nes.run_frame().unwrap();
+        assert_eq!(nes.frames(), 1);
+        assert_eq!(nes.cpu().bus().probe(TEST_STATUS), Some(TEST_RUNNING));
+    }
+
+    #[test]
+    fn test_run_test_rom_with_text() {
+        let path = std::env::temp_dir().join(format!("res_run_test_rom_{}.nes", std::process::id()));
+        std::fs::write(&path, test_rom(2, 0x03)).unwrap();
+        let result = Nes::run_test_rom_with_text(&path, 10);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result, Ok((TestResult::Fail(0x03), String::from("ok"))));
+
+        assert!(Nes::run_test_rom(Path::new("missing.nes"), 10).is_err());
+    }
+
+    // blargg's instr_test-v5 (official_only.nes runs the tests of the official instructions).
+    #[test]
+    #[ignore = "needs cartridges/official_only.nes"]
+    fn test_blargg_instr_test() {
+        let (result, text) = Nes::run_test_rom_with_text(Path::new("./cartridges/official_only.nes"), 3000).unwrap();
+        assert_eq!(result, TestResult::Pass, "{}", text);
+    }
+}
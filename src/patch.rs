@@ -0,0 +1,108 @@
+use std::fmt;
+
+// IPS patches start with the "PATCH" magic, followed by records and the "EOF" terminator.
+// A record is a 3 byte offset and a 2 byte length followed by the data to write. A record with
+// length 0 is run-length encoded: a 2 byte repeat count and the byte to repeat.
+// All numbers are big endian.
+const MAGIC: &[u8] = b"PATCH";
+const EOF: &[u8] = b"EOF";
+
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    InvalidMagic,
+    InvalidEof,
+    OffsetOutOfBounds(usize),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::InvalidMagic => write!(f, "IPS patch does not start with PATCH."),
+            PatchError::InvalidEof => write!(f, "IPS patch ended before the EOF marker."),
+            PatchError::OffsetOutOfBounds(offset) => write!(f, "IPS record at {:#x} is outside of the rom.", offset),
+        }
+    }
+}
+
+// Reads 'len' bytes at 'pos' as a big endian number.
+fn read_be(patch: &[u8], pos: usize, len: usize) -> Result<usize, PatchError> {
+    match patch.get(pos..pos + len) {
+        Some(bytes) => Ok(bytes.iter().fold(0, |acc, b| (acc << 8) | *b as usize)),
+        None => Err(PatchError::InvalidEof),
+    }
+}
+
+pub fn apply_ips_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(MAGIC) { return Err(PatchError::InvalidMagic); }
+
+    let mut patched: Vec<u8> = rom.to_vec();
+    let mut pos: usize = MAGIC.len();
+
+    loop {
+        if patch.get(pos..pos + EOF.len()) == Some(EOF) { return Ok(patched); }
+
+        let offset = read_be(patch, pos, 3)?;
+        let length = read_be(patch, pos + 3, 2)?;
+        pos += 5;
+
+        let data: Vec<u8> = if length == 0 {
+            let count = read_be(patch, pos, 2)?;
+            let val = read_be(patch, pos + 2, 1)? as u8;
+            pos += 3;
+            vec![val; count]
+        } else {
+            let data = patch.get(pos..pos + length).ok_or(PatchError::InvalidEof)?;
+            pos += length;
+            data.to_vec()
+        };
+
+        match patched.get_mut(offset..offset + data.len()) {
+            Some(target) => target.copy_from_slice(&data),
+            None => return Err(PatchError::OffsetOutOfBounds(offset)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_records() {
+        let rom: Vec<u8> = vec![0; 16];
+        let mut patch: Vec<u8> = b"PATCH".to_vec();
+        patch.extend([0x00, 0x00, 0x02, 0x00, 0x03, 0xaa, 0xbb, 0xcc]); // 3 bytes at 0x02
+        patch.extend([0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x04, 0x11]); // 0x11 repeated 4 times at 0x0a
+        patch.extend(b"EOF");
+
+        let patched = apply_ips_patch(&rom, &patch).unwrap();
+
+        assert_eq!(patched, vec![0, 0, 0xaa, 0xbb, 0xcc, 0, 0, 0, 0, 0, 0x11, 0x11, 0x11, 0x11, 0, 0]);
+    }
+
+    #[test]
+    fn test_empty_patch() {
+        let rom: Vec<u8> = (0..16).collect();
+        assert_eq!(apply_ips_patch(&rom, b"PATCHEOF").unwrap(), rom);
+    }
+
+    #[test]
+    fn test_invalid_magic() {
+        assert_eq!(apply_ips_patch(&[0; 16], b"PATHCEOF"), Err(PatchError::InvalidMagic));
+    }
+
+    #[test]
+    fn test_missing_eof() {
+        let patch: Vec<u8> = [b"PATCH".as_slice(), &[0x00, 0x00, 0x02, 0x00, 0x01, 0xaa]].concat();
+        assert_eq!(apply_ips_patch(&[0; 16], &patch), Err(PatchError::InvalidEof));
+
+        let truncated: Vec<u8> = [b"PATCH".as_slice(), &[0x00, 0x00, 0x02, 0x00, 0x04, 0xaa], b"EOF"].concat();
+        assert_eq!(apply_ips_patch(&[0; 16], &truncated), Err(PatchError::InvalidEof));
+    }
+
+    #[test]
+    fn test_offset_out_of_bounds() {
+        let patch: Vec<u8> = [b"PATCH".as_slice(), &[0x00, 0x00, 0x0f, 0x00, 0x02, 0xaa, 0xbb], b"EOF"].concat();
+        assert_eq!(apply_ips_patch(&[0; 16], &patch), Err(PatchError::OffsetOutOfBounds(0x0f)));
+    }
+}
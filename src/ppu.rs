@@ -0,0 +1,366 @@
+use crate::event_bus::Event;
+use crate::mirror::{nametable_index, palette_index};
+
+// The PPU is connected to the bus through 8 registers, mirrored in 0x2000..=0x3fff.
+// See: https://www.nesdev.org/wiki/PPU_registers
+pub const PPUCTRL: u8 = 0;
+pub const PPUMASK: u8 = 1;
+pub const PPUSTATUS: u8 = 2;
+pub const OAMADDR: u8 = 3;
+pub const OAMDATA: u8 = 4;
+pub const PPUSCROLL: u8 = 5;
+pub const PPUADDR: u8 = 6;
+pub const PPUDATA: u8 = 7;
+
+const VBLANK_FLAG: u8 = 0b1000_0000;
+// PPUCTRL bit that enables the NMI at the start of vblank.
+const NMI_ENABLE: u8 = 0b1000_0000;
+// PPUCTRL bit that makes PPUDATA accesses step the address by 32 (a row) instead of 1.
+const VRAM_INCREMENT_32: u8 = 0b0000_0100;
+
+// A frame is 341 dots on 262 scanlines, and the PPU draws 3 dots per cpu cycle.
+const DOTS_PER_SCANLINE: u64 = 341;
+const SCANLINES_PER_FRAME: u64 = 262;
+// Scanlines from the start of vblank to the pre-render scanline, where it ends.
+const VBLANK_SCANLINES: u64 = 20;
+const DOTS_PER_CPU_CYCLE: u64 = 3;
+
+pub trait Ppu {
+    // 'reg' is the register number (0..=7), not the address.
+    fn read_register(&mut self, reg: u8) -> u8;
+    fn write_register(&mut self, reg: u8, val: u8);
+    // Called with the number of cpu cycles that passed, see Mem::tick. The PPU draws 3 dots per
+    // cycle.
+    fn tick(&mut self, _cycles: u16) {}
+    // Reports (and clears) an NMI raised since the previous call.
+    fn poll_nmi(&mut self) -> bool { false }
+    // Events since the previous call (e.g. VblankStarted), for the bus to publish.
+    fn take_events(&mut self) -> Vec<Event> { Vec::new() }
+    // Blank scanlines added to every frame for overclocking, and whether they are being drawn. The
+    // cpu runs faster to make up for them, the APU stands still meanwhile.
+    fn extra_scanlines(&self) -> u16 { 0 }
+    fn in_extra_scanlines(&self) -> bool { false }
+}
+
+// Placeholder until rendering is implemented. It keeps the state behind the registers (OAM, VRAM,
+// palette, scroll and address latches), and raises the vblank flag (and the NMI, if enabled in
+// PPUCTRL) once per frame, so that games waiting for vblank can get past it.
+// The pattern tables are 8KB of RAM until the cartridge CHR is connected to the PPU, and the
+// nametables are mirrored vertically.
+// See: https://www.nesdev.org/wiki/PPU_memory_map
+pub struct StubPpu {
+    dots: u64,
+    // Blank scanlines added to every frame after the NMI, see set_extra_scanlines.
+    extra_scanlines: u64,
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    nmi: bool,
+    in_vblank: bool,
+    events: Vec<Event>,
+    oam_addr: u8,
+    oam: [u8; 0x100],
+    // VRAM address, and the temporary one PPUSCROLL and PPUADDR write to.
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    // First or second write to PPUSCROLL / PPUADDR. Reset by reading PPUSTATUS.
+    w: bool,
+    // PPUDATA reads return the value read by the previous one, except in the palette.
+    read_buffer: u8,
+    chr: [u8; 0x2000],
+    nametables: [u8; 0x0800],
+    palette: [u8; 0x20],
+}
+
+impl Default for StubPpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StubPpu {
+    pub fn new() -> Self {
+        Self {
+            dots: 0,
+            extra_scanlines: 0,
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            nmi: false,
+            in_vblank: false,
+            events: Vec::new(),
+            oam_addr: 0,
+            oam: [0; 0x100],
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
+            read_buffer: 0,
+            chr: [0; 0x2000],
+            nametables: [0; 0x0800],
+            palette: [0; 0x20],
+        }
+    }
+
+    pub fn oam(&self) -> &[u8; 0x100] {
+        &self.oam
+    }
+
+    // The VRAM address and fine x scroll, as set through PPUSCROLL and PPUADDR.
+    pub fn scroll(&self) -> (u16, u8) {
+        (self.t, self.fine_x)
+    }
+
+    // Reads the PPU address space (0x0000..=0x3fff, mirrored above) without side effects.
+    pub fn vram_read(&self, addr: u16) -> u8 {
+        match addr & 0x3fff {
+            addr @ 0..=0x1fff => self.chr[addr as usize],
+            addr @ 0x2000..=0x3eff => self.nametables[nametable_index(addr)],
+            addr => self.palette[palette_index(addr)],
+        }
+    }
+
+    fn vram_write(&mut self, addr: u16, val: u8) {
+        match addr & 0x3fff {
+            addr @ 0..=0x1fff => self.chr[addr as usize] = val,
+            addr @ 0x2000..=0x3eff => self.nametables[nametable_index(addr)] = val,
+            addr => self.palette[palette_index(addr)] = val,
+        }
+    }
+
+    fn increment_v(&mut self) {
+        let step = if self.ctrl & VRAM_INCREMENT_32 != 0 { 32 } else { 1 };
+        self.v = self.v.wrapping_add(step) & 0x3fff;
+    }
+
+    // Overclocking: makes every frame longer by 'scanlines' blank lines right after the NMI, so the
+    // game gets that many more cpu cycles per frame. The cpu clock follows (see CPU::clock_hz) to
+    // keep the frame rate.
+    pub fn set_extra_scanlines(&mut self, scanlines: u16) {
+        self.extra_scanlines = scanlines as u64;
+    }
+
+    fn dots_per_frame(&self) -> u64 {
+        (SCANLINES_PER_FRAME + self.extra_scanlines) * DOTS_PER_SCANLINE
+    }
+}
+
+impl Ppu for StubPpu {
+    fn read_register(&mut self, reg: u8) -> u8 {
+        match reg {
+            // Reading the status clears the vblank flag and the write toggle.
+            PPUSTATUS => {
+                self.w = false;
+                std::mem::take(&mut self.status)
+            },
+            OAMDATA => self.oam[self.oam_addr as usize],
+            PPUDATA => {
+                let addr = self.v;
+                let val = self.vram_read(addr);
+                self.increment_v();
+                if addr >= 0x3f00 {
+                    // The buffer gets the nametable byte under the palette instead.
+                    self.read_buffer = self.vram_read(addr - 0x1000);
+                    val
+                } else {
+                    std::mem::replace(&mut self.read_buffer, val)
+                }
+            },
+            _ => 0, // write only
+        }
+    }
+
+    fn write_register(&mut self, reg: u8, val: u8) {
+        match reg {
+            PPUCTRL => {
+                // Enabling the NMI during vblank raises it immediately.
+                if val & NMI_ENABLE != 0 && self.ctrl & NMI_ENABLE == 0 && self.status & VBLANK_FLAG != 0 {
+                    self.nmi = true;
+                }
+                self.ctrl = val;
+                self.t = (self.t & !0x0c00) | ((val as u16 & 0b11) << 10);
+            },
+            PPUMASK => self.mask = val,
+            OAMADDR => self.oam_addr = val,
+            OAMDATA => {
+                self.oam[self.oam_addr as usize] = val;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            },
+            // The bits of t are laid out as yyy NN YYYYY XXXXX (fine y, nametable, coarse y, coarse x).
+            // See: https://www.nesdev.org/wiki/PPU_scrolling
+            PPUSCROLL => {
+                if !self.w {
+                    self.t = (self.t & !0x001f) | (val as u16 >> 3);
+                    self.fine_x = val & 0b111;
+                } else {
+                    self.t = (self.t & !0x73e0) | ((val as u16 & 0b111) << 12) | ((val as u16 >> 3) << 5);
+                }
+                self.w = !self.w;
+            },
+            PPUADDR => {
+                if !self.w {
+                    self.t = (self.t & 0x00ff) | ((val as u16 & 0x3f) << 8);
+                } else {
+                    self.t = (self.t & 0xff00) | val as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            },
+            PPUDATA => {
+                self.vram_write(self.v, val);
+                self.increment_v();
+            },
+            _ => {}, // PPUSTATUS is read only
+        }
+    }
+
+    fn tick(&mut self, cycles: u16) {
+        self.dots += cycles as u64 * DOTS_PER_CPU_CYCLE;
+        if self.dots >= self.dots_per_frame() {
+            self.dots -= self.dots_per_frame();
+            self.status |= VBLANK_FLAG;
+            self.nmi |= self.ctrl & NMI_ENABLE != 0;
+            self.in_vblank = true;
+            // Nothing is drawn yet, the frame is complete when vblank starts.
+            self.events.push(Event::VblankStarted);
+            self.events.push(Event::FrameComplete);
+        }
+        // The extra scanlines make vblank longer.
+        if self.in_vblank && self.dots >= (VBLANK_SCANLINES + self.extra_scanlines) * DOTS_PER_SCANLINE {
+            self.in_vblank = false;
+            self.status &= !VBLANK_FLAG;
+            self.events.push(Event::VblankEnded);
+        }
+    }
+
+    fn poll_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi)
+    }
+
+    fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn extra_scanlines(&self) -> u16 {
+        self.extra_scanlines as u16
+    }
+
+    fn in_extra_scanlines(&self) -> bool {
+        self.in_vblank && self.dots < self.extra_scanlines * DOTS_PER_SCANLINE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stub_vblank() {
+        let mut ppu = StubPpu::new();
+        assert_eq!(ppu.read_register(PPUSTATUS), 0);
+
+        // 29780 cycles is just short of a frame.
+        for _ in 0..(29780 / 4) { ppu.tick(4); }
+        assert_eq!(ppu.read_register(PPUSTATUS) & VBLANK_FLAG, 0);
+
+        ppu.tick(2);
+        assert_eq!(ppu.read_register(PPUSTATUS) & VBLANK_FLAG, VBLANK_FLAG);
+        assert_eq!(ppu.read_register(PPUSTATUS) & VBLANK_FLAG, 0); // cleared by the read
+        assert!(!ppu.poll_nmi()); // not enabled
+        assert_eq!(ppu.take_events(), [Event::VblankStarted, Event::FrameComplete]);
+
+        // Vblank ends 20 scanlines (6820 dots) later. It started 4 dots ago.
+        ppu.tick(2270);
+        assert!(ppu.take_events().is_empty());
+        ppu.tick(2);
+        assert_eq!(ppu.take_events(), [Event::VblankEnded]);
+    }
+
+    #[test]
+    fn test_stub_nmi() {
+        let mut ppu = StubPpu::new();
+        ppu.write_register(PPUCTRL, NMI_ENABLE);
+        ppu.tick(100);
+        assert!(!ppu.poll_nmi());
+
+        for _ in 0..(29781 / 100) { ppu.tick(100); }
+        assert!(ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
+
+        // Enabling it again while the vblank flag is still set.
+        ppu.write_register(PPUCTRL, 0);
+        ppu.write_register(PPUCTRL, NMI_ENABLE);
+        assert!(ppu.poll_nmi());
+    }
+
+    #[test]
+    fn test_ppudata() {
+        let mut ppu = StubPpu::new();
+        ppu.write_register(PPUADDR, 0x21);
+        ppu.write_register(PPUADDR, 0x08);
+        for val in [0x11, 0x22, 0x33] { ppu.write_register(PPUDATA, val); }
+        assert_eq!(ppu.vram_read(0x2109), 0x22);
+        assert_eq!(ppu.vram_read(0x2909), 0x22); // mirrored
+
+        // Reads are delayed by one through the buffer.
+        ppu.write_register(PPUADDR, 0x21);
+        ppu.write_register(PPUADDR, 0x08);
+        ppu.read_register(PPUDATA);
+        assert_eq!(ppu.read_register(PPUDATA), 0x11);
+        assert_eq!(ppu.read_register(PPUDATA), 0x22);
+
+        // Stepping by 32, into the palette, which isn't buffered.
+        ppu.write_register(PPUCTRL, VRAM_INCREMENT_32);
+        ppu.write_register(PPUADDR, 0x3f);
+        ppu.write_register(PPUADDR, 0x00);
+        ppu.write_register(PPUDATA, 0x0f);
+        assert_eq!(ppu.vram_read(0x3f20), 0x0f);
+        ppu.write_register(PPUADDR, 0x3f);
+        ppu.write_register(PPUADDR, 0x10);
+        assert_eq!(ppu.read_register(PPUDATA), 0x0f); // $3f10 mirrors $3f00
+    }
+
+    #[test]
+    fn test_oam_and_scroll() {
+        let mut ppu = StubPpu::new();
+        ppu.write_register(OAMADDR, 0xff);
+        ppu.write_register(OAMDATA, 0x12);
+        ppu.write_register(OAMDATA, 0x34); // wraps around
+        assert_eq!((ppu.oam()[0xff], ppu.oam()[0x00]), (0x12, 0x34));
+        ppu.write_register(OAMADDR, 0xff);
+        assert_eq!(ppu.read_register(OAMDATA), 0x12);
+
+        ppu.write_register(PPUCTRL, 0b01);
+        ppu.write_register(PPUSCROLL, 0x7d); // coarse x 15, fine x 5
+        ppu.write_register(PPUSCROLL, 0x5e); // coarse y 11, fine y 6
+        assert_eq!(ppu.scroll(), (0x656f, 5)); // 110 01 01011 01111
+
+        // Reading the status resets the write toggle.
+        ppu.write_register(PPUSCROLL, 0x00);
+        ppu.read_register(PPUSTATUS);
+        ppu.write_register(PPUSCROLL, 0x08);
+        assert_eq!(ppu.scroll(), (0x6561, 0)); // 110 01 01011 00001
+    }
+
+    #[test]
+    fn test_stub_extra_scanlines() {
+        let mut ppu = StubPpu::new();
+        ppu.write_register(PPUCTRL, NMI_ENABLE);
+        ppu.set_extra_scanlines(30);
+
+        // (262 + 30) * 341 / 3 = 33190.7 cycles per frame
+        for _ in 0..33190 { ppu.tick(1); }
+        assert!(!ppu.poll_nmi());
+        assert!(!ppu.in_extra_scanlines());
+        ppu.tick(1);
+        assert!(ppu.poll_nmi());
+
+        // 30 * 341 / 3 = 3410 cycles
+        assert!(ppu.in_extra_scanlines());
+        for _ in 0..3409 { ppu.tick(1); }
+        assert!(ppu.in_extra_scanlines());
+        ppu.tick(1);
+        assert!(!ppu.in_extra_scanlines());
+    }
+}
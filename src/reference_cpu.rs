@@ -0,0 +1,371 @@
+// Straightforward implementation of the official 6502 instruction set, following the 6502
+// documentation as literally as possible. It is only used to cross-validate the CPU in tests and
+// in lockstep mode, so it favours being obviously correct over being fast. Like the 2A03 in the
+// NES, it has no decimal mode.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX, // (d,x)
+    IndirectY, // (d),y
+    Relative,
+}
+
+const N: u8 = 0b1000_0000;
+const V: u8 = 0b0100_0000;
+const U: u8 = 0b0010_0000;
+const B: u8 = 0b0001_0000;
+const D: u8 = 0b0000_1000;
+const I: u8 = 0b0000_0100;
+const Z: u8 = 0b0000_0010;
+const C: u8 = 0b0000_0001;
+
+#[derive(Clone)]
+pub struct MOS6502Reference {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub status: u8,
+    pub pc: u16,
+    // Addresses that were never written read as 0xff, same as SparseMemory.
+    pub memory: HashMap<u16, u8>,
+    // Addresses read while they were missing from 'memory'.
+    pub missed_reads: RefCell<Vec<u16>>,
+}
+
+fn decode(opcode: u8) -> Option<(&'static str, Mode)> {
+    use Mode::*;
+    let decoded = match opcode {
+        0x69 => ("ADC", Immediate), 0x65 => ("ADC", ZeroPage), 0x75 => ("ADC", ZeroPageX), 0x6d => ("ADC", Absolute),
+        0x7d => ("ADC", AbsoluteX), 0x79 => ("ADC", AbsoluteY), 0x61 => ("ADC", IndirectX), 0x71 => ("ADC", IndirectY),
+        0x29 => ("AND", Immediate), 0x25 => ("AND", ZeroPage), 0x35 => ("AND", ZeroPageX), 0x2d => ("AND", Absolute),
+        0x3d => ("AND", AbsoluteX), 0x39 => ("AND", AbsoluteY), 0x21 => ("AND", IndirectX), 0x31 => ("AND", IndirectY),
+        0x0a => ("ASL", Accumulator), 0x06 => ("ASL", ZeroPage), 0x16 => ("ASL", ZeroPageX), 0x0e => ("ASL", Absolute),
+        0x1e => ("ASL", AbsoluteX),
+        0x90 => ("BCC", Relative), 0xb0 => ("BCS", Relative), 0xf0 => ("BEQ", Relative), 0x30 => ("BMI", Relative),
+        0xd0 => ("BNE", Relative), 0x10 => ("BPL", Relative), 0x50 => ("BVC", Relative), 0x70 => ("BVS", Relative),
+        0x24 => ("BIT", ZeroPage), 0x2c => ("BIT", Absolute),
+        0x00 => ("BRK", Implied),
+        0x18 => ("CLC", Implied), 0xd8 => ("CLD", Implied), 0x58 => ("CLI", Implied), 0xb8 => ("CLV", Implied),
+        0xc9 => ("CMP", Immediate), 0xc5 => ("CMP", ZeroPage), 0xd5 => ("CMP", ZeroPageX), 0xcd => ("CMP", Absolute),
+        0xdd => ("CMP", AbsoluteX), 0xd9 => ("CMP", AbsoluteY), 0xc1 => ("CMP", IndirectX), 0xd1 => ("CMP", IndirectY),
+        0xe0 => ("CPX", Immediate), 0xe4 => ("CPX", ZeroPage), 0xec => ("CPX", Absolute),
+        0xc0 => ("CPY", Immediate), 0xc4 => ("CPY", ZeroPage), 0xcc => ("CPY", Absolute),
+        0xc6 => ("DEC", ZeroPage), 0xd6 => ("DEC", ZeroPageX), 0xce => ("DEC", Absolute), 0xde => ("DEC", AbsoluteX),
+        0xca => ("DEX", Implied), 0x88 => ("DEY", Implied),
+        0x49 => ("EOR", Immediate), 0x45 => ("EOR", ZeroPage), 0x55 => ("EOR", ZeroPageX), 0x4d => ("EOR", Absolute),
+        0x5d => ("EOR", AbsoluteX), 0x59 => ("EOR", AbsoluteY), 0x41 => ("EOR", IndirectX), 0x51 => ("EOR", IndirectY),
+        0xe6 => ("INC", ZeroPage), 0xf6 => ("INC", ZeroPageX), 0xee => ("INC", Absolute), 0xfe => ("INC", AbsoluteX),
+        0xe8 => ("INX", Implied), 0xc8 => ("INY", Implied),
+        0x4c => ("JMP", Absolute), 0x6c => ("JMP", Indirect),
+        0x20 => ("JSR", Absolute),
+        0xa9 => ("LDA", Immediate), 0xa5 => ("LDA", ZeroPage), 0xb5 => ("LDA", ZeroPageX), 0xad => ("LDA", Absolute),
+        0xbd => ("LDA", AbsoluteX), 0xb9 => ("LDA", AbsoluteY), 0xa1 => ("LDA", IndirectX), 0xb1 => ("LDA", IndirectY),
+        0xa2 => ("LDX", Immediate), 0xa6 => ("LDX", ZeroPage), 0xb6 => ("LDX", ZeroPageY), 0xae => ("LDX", Absolute),
+        0xbe => ("LDX", AbsoluteY),
+        0xa0 => ("LDY", Immediate), 0xa4 => ("LDY", ZeroPage), 0xb4 => ("LDY", ZeroPageX), 0xac => ("LDY", Absolute),
+        0xbc => ("LDY", AbsoluteX),
+        0x4a => ("LSR", Accumulator), 0x46 => ("LSR", ZeroPage), 0x56 => ("LSR", ZeroPageX), 0x4e => ("LSR", Absolute),
+        0x5e => ("LSR", AbsoluteX),
+        0xea => ("NOP", Implied),
+        0x09 => ("ORA", Immediate), 0x05 => ("ORA", ZeroPage), 0x15 => ("ORA", ZeroPageX), 0x0d => ("ORA", Absolute),
+        0x1d => ("ORA", AbsoluteX), 0x19 => ("ORA", AbsoluteY), 0x01 => ("ORA", IndirectX), 0x11 => ("ORA", IndirectY),
+        0x48 => ("PHA", Implied), 0x08 => ("PHP", Implied), 0x68 => ("PLA", Implied), 0x28 => ("PLP", Implied),
+        0x2a => ("ROL", Accumulator), 0x26 => ("ROL", ZeroPage), 0x36 => ("ROL", ZeroPageX), 0x2e => ("ROL", Absolute),
+        0x3e => ("ROL", AbsoluteX),
+        0x6a => ("ROR", Accumulator), 0x66 => ("ROR", ZeroPage), 0x76 => ("ROR", ZeroPageX), 0x6e => ("ROR", Absolute),
+        0x7e => ("ROR", AbsoluteX),
+        0x40 => ("RTI", Implied), 0x60 => ("RTS", Implied),
+        0xe9 => ("SBC", Immediate), 0xe5 => ("SBC", ZeroPage), 0xf5 => ("SBC", ZeroPageX), 0xed => ("SBC", Absolute),
+        0xfd => ("SBC", AbsoluteX), 0xf9 => ("SBC", AbsoluteY), 0xe1 => ("SBC", IndirectX), 0xf1 => ("SBC", IndirectY),
+        0x38 => ("SEC", Implied), 0xf8 => ("SED", Implied), 0x78 => ("SEI", Implied),
+        0x85 => ("STA", ZeroPage), 0x95 => ("STA", ZeroPageX), 0x8d => ("STA", Absolute), 0x9d => ("STA", AbsoluteX),
+        0x99 => ("STA", AbsoluteY), 0x81 => ("STA", IndirectX), 0x91 => ("STA", IndirectY),
+        0x86 => ("STX", ZeroPage), 0x96 => ("STX", ZeroPageY), 0x8e => ("STX", Absolute),
+        0x84 => ("STY", ZeroPage), 0x94 => ("STY", ZeroPageX), 0x8c => ("STY", Absolute),
+        0xaa => ("TAX", Implied), 0xa8 => ("TAY", Implied), 0xba => ("TSX", Implied), 0x8a => ("TXA", Implied),
+        0x9a => ("TXS", Implied), 0x98 => ("TYA", Implied),
+        _ => return None,
+    };
+    Some(decoded)
+}
+
+// Whether the opcode is one of the 151 official instructions.
+#[cfg(test)]
+pub fn is_official(opcode: u8) -> bool {
+    decode(opcode).is_some()
+}
+
+impl MOS6502Reference {
+    pub fn new() -> Self {
+        Self { a: 0, x: 0, y: 0, sp: 0xff, status: U, pc: 0, memory: HashMap::new(), missed_reads: RefCell::new(Vec::new()) }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        self.memory.get(&addr).copied().unwrap_or_else(|| {
+            self.missed_reads.borrow_mut().push(addr);
+            0xff
+        })
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        self.memory.insert(addr, val);
+    }
+
+    fn read_u16(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.read(addr), self.read(addr.wrapping_add(1))])
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let val = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        val
+    }
+
+    fn fetch_u16(&mut self) -> u16 {
+        u16::from_le_bytes([self.fetch(), self.fetch()])
+    }
+
+    fn push(&mut self, val: u8) {
+        self.write(0x0100 | self.sp as u16, val);
+        self.sp = self.sp.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
+        self.read(0x0100 | self.sp as u16)
+    }
+
+    fn flag(&self, flag: u8) -> bool {
+        self.status & flag != 0
+    }
+
+    fn set(&mut self, flag: u8, val: bool) {
+        if val { self.status |= flag } else { self.status &= !flag }
+    }
+
+    fn set_nz(&mut self, val: u8) {
+        self.set(Z, val == 0);
+        self.set(N, val & 0x80 != 0);
+    }
+
+    // Zero page pointers wrap around within the zero page.
+    fn read_zero_page_u16(&self, addr: u8) -> u16 {
+        u16::from_le_bytes([self.read(addr as u16), self.read(addr.wrapping_add(1) as u16)])
+    }
+
+    fn address(&mut self, mode: Mode) -> u16 {
+        match mode {
+            Mode::Implied | Mode::Accumulator => 0,
+            Mode::Immediate => { let addr = self.pc; self.pc = self.pc.wrapping_add(1); addr },
+            Mode::ZeroPage => self.fetch() as u16,
+            Mode::ZeroPageX => self.fetch().wrapping_add(self.x) as u16,
+            Mode::ZeroPageY => self.fetch().wrapping_add(self.y) as u16,
+            Mode::Absolute => self.fetch_u16(),
+            Mode::AbsoluteX => self.fetch_u16().wrapping_add(self.x as u16),
+            Mode::AbsoluteY => self.fetch_u16().wrapping_add(self.y as u16),
+            Mode::Indirect => {
+                // The high byte is read from the same page when the pointer is at $xxff.
+                let ptr = self.fetch_u16();
+                let hi_ptr = (ptr & 0xff00) | (ptr.wrapping_add(1) & 0x00ff);
+                u16::from_le_bytes([self.read(ptr), self.read(hi_ptr)])
+            },
+            Mode::IndirectX => {
+                let ptr = self.fetch().wrapping_add(self.x);
+                self.read_zero_page_u16(ptr)
+            },
+            Mode::IndirectY => {
+                let ptr = self.fetch();
+                self.read_zero_page_u16(ptr).wrapping_add(self.y as u16)
+            },
+            Mode::Relative => {
+                let offset = self.fetch() as i8;
+                self.pc.wrapping_add(offset as u16)
+            },
+        }
+    }
+
+    fn add(&mut self, val: u8) {
+        let sum = self.a as u16 + val as u16 + self.flag(C) as u16;
+        let result = sum as u8;
+        self.set(C, sum > 0xff);
+        self.set(V, (self.a ^ result) & (val ^ result) & 0x80 != 0);
+        self.a = result;
+        self.set_nz(result);
+    }
+
+    fn compare(&mut self, reg: u8, val: u8) {
+        self.set(C, reg >= val);
+        self.set_nz(reg.wrapping_sub(val));
+    }
+
+    // Read-modify-write on the accumulator or memory.
+    fn modify(&mut self, mode: Mode, addr: u16, op: impl Fn(u8, bool) -> (u8, bool)) {
+        let old = match mode { Mode::Accumulator => self.a, _ => self.read(addr) };
+        let (new, carry) = op(old, self.flag(C));
+        match mode { Mode::Accumulator => self.a = new, _ => self.write(addr, new) };
+        self.set(C, carry);
+        self.set_nz(new);
+    }
+
+    fn branch(&mut self, condition: bool, target: u16) {
+        if condition { self.pc = target; }
+    }
+
+    // Executes one instruction. Returns false for opcodes that are not official instructions.
+    pub fn step(&mut self) -> bool {
+        let opcode = self.fetch();
+        let Some((mnemonic, mode)) = decode(opcode) else { return false };
+        let addr = self.address(mode);
+
+        match mnemonic {
+            "ADC" => { let val = self.read(addr); self.add(val) },
+            "SBC" => { let val = self.read(addr); self.add(!val) },
+            "AND" => { self.a &= self.read(addr); self.set_nz(self.a) },
+            "ORA" => { self.a |= self.read(addr); self.set_nz(self.a) },
+            "EOR" => { self.a ^= self.read(addr); self.set_nz(self.a) },
+            "ASL" => self.modify(mode, addr, |v, _| (v << 1, v & 0x80 != 0)),
+            "LSR" => self.modify(mode, addr, |v, _| (v >> 1, v & 0x01 != 0)),
+            "ROL" => self.modify(mode, addr, |v, c| ((v << 1) | c as u8, v & 0x80 != 0)),
+            "ROR" => self.modify(mode, addr, |v, c| ((v >> 1) | ((c as u8) << 7), v & 0x01 != 0)),
+            "BIT" => {
+                let val = self.read(addr);
+                self.set(Z, self.a & val == 0);
+                self.set(N, val & N != 0);
+                self.set(V, val & V != 0);
+            },
+            "BCC" => self.branch(!self.flag(C), addr),
+            "BCS" => self.branch(self.flag(C), addr),
+            "BNE" => self.branch(!self.flag(Z), addr),
+            "BEQ" => self.branch(self.flag(Z), addr),
+            "BPL" => self.branch(!self.flag(N), addr),
+            "BMI" => self.branch(self.flag(N), addr),
+            "BVC" => self.branch(!self.flag(V), addr),
+            "BVS" => self.branch(self.flag(V), addr),
+            "BRK" => {
+                // The byte after brk is skipped.
+                let ret = self.pc.wrapping_add(1);
+                self.push((ret >> 8) as u8);
+                self.push(ret as u8);
+                self.push(self.status | B | U);
+                self.set(I, true);
+                self.pc = self.read_u16(0xfffe);
+            },
+            "CLC" => self.set(C, false),
+            "CLD" => self.set(D, false),
+            "CLI" => self.set(I, false),
+            "CLV" => self.set(V, false),
+            "SEC" => self.set(C, true),
+            "SED" => self.set(D, true),
+            "SEI" => self.set(I, true),
+            "CMP" => { let val = self.read(addr); self.compare(self.a, val) },
+            "CPX" => { let val = self.read(addr); self.compare(self.x, val) },
+            "CPY" => { let val = self.read(addr); self.compare(self.y, val) },
+            "DEC" => { let val = self.read(addr).wrapping_sub(1); self.write(addr, val); self.set_nz(val) },
+            "INC" => { let val = self.read(addr).wrapping_add(1); self.write(addr, val); self.set_nz(val) },
+            "DEX" => { self.x = self.x.wrapping_sub(1); self.set_nz(self.x) },
+            "DEY" => { self.y = self.y.wrapping_sub(1); self.set_nz(self.y) },
+            "INX" => { self.x = self.x.wrapping_add(1); self.set_nz(self.x) },
+            "INY" => { self.y = self.y.wrapping_add(1); self.set_nz(self.y) },
+            "JMP" => self.pc = addr,
+            "JSR" => {
+                let ret = self.pc.wrapping_sub(1);
+                self.push((ret >> 8) as u8);
+                self.push(ret as u8);
+                self.pc = addr;
+            },
+            "RTS" => {
+                let lo = self.pull();
+                let hi = self.pull();
+                self.pc = u16::from_le_bytes([lo, hi]).wrapping_add(1);
+            },
+            "RTI" => {
+                self.status = (self.pull() & !B) | U;
+                let lo = self.pull();
+                let hi = self.pull();
+                self.pc = u16::from_le_bytes([lo, hi]);
+            },
+            "LDA" => { self.a = self.read(addr); self.set_nz(self.a) },
+            "LDX" => { self.x = self.read(addr); self.set_nz(self.x) },
+            "LDY" => { self.y = self.read(addr); self.set_nz(self.y) },
+            "STA" => self.write(addr, self.a),
+            "STX" => self.write(addr, self.x),
+            "STY" => self.write(addr, self.y),
+            "NOP" => (),
+            "PHA" => self.push(self.a),
+            "PHP" => self.push(self.status | B | U),
+            "PLA" => { self.a = self.pull(); self.set_nz(self.a) },
+            "PLP" => self.status = (self.pull() & !B) | U,
+            "TAX" => { self.x = self.a; self.set_nz(self.x) },
+            "TAY" => { self.y = self.a; self.set_nz(self.y) },
+            "TSX" => { self.x = self.sp; self.set_nz(self.x) },
+            "TXA" => { self.a = self.x; self.set_nz(self.a) },
+            "TXS" => self.sp = self.x,
+            "TYA" => { self.a = self.y; self.set_nz(self.a) },
+            _ => unreachable!("{} is decoded but not implemented", mnemonic),
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_official_opcode_count() {
+        assert_eq!((0..=255).filter(|op| is_official(*op)).count(), 151);
+    }
+
+    #[test]
+    fn test_adc_sbc() {
+        let mut cpu = MOS6502Reference::new();
+        cpu.memory.extend([(0x0000, 0x69), (0x0001, 0x50), (0x0002, 0xe9), (0x0003, 0xf0)]);
+        cpu.a = 0x50;
+
+        cpu.step(); // 0x50 + 0x50 = 0xa0
+        assert_eq!(cpu.a, 0xa0);
+        assert!(cpu.flag(V) && cpu.flag(N) && !cpu.flag(C));
+
+        cpu.step(); // 0xa0 - 0xf0 - 1 = 0xaf with borrow
+        assert_eq!(cpu.a, 0xaf);
+        assert!(!cpu.flag(V) && cpu.flag(N) && !cpu.flag(C));
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_wrap() {
+        let mut cpu = MOS6502Reference::new();
+        cpu.memory.extend([(0x0000, 0x6c), (0x0001, 0xff), (0x0002, 0x02), (0x02ff, 0x34), (0x0200, 0x12)]);
+
+        cpu.step();
+        assert_eq!(cpu.pc, 0x1234);
+    }
+
+    #[test]
+    fn test_jsr_rts() {
+        let mut cpu = MOS6502Reference::new();
+        cpu.memory.extend([(0x8000, 0x20), (0x8001, 0x00), (0x8002, 0x90), (0x9000, 0x60)]);
+        cpu.pc = 0x8000;
+
+        cpu.step();
+        assert_eq!(cpu.pc, 0x9000);
+        assert_eq!((cpu.read(0x01ff), cpu.read(0x01fe)), (0x80, 0x02));
+
+        cpu.step();
+        assert_eq!(cpu.pc, 0x8003);
+        assert_eq!(cpu.sp, 0xff);
+    }
+}
@@ -1,29 +1,86 @@
 use std::fs;
+use std::path::Path;
+
+use crate::patch::apply_ips_patch;
+#[cfg(feature = "gzip-rom")]
+use std::io::Read;
+#[cfg(feature = "gzip-rom")]
+use flate2::read::GzDecoder;
 
 pub trait Rom {
-    fn load(&mut self, raw: &Vec<u8>, trainer: bool) -> Result<(), String>;
+    fn load(&mut self, raw: &[u8], trainer: bool) -> Result<(), String>;
     fn prg_read(&self, address: u16) -> u8;
     fn chr_read(&self, address: u16) -> u8;
+    // Some mappers (e.g. MMC5) put registers or RAM in the expansion area (0x4020..=0x5fff), and
+    // most cartridges have PRG RAM at 0x6000..=0x7fff. Those override maps_address to claim the
+    // addresses they handle in prg_read and prg_write, e.g. only while the RAM is enabled.
+    fn maps_address(&self, address: u16) -> bool { address >= 0x8000 }
+    // Mappers with registers handle writes to the cartridge here. Returns false if the write
+    // went to read-only memory.
+    fn prg_write(&mut self, _address: u16, _val: u8) -> bool { false }
+    // Mappers with a counter (e.g. counting scanlines) are ticked with the cpu, and pull the IRQ
+    // line while irq is true. It is cleared by writing to their registers.
+    fn tick(&mut self, _cycles: u16) {}
+    fn irq(&self) -> bool { false }
 }
 
 
-pub fn rom_reader() -> Result<Box<dyn Rom>, String> {
-    let raw: Vec<u8> = match fs::read("./cartridges/nestest.nes") {
+#[cfg(feature = "gzip-rom")]
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+pub fn rom_reader(path: impl AsRef<Path>, patch: Option<&[u8]>) -> Result<Box<dyn Rom>, String> {
+    let path = path.as_ref();
+    let raw: Vec<u8> = match fs::read(path) {
         Ok(raw) => raw,
-        Err(e) => return Err(e.to_string()),
+        Err(e) => return Err(format!("{} ({})", e, path.display())),
     };
 
-    if (raw[0] != ('N' as u8)) || (raw[1] != ('E' as u8)) || (raw[2] != ('S' as u8)) { panic!("Can't recognize iNES header!"); }
+    #[cfg(feature = "gzip-rom")]
+    let raw = gunzip(path, raw)?;
+
+    rom_from_bytes(&raw, patch)
+}
+
+// Decompresses the cartridge if it starts with the gzip magic bytes. The extension is only used
+// to report files that claim to be compressed but are not, as some tools mis-name .nes.gz files.
+#[cfg(feature = "gzip-rom")]
+fn gunzip(path: &Path, raw: Vec<u8>) -> Result<Vec<u8>, String> {
+    if !raw.starts_with(&GZIP_MAGIC) {
+        if path.extension().is_some_and(|ext| ext == "gz") {
+            return Err(format!("{} has a .gz extension, but it is not gzip compressed.", path.display()));
+        }
+        return Ok(raw);
+    }
+
+    let mut decompressed: Vec<u8> = Vec::new();
+    match GzDecoder::new(&raw[..]).read_to_end(&mut decompressed) {
+        Ok(_) => Ok(decompressed),
+        Err(e) => Err(format!("Failed to decompress {} ({}).", path.display(), e)),
+    }
+}
+
+// Parses an INES cartridge. If an IPS patch is given, it is applied before parsing.
+pub fn rom_from_bytes(raw: &[u8], patch: Option<&[u8]>) -> Result<Box<dyn Rom>, String> {
+    let patched: Vec<u8>;
+    let raw: &[u8] = match patch {
+        Some(patch) => {
+            patched = apply_ips_patch(raw, patch).map_err(|e| e.to_string())?;
+            &patched
+        },
+        None => raw,
+    };
 
     if raw.len() < 16 { return Err(String::from("Invalid INES header...")) }
 
+    if !raw.starts_with(b"NES\x1a") { return Err(String::from("Can't recognize iNES header!")); }
+
     let prg_rom_chunks = raw[4];
-    let _chr_rom_chunks = raw[5];
+    let chr_rom_chunks = raw[5];
     let trainer: bool = raw[6] & 0b100 != 0;
     let rom_mapper = ((raw[6] & 0b1111_0000) >> 4) | (raw[7] & 0b1111_0000);
-    let ines_version = if (raw[7] & 0b1100 >> 1) == 0b10 { 2 } else { 1 };
+    let ines_version = if (raw[7] & 0b1100) == 0b1000 { 2 } else { 1 };
 
-    if ines_version != 1 { panic!("Only INES version 1 is supported."); }
+    if ines_version != 1 { return Err(String::from("Only INES version 1 is supported.")); }
 
     let mut rom: Box<dyn Rom> = match rom_mapper {
         0 => {
@@ -33,20 +90,30 @@ pub fn rom_reader() -> Result<Box<dyn Rom>, String> {
                 _ => return Err(format!("NROM does not support {:?} prg chunks!", prg_rom_chunks)),
             }
         },
+        1 => {
+            if prg_rom_chunks == 0 { return Err(String::from("MMC1 needs at least one prg chunk!")); }
+            Box::new(Mmc1::new(prg_rom_chunks, chr_rom_chunks))
+        },
         _ => {
-            return Err(String::from(format!("INES rom mapper {:?} is not supported.", rom_mapper)))
+            return Err(format!("INES rom mapper {:?} is not supported.", rom_mapper))
         }
     };
 
-    match rom.load(&raw, trainer) {
+    match rom.load(raw, trainer) {
         Ok(()) => Ok(rom),
         Err(e) => Err(e),
     }
 }
 
+// Cartridge RAM (8 KiB) in the address space of the cpu.
+const PRG_RAM: std::ops::RangeInclusive<u16> = 0x6000..=0x7fff;
+
+// Only Family Basic has PRG RAM on an NROM board, but games not using it don't notice it, so every
+// NROM gets it, for test roms reporting their results there.
 pub struct Nrom128 {
     prg_rom: [u8; 0x4000],
     chr_rom: [u8; 0x2000],
+    prg_ram: [u8; 0x2000],
 }
 
 impl Nrom128 {
@@ -55,12 +122,13 @@ impl Nrom128 {
         Self {
             prg_rom: [0; 0x4000],
             chr_rom: [0; 0x2000],
+            prg_ram: [0; 0x2000],
         }
     }
 }
 
 impl Rom for Nrom128 {
-    fn load(&mut self, raw: &Vec<u8>, trainer: bool) -> Result<(), String> {
+    fn load(&mut self, raw: &[u8], trainer: bool) -> Result<(), String> {
         let offset: usize = if trainer {512 + 16} else {16};
         if raw.len() != offset + 0x6000 {
             return Err(String::from("The size of the cartridge does not match the header information."))
@@ -71,6 +139,7 @@ impl Rom for Nrom128 {
     }
 
     fn prg_read(&self, address: u16) -> u8 {
+        if PRG_RAM.contains(&address) { return self.prg_ram[(address - 0x6000) as usize]; }
         let source_addr = (address - 0x8000) % 0x4000;
         self.prg_rom[source_addr as usize]
     }
@@ -78,11 +147,20 @@ impl Rom for Nrom128 {
     fn chr_read(&self, address: u16) -> u8 {
         self.chr_rom[address as usize]
     }
+
+    fn maps_address(&self, address: u16) -> bool { address >= 0x6000 }
+
+    fn prg_write(&mut self, address: u16, val: u8) -> bool {
+        if !PRG_RAM.contains(&address) { return false; }
+        self.prg_ram[(address - 0x6000) as usize] = val;
+        true
+    }
 }
 
 pub struct Nrom256 {
     prg_rom: [u8; 0x8000],
     chr_rom: [u8; 0x2000],
+    prg_ram: [u8; 0x2000],
 }
 
 impl Nrom256 {
@@ -91,15 +169,16 @@ impl Nrom256 {
         Self {
             prg_rom: [0; 0x8000],
             chr_rom: [0; 0x2000],
+            prg_ram: [0; 0x2000],
         }
     }
 }
 
 impl Rom for Nrom256 {
 
-    fn load(&mut self, raw: &Vec<u8>, trainer: bool) -> Result<(), String> {
+    fn load(&mut self, raw: &[u8], trainer: bool) -> Result<(), String> {
         let offset: usize = if trainer {512 + 16} else {16};
-        if raw.len() != offset + 0x6000 {
+        if raw.len() != offset + 0xa000 {
             return Err(String::from("The size of the cartridge does not match the header information."))
         }
         self.prg_rom = raw[offset..(0x8000 + offset)].try_into().expect("slice with incorrect length");
@@ -108,14 +187,137 @@ impl Rom for Nrom256 {
     }
 
     fn prg_read(&self, address: u16) -> u8 {
-        self.prg_rom[address as usize]
+        if PRG_RAM.contains(&address) { return self.prg_ram[(address - 0x6000) as usize]; }
+        self.prg_rom[(address - 0x8000) as usize]
     }
 
     fn chr_read(&self, address: u16) -> u8 {
         self.chr_rom[address as usize]
     }
+
+    fn maps_address(&self, address: u16) -> bool { address >= 0x6000 }
+
+    fn prg_write(&mut self, address: u16, val: u8) -> bool {
+        if !PRG_RAM.contains(&address) { return false; }
+        self.prg_ram[(address - 0x6000) as usize] = val;
+        true
+    }
+}
+
+// MMC1 has a serial interface: writes to 0x8000..=0xffff shift bit 0 of the value into a shift
+// register, and the fifth write stores it in the register selected by address bits 13-14. Writing a
+// value with bit 7 set resets the shift register.
+// See: https://www.nesdev.org/wiki/MMC1
+const MMC1_SHIFT_RESET: u8 = 0b1_0000; // the 1 reaches bit 0 when the register is full
+
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>, // CHR RAM if the cartridge has no CHR ROM
+    prg_ram: [u8; 0x2000],
+    shift: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    fn new(prg_rom_chunks: u8, chr_rom_chunks: u8) -> Self {
+        println!("INFO\tInitializing MMC1...");
+        Self {
+            prg_rom: vec![0; prg_rom_chunks as usize * 0x4000],
+            chr: vec![0; chr_rom_chunks.max(1) as usize * 0x2000],
+            prg_ram: [0; 0x2000],
+            shift: MMC1_SHIFT_RESET,
+            control: 0b0_1100, // PRG mode 3, fixed last bank
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn prg_banks(&self) -> usize {
+        self.prg_rom.len() / 0x4000
+    }
+
+    // Bit 4 of the PRG bank register disables the PRG RAM (MMC1B and later).
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0b1_0000 == 0
+    }
+}
+
+impl Rom for Mmc1 {
+    fn load(&mut self, raw: &[u8], trainer: bool) -> Result<(), String> {
+        let offset: usize = if trainer {512 + 16} else {16};
+        let prg_end = offset + self.prg_rom.len();
+        let chr_rom_size = raw[5] as usize * 0x2000;
+        if raw.len() != prg_end + chr_rom_size {
+            return Err(String::from("The size of the cartridge does not match the header information."))
+        }
+        self.prg_rom.copy_from_slice(&raw[offset..prg_end]);
+        self.chr[..chr_rom_size].copy_from_slice(&raw[prg_end..]);
+        Ok(())
+    }
+
+    fn prg_read(&self, address: u16) -> u8 {
+        if PRG_RAM.contains(&address) { return self.prg_ram[(address - 0x6000) as usize]; }
+        let upper = address >= 0xc000;
+        let bank = (self.prg_bank & 0b1111) as usize;
+        let bank = match (self.control >> 2) & 0b11 {
+            0 | 1 => (bank & !1) + upper as usize,              // 32 KiB switched
+            2 => if upper { bank } else { 0 },                  // first bank fixed at 0x8000
+            _ => if upper { self.prg_banks() - 1 } else { bank }, // last bank fixed at 0xc000
+        };
+        self.prg_rom[(bank % self.prg_banks()) * 0x4000 + (address as usize & 0x3fff)]
+    }
+
+    fn chr_read(&self, address: u16) -> u8 {
+        let upper = address >= 0x1000;
+        // Banks are counted in 4 KiB units.
+        let bank = if self.control & 0b1_0000 == 0 {
+            (self.chr_bank_0 & !1) as usize + upper as usize // 8 KiB switched
+        } else if upper {
+            self.chr_bank_1 as usize
+        } else {
+            self.chr_bank_0 as usize
+        };
+        self.chr[(bank * 0x1000 + (address as usize & 0x0fff)) % self.chr.len()]
+    }
+
+    fn maps_address(&self, address: u16) -> bool {
+        address >= 0x8000 || PRG_RAM.contains(&address) && self.prg_ram_enabled()
+    }
+
+    fn prg_write(&mut self, address: u16, val: u8) -> bool {
+        if PRG_RAM.contains(&address) {
+            if !self.prg_ram_enabled() { return false; }
+            self.prg_ram[(address - 0x6000) as usize] = val;
+            return true;
+        }
+        if address < 0x8000 { return false; }
+
+        if val & 0b1000_0000 != 0 {
+            self.shift = MMC1_SHIFT_RESET;
+            self.control |= 0b0_1100;
+            return true;
+        }
+
+        let full = self.shift & 1 == 1;
+        self.shift = (self.shift >> 1) | ((val & 1) << 4);
+        if full {
+            match address {
+                0x8000..=0x9fff => self.control = self.shift,
+                0xa000..=0xbfff => self.chr_bank_0 = self.shift,
+                0xc000..=0xdfff => self.chr_bank_1 = self.shift,
+                _ => self.prg_bank = self.shift,
+            }
+            self.shift = MMC1_SHIFT_RESET;
+        }
+        true
+    }
 }
 
+#[derive(Default)]
 pub struct EmptyRom;
 
 impl EmptyRom {
@@ -124,14 +326,191 @@ impl EmptyRom {
     }
 }
 
+// Stands in for a missing cartridge. It maps no addresses, so reads from the cartridge space are
+// open bus.
 impl Rom for EmptyRom {
-    fn load(&mut self, _raw: &Vec<u8>, _trainer: bool) -> Result<(), String> {
-        panic!("Empty ROM.")
+    fn load(&mut self, _raw: &[u8], _trainer: bool) -> Result<(), String> {
+        Err(String::from("Empty ROM."))
+    }
+    fn prg_read(&self, _address: u16) -> u8 { 0 }
+    fn chr_read(&self, _address: u16) -> u8 { 0 }
+    fn maps_address(&self, _address: u16) -> bool { false }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_rom::TestRomBuilder;
+    #[cfg(feature = "gzip-rom")]
+    use flate2::write::GzEncoder;
+    #[cfg(feature = "gzip-rom")]
+    use flate2::Compression;
+    #[cfg(feature = "gzip-rom")]
+    use std::io::Write;
+
+    // NROM128 cartridge with a recognisable pattern in the PRG ROM.
+    fn synthetic_rom() -> Vec<u8> {
+        let prg: Vec<u8> = (0..0x4000).map(|i| (i % 251) as u8).collect();
+        TestRomBuilder::new().prg_data(&prg).build()
+    }
+
+    #[test]
+    fn ips_patched_rom() {
+        // Replaces the first two bytes of the PRG ROM, located right after the header.
+        let patch: Vec<u8> = [b"PATCH".as_slice(), &[0x00, 0x00, 0x10, 0x00, 0x02, 0xea, 0xea], b"EOF"].concat();
+        let rom = rom_from_bytes(&synthetic_rom(), Some(&patch)).unwrap();
+
+        assert_eq!(rom.prg_read(0x8000), 0xea);
+        assert_eq!(rom.prg_read(0x8001), 0xea);
+        assert_eq!(rom.prg_read(0x8002), 2);
+    }
+
+    #[test]
+    fn malformed_headers() {
+        assert!(rom_from_bytes(&[], None).is_err());
+        assert!(rom_from_bytes(b"NES\x1a", None).is_err());
+        assert!(rom_from_bytes(&[0; 0x6010], None).is_err());
+        assert!(rom_from_bytes(&[0xff; 0x6010], None).is_err());
+
+        let mut ines2 = synthetic_rom();
+        ines2[7] = 0b0000_1000;
+        assert!(rom_from_bytes(&ines2, None).is_err());
+
+        let truncated = synthetic_rom();
+        assert!(rom_from_bytes(&truncated[..0x1000], None).is_err());
+    }
+
+    #[test]
+    fn invalid_ips_patch() {
+        assert!(rom_from_bytes(&synthetic_rom(), Some(b"PATCH")).is_err());
+    }
+
+    // Writes the 5 bit value to an MMC1 register, one bit at a time.
+    fn mmc1_write(rom: &mut Box<dyn Rom>, address: u16, val: u8) {
+        for i in 0..5 {
+            assert!(rom.prg_write(address, (val >> i) & 1));
+        }
+    }
+
+    fn mmc1_rom() -> Box<dyn Rom> {
+        // The first byte of every 16 KiB PRG bank and 4 KiB CHR bank is its number.
+        let prg: Vec<u8> = (0..4 * 0x4000).map(|i| if i % 0x4000 == 0 { (i / 0x4000) as u8 } else { 0xea }).collect();
+        let chr: Vec<u8> = (0..2 * 0x2000).map(|i| if i % 0x1000 == 0 { (i / 0x1000) as u8 } else { 0 }).collect();
+        let raw = TestRomBuilder::new().mapper(1).prg_banks(4).chr_banks(2).prg_data(&prg).chr_data(&chr).build();
+        rom_from_bytes(&raw, None).unwrap()
+    }
+
+    #[test]
+    fn mmc1_prg_banks() {
+        let mut rom = mmc1_rom();
+        assert_eq!((rom.prg_read(0x8000), rom.prg_read(0xc000)), (0, 3));
+
+        mmc1_write(&mut rom, 0xe000, 2);
+        assert_eq!((rom.prg_read(0x8000), rom.prg_read(0xc000)), (2, 3));
+
+        // PRG mode 2, the first bank is fixed at 0x8000.
+        mmc1_write(&mut rom, 0x8000, 0b0_1000);
+        assert_eq!((rom.prg_read(0x8000), rom.prg_read(0xc000)), (0, 2));
+
+        // PRG mode 0, 32 KiB banks ignore the lowest bit.
+        mmc1_write(&mut rom, 0x8000, 0b0_0000);
+        mmc1_write(&mut rom, 0xe000, 3);
+        assert_eq!((rom.prg_read(0x8000), rom.prg_read(0xc000)), (2, 3));
+    }
+
+    #[test]
+    fn mmc1_shift_register_reset() {
+        let mut rom = mmc1_rom();
+        rom.prg_write(0xe000, 1);
+        rom.prg_write(0xe000, 1);
+        rom.prg_write(0xe000, 0x80);
+        mmc1_write(&mut rom, 0xe000, 1);
+        assert_eq!(rom.prg_read(0x8000), 1);
+    }
+
+    #[test]
+    fn mmc1_chr_banks() {
+        let mut rom = mmc1_rom();
+        assert_eq!((rom.chr_read(0x0000), rom.chr_read(0x1000)), (0, 1));
+
+        // 8 KiB mode ignores the lowest bit.
+        mmc1_write(&mut rom, 0xa000, 3);
+        assert_eq!((rom.chr_read(0x0000), rom.chr_read(0x1000)), (2, 3));
+
+        // 4 KiB mode.
+        mmc1_write(&mut rom, 0x8000, 0b1_1100);
+        mmc1_write(&mut rom, 0xc000, 1);
+        assert_eq!((rom.chr_read(0x0000), rom.chr_read(0x1000)), (3, 1));
+    }
+
+    #[test]
+    fn prg_ram() {
+        let mut rom = rom_from_bytes(&synthetic_rom(), None).unwrap();
+        assert!(rom.maps_address(0x6000));
+        assert!(rom.prg_write(0x7fff, 0x42));
+        assert_eq!(rom.prg_read(0x7fff), 0x42);
+
+        // MMC1 can disable it.
+        let mut rom = mmc1_rom();
+        assert!(rom.prg_write(0x6000, 0x42));
+        mmc1_write(&mut rom, 0xe000, 0b1_0000);
+        assert!(!rom.maps_address(0x6000));
+        assert!(!rom.prg_write(0x6000, 0x00));
+        mmc1_write(&mut rom, 0xe000, 0b0_0000);
+        assert_eq!(rom.prg_read(0x6000), 0x42);
+    }
+
+    #[test]
+    fn rom_from_path() {
+        let path = std::env::temp_dir().join(format!("res_rom_from_path_{}.nes", std::process::id()));
+        fs::write(&path, synthetic_rom()).unwrap();
+        let rom = rom_reader(&path, None);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rom.unwrap().prg_read(0x8002), 2);
+
+        let missing = rom_reader("./cartridges/missing.nes", None);
+        assert!(missing.is_err_and(|e| e.contains("missing.nes")));
     }
-    fn prg_read(&self, _address: u16) -> u8 {
-        panic!("Empty ROM.");
+
+    #[cfg(feature = "gzip-rom")]
+    fn compress(raw: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(raw).unwrap();
+        encoder.finish().unwrap()
     }
-    fn chr_read(&self, _address: u16) -> u8 {
-        panic!("Empty ROM.");
+
+    #[cfg(feature = "gzip-rom")]
+    #[test]
+    fn gzip_rom_loads_identically() {
+        let raw = synthetic_rom();
+        let compressed = compress(&raw);
+        assert_eq!(compressed[0..2], GZIP_MAGIC);
+
+        let plain = rom_from_bytes(&raw, None).unwrap();
+        let unzipped = rom_from_bytes(&gunzip(Path::new("synthetic.nes.gz"), compressed).unwrap(), None).unwrap();
+
+        for addr in 0x8000..=0xffff {
+            assert_eq!(plain.prg_read(addr), unzipped.prg_read(addr));
+        }
+    }
+
+    #[cfg(feature = "gzip-rom")]
+    #[test]
+    fn gzip_rom_with_nes_extension() {
+        let raw = synthetic_rom();
+        assert_eq!(gunzip(Path::new("synthetic.nes"), compress(&raw)).unwrap(), raw);
+    }
+
+    #[cfg(feature = "gzip-rom")]
+    #[test]
+    fn plain_rom_is_left_untouched() {
+        let raw = synthetic_rom();
+        assert_eq!(gunzip(Path::new("synthetic.nes"), raw.clone()).unwrap(), raw);
+    }
+
+    #[cfg(feature = "gzip-rom")]
+    #[test]
+    fn gz_extension_without_magic() {
+        assert!(gunzip(Path::new("synthetic.nes.gz"), synthetic_rom()).is_err());
     }
 }
@@ -0,0 +1,116 @@
+// Assembles synthetic INES 1.0 cartridges for tests, e.g.
+//
+//     let rom = TestRomBuilder::new().mapper(0).prg_banks(1).prg_data(&[0xa9, 0x42, 0x00]).build();
+//
+// The PRG and CHR data is placed at the start of the respective region, the rest is zero padded.
+
+pub struct TestRomBuilder {
+    mapper: u8,
+    prg_banks: u8,
+    chr_banks: u8,
+    prg_data: Vec<u8>,
+    chr_data: Vec<u8>,
+}
+
+impl TestRomBuilder {
+    pub fn new() -> Self {
+        Self {
+            mapper: 0,
+            prg_banks: 1,
+            chr_banks: 1,
+            prg_data: Vec::new(),
+            chr_data: Vec::new(),
+        }
+    }
+
+    pub fn mapper(mut self, n: u8) -> Self {
+        self.mapper = n;
+        self
+    }
+
+    // Number of 16 KiB PRG ROM banks.
+    pub fn prg_banks(mut self, n: u8) -> Self {
+        self.prg_banks = n;
+        self
+    }
+
+    // Number of 8 KiB CHR ROM banks.
+    pub fn chr_banks(mut self, n: u8) -> Self {
+        self.chr_banks = n;
+        self
+    }
+
+    pub fn prg_data(mut self, data: &[u8]) -> Self {
+        self.prg_data = data.to_vec();
+        self
+    }
+
+    pub fn chr_data(mut self, data: &[u8]) -> Self {
+        self.chr_data = data.to_vec();
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut raw: Vec<u8> = vec![
+            b'N', b'E', b'S', 0x1a,
+            self.prg_banks,
+            self.chr_banks,
+            (self.mapper & 0b0000_1111) << 4,
+            self.mapper & 0b1111_0000,
+        ];
+        raw.resize(16, 0);
+
+        let mut prg: Vec<u8> = vec![0; self.prg_banks as usize * 0x4000];
+        assert!(self.prg_data.len() <= prg.len(), "PRG data does not fit into {} banks.", self.prg_banks);
+        prg[..self.prg_data.len()].copy_from_slice(&self.prg_data);
+
+        let mut chr: Vec<u8> = vec![0; self.chr_banks as usize * 0x2000];
+        assert!(self.chr_data.len() <= chr.len(), "CHR data does not fit into {} banks.", self.chr_banks);
+        chr[..self.chr_data.len()].copy_from_slice(&self.chr_data);
+
+        raw.extend(prg);
+        raw.extend(chr);
+        raw
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::rom_from_bytes;
+
+    #[test]
+    fn test_header() {
+        let raw = TestRomBuilder::new().mapper(0x12).prg_banks(2).chr_banks(0).build();
+        assert_eq!(raw[0..8], [b'N', b'E', b'S', 0x1a, 2, 0, 0x20, 0x10]);
+        assert_eq!(raw.len(), 16 + 2 * 0x4000);
+    }
+
+    #[test]
+    fn test_nrom128_loads() {
+        let raw = TestRomBuilder::new().mapper(0).prg_banks(1).prg_data(&[0xa9, 0x42, 0x00]).build();
+        let rom = rom_from_bytes(&raw, None).unwrap();
+
+        assert_eq!(rom.prg_read(0x8000), 0xa9);
+        assert_eq!(rom.prg_read(0x8001), 0x42);
+        assert_eq!(rom.prg_read(0xc001), 0x42); // mirrored
+    }
+
+    #[test]
+    fn test_nrom256_loads() {
+        let mut prg: Vec<u8> = vec![0; 0x8000];
+        prg[0x7ffc] = 0x00;
+        prg[0x7ffd] = 0x80;
+        let raw = TestRomBuilder::new().prg_banks(2).prg_data(&prg).chr_data(&[0x55]).build();
+        let rom = rom_from_bytes(&raw, None).unwrap();
+
+        assert_eq!(rom.prg_read(0xfffc), 0x00);
+        assert_eq!(rom.prg_read(0xfffd), 0x80);
+        assert_eq!(rom.chr_read(0x0000), 0x55);
+    }
+
+    #[test]
+    fn test_unsupported_mapper() {
+        assert!(rom_from_bytes(&TestRomBuilder::new().mapper(4).build(), None).is_err());
+    }
+}
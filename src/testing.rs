@@ -0,0 +1,129 @@
+// A scripted bus for tests. Reads are answered from the read targets set up beforehand, and
+// writes are checked against the write targets: any other access panics, so a test fails as soon
+// as the code under test touches memory it shouldn't.
+
+use std::collections::HashMap;
+
+use crate::bus::Mem;
+
+#[derive(Debug, Default)]
+pub struct TestBus {
+    read_targets: HashMap<u16, u8>,
+    write_targets: HashMap<u16, u8>,
+    written: HashMap<u16, u8>,
+    pub nmi: bool,
+    pub irq: bool,
+    pub stall: u16,
+    // Every bus access in order, as (write, address, value).
+    pub accesses: Vec<(bool, u16, u8)>,
+    // Cycles ticked so far, and when each access happened.
+    pub clock: u64,
+    pub access_clock: Vec<u64>,
+}
+
+impl TestBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_read_target(&mut self, addr: u16, val: u8) {
+        self.read_targets.insert(addr, val);
+    }
+
+    // Little endian, like the cpu reads it.
+    pub fn set_read_u16_target(&mut self, addr: u16, val: u16) {
+        let low: u8 = (val & 0xff) as u8;
+        let high: u8 = (val >> 8) as u8;
+        self.read_targets.insert(addr, low);
+        self.read_targets.insert(addr.wrapping_add(1), high);
+    }
+
+    pub fn set_vector_read_target(&mut self, addr: u16, values: Vec<u8>) {
+        for (offset, val) in values.into_iter().enumerate() {
+            self.read_targets.insert(addr.wrapping_add(offset as u16), val);
+        }
+    }
+
+    pub fn set_write_target(&mut self, addr: u16, val: u8) {
+        self.write_targets.insert(addr, val);
+    }
+}
+
+impl Mem for TestBus {
+    fn size() -> usize {
+        u16::MAX as usize + 1
+    }
+    // Addresses that were actually written.
+    fn probe(&self, addr: u16) -> Option<u8> {
+        self.written.get(&addr).copied()
+    }
+    fn poll_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi)
+    }
+    fn poll_irq(&mut self) -> bool {
+        self.irq
+    }
+    fn take_stall(&mut self) -> u16 {
+        std::mem::take(&mut self.stall)
+    }
+    fn read(&mut self, addr: u16) -> u8 {
+        let val = match self.read_targets.get(&addr) {
+            Some(val) => *val,
+            None => panic!("Method trying to read from forbidden memory (addr: {:x})", addr),
+        };
+        self.accesses.push((false, addr, val));
+        self.access_clock.push(self.clock);
+        val
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        let result: Option<&u8> = self.write_targets.get(&addr);
+        // Read-modify-write instructions write back the value they read first.
+        let unchanged = self.read_targets.get(&addr) == Some(&val);
+        match result {
+            Some(_) if unchanged => (),
+            Some(expected) => {
+                if *expected != val { panic!("Method trying to write invalid data(expected: {:b}, got: {:b})", *expected, val); }
+                self.written.insert(addr, val);
+            },
+            None => panic!("Method trying to write to forbidden memory(addr: {:x}, val: {:b})", addr, val),
+        }
+        self.accesses.push((true, addr, val));
+        self.access_clock.push(self.clock);
+    }
+    fn tick(&mut self, cycles: u16) {
+        self.clock += cycles as u64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_targets() {
+        let mut bus = TestBus::new();
+        bus.set_read_u16_target(0xfffc, 0x8000);
+        bus.set_write_target(0x0200, 0x42);
+        bus.tick(2);
+        assert_eq!(bus.read(0xfffd), 0x80);
+        bus.write(0x0200, 0x42);
+        assert_eq!(bus.probe(0x0200), Some(0x42));
+        assert_eq!(bus.probe(0xfffd), None);
+        assert_eq!(bus.accesses, [(false, 0xfffd, 0x80), (true, 0x0200, 0x42)]);
+        assert_eq!(bus.access_clock, [2, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "forbidden memory")]
+    fn test_unexpected_read() {
+        TestBus::new().read(0x0000);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid data")]
+    fn test_unexpected_write() {
+        let mut bus = TestBus::new();
+        bus.set_write_target(0x0000, 0x01);
+        bus.write(0x0000, 0x02);
+    }
+}
@@ -0,0 +1,131 @@
+// Runs random instructions through both the CPU and the reference implementation, and compares
+// the registers and memory afterwards.
+
+use crate::bus::SparseMemory;
+use crate::cpu::cpu::{CpuState, CPU};
+use crate::reference_cpu::{is_official, MOS6502Reference};
+use rand::prelude::*;
+
+const TRIALS: usize = 3000;
+
+// Opcodes the CPU is known to get wrong. Remove them from here as they get fixed.
+const KNOWN_DIFFERENCES: &[u8] = &[];
+
+struct Trial {
+    a: u8,
+    x: u8,
+    y: u8,
+    sp: u8,
+    status: u8,
+    pc: u16,
+    memory: Vec<(u16, u8)>,
+}
+
+fn random_trial(rng: &mut ThreadRng, opcode: u8) -> Trial {
+    let pc: u16 = rng.gen_range(0x0200..0xfff0);
+    let mut memory: Vec<(u16, u8)> = Vec::new();
+
+    // The first 1 KiB of RAM is fully populated. Half of the zero page bytes and operand high bytes
+    // point back into it, so that indirect and absolute accesses mostly read random values too.
+    let high_byte = |rng: &mut ThreadRng| if rng.gen() { rng.gen_range(0x00..0x04) } else { rng.gen() };
+    for addr in 0x0000..0x0400 {
+        let val: u8 = if addr < 0x0100 { high_byte(rng) } else { rng.gen() };
+        memory.push((addr, val));
+    }
+    memory.push((pc, opcode));
+    memory.push((pc + 1, rng.gen()));
+    memory.push((pc + 2, high_byte(rng)));
+
+    Trial {
+        a: rng.gen(),
+        x: rng.gen(),
+        y: rng.gen(),
+        sp: rng.gen(),
+        status: rng.gen::<u8>() | 0b0010_0000,
+        pc,
+        memory,
+    }
+}
+
+fn run_cpu(trial: &Trial) -> CPU<SparseMemory> {
+    let mut memory = SparseMemory::new();
+    for (addr, val) in trial.memory.iter() {
+        memory.data.insert(*addr, *val);
+    }
+
+    let mut cpu = CPU::from_state(memory, CpuState {
+        register_a: trial.a,
+        register_x: trial.x,
+        register_y: trial.y,
+        stack_pointer: trial.sp,
+        status: trial.status,
+        program_counter: trial.pc,
+    });
+    cpu.step().unwrap();
+    cpu
+}
+
+fn run_reference(trial: &Trial) -> MOS6502Reference {
+    let mut cpu = MOS6502Reference::new();
+    cpu.memory.extend(trial.memory.iter().copied());
+    cpu.a = trial.a;
+    cpu.x = trial.x;
+    cpu.y = trial.y;
+    cpu.sp = trial.sp;
+    cpu.status = trial.status;
+    cpu.pc = trial.pc;
+    assert!(cpu.step());
+    cpu
+}
+
+// Describes how the two cpus differ after running the same trial, if they do.
+fn compare(cpu: &CPU<SparseMemory>, reference: &MOS6502Reference) -> Option<String> {
+    let registers = [
+        ("A", cpu.register_a as u16, reference.a as u16),
+        ("X", cpu.register_x as u16, reference.x as u16),
+        ("Y", cpu.register_y as u16, reference.y as u16),
+        ("SP", cpu.stack_pointer as u16, reference.sp as u16),
+        ("P", cpu.status as u16, reference.status as u16),
+        ("PC", cpu.program_counter, reference.pc),
+    ];
+    for (name, actual, expected) in registers {
+        if actual != expected {
+            return Some(format!("{} is {:#x}, expected {:#x}", name, actual, expected));
+        }
+    }
+
+    if cpu.memory().data != reference.memory {
+        let mut addresses: Vec<&u16> = cpu.memory().data.keys().chain(reference.memory.keys()).collect();
+        addresses.sort();
+        for addr in addresses {
+            let actual = cpu.memory().data.get(addr);
+            let expected = reference.memory.get(addr);
+            if actual != expected {
+                return Some(format!("memory at {:#06x} is {:x?}, expected {:x?}", addr, actual, expected));
+            }
+        }
+    }
+    None
+}
+
+#[test]
+fn test_cross_validate() {
+    let mut rng = rand::thread_rng();
+    let opcodes: Vec<u8> = (0..=255).filter(|op| is_official(*op) && !KNOWN_DIFFERENCES.contains(op)).collect();
+    let mut failures: Vec<String> = Vec::new();
+
+    for _ in 0..TRIALS {
+        let opcode = *opcodes.choose(&mut rng).unwrap();
+        let trial = random_trial(&mut rng, opcode);
+
+        if let Some(difference) = compare(&run_cpu(&trial), &run_reference(&trial)) {
+            failures.push(format!(
+                "opcode {:#04x} at {:#06x} (A: {:#x}, X: {:#x}, Y: {:#x}, SP: {:#x}, P: {:#010b}, operand: {:02x} {:02x}): {}",
+                opcode, trial.pc, trial.a, trial.x, trial.y, trial.sp, trial.status,
+                trial.memory[trial.memory.len() - 2].1, trial.memory[trial.memory.len() - 1].1, difference,
+            ));
+        }
+    }
+
+    assert!(failures.is_empty(), "{} of {} trials differ:\n{}", failures.len(), TRIALS, failures.join("\n"));
+}
@@ -0,0 +1 @@
+mod cross_validate;